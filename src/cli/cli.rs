@@ -0,0 +1,777 @@
+/*!
+ * Command Line
+ *
+ * The commands `remu` understands:
+ * - run: run a machine without a debugger, until it has run for --cycles, reaches a --break or --watch address, or the
+ *   CPU stops. Devices connected to the host take over the terminal, where Ctrl-] quits, unless run --headless.
+ *   It runs at the machine's clock rate, times --speed, or flat out with --unthrottled or --headless.
+ * - debug: debug a machine with the monitor on the terminal, a gdb server with --gdb [port], or the Debug Adapter
+ *   Protocol on standard input and output with --dap. monitor is another name for it, as the command was once called
+ * - disasm: disassemble a binary file
+ * - test: run the Klaus Dormann and Tom Harte CPU test suites that are in test-roms, or just one of them
+ * - map: print the memory map of a machine
+ *
+ * A machine is a preset or a configuration file chosen with --machine, and the BE6502 otherwise. Numbers are decimal,
+ * or hex after $ or 0x.
+ *
+ * The exit code of run says why it stopped: 0 at the cycle limit or on Ctrl-], 3 at a breakpoint, 4 at a watchpoint,
+ * 5 when a 65C02 executed STP, 6 on an illegal opcode and 7 on a bus error. Every command exits with 1 when it fails
+ * (such as when a file cannot be read or a test fails) and 2 when its arguments are wrong.
+ */
+
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::slice::Iter;
+
+use crate::cpu::cpu::*;
+use crate::cpu::disassembler::*;
+use crate::cpu::opcodes::*;
+use crate::dap::dap::*;
+use crate::devices::keyboard::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+use crate::emulator::breakpoints::*;
+use crate::emulator::emulator::*;
+use crate::emulator::throttle::*;
+use crate::emulator::trace::*;
+use crate::gdb::gdb::*;
+use crate::harness::klaus;
+use crate::harness::tom_harte;
+use crate::machine::machine::*;
+use crate::monitor::monitor::*;
+use crate::symbols::symbols::*;
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_BREAKPOINT: i32 = 3;
+pub const EXIT_WATCHPOINT: i32 = 4;
+pub const EXIT_STOPPED: i32 = 5;
+pub const EXIT_ILLEGAL_OPCODE: i32 = 6;
+pub const EXIT_BUS_ERROR: i32 = 7;
+
+// How many cycles run between looking for Ctrl-] and pacing to the clock
+const BATCH_CYCLES: u64 = 10_000;
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+const USAGE: &str = "\
+Usage: remu <command> [options]
+
+Commands:
+  run                   Run a machine until the cycle limit, a breakpoint, or the CPU stops
+  debug, monitor        Debug a machine with the monitor, gdb or a DAP client
+  disasm FILE           Disassemble a binary file
+  test [klaus|harte]    Run the CPU test suites
+  map                   Print the memory map of a machine
+  help                  Print this
+
+Machine options, for run, debug and map:
+  --machine NAME|FILE   be6502 (the default), apple1, kim1, generic, or a configuration file
+  --rom FILE            An image to load into the ROM region
+  --symbols FILE        Labels for the trace and the debugger (not for map)
+
+run:
+  --cycles N            Stop after N cycles
+  --trace FILE          Write every instruction executed to FILE
+  --break ADDRESS       Stop before running the instruction at ADDRESS (can be given more than once)
+  --watch ADDRESS       Stop after ADDRESS is written (can be given more than once)
+  --headless            Keep every device off the terminal, and run unthrottled unless given --speed
+  --speed X             Run at X times the machine's clock rate, such as 0.5 or 4 (the default is 1)
+  --unthrottled         Run as fast as possible
+
+debug:
+  --gdb [PORT]          Wait for gdb on 127.0.0.1 (port 1234), instead of starting the monitor
+  --dap                 Serve the Debug Adapter Protocol on standard input and output instead
+
+disasm:
+  --origin ADDRESS      Where the file is loaded, so that it ends at $FFFF by default
+  --start ADDRESS       The first address to disassemble (the origin)
+  --end ADDRESS         The last address to disassemble (the end of the file)
+  --model 6502|65c02    The CPU (6502)
+  --symbols FILE        Labels to show
+
+test:
+  --dir DIRECTORY       Where the files of klaus or harte are, instead of test-roms
+
+Numbers are decimal, or hex after $ or 0x.
+Exit codes: 0 success, 1 failure, 2 bad arguments.
+run exits with 3 at a breakpoint, 4 at a watchpoint, 5 on STP, 6 on an illegal opcode and 7 on a bus error.
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CliError(pub String);
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+type CliResult<T> = Result<T, CliError>;
+
+fn error<T>(message: String) -> CliResult<T> {
+    Err(CliError(message))
+}
+
+fn file_error(path: &Path, error: impl fmt::Display) -> CliError {
+    CliError(format!("{}: {}", path.display(), error))
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MachineOptions {
+    // A preset name or a configuration file
+    pub machine: Option<String>,
+    pub rom: Option<PathBuf>,
+    pub symbols: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frontend {
+    Monitor,
+    Gdb(u16),
+    Dap
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Suite {
+    All,
+    Klaus,
+    TomHarte
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run {
+        machine: MachineOptions,
+        cycles: Option<u64>,
+        trace: Option<PathBuf>,
+        breakpoints: Vec<u16>,
+        watchpoints: Vec<u16>,
+        headless: bool,
+        speed: Option<Speed>
+    },
+    Debug { machine: MachineOptions, frontend: Frontend },
+    Disasm {
+        file: PathBuf,
+        model: CpuModel,
+        origin: Option<u16>,
+        start: Option<u16>,
+        end: Option<u16>,
+        symbols: Option<PathBuf>
+    },
+    Test { suite: Suite, directory: Option<PathBuf> },
+    Map { machine: MachineOptions },
+    Help
+}
+
+// The arguments after the command, with the command's name for error messages
+struct Arguments<'a> {
+    command: &'a str,
+    arguments: Peekable<Iter<'a, String>>
+}
+
+impl<'a> Arguments<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        self.arguments.next().map(String::as_str)
+    }
+
+    // The next argument if it is not an option
+    fn next_if_value(&mut self) -> Option<&'a str> {
+        self.arguments.next_if(|argument| !argument.starts_with("--")).map(String::as_str)
+    }
+
+    fn value(&mut self, option: &str) -> CliResult<&'a str> {
+        self.next_if_value().map_or_else(|| error(format!("{} needs a value", option)), Ok)
+    }
+
+    fn number(&mut self, option: &str, minimum: u64, maximum: u64) -> CliResult<u64> {
+        let value = self.value(option)?;
+        match parse_number(value) {
+            Some(number) if number >= minimum && number <= maximum => Ok(number),
+            _ => error(format!("{} cannot be {}", option, value))
+        }
+    }
+
+    fn address(&mut self, option: &str) -> CliResult<u16> {
+        Ok(self.number(option, 0, 0xffff)? as u16)
+    }
+
+    fn speed(&mut self, option: &str) -> CliResult<Speed> {
+        let value = self.value(option)?;
+        match value.parse::<f64>() {
+            Ok(scale) if scale.is_finite() && scale > 0.0 => Ok(Speed::Scaled(scale)),
+            _ => error(format!("{} cannot be {}", option, value))
+        }
+    }
+
+    fn path(&mut self, option: &str) -> CliResult<PathBuf> {
+        Ok(PathBuf::from(self.value(option)?))
+    }
+
+    fn unknown<T>(&self, argument: &str) -> CliResult<T> {
+        error(format!("{} does not take {}", self.command, argument))
+    }
+}
+
+impl MachineOptions {
+    // Take a machine option, returning false if the argument is not one
+    fn parse(&mut self, argument: &str, arguments: &mut Arguments) -> CliResult<bool> {
+        match argument {
+            "--machine" => self.machine = Some(String::from(arguments.value(argument)?)),
+            "--rom" => self.rom = Some(arguments.path(argument)?),
+            "--symbols" => self.symbols = Some(arguments.path(argument)?),
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    fn config(&self) -> CliResult<MachineConfig> {
+        let Some(machine) = &self.machine else {
+            return Ok(MachineConfig::preset("be6502").unwrap());
+        };
+        if let Some(config) = MachineConfig::preset(machine) {
+            return Ok(config);
+        }
+        if !Path::new(machine).exists() {
+            return error(format!("{} is neither a machine preset nor a configuration file", machine));
+        }
+        MachineConfig::load(machine).map_err(|error| CliError(error.to_string()))
+    }
+
+    // Build the machine with the ROM image and symbols loaded, and reset it
+    fn build(&self, config: &MachineConfig) -> CliResult<Emulator> {
+        let mut emulator = Emulator::from_config(config).map_err(|error| CliError(error.to_string()))?;
+        if let Some(path) = &self.rom {
+            let data = std::fs::read(path).map_err(|error| file_error(path, error))?;
+            match emulator.memory_map_mut().load("ROM", data) {
+                Ok(()) => {}
+                Err(MemoryError::Unmapped) => return error(format!("{} has no region called ROM", config.name)),
+                Err(_) => return Err(file_error(path, "too big for the ROM")),
+            }
+        }
+        if let Some(path) = &self.symbols {
+            emulator.symbols_mut().load(path).map_err(|error| file_error(path, error))?;
+        }
+        emulator.start().map_err(|error| CliError(format!("Unable to reset the CPU: {}", describe_error(&error))))?;
+        Ok(emulator)
+    }
+}
+
+// A number in decimal, or in hex after $ or 0x
+pub fn parse_number(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    match text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+// Parse the arguments after the program's name
+pub fn parse(arguments: &[String]) -> CliResult<Command> {
+    let Some((command, rest)) = arguments.split_first() else {
+        return Ok(Command::Help);
+    };
+    let mut arguments = Arguments { command, arguments: rest.iter().peekable() };
+    let mut machine = MachineOptions::default();
+
+    match command.as_str() {
+        "run" => {
+            let (mut cycles, mut trace, mut headless, mut speed) = (None, None, false, None);
+            let (mut breakpoints, mut watchpoints) = (Vec::new(), Vec::new());
+            while let Some(argument) = arguments.next() {
+                if machine.parse(argument, &mut arguments)? {
+                    continue;
+                }
+                match argument {
+                    "--cycles" => cycles = Some(arguments.number(argument, 1, u64::MAX)?),
+                    "--trace" => trace = Some(arguments.path(argument)?),
+                    "--break" => breakpoints.push(arguments.address(argument)?),
+                    "--watch" => watchpoints.push(arguments.address(argument)?),
+                    "--headless" => headless = true,
+                    "--speed" => speed = Some(arguments.speed(argument)?),
+                    "--unthrottled" => speed = Some(Speed::Unthrottled),
+                    _ => return arguments.unknown(argument)
+                }
+            }
+            Ok(Command::Run { machine, cycles, trace, breakpoints, watchpoints, headless, speed })
+        }
+        "debug" | "monitor" => {
+            let mut frontend = None;
+            while let Some(argument) = arguments.next() {
+                if machine.parse(argument, &mut arguments)? {
+                    continue;
+                }
+                let chosen = match argument {
+                    "--gdb" => match arguments.next_if_value() {
+                        None => Frontend::Gdb(DEFAULT_GDB_PORT),
+                        Some(port) => match port.parse::<u16>() {
+                            Ok(port) if port > 0 => Frontend::Gdb(port),
+                            _ => return error(format!("--gdb cannot be {}", port))
+                        }
+                    },
+                    "--dap" => Frontend::Dap,
+                    _ => return arguments.unknown(argument)
+                };
+                if frontend.replace(chosen).is_some() {
+                    return error(String::from("debug takes one of --gdb and --dap"));
+                }
+            }
+            Ok(Command::Debug { machine, frontend: frontend.unwrap_or(Frontend::Monitor) })
+        }
+        "disasm" => {
+            let (mut file, mut model, mut origin, mut start, mut end) = (None, CpuModel::MOS6502, None, None, None);
+            while let Some(argument) = arguments.next() {
+                match argument {
+                    "--origin" => origin = Some(arguments.address(argument)?),
+                    "--start" => start = Some(arguments.address(argument)?),
+                    "--end" => end = Some(arguments.address(argument)?),
+                    "--symbols" => machine.symbols = Some(arguments.path(argument)?),
+                    "--model" => {
+                        model = match arguments.value(argument)? {
+                            "6502" => CpuModel::MOS6502,
+                            "65c02" | "65C02" => CpuModel::WDC65C02,
+                            value => return error(format!("--model cannot be {}", value))
+                        }
+                    }
+                    _ if !argument.starts_with("--") && file.is_none() => file = Some(PathBuf::from(argument)),
+                    _ => return arguments.unknown(argument)
+                }
+            }
+            let Some(file) = file else {
+                return error(String::from("disasm needs a file"));
+            };
+            Ok(Command::Disasm { file, model, origin, start, end, symbols: machine.symbols })
+        }
+        "test" => {
+            let (mut suite, mut directory) = (None, None);
+            while let Some(argument) = arguments.next() {
+                match argument {
+                    "--dir" => directory = Some(arguments.path(argument)?),
+                    "klaus" if suite.is_none() => suite = Some(Suite::Klaus),
+                    "harte" if suite.is_none() => suite = Some(Suite::TomHarte),
+                    "all" if suite.is_none() => suite = Some(Suite::All),
+                    _ => return arguments.unknown(argument)
+                }
+            }
+            let suite = suite.unwrap_or(Suite::All);
+            if suite == Suite::All && directory.is_some() {
+                return error(String::from("--dir needs one suite, klaus or harte"));
+            }
+            Ok(Command::Test { suite, directory })
+        }
+        "map" => {
+            while let Some(argument) = arguments.next() {
+                if argument == "--symbols" || !machine.parse(argument, &mut arguments)? {
+                    return arguments.unknown(argument);
+                }
+            }
+            Ok(Command::Map { machine })
+        }
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        command => error(format!("Unknown command {}", command))
+    }
+}
+
+// The exit code of run for how the machine stopped
+pub fn exit_code(result: &Result<StopReason, CpuError>) -> i32 {
+    match result {
+        Ok(StopReason::Limit) => EXIT_SUCCESS,
+        Ok(StopReason::Breakpoint(_)) => EXIT_BREAKPOINT,
+        Ok(StopReason::Watchpoint(..)) => EXIT_WATCHPOINT,
+        Err(CpuError::Stopped(_)) => EXIT_STOPPED,
+        Err(CpuError::IllegalOpcode(..)) => EXIT_ILLEGAL_OPCODE,
+        Err(CpuError::Memory(..)) => EXIT_BUS_ERROR
+    }
+}
+
+fn describe_error(error: &CpuError) -> String {
+    match error {
+        CpuError::IllegalOpcode(address, opcode) => format!("Illegal opcode ${:02x} at ${:04x}", opcode, address),
+        CpuError::Stopped(address) => format!("CPU stopped at ${:04x}", address),
+        CpuError::Memory(address, error) => format!("{:?} memory access at ${:04x}", error, address)
+    }
+}
+
+// Run the command line, returning the exit code
+pub fn main(arguments: &[String]) -> i32 {
+    let command = match parse(arguments) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("remu: {}", error);
+            eprintln!("Run remu help for the commands and their options");
+            return EXIT_USAGE;
+        }
+    };
+    match execute(command) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("remu: {}", error);
+            EXIT_FAILURE
+        }
+    }
+}
+
+// Carry out a command, returning the exit code
+pub fn execute(command: Command) -> CliResult<i32> {
+    match command {
+        Command::Run { machine, cycles, trace, breakpoints, watchpoints, headless, speed } => {
+            let mut config = machine.config()?;
+            if headless {
+                config.set_host(false);
+            }
+            let mut emulator = machine.build(&config)?;
+            // Nobody is watching a headless machine, so there is no point running it in real time
+            emulator.set_speed(speed.unwrap_or(if headless { Speed::Unthrottled } else { Speed::REAL_TIME }));
+            for address in breakpoints {
+                emulator.breakpoints_mut().add(Breakpoint::execute(address));
+            }
+            for address in watchpoints {
+                emulator.breakpoints_mut().add(Breakpoint::watch(address, address, WatchKind::Write));
+            }
+            if let Some(path) = trace {
+                let tracer = Tracer::file(&path, None).map_err(|error| file_error(&path, error))?;
+                emulator.set_tracer(Some(tracer));
+            }
+
+            // The terminal is only taken over when a device uses it, and given back before the report
+            let terminal = if config.uses_host() && std::io::stdin().is_terminal() {
+                eprintln!("Press Ctrl-] to quit");
+                Some(RawTerminal::enable().map_err(|error| CliError(format!("Terminal: {}", error)))?)
+            } else {
+                None
+            };
+            let result = run(&mut emulator, cycles);
+            drop(terminal);
+            emulator.set_tracer(None);
+
+            let pc = emulator.cpu().pc();
+            let reason = match &result {
+                Ok(StopReason::Limit) if quit_requested() => String::from("Quit"),
+                Ok(StopReason::Limit) => String::from("Ran to the cycle limit"),
+                Ok(StopReason::Breakpoint(_)) => format!("Breakpoint at {}", emulator.symbols().describe(pc)),
+                Ok(StopReason::Watchpoint(_, access)) => {
+                    format!("Watchpoint: ${:02x} written to ${:04x}", access.value, access.address)
+                }
+                Err(error) => describe_error(error)
+            };
+            eprintln!("{} after {} cycles", reason, emulator.cpu().cycles());
+            eprintln!("{}", trace_line(emulator.cpu(), emulator.memory_map()));
+            Ok(exit_code(&result))
+        }
+        Command::Debug { machine, frontend } => {
+            // The debugger has the terminal to itself
+            let mut config = machine.config()?;
+            config.set_host(false);
+            let emulator = machine.build(&config)?;
+            let result = match frontend {
+                Frontend::Monitor => Monitor::new(emulator).run(std::io::stdin().lock(), std::io::stdout()),
+                Frontend::Gdb(port) => {
+                    eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+                    GdbServer::new(emulator).listen(("127.0.0.1", port))
+                }
+                Frontend::Dap => {
+                    let input = std::io::BufReader::new(std::io::stdin());
+                    DapServer::new(emulator).serve(input, std::io::stdout())
+                }
+            };
+            result.map_err(|error| CliError(error.to_string()))?;
+            Ok(EXIT_SUCCESS)
+        }
+        Command::Disasm { file, model, origin, start, end, symbols } => {
+            let data = std::fs::read(&file).map_err(|error| file_error(&file, error))?;
+            if data.is_empty() || data.len() > 0x10000 {
+                return Err(file_error(&file, "must be from 1 byte to 64K long"));
+            }
+            let origin = origin.map_or(0x10000 - data.len() as u32, u32::from);
+            let last = origin + data.len() as u32 - 1;
+            if last > 0xffff {
+                return Err(file_error(&file, format!("does not fit in memory at ${:04x}", origin)));
+            }
+
+            let mut memory = MemoryMap::new();
+            memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+            for (offset, &byte) in data.iter().enumerate() {
+                memory.write((origin + offset as u32) as u16, byte).unwrap();
+            }
+            let mut table = SymbolTable::new();
+            if let Some(path) = &symbols {
+                table.load(path).map_err(|error| file_error(path, error))?;
+            }
+            let (start, end) = (start.unwrap_or(origin as u16), end.unwrap_or(last as u16));
+            if end < start {
+                return error(String::from("--end is before --start"));
+            }
+            let text = disassembly(&memory, model, &table, start, end);
+            std::io::stdout().write_all(text.as_bytes()).map_err(|error| CliError(error.to_string()))?;
+            Ok(EXIT_SUCCESS)
+        }
+        Command::Test { suite, directory } => test(suite, directory),
+        Command::Map { machine } => {
+            let mut config = machine.config()?;
+            config.set_host(false);
+            machine.build(&config)?.memory_map().print_table();
+            Ok(EXIT_SUCCESS)
+        }
+        Command::Help => {
+            print!("{}", USAGE);
+            Ok(EXIT_SUCCESS)
+        }
+    }
+}
+
+// Run for a number of cycles or until stopped, looking for Ctrl-] and pacing to the clock between batches of cycles
+fn run(emulator: &mut Emulator, cycles: Option<u64>) -> Result<StopReason, CpuError> {
+    let end = cycles.map(|cycles| emulator.cpu().cycles().saturating_add(cycles));
+    loop {
+        let remaining = end.map_or(BATCH_CYCLES, |end| end.saturating_sub(emulator.cpu().cycles()));
+        if remaining == 0 || quit_requested() {
+            return Ok(StopReason::Limit);
+        }
+        // run_cycles does not check the instruction it starts on, which includes the entry point of a fresh run
+        if let Some(stop) = emulator.check_breakpoints() {
+            return Ok(stop);
+        }
+        match emulator.run_cycles(remaining.min(BATCH_CYCLES))? {
+            StopReason::Limit => emulator.pace(),
+            stop => return Ok(stop)
+        }
+    }
+}
+
+// The instructions from `start` to `end`, a line each, with labels on lines of their own
+pub fn disassembly(memory: &MemoryMap, model: CpuModel, symbols: &SymbolTable, start: u16, end: u16) -> String {
+    let mut out = String::new();
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let instruction = disassemble(memory, model, address as u16);
+        let bytes = instruction.bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(" ");
+        if let Some(name) = symbols.name(address as u16) {
+            out += &format!("{}:\n", name);
+        }
+        let text = instruction.format(Syntax::Ca65, Some(symbols));
+        out += &format!("${:04x}  {:<9}    {}\n", address, bytes, text);
+        address += instruction.length() as u32;
+    }
+    out
+}
+
+fn test(suite: Suite, directory: Option<PathBuf>) -> CliResult<i32> {
+    let (mut ran, mut failed) = (0, 0);
+    if suite != Suite::TomHarte {
+        let directory = directory.clone().unwrap_or_else(|| PathBuf::from(klaus::ROM_DIRECTORY));
+        for test in [klaus::FUNCTIONAL_TEST, klaus::EXTENDED_OPCODES_TEST, klaus::DECIMAL_TEST] {
+            match klaus::run_file(&test, &directory).map_err(|error| CliError(format!("{}: {}", test.name, error)))? {
+                None => println!("{}: skipped, {} is not in {}", test.name, test.file, directory.display()),
+                Some(outcome) => {
+                    ran += 1;
+                    if !outcome.passed() {
+                        failed += 1;
+                    }
+                    println!("{}: {}", test.name, outcome);
+                }
+            }
+        }
+    }
+    if suite != Suite::Klaus {
+        let directory = directory.unwrap_or_else(|| PathBuf::from(tom_harte::TEST_DIRECTORY));
+        for model in [CpuModel::MOS6502, CpuModel::WDC65C02] {
+            let name = format!("{:?} single step tests", model);
+            match tom_harte::run_model(model, &directory).map_err(|error| CliError(format!("{}: {}", name, error)))? {
+                None => {
+                    let files = tom_harte::model_directory(model);
+                    println!("{}: skipped, {} is not in {}", name, files, directory.display());
+                }
+                Some(reports) => {
+                    ran += 1;
+                    let failures = reports.iter().filter(|report| !report.passed()).collect::<Vec<_>>();
+                    for report in &failures {
+                        println!("  {}", report);
+                    }
+                    if !failures.is_empty() {
+                        failed += 1;
+                    }
+                    println!("{}: {} of {} opcodes passed", name, reports.len() - failures.len(), reports.len());
+                }
+            }
+        }
+    }
+
+    if ran == 0 {
+        return error(String::from("None of the test suites were found"));
+    }
+    Ok(if failed == 0 { EXIT_SUCCESS } else { EXIT_FAILURE })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn parse_line(line: &str) -> CliResult<Command> {
+        parse(&arguments(line))
+    }
+
+    #[test]
+    fn cli_parse() {
+        let command = parse_line("run --rom firmware.bin --machine be6502 --cycles 1_000_000 --trace out.log").unwrap();
+        let machine = MachineOptions {
+            machine: Some(String::from("be6502")),
+            rom: Some(PathBuf::from("firmware.bin")),
+            symbols: None
+        };
+        let expected = Command::Run {
+            machine: machine.clone(),
+            cycles: Some(1_000_000),
+            trace: Some(PathBuf::from("out.log")),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            headless: false,
+            speed: None
+        };
+        assert_eq!(command, expected);
+        let Command::Run { breakpoints, watchpoints, headless, .. } =
+            parse_line("run --break $c000 --break 0x8000 --watch 512 --headless").unwrap()
+        else {
+            panic!("Not a run command");
+        };
+        assert_eq!((breakpoints, watchpoints, headless), (vec![0xc000, 0x8000], vec![0x0200], true));
+        let speed = |line: &str| match parse_line(line).unwrap() {
+            Command::Run { speed, .. } => speed,
+            command => panic!("Not a run command: {:?}", command)
+        };
+        assert_eq!(speed("run --speed 4"), Some(Speed::Scaled(4.0)));
+        assert_eq!(speed("run --speed 0.5 --headless"), Some(Speed::Scaled(0.5)));
+        assert_eq!(speed("run --unthrottled"), Some(Speed::Unthrottled));
+
+        let debug = |line: &str| match parse_line(line).unwrap() {
+            Command::Debug { frontend, .. } => frontend,
+            command => panic!("Not a debug command: {:?}", command)
+        };
+        assert_eq!(debug("debug --rom firmware.bin"), Frontend::Monitor);
+        assert_eq!(debug("debug --gdb --machine apple1"), Frontend::Gdb(DEFAULT_GDB_PORT));
+        assert_eq!(debug("debug --gdb 3333"), Frontend::Gdb(3333));
+        assert_eq!(debug("debug --dap"), Frontend::Dap);
+        assert_eq!(debug("monitor --machine kim1"), Frontend::Monitor);
+
+        let command = parse_line("disasm wozmon.bin --model 65c02 --start $ff00").unwrap();
+        let expected = Command::Disasm {
+            file: PathBuf::from("wozmon.bin"),
+            model: CpuModel::WDC65C02,
+            origin: None,
+            start: Some(0xff00),
+            end: None,
+            symbols: None
+        };
+        assert_eq!(command, expected);
+        assert_eq!(parse_line("test").unwrap(), Command::Test { suite: Suite::All, directory: None });
+        let command = parse_line("test harte --dir cases").unwrap();
+        assert_eq!(command, Command::Test { suite: Suite::TomHarte, directory: Some(PathBuf::from("cases")) });
+        assert_eq!(parse_line("map --machine kim1").unwrap(), Command::Map { machine: MachineOptions {
+            machine: Some(String::from("kim1")),
+            ..MachineOptions::default()
+        } });
+        assert_eq!(parse(&[]).unwrap(), Command::Help);
+    }
+
+    #[test]
+    fn cli_errors() {
+        let message = |line: &str| parse_line(line).unwrap_err().to_string();
+        assert_eq!(message("go"), "Unknown command go");
+        assert_eq!(message("run --cycles"), "--cycles needs a value");
+        assert_eq!(message("run --cycles 0"), "--cycles cannot be 0");
+        assert_eq!(message("run --rom --cycles 10"), "--rom needs a value");
+        assert_eq!(message("run --break $10000"), "--break cannot be $10000");
+        assert_eq!(message("run firmware.bin"), "run does not take firmware.bin");
+        assert_eq!(message("run --speed 0"), "--speed cannot be 0");
+        assert_eq!(message("run --speed fast"), "--speed cannot be fast");
+        assert_eq!(message("debug --gdb 0"), "--gdb cannot be 0");
+        assert_eq!(message("debug --gdb --dap"), "debug takes one of --gdb and --dap");
+        assert_eq!(message("disasm --model z80 rom.bin"), "--model cannot be z80");
+        assert_eq!(message("disasm"), "disasm needs a file");
+        assert_eq!(message("disasm a.bin b.bin"), "disasm does not take b.bin");
+        assert_eq!(message("test --dir roms"), "--dir needs one suite, klaus or harte");
+        assert_eq!(message("map --symbols labels.txt"), "map does not take --symbols");
+        assert_eq!(main(&arguments("run --headless --headless --bogus")), EXIT_USAGE);
+
+        let machine = MachineOptions { machine: Some(String::from("c64")), ..MachineOptions::default() };
+        let message = "c64 is neither a machine preset nor a configuration file";
+        assert_eq!(machine.config().unwrap_err().to_string(), message);
+    }
+
+    #[test]
+    fn cli_run() {
+        // The BE6502 ROM at $8000: LDA #$01; STA $0200; loop: INC $0200; JMP loop, or STP when $8009 is patched
+        let directory = std::env::temp_dir().join(format!("mini-6502-remu-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut rom = vec![0xea; 0x8000];
+        rom[..12].copy_from_slice(&[0xa9, 0x01, 0x8d, 0x00, 0x02, 0xee, 0x00, 0x02, 0x4c, 0x05, 0x80, 0xdb]);
+        rom[0x7ffc..0x7ffe].copy_from_slice(&[0x00, 0x80]);
+        let path = directory.join("rom.bin");
+        std::fs::write(&path, &rom).unwrap();
+
+        let run = |options: &str| {
+            let line = format!("run --headless --rom {} {}", path.display(), options);
+            execute(parse_line(&line).unwrap()).unwrap()
+        };
+        assert_eq!(run("--cycles 1000"), EXIT_SUCCESS);
+        assert_eq!(run("--break $8008"), EXIT_BREAKPOINT);
+        assert_eq!(run("--watch $0200 --cycles 5"), EXIT_WATCHPOINT);
+
+        // The trace has every instruction up to the breakpoint
+        let trace = directory.join("trace.log");
+        assert_eq!(run(&format!("--trace {} --break $8005 --break $8008", trace.display())), EXIT_BREAKPOINT);
+        assert_eq!(std::fs::read_to_string(&trace).unwrap().lines().count(), 2);
+
+        // Including one on the very first instruction
+        assert_eq!(run(&format!("--trace {} --break $8000 --cycles 1000", trace.display())), EXIT_BREAKPOINT);
+        assert_eq!(std::fs::read_to_string(&trace).unwrap().lines().count(), 0);
+
+        rom[8] = 0xdb;
+        std::fs::write(&path, &rom).unwrap();
+        assert_eq!(run(""), EXIT_STOPPED);
+        let line = format!("run --machine generic --rom {}", path.display());
+        let error = execute(parse_line(&line).unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "Generic 64K has no region called ROM");
+
+        // A JAM in the Apple-1's ROM, which has a 6502
+        let mut rom = vec![0x02; 0x100];
+        rom[0xfc..0xfe].copy_from_slice(&[0x00, 0xff]);
+        std::fs::write(&path, &rom).unwrap();
+        assert_eq!(run("--machine apple1"), EXIT_ILLEGAL_OPCODE);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn cli_disasm() {
+        let mut memory = MemoryMap::new();
+        memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        for (offset, &byte) in [0xa9, 0x01, 0x8d, 0x00, 0x02, 0xd0, 0xf9].iter().enumerate() {
+            memory.write(0xfff0 + offset as u16, byte).unwrap();
+        }
+        let mut symbols = SymbolTable::new();
+        symbols.add_label("start", 0xfff0);
+        assert_eq!(
+            disassembly(&memory, CpuModel::MOS6502, &symbols, 0xfff0, 0xfff6),
+            "start:\n\
+             $fff0  a9 01        lda #$01\n\
+             $fff2  8d 00 02     sta $0200\n\
+             $fff5  d0 f9        bne start\n"
+        );
+        // The last instruction can run past the end of memory without wrapping around forever
+        assert_eq!(disassembly(&memory, CpuModel::MOS6502, &symbols, 0xffff, 0xffff).lines().count(), 1);
+    }
+
+    #[test]
+    fn cli_exit_codes() {
+        assert_eq!(exit_code(&Ok(StopReason::Limit)), EXIT_SUCCESS);
+        assert_eq!(exit_code(&Ok(StopReason::Breakpoint(1))), EXIT_BREAKPOINT);
+        assert_eq!(exit_code(&Err(CpuError::Stopped(0x8000))), EXIT_STOPPED);
+        assert_eq!(exit_code(&Err(CpuError::IllegalOpcode(0x8000, 0x02))), EXIT_ILLEGAL_OPCODE);
+        assert_eq!(exit_code(&Err(CpuError::Memory(0x4000, MemoryError::Unmapped))), EXIT_BUS_ERROR);
+    }
+}
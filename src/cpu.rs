@@ -1,2 +1,6 @@
 pub mod register;
-pub mod cpu;
\ No newline at end of file
+pub mod opcodes;
+pub mod disassembler;
+pub mod assembler;
+#[allow(clippy::module_inception)]
+pub mod cpu;
@@ -0,0 +1,333 @@
+/*!
+ * Line Assembler
+ *
+ * Assembles single instructions, as typed into the monitor, using the same opcode table as the CPU and the
+ * disassembler. Numbers are hexadecimal with an optional `$` prefix, and every addressing mode of the selected CpuModel
+ * is understood:
+ * - implied and accumulator: `nop`, `asl`, `asl a`
+ * - immediate: `lda #$01`
+ * - zero page and absolute, optionally indexed: `lda $10`, `lda $1234,x`, `ldx $10,y`
+ * - indirect: `jmp ($fffc)`, `lda ($10,x)`, `lda ($10),y`, and on the 65C02 `lda ($10)` and `jmp ($1234,x)`
+ * - relative: `bne $c000`, where the operand is the target and the offset is worked out from the address
+ * - zero page relative: `bbr0 $10,$c000`
+ *
+ * A zero page address picks the shorter encoding unless it is forced to be absolute with ca65's `a:` or 64tass' `@w`,
+ * so the output of the disassembler assembles back to the same bytes. Both assemblers' names for the undocumented
+ * opcodes are accepted.
+ */
+
+use crate::cpu::opcodes::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+
+#[derive(Debug, PartialEq)]
+pub enum AssemblerError {
+    // There is no instruction of that name on the selected CpuModel
+    UnknownMnemonic(String),
+    // The operand could not be parsed
+    BadOperand(String),
+    // The instruction exists, but not with the addressing mode of the operand
+    BadAddressingMode(String),
+    // A branch target that is too far away, and where it was branched to
+    BranchOutOfRange(u16),
+    // Writing the instruction failed at the given address
+    Memory(u16, MemoryError)
+}
+
+pub type AssemblerResult = Result<Vec<u8>, AssemblerError>;
+
+// The names some assemblers use for undocumented opcodes. `lax #imm` is how both write LXA.
+fn names(mnemonic: &str) -> Vec<&str> {
+    match mnemonic {
+        "axs" => vec!["sbx"],
+        "isb" => vec!["isc"],
+        "asr" => vec!["alr"],
+        "lds" => vec!["las"],
+        "shs" => vec!["tas"],
+        "lax" => vec!["lax", "lxa"],
+        _ => vec![mnemonic]
+    }
+}
+
+fn name(mnemonic: Mnemonic) -> String {
+    mnemonic.to_string().to_lowercase()
+}
+
+fn parse_value(text: &str) -> Result<u16, AssemblerError> {
+    let text = text.trim();
+    let digits = text.strip_prefix('$').unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| AssemblerError::BadOperand(String::from(text)))
+}
+
+// Parse an address, which may be forced to be absolute
+fn parse_address(text: &str) -> Result<(u16, bool), AssemblerError> {
+    let text = text.trim();
+    for prefix in ["a:", "A:", "@w", "@W"] {
+        if let Some(rest) = text.strip_prefix(prefix) {
+            return Ok((parse_value(rest)?, true));
+        }
+    }
+    Ok((parse_value(text)?, false))
+}
+
+// Work out the addressing modes an operand could be encoded with, in order of preference, and its value
+fn modes(operand: &str) -> Result<(Vec<AddressingMode>, u16, Option<u16>), AssemblerError> {
+    use AddressingMode::*;
+
+    let operand = operand.trim();
+    let lower = operand.to_lowercase().split_whitespace().collect::<String>();
+    let bad = || AssemblerError::BadOperand(String::from(operand));
+    let zero_page = |address: u16, forced: bool| address < 0x100 && !forced;
+
+    if operand.is_empty() {
+        return Ok((vec![Implied, Accumulator], 0, None));
+    }
+    if lower == "a" {
+        return Ok((vec![Accumulator], 0, None));
+    }
+    if let Some(value) = operand.strip_prefix('#') {
+        let value = parse_value(value)?;
+        return if value > 0xff { Err(bad()) } else { Ok((vec![Immediate], value, None)) };
+    }
+
+    if let Some(inner) = lower.strip_prefix('(') {
+        if let Some(pointer) = inner.strip_suffix("),y") {
+            let (pointer, _) = parse_address(pointer)?;
+            return if pointer > 0xff { Err(bad()) } else { Ok((vec![IndirectY], pointer, None)) };
+        }
+        if let Some(pointer) = inner.strip_suffix(",x)") {
+            let (pointer, forced) = parse_address(pointer)?;
+            let modes = if zero_page(pointer, forced) {
+                vec![IndirectX, AbsoluteIndexedIndirect]
+            } else {
+                vec![AbsoluteIndexedIndirect]
+            };
+            return Ok((modes, pointer, None));
+        }
+        if let Some(pointer) = inner.strip_suffix(')') {
+            let (pointer, forced) = parse_address(pointer)?;
+            let modes = if zero_page(pointer, forced) { vec![ZeroPageIndirect, Indirect] } else { vec![Indirect] };
+            return Ok((modes, pointer, None));
+        }
+        return Err(bad());
+    }
+
+    match lower.split_once(',') {
+        Some((address, "x")) => {
+            let (address, forced) = parse_address(address)?;
+            let modes = if zero_page(address, forced) { vec![ZeroPageX, AbsoluteX] } else { vec![AbsoluteX] };
+            Ok((modes, address, None))
+        }
+        Some((address, "y")) => {
+            let (address, forced) = parse_address(address)?;
+            let modes = if zero_page(address, forced) { vec![ZeroPageY, AbsoluteY] } else { vec![AbsoluteY] };
+            Ok((modes, address, None))
+        }
+        Some((address, target)) => {
+            let address = parse_value(address)?;
+            let target = parse_value(target)?;
+            if address > 0xff { Err(bad()) } else { Ok((vec![ZeroPageRelative], address, Some(target))) }
+        }
+        None => {
+            let (address, forced) = parse_address(&lower)?;
+            let modes = if zero_page(address, forced) {
+                vec![Relative, ZeroPage, Absolute]
+            } else {
+                vec![Relative, Absolute]
+            };
+            Ok((modes, address, None))
+        }
+    }
+}
+
+// Find the opcode for an instruction, preferring a documented one when there are several encodings
+fn find(model: CpuModel, names: &[&str], mode: AddressingMode) -> Option<(u8, Opcode)> {
+    let matches = (0..=0xff)
+        .map(|code| (code, decode(model, code)))
+        .filter(|(_, opcode)| opcode.mode == mode && names.contains(&name(opcode.mnemonic).as_str()))
+        .collect::<Vec<(u8, Opcode)>>();
+    matches.iter().find(|(_, opcode)| !opcode.illegal).or(matches.first()).copied()
+}
+
+// The offset of a branch to `target` from the instruction that follows it
+fn branch_offset(next: u16, target: u16) -> Result<u8, AssemblerError> {
+    let offset = target.wrapping_sub(next) as i16;
+    if (-128..=127).contains(&offset) { Ok(offset as u8) } else { Err(AssemblerError::BranchOutOfRange(target)) }
+}
+
+// Assemble one instruction to be placed at `address`, returning its bytes
+pub fn assemble(model: CpuModel, address: u16, source: &str) -> AssemblerResult {
+    let source = source.trim();
+    let (mnemonic, operand) = source.split_once(char::is_whitespace).unwrap_or((source, ""));
+    let mnemonic = mnemonic.to_lowercase();
+    let names = names(&mnemonic);
+
+    let known = (0..=0xff).any(|code| names.contains(&name(decode(model, code).mnemonic).as_str()));
+    if !known {
+        return Err(AssemblerError::UnknownMnemonic(mnemonic));
+    }
+
+    let (modes, value, target) = modes(operand)?;
+    let (code, opcode) = modes
+        .iter()
+        .find_map(|&mode| find(model, &names, mode))
+        .ok_or_else(|| AssemblerError::BadAddressingMode(String::from(source)))?;
+
+    let next = address.wrapping_add(opcode.length());
+    let mut bytes = vec![code];
+    match opcode.mode {
+        AddressingMode::Implied | AddressingMode::Accumulator => {}
+        AddressingMode::Relative => bytes.push(branch_offset(next, value)?),
+        AddressingMode::ZeroPageRelative => {
+            bytes.push(value as u8);
+            bytes.push(branch_offset(next, target.unwrap_or(next))?);
+        }
+        _ if opcode.mode.operand_length() == 2 => bytes.extend_from_slice(&value.to_le_bytes()),
+        _ => bytes.push(value as u8)
+    }
+    Ok(bytes)
+}
+
+// Assemble one instruction straight into memory, returning its bytes. Writes go through MemoryMap::poke, so ROM can
+// be patched when the map is in debug mode.
+pub fn assemble_into(memory: &mut MemoryMap, model: CpuModel, address: u16, source: &str) -> AssemblerResult {
+    let bytes = assemble(model, address, source)?;
+    for (offset, &byte) in bytes.iter().enumerate() {
+        let address = address.wrapping_add(offset as u16);
+        memory.poke(address, byte).map_err(|error| AssemblerError::Memory(address, error))?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::disassembler::*;
+
+    fn bytes(model: CpuModel, source: &str) -> Vec<u8> {
+        assemble(model, 0x0200, source).unwrap()
+    }
+
+    #[test]
+    fn assembler() {
+        let cases: &[(&str, &[u8])] = &[
+            ("nop", &[0xea]),
+            ("ASL", &[0x0a]),
+            ("asl a", &[0x0a]),
+            ("lda #$01", &[0xa9, 0x01]),
+            ("ldx #ff", &[0xa2, 0xff]),
+            ("lda $10", &[0xa5, 0x10]),
+            ("lda $10,x", &[0xb5, 0x10]),
+            ("ldx $10,y", &[0xb6, 0x10]),
+            ("lda $1234", &[0xad, 0x34, 0x12]),
+            ("lda $1234,X", &[0xbd, 0x34, 0x12]),
+            ("lda ( $10 ), y", &[0xb1, 0x10]),
+            ("lda $1234,y", &[0xb9, 0x34, 0x12]),
+            ("lda $10,y", &[0xb9, 0x10, 0x00]),
+            ("jmp ($fffc)", &[0x6c, 0xfc, 0xff]),
+            ("lda ($10,x)", &[0xa1, 0x10]),
+            ("lda ($10),y", &[0xb1, 0x10]),
+            ("jsr $c000", &[0x20, 0x00, 0xc0]),
+            ("bne $0200", &[0xd0, 0xfe]),
+            ("bpl $0212", &[0x10, 0x10]),
+            ("sbc #$01", &[0xe9, 0x01])
+        ];
+        for (source, expected) in cases {
+            assert_eq!(bytes(CpuModel::MOS6502, source), *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn assembler_forced_absolute() {
+        assert_eq!(bytes(CpuModel::MOS6502, "lda a:$10"), vec![0xad, 0x10, 0x00]);
+        assert_eq!(bytes(CpuModel::MOS6502, "lda @w $10,x"), vec![0xbd, 0x10, 0x00]);
+    }
+
+    #[test]
+    fn assembler_65c02() {
+        let cases: &[(&str, &[u8])] = &[
+            ("lda ($10)", &[0xb2, 0x10]),
+            ("jmp ($3000,x)", &[0x7c, 0x00, 0x30]),
+            ("bra $0204", &[0x80, 0x02]),
+            ("stz $10", &[0x64, 0x10]),
+            ("inc", &[0x1a]),
+            ("bit #$80", &[0x89, 0x80]),
+            ("smb7 $12", &[0xf7, 0x12]),
+            ("bbr0 $12,$0200", &[0x0f, 0x12, 0xfd])
+        ];
+        for (source, expected) in cases {
+            assert_eq!(bytes(CpuModel::WDC65C02, source), *expected, "{}", source);
+        }
+
+        // The NMOS part has none of these
+        assert_eq!(assemble(CpuModel::MOS6502, 0x0200, "stz $10"), Err(AssemblerError::UnknownMnemonic("stz".into())));
+        assert!(matches!(assemble(CpuModel::MOS6502, 0x0200, "lda ($10)"), Err(AssemblerError::BadAddressingMode(_))));
+    }
+
+    #[test]
+    fn assembler_undocumented() {
+        assert_eq!(bytes(CpuModel::MOS6502, "lax ($10),y"), vec![0xb3, 0x10]);
+        assert_eq!(bytes(CpuModel::MOS6502, "lax #$10"), vec![0xab, 0x10]);
+        assert_eq!(bytes(CpuModel::MOS6502, "axs #$10"), vec![0xcb, 0x10]);
+        assert_eq!(bytes(CpuModel::MOS6502, "sbx #$10"), vec![0xcb, 0x10]);
+        assert_eq!(bytes(CpuModel::MOS6502, "isb $3000"), vec![0xef, 0x00, 0x30]);
+        assert_eq!(bytes(CpuModel::MOS6502, "asr #$0f"), vec![0x4b, 0x0f]);
+    }
+
+    #[test]
+    fn assembler_errors() {
+        let assemble = |source| assemble(CpuModel::MOS6502, 0x0200, source);
+        assert_eq!(assemble("foo #$01"), Err(AssemblerError::UnknownMnemonic("foo".into())));
+        assert_eq!(assemble("lda #$100"), Err(AssemblerError::BadOperand("#$100".into())));
+        assert_eq!(assemble("lda ($1234),y"), Err(AssemblerError::BadOperand("($1234),y".into())));
+        assert_eq!(assemble("lda zz"), Err(AssemblerError::BadOperand("zz".into())));
+        assert!(matches!(assemble("stx $1234,x"), Err(AssemblerError::BadAddressingMode(_))));
+        assert_eq!(assemble("bne $0300"), Err(AssemblerError::BranchOutOfRange(0x0300)));
+        assert_eq!(assemble("bne $0182"), Ok(vec![0xd0, 0x80]));
+    }
+
+    #[test]
+    fn assembler_round_trip() {
+        // Everything the disassembler prints assembles back to the same instruction, although undocumented
+        // duplicates such as the extra NOPs come back as their documented encoding
+        for model in [CpuModel::MOS6502, CpuModel::WDC65C02] {
+            let mut memory = MemoryMap::new();
+            memory.create("RAM".to_string(), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+            for code in 0..=0xff {
+                memory.write(0x0200, code).unwrap();
+                memory.write(0x0201, 0x12).unwrap();
+                memory.write(0x0202, 0x34).unwrap();
+                let instruction = disassemble(&memory, model, 0x0200);
+                if instruction.opcode.mnemonic == Mnemonic::JAM {
+                    continue;
+                }
+                let text = instruction.format(Syntax::Ca65, None);
+                let bytes = assemble(model, 0x0200, &text).unwrap();
+                let opcode = decode(model, bytes[0]);
+                assert_eq!(opcode.mnemonic, instruction.opcode.mnemonic, "{:02x} {}", code, text);
+                assert_eq!(opcode.mode, instruction.opcode.mode, "{:02x} {}", code, text);
+                assert_eq!(bytes[1..], instruction.bytes[1..], "{:02x} {}", code, text);
+            }
+        }
+    }
+
+    #[test]
+    fn assembler_into_rom() {
+        let mut memory = MemoryMap::new();
+        memory.create("ROM".to_string(), MemoryType::ROM, 0x1000, 0xf000).unwrap();
+
+        // ROM ignores the write unless the map is in debug mode
+        assemble_into(&mut memory, CpuModel::MOS6502, 0xf000, "lda #$01").unwrap();
+        assert_eq!(memory.read(0xf000).unwrap(), 0x00);
+
+        memory.set_debug(true);
+        assert_eq!(assemble_into(&mut memory, CpuModel::MOS6502, 0xf000, "lda #$01").unwrap(), vec![0xa9, 0x01]);
+        assert_eq!(memory.read(0xf000).unwrap(), 0xa9);
+        assert_eq!(memory.read(0xf001).unwrap(), 0x01);
+
+        assert_eq!(
+            assemble_into(&mut memory, CpuModel::MOS6502, 0x0200, "nop"),
+            Err(AssemblerError::Memory(0x0200, MemoryError::Unmapped))
+        );
+    }
+}
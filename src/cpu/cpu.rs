@@ -1,24 +1,80 @@
+use crate::cpu::opcodes::*;
 use crate::cpu::register::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+use crate::devices::save_state::*;
+
+pub const FLAG_CARRY: u8 = 0x01;
+pub const FLAG_ZERO: u8 = 0x02;
+pub const FLAG_INTERRUPT: u8 = 0x04;
+pub const FLAG_DECIMAL: u8 = 0x08;
+pub const FLAG_BREAK: u8 = 0x10;
+pub const FLAG_UNUSED: u8 = 0x20;
+pub const FLAG_OVERFLOW: u8 = 0x40;
+pub const FLAG_NEGATIVE: u8 = 0x80;
+
+pub const NMI_VECTOR: u16 = 0xfffa;
+pub const RESET_VECTOR: u16 = 0xfffc;
+pub const IRQ_VECTOR: u16 = 0xfffe;
+
+const STACK_PAGE: u16 = 0x0100;
+
+// The unstable "magic" constant ORed into A by ANE and LXA, which varies between chips
+const ANE_MAGIC: u8 = 0xee;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+    // A JAM opcode locked up the CPU, and the address it was fetched from
+    IllegalOpcode(u16, u8),
+    // A bus access failed at the given address
+    Memory(u16, MemoryError),
+    // The 65C02 executed STP and stays stopped until it is reset
+    Stopped(u16)
+}
+
+// The number of cycles taken by an instruction or interrupt
+pub type CpuStepResult = Result<u32, CpuError>;
+
+// The value an instruction operates on, once its addressing mode has been resolved
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(u8),
+    Address(u16)
+}
 
 #[derive(Debug)]
 pub struct CPU {
+    model: CpuModel,
     x: ByteRegister,
     y: ByteRegister,
     a: ByteRegister,
     pc: WordRegister,
     sp: ByteRegister,
-    flags: ByteRegister
+    flags: ByteRegister,
+    cycles: u64,
+    waiting: bool,
+    stopped: bool
 }
 
 impl CPU {
     pub fn new() -> CPU {
+        CPU::with_model(CpuModel::MOS6502)
+    }
+
+    pub fn with_model(model: CpuModel) -> CPU {
         CPU {
+            model,
             x: ByteRegister::new(),
             y: ByteRegister::new(),
             a: ByteRegister::new(),
             pc: WordRegister::new(),
             sp: ByteRegister::new(),
-            flags: ByteRegister::new()
+            flags: ByteRegister::new(),
+            cycles: 0,
+            waiting: false,
+            stopped: false
         }
     }
 
@@ -29,6 +85,942 @@ impl CPU {
         self.pc.set(0);
         self.sp.set(0);
         self.flags.set(0);
+        self.waiting = false;
+        self.stopped = false;
+    }
+
+    // The reset sequence: interrupts are disabled and execution starts at the address in the reset vector
+    pub fn start(&mut self, memory: &MemoryMap) -> CpuStepResult {
+        self.sp.set(0xfd);
+        self.flags.set(FLAG_INTERRUPT | FLAG_UNUSED);
+        self.waiting = false;
+        self.stopped = false;
+        let pc = self.read_word(memory, RESET_VECTOR)?;
+        self.pc.set(pc);
+        self.cycles += 7;
+        Ok(7)
+    }
+
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
+    pub fn set_model(&mut self, model: CpuModel) {
+        self.model = model;
+    }
+
+    pub fn a(&self) -> u8 {
+        self.a.get()
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x.get()
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y.get()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc.get()
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp.get()
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags.get()
+    }
+
+    // The total number of cycles executed
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Whether a 65C02 is waiting for an interrupt after WAI
+    pub fn waiting(&self) -> bool {
+        self.waiting
+    }
+
+    pub fn set_a(&mut self, value: u8) {
+        self.a.set(value);
+    }
+
+    pub fn set_x(&mut self, value: u8) {
+        self.x.set(value);
+    }
+
+    pub fn set_y(&mut self, value: u8) {
+        self.y.set(value);
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc.set(value);
+    }
+
+    pub fn set_sp(&mut self, value: u8) {
+        self.sp.set(value);
+    }
+
+    pub fn set_flags(&mut self, value: u8) {
+        self.flags.set(value | FLAG_UNUSED);
+    }
+
+    pub fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    // The registers, the cycle count and whether the CPU is waiting or stopped, for a save state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(match self.model {
+            CpuModel::MOS6502 => 0,
+            CpuModel::WDC65C02 => 1
+        });
+        state.write_u8(self.a.get());
+        state.write_u8(self.x.get());
+        state.write_u8(self.y.get());
+        state.write_u16(self.pc.get());
+        state.write_u8(self.sp.get());
+        state.write_u8(self.flags.get());
+        state.write_u64(self.cycles);
+        state.write_bool(self.waiting);
+        state.write_bool(self.stopped);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.model = match state.read_u8()? {
+            0 => CpuModel::MOS6502,
+            1 => CpuModel::WDC65C02,
+            model => return Err(StateError::Invalid(format!("CPU model {}", model)))
+        };
+        self.a.set(state.read_u8()?);
+        self.x.set(state.read_u8()?);
+        self.y.set(state.read_u8()?);
+        self.pc.set(state.read_u16()?);
+        self.sp.set(state.read_u8()?);
+        self.flags.set(state.read_u8()?);
+        self.cycles = state.read_u64()?;
+        self.waiting = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        Ok(())
+    }
+
+    pub fn flag(&self, flag: u8) -> bool {
+        self.flags.get() & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        let flags = self.flags.get();
+        self.flags.set(if value { flags | flag } else { flags & !flag });
+    }
+
+    fn set_nz(&mut self, value: u8) {
+        self.set_flag(FLAG_ZERO, value == 0);
+        self.set_flag(FLAG_NEGATIVE, value & 0x80 != 0);
+    }
+
+    fn cmos(&self) -> bool {
+        self.model == CpuModel::WDC65C02
+    }
+
+    fn read(&self, memory: &MemoryMap, address: u16) -> Result<u8, CpuError> {
+        memory.read(address).map_err(|error| CpuError::Memory(address, error))
+    }
+
+    fn read_word(&self, memory: &MemoryMap, address: u16) -> Result<u16, CpuError> {
+        let low = self.read(memory, address)?;
+        let high = self.read(memory, address.wrapping_add(1))?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+
+    // Read a pointer from the zero page, where the high byte wraps around within the page
+    fn read_zero_page_word(&self, memory: &MemoryMap, address: u8) -> Result<u16, CpuError> {
+        let low = self.read(memory, address as u16)?;
+        let high = self.read(memory, address.wrapping_add(1) as u16)?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+
+    // A read made only because the CPU drives the bus on every cycle. The value is thrown away, so an unmapped address
+    // is not an error.
+    fn dummy_read(&self, memory: &MemoryMap, address: u16) {
+        let _ = memory.read(address);
+    }
+
+    // Instructions that pull from the stack spend a cycle reading where the stack pointer is before it moves
+    fn dummy_stack_read(&self, memory: &MemoryMap) {
+        self.dummy_read(memory, STACK_PAGE | self.sp.get() as u16);
+    }
+
+    fn write(&self, memory: &mut MemoryMap, address: u16, value: u8) -> Result<(), CpuError> {
+        memory.write(address, value).map_err(|error| CpuError::Memory(address, error))
+    }
+
+    fn fetch(&mut self, memory: &MemoryMap) -> Result<u8, CpuError> {
+        let pc = self.pc.get();
+        let value = self.read(memory, pc)?;
+        self.pc.set(pc.wrapping_add(1));
+        Ok(value)
+    }
+
+    fn fetch_word(&mut self, memory: &MemoryMap) -> Result<u16, CpuError> {
+        let low = self.fetch(memory)?;
+        let high = self.fetch(memory)?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+
+    fn push(&mut self, memory: &mut MemoryMap, value: u8) -> Result<(), CpuError> {
+        let sp = self.sp.get();
+        self.write(memory, STACK_PAGE | sp as u16, value)?;
+        self.sp.set(sp.wrapping_sub(1));
+        Ok(())
+    }
+
+    fn push_word(&mut self, memory: &mut MemoryMap, value: u16) -> Result<(), CpuError> {
+        self.push(memory, (value >> 8) as u8)?;
+        self.push(memory, value as u8)
+    }
+
+    fn pull(&mut self, memory: &MemoryMap) -> Result<u8, CpuError> {
+        let sp = self.sp.get().wrapping_add(1);
+        self.sp.set(sp);
+        self.read(memory, STACK_PAGE | sp as u16)
+    }
+
+    fn pull_word(&mut self, memory: &MemoryMap) -> Result<u16, CpuError> {
+        let low = self.pull(memory)?;
+        let high = self.pull(memory)?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+
+    // Push the return address and status, then continue at the handler in `vector`. A hardware interrupt first spends
+    // two cycles reading the next opcode without fetching it, where BRK has read its opcode and padding byte.
+    fn interrupt(&mut self, memory: &mut MemoryMap, vector: u16, brk: bool) -> CpuStepResult {
+        let pc = self.pc.get();
+        if !brk {
+            self.dummy_read(memory, pc);
+            self.dummy_read(memory, pc);
+        }
+        self.push_word(memory, pc)?;
+        let flags = self.flags.get() | FLAG_UNUSED;
+        self.push(memory, if brk { flags | FLAG_BREAK } else { flags & !FLAG_BREAK })?;
+        self.set_flag(FLAG_INTERRUPT, true);
+        if self.cmos() {
+            self.set_flag(FLAG_DECIMAL, false);
+        }
+        let handler = self.read_word(memory, vector)?;
+        self.pc.set(handler);
+        Ok(7)
+    }
+
+    // Service a maskable interrupt, returning the cycles taken (none when interrupts are disabled). An IRQ always
+    // ends WAI, even when it is masked.
+    pub fn irq(&mut self, memory: &mut MemoryMap) -> CpuStepResult {
+        self.waiting = false;
+        if self.flag(FLAG_INTERRUPT) || self.stopped {
+            return Ok(0);
+        }
+        let cycles = self.interrupt(memory, IRQ_VECTOR, false)?;
+        self.cycles += cycles as u64;
+        Ok(cycles)
+    }
+
+    pub fn nmi(&mut self, memory: &mut MemoryMap) -> CpuStepResult {
+        self.waiting = false;
+        if self.stopped {
+            return Ok(0);
+        }
+        let cycles = self.interrupt(memory, NMI_VECTOR, false)?;
+        self.cycles += cycles as u64;
+        Ok(cycles)
+    }
+
+    // Resolve the operand of an instruction, returning whether indexing crossed a page boundary. This makes the dummy
+    // reads of the addressing mode too: where the NMOS part puts a half-computed address on the bus, the 65C02 reads
+    // the last byte of the instruction again.
+    fn operand(&mut self, memory: &MemoryMap, opcode: &Opcode) -> Result<(Operand, bool), CpuError> {
+        // Indexing an absolute address takes a cycle to carry into the high byte, which instructions that only read
+        // skip when there is no carry. The NMOS part reads from the address before the carry meanwhile.
+        let indexed = |cpu: &CPU, base: u16, index: u8| {
+            let address = base.wrapping_add(index as u16);
+            let page_crossed = base & 0xff00 != address & 0xff00;
+            if page_crossed || !opcode.page_cycle {
+                let uncarried = (base & 0xff00) | (address & 0x00ff);
+                let dummy = if cpu.cmos() && page_crossed { cpu.pc.get().wrapping_sub(1) } else { uncarried };
+                cpu.dummy_read(memory, dummy);
+            }
+            (Operand::Address(address), page_crossed)
+        };
+        // Indexing a zero page address takes a cycle of its own
+        let zero_page_indexed = |cpu: &CPU, base: u8| {
+            let dummy = if cpu.cmos() { cpu.pc.get().wrapping_sub(1) } else { base as u16 };
+            cpu.dummy_read(memory, dummy);
+        };
+
+        Ok(match opcode.mode {
+            AddressingMode::Implied | AddressingMode::Accumulator => {
+                // Single byte instructions read the next one without fetching it, except for the 65C02's one cycle
+                // NOPs
+                if opcode.cycles > 1 {
+                    self.dummy_read(memory, self.pc.get());
+                }
+                match opcode.mode {
+                    AddressingMode::Accumulator => (Operand::Accumulator, false),
+                    _ => (Operand::None, false)
+                }
+            }
+            AddressingMode::Immediate => (Operand::Immediate(self.fetch(memory)?), false),
+            AddressingMode::ZeroPage => (Operand::Address(self.fetch(memory)? as u16), false),
+            AddressingMode::ZeroPageX => {
+                let base = self.fetch(memory)?;
+                zero_page_indexed(self, base);
+                (Operand::Address(base.wrapping_add(self.x.get()) as u16), false)
+            }
+            AddressingMode::ZeroPageY => {
+                let base = self.fetch(memory)?;
+                zero_page_indexed(self, base);
+                (Operand::Address(base.wrapping_add(self.y.get()) as u16), false)
+            }
+            AddressingMode::Absolute => (Operand::Address(self.fetch_word(memory)?), false),
+            AddressingMode::AbsoluteX => {
+                let base = self.fetch_word(memory)?;
+                indexed(self, base, self.x.get())
+            }
+            AddressingMode::AbsoluteY => {
+                let base = self.fetch_word(memory)?;
+                indexed(self, base, self.y.get())
+            }
+            AddressingMode::Indirect => {
+                // The NMOS part does not carry into the high byte when the pointer sits at the end of a page. The
+                // 65C02 fixes that at the cost of a cycle.
+                let pointer = self.fetch_word(memory)?;
+                let high_address = if self.cmos() {
+                    self.dummy_read(memory, self.pc.get().wrapping_sub(1));
+                    pointer.wrapping_add(1)
+                } else {
+                    (pointer & 0xff00) | (pointer.wrapping_add(1) & 0x00ff)
+                };
+                let low = self.read(memory, pointer)?;
+                let high = self.read(memory, high_address)?;
+                (Operand::Address(u16::from_le_bytes([low, high])), false)
+            }
+            AddressingMode::IndirectX => {
+                let base = self.fetch(memory)?;
+                zero_page_indexed(self, base);
+                let pointer = base.wrapping_add(self.x.get());
+                (Operand::Address(self.read_zero_page_word(memory, pointer)?), false)
+            }
+            AddressingMode::IndirectY => {
+                let pointer = self.fetch(memory)?;
+                let base = self.read_zero_page_word(memory, pointer)?;
+                indexed(self, base, self.y.get())
+            }
+            AddressingMode::ZeroPageIndirect => {
+                let pointer = self.fetch(memory)?;
+                (Operand::Address(self.read_zero_page_word(memory, pointer)?), false)
+            }
+            AddressingMode::AbsoluteIndexedIndirect => {
+                let base = self.fetch_word(memory)?;
+                self.dummy_read(memory, self.pc.get().wrapping_sub(1));
+                let pointer = base.wrapping_add(self.x.get() as u16);
+                (Operand::Address(self.read_word(memory, pointer)?), false)
+            }
+            AddressingMode::Relative => {
+                let offset = self.fetch(memory)? as i8;
+                (Operand::Address(self.pc.get().wrapping_add(offset as u16)), false)
+            }
+            // BBR and BBS test the zero page byte before they fetch their offset
+            AddressingMode::ZeroPageRelative => (Operand::Address(self.fetch(memory)? as u16), false)
+        })
+    }
+
+    fn load(&self, memory: &MemoryMap, operand: Operand) -> Result<u8, CpuError> {
+        match operand {
+            Operand::Accumulator => Ok(self.a.get()),
+            Operand::Immediate(value) => Ok(value),
+            Operand::Address(address) => self.read(memory, address),
+            Operand::None => Ok(0)
+        }
+    }
+
+    fn store(&mut self, memory: &mut MemoryMap, operand: Operand, value: u8) -> Result<(), CpuError> {
+        match operand {
+            Operand::Accumulator => {
+                self.a.set(value);
+                Ok(())
+            }
+            Operand::Address(address) => self.write(memory, address, value),
+            _ => Ok(())
+        }
+    }
+
+    // Read-modify-write instructions take a cycle to work out the result, during which the NMOS part writes back the
+    // value it read and the 65C02 reads it again
+    fn read_modify_write<F>(&mut self, memory: &mut MemoryMap, operand: Operand, operation: F) -> Result<u8, CpuError>
+    where
+        F: FnOnce(&mut CPU, u8) -> u8
+    {
+        let value = self.load(memory, operand)?;
+        if let Operand::Address(address) = operand {
+            if self.cmos() {
+                self.dummy_read(memory, address);
+            } else {
+                self.write(memory, address, value)?;
+            }
+        }
+        let result = operation(self, value);
+        self.store(memory, operand, result)?;
+        Ok(result)
+    }
+
+    // Shifts, rotates, increments and decrements set N and Z from the result, which is also returned
+    fn modify<F>(&mut self, memory: &mut MemoryMap, operand: Operand, operation: F) -> Result<u8, CpuError>
+    where
+        F: FnOnce(&mut CPU, u8) -> u8
+    {
+        let result = self.read_modify_write(memory, operand, operation)?;
+        self.set_nz(result);
+        Ok(result)
+    }
+
+    fn asl(&mut self, value: u8) -> u8 {
+        self.set_flag(FLAG_CARRY, value & 0x80 != 0);
+        value << 1
+    }
+
+    fn lsr(&mut self, value: u8) -> u8 {
+        self.set_flag(FLAG_CARRY, value & 0x01 != 0);
+        value >> 1
+    }
+
+    fn rol(&mut self, value: u8) -> u8 {
+        let carry = self.flag(FLAG_CARRY) as u8;
+        self.set_flag(FLAG_CARRY, value & 0x80 != 0);
+        (value << 1) | carry
+    }
+
+    fn ror(&mut self, value: u8) -> u8 {
+        let carry = (self.flag(FLAG_CARRY) as u8) << 7;
+        self.set_flag(FLAG_CARRY, value & 0x01 != 0);
+        (value >> 1) | carry
+    }
+
+    fn set_a_nz(&mut self, value: u8) {
+        self.a.set(value);
+        self.set_nz(value);
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_flag(FLAG_CARRY, register >= value);
+        self.set_nz(register.wrapping_sub(value));
+    }
+
+    fn adc(&mut self, value: u8) {
+        let a = self.a.get();
+        let carry = self.flag(FLAG_CARRY) as u16;
+        let binary = a as u16 + value as u16 + carry;
+
+        if !self.flag(FLAG_DECIMAL) {
+            self.set_flag(FLAG_CARRY, binary > 0xff);
+            self.set_flag(FLAG_OVERFLOW, (a ^ binary as u8) & (value ^ binary as u8) & 0x80 != 0);
+            self.set_a_nz(binary as u8);
+            return;
+        }
+
+        // On the NMOS part Z comes from the binary sum, while N and V reflect the sum after the low nibble is adjusted
+        let mut low = (a & 0x0f) as u16 + (value & 0x0f) as u16 + carry;
+        if low > 0x09 {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) as u16 + (value & 0xf0) as u16 + low;
+        self.set_flag(FLAG_ZERO, binary as u8 == 0);
+        self.set_flag(FLAG_NEGATIVE, sum & 0x80 != 0);
+        self.set_flag(FLAG_OVERFLOW, (a ^ sum as u8) & (value ^ sum as u8) & 0x80 != 0);
+        if sum > 0x9f {
+            sum += 0x60;
+        }
+        self.set_flag(FLAG_CARRY, sum > 0xff);
+        self.a.set(sum as u8);
+
+        // The 65C02 fixes N and Z to match the decimal result
+        if self.cmos() {
+            self.set_nz(sum as u8);
+        }
+    }
+
+    fn sbc(&mut self, value: u8) {
+        let a = self.a.get();
+        let borrow = !self.flag(FLAG_CARRY) as i16;
+        let binary = a as i16 - value as i16 - borrow;
+
+        // C and V come from the binary difference, even in decimal mode
+        self.set_flag(FLAG_CARRY, binary >= 0);
+        self.set_flag(FLAG_OVERFLOW, (a ^ value) & (a ^ binary as u8) & 0x80 != 0);
+        self.set_nz(binary as u8);
+
+        if !self.flag(FLAG_DECIMAL) {
+            self.a.set(binary as u8);
+            return;
+        }
+
+        let low = (a & 0x0f) as i16 - (value & 0x0f) as i16 - borrow;
+        if self.cmos() {
+            let mut result = binary;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if low < 0 {
+                result -= 0x06;
+            }
+            self.set_a_nz(result as u8);
+            return;
+        }
+
+        let mut low = low;
+        let mut high = (a >> 4) as i16 - (value >> 4) as i16;
+        if low < 0 {
+            low -= 6;
+            high -= 1;
+        }
+        if high < 0 {
+            high -= 6;
+        }
+        self.a.set((((high as u8) << 4) & 0xf0) | (low as u8 & 0x0f));
+    }
+
+    // ARR is AND followed by ROR, with flags (and a decimal adjustment) of its own
+    fn arr(&mut self, value: u8) {
+        let and = self.a.get() & value;
+        let carry = self.flag(FLAG_CARRY);
+        let mut result = (and >> 1) | ((carry as u8) << 7);
+
+        if !self.flag(FLAG_DECIMAL) {
+            self.set_a_nz(result);
+            self.set_flag(FLAG_CARRY, result & 0x40 != 0);
+            self.set_flag(FLAG_OVERFLOW, ((result >> 6) ^ (result >> 5)) & 0x01 != 0);
+            return;
+        }
+
+        self.set_flag(FLAG_NEGATIVE, carry);
+        self.set_flag(FLAG_ZERO, result == 0);
+        self.set_flag(FLAG_OVERFLOW, (and ^ result) & 0x40 != 0);
+        if (and & 0x0f) + (and & 0x01) > 0x05 {
+            result = (result & 0xf0) | (result.wrapping_add(0x06) & 0x0f);
+        }
+        let adjust_high = (and & 0xf0) as u16 + (and & 0x10) as u16 > 0x50;
+        self.set_flag(FLAG_CARRY, adjust_high);
+        if adjust_high {
+            result = result.wrapping_add(0x60);
+        }
+        self.a.set(result);
+    }
+
+    // SHA, SHX, SHY and TAS store a register ANDed with the high byte of the base address plus one. When indexing
+    // crosses a page, that value also replaces the high byte of the address.
+    fn store_high(
+        &mut self,
+        memory: &mut MemoryMap,
+        operand: Operand,
+        page_crossed: bool,
+        value: u8
+    ) -> Result<(), CpuError> {
+        if let Operand::Address(address) = operand {
+            let high = (address >> 8) as u8;
+            let base_high = if page_crossed { high } else { high.wrapping_add(1) };
+            let value = value & base_high;
+            let address = if page_crossed { ((value as u16) << 8) | (address & 0x00ff) } else { address };
+            self.write(memory, address, value)?;
+        }
+        Ok(())
+    }
+
+    // A branch that is taken reads the next opcode while it adds the offset, then the target before the carry into
+    // its high byte when that crosses a page. The extra cycles are returned.
+    fn branch(&mut self, memory: &MemoryMap, condition: bool, operand: Operand) -> u32 {
+        let Operand::Address(target) = operand else {
+            return 0;
+        };
+        if !condition {
+            return 0;
+        }
+        let pc = self.pc.get();
+        self.dummy_read(memory, pc);
+        self.pc.set(target);
+        if pc & 0xff00 == target & 0xff00 {
+            return 1;
+        }
+        self.dummy_read(memory, (pc & 0xff00) | (target & 0x00ff));
+        2
+    }
+
+    // BBR and BBS read the zero page byte they test twice, then fetch the offset. The byte and the target are returned.
+    fn bit_branch(&mut self, memory: &MemoryMap, operand: Operand) -> Result<(u8, Operand), CpuError> {
+        let value = self.load(memory, operand)?;
+        if let Operand::Address(address) = operand {
+            self.dummy_read(memory, address);
+        }
+        let offset = self.fetch(memory)? as i8;
+        Ok((value, Operand::Address(self.pc.get().wrapping_add(offset as u16))))
+    }
+
+    // Execute a single instruction, returning the number of cycles it took
+    pub fn step(&mut self, memory: &mut MemoryMap) -> CpuStepResult {
+        let address = self.pc.get();
+        if self.stopped {
+            return Err(CpuError::Stopped(address));
+        }
+        if self.waiting {
+            self.cycles += 1;
+            return Ok(1);
+        }
+
+        let code = self.read(memory, address)?;
+        let opcode = decode(self.model, code);
+        self.pc.set(address.wrapping_add(1));
+
+        // JSR pushes the return address between fetching the two bytes of its target
+        let (operand, page_crossed) = match opcode.mnemonic {
+            Mnemonic::JSR => (Operand::None, false),
+            _ => self.operand(memory, &opcode)?
+        };
+        let mut cycles = opcode.cycles as u32;
+        if opcode.page_cycle && page_crossed {
+            cycles += 1;
+        }
+
+        match opcode.mnemonic {
+            Mnemonic::ADC => {
+                let value = self.load(memory, operand)?;
+                self.adc(value);
+                // The 65C02 takes a cycle to correct the flags in decimal mode
+                if self.cmos() && self.flag(FLAG_DECIMAL) {
+                    self.dummy_read(memory, self.pc.get());
+                    cycles += 1;
+                }
+            }
+            Mnemonic::SBC => {
+                let value = self.load(memory, operand)?;
+                self.sbc(value);
+                if self.cmos() && self.flag(FLAG_DECIMAL) {
+                    self.dummy_read(memory, self.pc.get());
+                    cycles += 1;
+                }
+            }
+            Mnemonic::AND => {
+                let value = self.a.get() & self.load(memory, operand)?;
+                self.set_a_nz(value);
+            }
+            Mnemonic::ORA => {
+                let value = self.a.get() | self.load(memory, operand)?;
+                self.set_a_nz(value);
+            }
+            Mnemonic::EOR => {
+                let value = self.a.get() ^ self.load(memory, operand)?;
+                self.set_a_nz(value);
+            }
+            Mnemonic::BIT => {
+                let value = self.load(memory, operand)?;
+                self.set_flag(FLAG_ZERO, self.a.get() & value == 0);
+
+                // The 65C02's immediate form only affects Z
+                if !matches!(operand, Operand::Immediate(_)) {
+                    self.set_flag(FLAG_OVERFLOW, value & 0x40 != 0);
+                    self.set_flag(FLAG_NEGATIVE, value & 0x80 != 0);
+                }
+            }
+            Mnemonic::CMP => {
+                let value = self.load(memory, operand)?;
+                self.compare(self.a.get(), value);
+            }
+            Mnemonic::CPX => {
+                let value = self.load(memory, operand)?;
+                self.compare(self.x.get(), value);
+            }
+            Mnemonic::CPY => {
+                let value = self.load(memory, operand)?;
+                self.compare(self.y.get(), value);
+            }
+            Mnemonic::ASL => {
+                self.modify(memory, operand, CPU::asl)?;
+            }
+            Mnemonic::LSR => {
+                self.modify(memory, operand, CPU::lsr)?;
+            }
+            Mnemonic::ROL => {
+                self.modify(memory, operand, CPU::rol)?;
+            }
+            Mnemonic::ROR => {
+                self.modify(memory, operand, CPU::ror)?;
+            }
+            Mnemonic::INC => {
+                self.modify(memory, operand, |_, value| value.wrapping_add(1))?;
+            }
+            Mnemonic::DEC => {
+                self.modify(memory, operand, |_, value| value.wrapping_sub(1))?;
+            }
+            Mnemonic::INX => {
+                let value = self.x.get().wrapping_add(1);
+                self.x.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::INY => {
+                let value = self.y.get().wrapping_add(1);
+                self.y.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::DEX => {
+                let value = self.x.get().wrapping_sub(1);
+                self.x.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::DEY => {
+                let value = self.y.get().wrapping_sub(1);
+                self.y.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::LDA => {
+                let value = self.load(memory, operand)?;
+                self.set_a_nz(value);
+            }
+            Mnemonic::LDX => {
+                let value = self.load(memory, operand)?;
+                self.x.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::LDY => {
+                let value = self.load(memory, operand)?;
+                self.y.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::STA => self.store(memory, operand, self.a.get())?,
+            Mnemonic::STX => self.store(memory, operand, self.x.get())?,
+            Mnemonic::STY => self.store(memory, operand, self.y.get())?,
+            Mnemonic::STZ => self.store(memory, operand, 0)?,
+            Mnemonic::TAX => {
+                self.x.set(self.a.get());
+                self.set_nz(self.a.get());
+            }
+            Mnemonic::TAY => {
+                self.y.set(self.a.get());
+                self.set_nz(self.a.get());
+            }
+            Mnemonic::TXA => self.set_a_nz(self.x.get()),
+            Mnemonic::TYA => self.set_a_nz(self.y.get()),
+            Mnemonic::TSX => {
+                self.x.set(self.sp.get());
+                self.set_nz(self.sp.get());
+            }
+            Mnemonic::TXS => self.sp.set(self.x.get()),
+            Mnemonic::PHA => self.push(memory, self.a.get())?,
+            Mnemonic::PHX => self.push(memory, self.x.get())?,
+            Mnemonic::PHY => self.push(memory, self.y.get())?,
+            Mnemonic::PHP => self.push(memory, self.flags.get() | FLAG_BREAK | FLAG_UNUSED)?,
+            Mnemonic::PLA => {
+                self.dummy_stack_read(memory);
+                let value = self.pull(memory)?;
+                self.set_a_nz(value);
+            }
+            Mnemonic::PLX => {
+                self.dummy_stack_read(memory);
+                let value = self.pull(memory)?;
+                self.x.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::PLY => {
+                self.dummy_stack_read(memory);
+                let value = self.pull(memory)?;
+                self.y.set(value);
+                self.set_nz(value);
+            }
+            Mnemonic::PLP => {
+                self.dummy_stack_read(memory);
+                let value = self.pull(memory)?;
+                self.set_flags(value & !FLAG_BREAK);
+            }
+            Mnemonic::CLC => self.set_flag(FLAG_CARRY, false),
+            Mnemonic::CLD => self.set_flag(FLAG_DECIMAL, false),
+            Mnemonic::CLI => self.set_flag(FLAG_INTERRUPT, false),
+            Mnemonic::CLV => self.set_flag(FLAG_OVERFLOW, false),
+            Mnemonic::SEC => self.set_flag(FLAG_CARRY, true),
+            Mnemonic::SED => self.set_flag(FLAG_DECIMAL, true),
+            Mnemonic::SEI => self.set_flag(FLAG_INTERRUPT, true),
+            Mnemonic::BCC => cycles += self.branch(memory, !self.flag(FLAG_CARRY), operand),
+            Mnemonic::BCS => cycles += self.branch(memory, self.flag(FLAG_CARRY), operand),
+            Mnemonic::BNE => cycles += self.branch(memory, !self.flag(FLAG_ZERO), operand),
+            Mnemonic::BEQ => cycles += self.branch(memory, self.flag(FLAG_ZERO), operand),
+            Mnemonic::BPL => cycles += self.branch(memory, !self.flag(FLAG_NEGATIVE), operand),
+            Mnemonic::BMI => cycles += self.branch(memory, self.flag(FLAG_NEGATIVE), operand),
+            Mnemonic::BVC => cycles += self.branch(memory, !self.flag(FLAG_OVERFLOW), operand),
+            Mnemonic::BVS => cycles += self.branch(memory, self.flag(FLAG_OVERFLOW), operand),
+            Mnemonic::BRA => cycles += self.branch(memory, true, operand),
+            Mnemonic::BBR(bit) => {
+                let (value, target) = self.bit_branch(memory, operand)?;
+                cycles += self.branch(memory, value & (1 << bit) == 0, target);
+            }
+            Mnemonic::BBS(bit) => {
+                let (value, target) = self.bit_branch(memory, operand)?;
+                cycles += self.branch(memory, value & (1 << bit) != 0, target);
+            }
+            Mnemonic::RMB(bit) => {
+                self.read_modify_write(memory, operand, |_, value| value & !(1 << bit))?;
+            }
+            Mnemonic::SMB(bit) => {
+                self.read_modify_write(memory, operand, |_, value| value | (1 << bit))?;
+            }
+            Mnemonic::TSB | Mnemonic::TRB => {
+                let tsb = opcode.mnemonic == Mnemonic::TSB;
+                self.read_modify_write(memory, operand, |cpu, value| {
+                    let a = cpu.a.get();
+                    cpu.set_flag(FLAG_ZERO, value & a == 0);
+                    if tsb { value | a } else { value & !a }
+                })?;
+            }
+            Mnemonic::JMP => {
+                if let Operand::Address(target) = operand {
+                    self.pc.set(target);
+                }
+            }
+            Mnemonic::JSR => {
+                // The return address pushed is that of the last byte of the JSR, which is only read afterwards
+                let low = self.fetch(memory)?;
+                self.dummy_stack_read(memory);
+                self.push_word(memory, self.pc.get())?;
+                let high = self.read(memory, self.pc.get())?;
+                self.pc.set(u16::from_le_bytes([low, high]));
+            }
+            Mnemonic::RTS => {
+                // The return address is read again while it is incremented
+                self.dummy_stack_read(memory);
+                let pc = self.pull_word(memory)?;
+                self.dummy_read(memory, pc);
+                self.pc.set(pc.wrapping_add(1));
+            }
+            Mnemonic::RTI => {
+                self.dummy_stack_read(memory);
+                let flags = self.pull(memory)?;
+                self.set_flags(flags & !FLAG_BREAK);
+                let pc = self.pull_word(memory)?;
+                self.pc.set(pc);
+            }
+            Mnemonic::BRK => {
+                // BRK skips a padding byte after the opcode
+                self.pc.set(self.pc.get().wrapping_add(1));
+                self.interrupt(memory, IRQ_VECTOR, true)?;
+            }
+            Mnemonic::WAI => {
+                self.dummy_read(memory, self.pc.get());
+                self.waiting = true;
+            }
+            Mnemonic::STP => {
+                // Like a JAM, the CPU stays on the instruction that stopped it
+                self.dummy_read(memory, self.pc.get());
+                self.pc.set(address);
+                self.stopped = true;
+                self.cycles += cycles as u64;
+                return Err(CpuError::Stopped(address));
+            }
+            Mnemonic::NOP if code == 0x5c && self.cmos() => {
+                // The 65C02's eight cycle NOP reads from the last page with the low byte of its operand, then from
+                // the top of memory
+                if let Operand::Address(operand) = operand {
+                    self.dummy_read(memory, 0xff00 | (operand & 0x00ff));
+                }
+                for _ in 0..4 {
+                    self.dummy_read(memory, 0xffff);
+                }
+            }
+            Mnemonic::NOP => {
+                // Undocumented NOPs with an operand still read it
+                if let Operand::Address(_) = operand {
+                    self.load(memory, operand)?;
+                }
+            }
+            Mnemonic::JAM => {
+                self.pc.set(address);
+                return Err(CpuError::IllegalOpcode(address, code));
+            }
+            Mnemonic::SLO => {
+                let value = self.modify(memory, operand, CPU::asl)?;
+                self.set_a_nz(self.a.get() | value);
+            }
+            Mnemonic::RLA => {
+                let value = self.modify(memory, operand, CPU::rol)?;
+                self.set_a_nz(self.a.get() & value);
+            }
+            Mnemonic::SRE => {
+                let value = self.modify(memory, operand, CPU::lsr)?;
+                self.set_a_nz(self.a.get() ^ value);
+            }
+            Mnemonic::RRA => {
+                let value = self.modify(memory, operand, CPU::ror)?;
+                self.adc(value);
+            }
+            Mnemonic::DCP => {
+                let value = self.modify(memory, operand, |_, value| value.wrapping_sub(1))?;
+                self.compare(self.a.get(), value);
+            }
+            Mnemonic::ISC => {
+                let value = self.modify(memory, operand, |_, value| value.wrapping_add(1))?;
+                self.sbc(value);
+            }
+            Mnemonic::SAX => self.store(memory, operand, self.a.get() & self.x.get())?,
+            Mnemonic::LAX => {
+                let value = self.load(memory, operand)?;
+                self.x.set(value);
+                self.set_a_nz(value);
+            }
+            Mnemonic::ANC => {
+                let value = self.a.get() & self.load(memory, operand)?;
+                self.set_a_nz(value);
+                self.set_flag(FLAG_CARRY, value & 0x80 != 0);
+            }
+            Mnemonic::ALR => {
+                let value = self.a.get() & self.load(memory, operand)?;
+                let value = self.lsr(value);
+                self.set_a_nz(value);
+            }
+            Mnemonic::ARR => {
+                let value = self.load(memory, operand)?;
+                self.arr(value);
+            }
+            Mnemonic::ANE => {
+                let value = (self.a.get() | ANE_MAGIC) & self.x.get() & self.load(memory, operand)?;
+                self.set_a_nz(value);
+            }
+            Mnemonic::LXA => {
+                let value = (self.a.get() | ANE_MAGIC) & self.load(memory, operand)?;
+                self.x.set(value);
+                self.set_a_nz(value);
+            }
+            Mnemonic::LAS => {
+                let value = self.load(memory, operand)? & self.sp.get();
+                self.sp.set(value);
+                self.x.set(value);
+                self.set_a_nz(value);
+            }
+            Mnemonic::SBX => {
+                let value = self.load(memory, operand)?;
+                let and = self.a.get() & self.x.get();
+                self.compare(and, value);
+                self.x.set(and.wrapping_sub(value));
+            }
+            Mnemonic::TAS => {
+                self.sp.set(self.a.get() & self.x.get());
+                self.store_high(memory, operand, page_crossed, self.sp.get())?;
+            }
+            Mnemonic::SHA => self.store_high(memory, operand, page_crossed, self.a.get() & self.x.get())?,
+            Mnemonic::SHX => self.store_high(memory, operand, page_crossed, self.x.get())?,
+            Mnemonic::SHY => self.store_high(memory, operand, page_crossed, self.y.get())?
+        }
+
+        self.cycles += cycles as u64;
+        Ok(cycles)
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -76,4 +1068,293 @@ mod tests {
         assert_eq!(cpu.sp.get(), 0);
         assert_eq!(cpu.flags.get(), 0);
     }
-}
\ No newline at end of file
+
+    // 64K of RAM with a program at $0200 and the reset vector pointing at it
+    fn machine(program: &[u8]) -> (CPU, MemoryMap) {
+        let mut memory = MemoryMap::new();
+        memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        for (index, &byte) in program.iter().enumerate() {
+            memory.write(0x0200 + index as u16, byte).unwrap();
+        }
+        memory.write(RESET_VECTOR, 0x00).unwrap();
+        memory.write(RESET_VECTOR + 1, 0x02).unwrap();
+
+        let mut cpu = CPU::new();
+        cpu.start(&memory).unwrap();
+        (cpu, memory)
+    }
+
+    fn run(cpu: &mut CPU, memory: &mut MemoryMap, instructions: usize) -> u32 {
+        (0..instructions).map(|_| cpu.step(memory).unwrap()).sum()
+    }
+
+    #[test]
+    fn cpu_start() {
+        let (cpu, _) = machine(&[]);
+        assert_eq!(cpu.pc(), 0x0200);
+        assert_eq!(cpu.sp(), 0xfd);
+        assert!(cpu.flag(FLAG_INTERRUPT));
+        assert_eq!(cpu.cycles(), 7);
+    }
+
+    #[test]
+    fn cpu_load_store() {
+        // LDA #$80; STA $10; LDX $10; INX; STA $0300,X
+        let (mut cpu, mut memory) = machine(&[0xa9, 0x80, 0x85, 0x10, 0xa6, 0x10, 0xe8, 0x9d, 0x00, 0x03]);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 2);
+        assert!(cpu.flag(FLAG_NEGATIVE));
+        run(&mut cpu, &mut memory, 4);
+        assert_eq!(memory.read(0x0010).unwrap(), 0x80);
+        assert_eq!(cpu.x(), 0x81);
+        assert_eq!(memory.read(0x0381).unwrap(), 0x80);
+    }
+
+    #[test]
+    fn cpu_arithmetic() {
+        // CLC; LDA #$7f; ADC #$01
+        let (mut cpu, mut memory) = machine(&[0x18, 0xa9, 0x7f, 0x69, 0x01]);
+        run(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.a(), 0x80);
+        assert!(cpu.flag(FLAG_OVERFLOW));
+        assert!(!cpu.flag(FLAG_CARRY));
+
+        // SEC; LDA #$00; SBC #$01
+        let (mut cpu, mut memory) = machine(&[0x38, 0xa9, 0x00, 0xe9, 0x01]);
+        run(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.a(), 0xff);
+        assert!(!cpu.flag(FLAG_CARRY));
+
+        // SED; CLC; LDA #$19; ADC #$28; SEC; SBC #$48
+        let (mut cpu, mut memory) = machine(&[0xf8, 0x18, 0xa9, 0x19, 0x69, 0x28, 0x38, 0xe9, 0x48]);
+        run(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.a(), 0x47);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.a(), 0x99);
+        assert!(!cpu.flag(FLAG_CARRY));
+    }
+
+    #[test]
+    fn cpu_branches() {
+        // LDX #$03; DEX; BNE -3; BEQ +0
+        let (mut cpu, mut memory) = machine(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0xf0, 0x00]);
+        let cycles = run(&mut cpu, &mut memory, 8);
+        assert_eq!(cpu.x(), 0);
+        assert_eq!(cpu.pc(), 0x0207);
+        assert_eq!(cycles, 2 + 3 * 2 + 2 * 3 + 2 + 3);
+    }
+
+    #[test]
+    fn cpu_subroutines() {
+        // JSR $0210; BRK ... $0210: PHA; LDA #$42; PLA; RTS
+        let mut program = vec![0x20, 0x10, 0x02, 0x00];
+        program.resize(0x10, 0xea);
+        program.extend([0x48, 0xa9, 0x42, 0x68, 0x60]);
+        let (mut cpu, mut memory) = machine(&program);
+        cpu.set_a(0x11);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 6);
+        assert_eq!(cpu.sp(), 0xfb);
+        assert_eq!(memory.read(0x01fd).unwrap(), 0x02);
+        assert_eq!(memory.read(0x01fc).unwrap(), 0x02);
+        run(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.a(), 0x11);
+        assert_eq!(cpu.pc(), 0x0203);
+        assert_eq!(cpu.sp(), 0xfd);
+    }
+
+    #[test]
+    fn cpu_interrupts() {
+        // CLI; NOP ... with the IRQ handler at $0300 (RTI) and BRK at $0210
+        let (mut cpu, mut memory) = machine(&[0x58, 0xea]);
+        memory.write(IRQ_VECTOR, 0x00).unwrap();
+        memory.write(IRQ_VECTOR + 1, 0x03).unwrap();
+        memory.write(0x0300, 0x40).unwrap();
+
+        // Masked
+        assert_eq!(cpu.irq(&mut memory).unwrap(), 0);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.irq(&mut memory).unwrap(), 7);
+        assert_eq!(cpu.pc(), 0x0300);
+        assert_eq!(memory.read(0x01fb).unwrap() & FLAG_BREAK, 0);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.pc(), 0x0201);
+        assert!(!cpu.flag(FLAG_INTERRUPT));
+
+        // BRK pushes the address after its padding byte, with B set
+        cpu.set_pc(0x0210);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.pc(), 0x0300);
+        assert_eq!(memory.read(0x01fb).unwrap() & FLAG_BREAK, FLAG_BREAK);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.pc(), 0x0212);
+    }
+
+    #[test]
+    fn cpu_addressing() {
+        // LDA ($10,X); LDA ($20),Y; JMP ($02ff)
+        let (mut cpu, mut memory) = machine(&[0xa1, 0x10, 0xb1, 0x20, 0x6c, 0xff, 0x02]);
+        cpu.set_x(0x04);
+        cpu.set_y(0x01);
+        memory.write(0x0014, 0x00).unwrap();
+        memory.write(0x0015, 0x04).unwrap();
+        memory.write(0x0400, 0x5a).unwrap();
+        memory.write(0x0020, 0xff).unwrap();
+        memory.write(0x0021, 0x04).unwrap();
+        memory.write(0x0500, 0xa5).unwrap();
+        memory.write(0x02ff, 0x34).unwrap();
+
+        assert_eq!(run(&mut cpu, &mut memory, 1), 6);
+        assert_eq!(cpu.a(), 0x5a);
+
+        // Crossing into the next page costs a cycle
+        assert_eq!(run(&mut cpu, &mut memory, 1), 6);
+        assert_eq!(cpu.a(), 0xa5);
+
+        // The indirect jump takes its high byte from the start of the same page, which holds the first opcode
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.pc(), 0xa134);
+    }
+
+    #[test]
+    fn cpu_bus_cycles() {
+        // Every cycle is a read or a write, whatever the opcode, operands and registers
+        let mut seed = 0x2545_f491_u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for model in [CpuModel::MOS6502, CpuModel::WDC65C02] {
+            let (_, mut memory) = machine(&[]);
+            for address in 0..=0xffff {
+                memory.write(address, random() as u8).unwrap();
+            }
+            for _ in 0..8 {
+                for code in 0..=0xff {
+                    let pc = random() as u16;
+                    memory.write(pc, code).unwrap();
+                    let mut cpu = CPU::with_model(model);
+                    cpu.set_pc(pc);
+                    cpu.set_sp(random() as u8);
+                    cpu.set_a(random() as u8);
+                    cpu.set_x(random() as u8);
+                    cpu.set_y(random() as u8);
+                    cpu.set_flags(random() as u8);
+
+                    memory.set_recording(true);
+                    let result = cpu.step(&mut memory);
+                    let accesses = memory.take_accesses().len();
+                    memory.set_recording(false);
+                    match result {
+                        Ok(cycles) => assert_eq!(accesses, cycles as usize, "{:?} ${:02x}", model, code),
+                        Err(CpuError::IllegalOpcode(..)) => assert_eq!(accesses, 2),
+                        Err(error) => assert_eq!(error, CpuError::Stopped(pc))
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cpu_illegal_opcode() {
+        let (mut cpu, mut memory) = machine(&[0x02]);
+        assert!(matches!(cpu.step(&mut memory), Err(CpuError::IllegalOpcode(0x0200, 0x02))));
+    }
+
+    #[test]
+    fn cpu_65c02() {
+        // LDA #$0F; STA $10; LDA #$F0; TSB $10; TRB $10; STZ $11; SMB0 $11; BBR1 $11,+2; BRK; BRK; BRA +0; LDA ($12)
+        let (mut cpu, mut memory) = machine(&[
+            0xa9, 0x0f, 0x85, 0x10, 0xa9, 0xf0, 0x04, 0x10, 0x14, 0x10, 0x64, 0x11, 0x87, 0x11, 0x1f, 0x11, 0x02,
+            0x00, 0x00, 0x80, 0x00, 0xb2, 0x12
+        ]);
+        cpu.set_model(CpuModel::WDC65C02);
+        memory.write(0x0011, 0xff).unwrap();
+        memory.write(0x0012, 0x00).unwrap();
+        memory.write(0x0013, 0x04).unwrap();
+        memory.write(0x0400, 0x77).unwrap();
+
+        run(&mut cpu, &mut memory, 4);
+        assert_eq!(memory.read(0x0010).unwrap(), 0xff);
+        assert!(cpu.flag(FLAG_ZERO));
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(memory.read(0x0010).unwrap(), 0x0f);
+        assert!(!cpu.flag(FLAG_ZERO));
+
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!(memory.read(0x0011).unwrap(), 0x01);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.pc(), 0x0215);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.a(), 0x77);
+    }
+
+    #[test]
+    fn cpu_decimal() {
+        // SED; CLC; LDA #$99; ADC #$01
+        let program = [0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01];
+
+        // The NMOS part takes Z from the binary sum
+        let (mut cpu, mut memory) = machine(&program);
+        assert_eq!(run(&mut cpu, &mut memory, 4), 8);
+        assert_eq!(cpu.a(), 0x00);
+        assert!(cpu.flag(FLAG_CARRY));
+        assert!(!cpu.flag(FLAG_ZERO));
+
+        // The 65C02 gets it right, at the cost of a cycle
+        let (mut cpu, mut memory) = machine(&program);
+        cpu.set_model(CpuModel::WDC65C02);
+        assert_eq!(run(&mut cpu, &mut memory, 4), 9);
+        assert_eq!(cpu.a(), 0x00);
+        assert!(cpu.flag(FLAG_CARRY));
+        assert!(cpu.flag(FLAG_ZERO));
+
+        // SBC: $10 - $01 = $09
+        let (mut cpu, mut memory) = machine(&[0xf8, 0x38, 0xa9, 0x10, 0xe9, 0x01]);
+        cpu.set_model(CpuModel::WDC65C02);
+        run(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.a(), 0x09);
+        assert!(cpu.flag(FLAG_CARRY));
+    }
+
+    #[test]
+    fn cpu_wai_stp() {
+        // WAI; STP
+        let (mut cpu, mut memory) = machine(&[0xcb, 0xdb]);
+        cpu.set_model(CpuModel::WDC65C02);
+        run(&mut cpu, &mut memory, 1);
+        assert!(cpu.waiting());
+        assert_eq!(run(&mut cpu, &mut memory, 2), 2);
+        assert_eq!(cpu.pc(), 0x0201);
+
+        // A masked interrupt still wakes the CPU, which carries on after WAI
+        assert_eq!(cpu.irq(&mut memory).unwrap(), 0);
+        assert!(!cpu.waiting());
+        assert!(matches!(cpu.step(&mut memory), Err(CpuError::Stopped(0x0201))));
+        assert!(matches!(cpu.step(&mut memory), Err(CpuError::Stopped(0x0201))));
+    }
+
+    #[test]
+    fn cpu_undocumented() {
+        // LAX $10; LDX #$0F; SAX $11; DCP $12; ISC $13; SLO $14; ANC #$80; SBX #$01
+        let (mut cpu, mut memory) = machine(&[
+            0xa7, 0x10, 0xa2, 0x0f, 0x87, 0x11, 0xc7, 0x12, 0xe7, 0x13, 0x07, 0x14, 0x0b, 0x80, 0xcb, 0x01
+        ]);
+        memory.write(0x0010, 0x81).unwrap();
+        memory.write(0x0012, 0x02).unwrap();
+        memory.write(0x0014, 0x21).unwrap();
+
+        assert_eq!(run(&mut cpu, &mut memory, 1), 3);
+        assert_eq!(cpu.a(), 0x81);
+        assert_eq!(cpu.x(), 0x81);
+
+        assert_eq!(run(&mut cpu, &mut memory, 7), 24);
+        assert_eq!(memory.read(0x0011).unwrap(), 0x01);
+        assert_eq!(memory.read(0x0012).unwrap(), 0x01);
+        assert_eq!(memory.read(0x0013).unwrap(), 0x01);
+        assert_eq!(memory.read(0x0014).unwrap(), 0x42);
+        assert_eq!(cpu.a(), 0x80);
+        assert_eq!(cpu.x(), 0xff);
+        assert!(!cpu.flag(FLAG_CARRY));
+    }
+}
@@ -0,0 +1,285 @@
+/*!
+ * Disassembler
+ *
+ * Decodes instructions straight out of a MemoryMap using the same opcode table as the CPU, so every CpuModel and the
+ * undocumented opcodes are covered. Memory is read with peek so that disassembling over I/O registers does not
+ * disturb the devices behind them.
+ *
+ * Instructions can be formatted for either ca65 or 64tass, which differ in the names of some undocumented opcodes and
+ * in how an absolute address in the zero page is forced. When a symbol table is given, addresses that have a name are
+ * printed as that name.
+ */
+
+use std::collections::HashMap;
+
+use crate::cpu::opcodes::*;
+use crate::devices::memory_map::*;
+
+// Anything that can name an address, such as a symbol table loaded from an assembler's label file
+pub trait SymbolLookup {
+    fn symbol(&self, address: u16) -> Option<String>;
+}
+
+impl SymbolLookup for HashMap<u16, String> {
+    fn symbol(&self, address: u16) -> Option<String> {
+        self.get(&address).cloned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    #[default]
+    Ca65,
+    Tass64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub opcode: Opcode,
+    pub bytes: Vec<u8>
+}
+
+impl Instruction {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    // The address of the next instruction in memory
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.length())
+    }
+
+    fn byte(&self, index: usize) -> u8 {
+        self.bytes.get(index).copied().unwrap_or(0)
+    }
+
+    fn word(&self) -> u16 {
+        u16::from_le_bytes([self.byte(1), self.byte(2)])
+    }
+
+    // The address a branch goes to when it is taken
+    pub fn branch_target(&self) -> Option<u16> {
+        let offset = match self.opcode.mode {
+            AddressingMode::Relative => self.byte(1),
+            AddressingMode::ZeroPageRelative => self.byte(2),
+            _ => return None
+        };
+        Some(self.next().wrapping_add(offset as i8 as u16))
+    }
+
+    // The name of the mnemonic in the given syntax. The assemblers agree on the documented and 65C02 instructions,
+    // but each has its own names for some of the undocumented ones.
+    pub fn mnemonic(&self, syntax: Syntax) -> String {
+        let name = match (syntax, self.opcode.mnemonic) {
+            (Syntax::Ca65, Mnemonic::SBX) => "axs",
+            (Syntax::Ca65, Mnemonic::LXA) => "lax",
+            (Syntax::Tass64, Mnemonic::ISC) => "isb",
+            (Syntax::Tass64, Mnemonic::ALR) => "asr",
+            (Syntax::Tass64, Mnemonic::LAS) => "lds",
+            (Syntax::Tass64, Mnemonic::TAS) => "shs",
+            (Syntax::Tass64, Mnemonic::LXA) => "lax",
+            (_, mnemonic) => return mnemonic.to_string().to_lowercase()
+        };
+        String::from(name)
+    }
+
+    // Format the instruction as assembler source, such as `lda ($10),y`
+    pub fn format(&self, syntax: Syntax, symbols: Option<&dyn SymbolLookup>) -> String {
+        let name = |address: u16, digits: usize| {
+            symbols
+                .and_then(|symbols| symbols.symbol(address))
+                .unwrap_or_else(|| format!("${:0digits$x}", address, digits = digits))
+        };
+        let low = self.byte(1);
+        let word = self.word();
+
+        // An absolute address in the zero page has to be forced, or the assembler would pick the shorter encoding
+        let absolute = |address: u16| {
+            let prefix = match (address < 0x100, syntax) {
+                (false, _) => "",
+                (true, Syntax::Ca65) => "a:",
+                (true, Syntax::Tass64) => "@w "
+            };
+            format!("{}{}", prefix, name(address, 4))
+        };
+
+        let operand = match self.opcode.mode {
+            AddressingMode::Implied => String::new(),
+            AddressingMode::Accumulator => String::from("a"),
+            AddressingMode::Immediate => format!("#${:02x}", low),
+            AddressingMode::ZeroPage => name(low as u16, 2),
+            AddressingMode::ZeroPageX => format!("{},x", name(low as u16, 2)),
+            AddressingMode::ZeroPageY => format!("{},y", name(low as u16, 2)),
+            AddressingMode::Absolute => absolute(word),
+            AddressingMode::AbsoluteX => format!("{},x", absolute(word)),
+            AddressingMode::AbsoluteY => format!("{},y", absolute(word)),
+            AddressingMode::Indirect => format!("({})", name(word, 4)),
+            AddressingMode::IndirectX => format!("({},x)", name(low as u16, 2)),
+            AddressingMode::IndirectY => format!("({}),y", name(low as u16, 2)),
+            AddressingMode::ZeroPageIndirect => format!("({})", name(low as u16, 2)),
+            AddressingMode::AbsoluteIndexedIndirect => format!("({},x)", name(word, 4)),
+            AddressingMode::Relative => name(self.branch_target().unwrap_or(0), 4),
+            AddressingMode::ZeroPageRelative => {
+                format!("{},{}", name(low as u16, 2), name(self.branch_target().unwrap_or(0), 4))
+            }
+        };
+
+        if operand.is_empty() {
+            self.mnemonic(syntax)
+        } else {
+            format!("{} {}", self.mnemonic(syntax), operand)
+        }
+    }
+}
+
+// Decode the instruction at `address`. Bytes that cannot be read, such as unmapped addresses, read as $ff.
+pub fn disassemble(memory: &MemoryMap, model: CpuModel, address: u16) -> Instruction {
+    let byte = |offset: u16| memory.peek(address.wrapping_add(offset)).unwrap_or(0xff);
+    let opcode = decode(model, byte(0));
+    let bytes = (0..opcode.length()).map(byte).collect();
+    Instruction { address, opcode, bytes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::keyboard::*;
+    use crate::devices::memory::*;
+
+    fn memory(program: &[u8]) -> MemoryMap {
+        let mut memory_map = MemoryMap::new();
+        memory_map.create("RAM".to_string(), MemoryType::RAM, 0x8000, 0x0000).unwrap();
+        for (offset, value) in program.iter().enumerate() {
+            memory_map.write(0x0200 + offset as u16, *value).unwrap();
+        }
+        memory_map
+    }
+
+    fn format(program: &[u8], model: CpuModel, syntax: Syntax) -> String {
+        disassemble(&memory(program), model, 0x0200).format(syntax, None)
+    }
+
+    #[test]
+    fn disassembler() {
+        let memory_map = memory(&[0xa9, 0x01, 0x8d, 0x00, 0x03, 0x60]);
+        let instruction = disassemble(&memory_map, CpuModel::MOS6502, 0x0200);
+        assert_eq!(instruction.opcode.mnemonic, Mnemonic::LDA);
+        assert_eq!(instruction.bytes, vec![0xa9, 0x01]);
+        assert_eq!(instruction.length(), 2);
+        assert_eq!(instruction.next(), 0x0202);
+
+        let instruction = disassemble(&memory_map, CpuModel::MOS6502, 0x0202);
+        assert_eq!(instruction.format(Syntax::Ca65, None), "sta $0300");
+        assert_eq!(instruction.length(), 3);
+    }
+
+    #[test]
+    fn disassembler_addressing_modes() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xea], "nop"),
+            (&[0x0a], "asl a"),
+            (&[0xa2, 0xff], "ldx #$ff"),
+            (&[0xa5, 0x10], "lda $10"),
+            (&[0xb5, 0x10], "lda $10,x"),
+            (&[0xb6, 0x10], "ldx $10,y"),
+            (&[0xad, 0x34, 0x12], "lda $1234"),
+            (&[0xbd, 0x34, 0x12], "lda $1234,x"),
+            (&[0xb9, 0x34, 0x12], "lda $1234,y"),
+            (&[0x6c, 0xfc, 0xff], "jmp ($fffc)"),
+            (&[0xa1, 0x10], "lda ($10,x)"),
+            (&[0xb1, 0x10], "lda ($10),y"),
+            (&[0xd0, 0xfe], "bne $0200"),
+            (&[0x10, 0x10], "bpl $0212")
+        ];
+        for (program, text) in cases {
+            assert_eq!(format(program, CpuModel::MOS6502, Syntax::Ca65), *text);
+        }
+    }
+
+    #[test]
+    fn disassembler_65c02() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xb2, 0x10], "lda ($10)"),
+            (&[0x7c, 0x00, 0x30], "jmp ($3000,x)"),
+            (&[0x80, 0x02], "bra $0204"),
+            (&[0xda], "phx"),
+            (&[0x9c, 0x00, 0x03], "stz $0300"),
+            (&[0x0f, 0x12, 0xfd], "bbr0 $12,$0200"),
+            (&[0xf7, 0x12], "smb7 $12"),
+            (&[0xcb], "wai")
+        ];
+        for (program, text) in cases {
+            assert_eq!(format(program, CpuModel::WDC65C02, Syntax::Ca65), *text);
+        }
+
+        // The same bytes mean something else on the NMOS part
+        assert_eq!(format(&[0xb2], CpuModel::MOS6502, Syntax::Ca65), "jam");
+        assert_eq!(format(&[0xda], CpuModel::MOS6502, Syntax::Ca65), "nop");
+    }
+
+    #[test]
+    fn disassembler_undocumented() {
+        let cases: &[(&[u8], &str, &str)] = &[
+            (&[0x07, 0x10], "slo $10", "slo $10"),
+            (&[0xb3, 0x10], "lax ($10),y", "lax ($10),y"),
+            (&[0xcb, 0x10], "axs #$10", "sbx #$10"),
+            (&[0xab, 0x10], "lax #$10", "lax #$10"),
+            (&[0xef, 0x00, 0x30], "isc $3000", "isb $3000"),
+            (&[0x4b, 0x0f], "alr #$0f", "asr #$0f"),
+            (&[0xbb, 0x00, 0x30], "las $3000,y", "lds $3000,y"),
+            (&[0x9b, 0x00, 0x30], "tas $3000,y", "shs $3000,y"),
+            (&[0x9f, 0x00, 0x30], "sha $3000,y", "sha $3000,y")
+        ];
+        for (program, ca65, tass64) in cases {
+            assert_eq!(format(program, CpuModel::MOS6502, Syntax::Ca65), *ca65);
+            assert_eq!(format(program, CpuModel::MOS6502, Syntax::Tass64), *tass64);
+        }
+    }
+
+    #[test]
+    fn disassembler_forced_absolute() {
+        assert_eq!(format(&[0xad, 0x10, 0x00], CpuModel::MOS6502, Syntax::Ca65), "lda a:$0010");
+        assert_eq!(format(&[0xad, 0x10, 0x00], CpuModel::MOS6502, Syntax::Tass64), "lda @w $0010");
+        assert_eq!(format(&[0xbd, 0x10, 0x00], CpuModel::MOS6502, Syntax::Ca65), "lda a:$0010,x");
+    }
+
+    #[test]
+    fn disassembler_symbols() {
+        let mut symbols = HashMap::new();
+        symbols.insert(0x0300, String::from("buffer"));
+        symbols.insert(0x0010, String::from("pointer"));
+        symbols.insert(0x0200, String::from("loop"));
+
+        let memory_map = memory(&[0xb1, 0x10, 0x9d, 0x00, 0x03, 0xd0, 0xf9, 0xa9, 0x10]);
+        let line = |address| disassemble(&memory_map, CpuModel::MOS6502, address).format(Syntax::Ca65, Some(&symbols));
+        assert_eq!(line(0x0200), "lda (pointer),y");
+        assert_eq!(line(0x0202), "sta buffer,x");
+        assert_eq!(line(0x0205), "bne loop");
+
+        // Immediate values are never substituted
+        assert_eq!(line(0x0207), "lda #$10");
+    }
+
+    #[test]
+    fn disassembler_unmapped() {
+        let memory_map = MemoryMap::new();
+        let instruction = disassemble(&memory_map, CpuModel::WDC65C02, 0x1000);
+        assert_eq!(instruction.bytes, vec![0xff, 0xff, 0xff]);
+        assert_eq!(instruction.format(Syntax::Ca65, None), "bbs7 $ff,$1002");
+    }
+
+    #[test]
+    fn disassembler_side_effects() {
+        let mut memory_map = MemoryMap::new();
+        let mut keyboard = Keyboard::new(KeyboardMode::Ascii, 0xc000);
+        keyboard.press(b'A');
+        memory_map.insert("Keyboard".to_string(), Box::new(keyboard), KEYBOARD_SIZE, 0xc000).unwrap();
+
+        // Disassembling over the keyboard latch does not acknowledge the key
+        let instruction = disassemble(&memory_map, CpuModel::MOS6502, 0xc000);
+        assert_eq!(instruction.bytes[0], 0xc1);
+        assert_eq!(memory_map.read(0xc000).unwrap(), 0xc1);
+        assert_eq!(memory_map.read(0xc000).unwrap(), 0x41);
+    }
+}
@@ -0,0 +1,469 @@
+/*!
+ * Opcode Table
+ *
+ * Decoding information for every opcode of each supported CpuModel: the mnemonic, the addressing mode and the base
+ * number of cycles. Instructions that take an extra cycle when their effective address crosses a page boundary are
+ * flagged as such; branches handle their own timing. The table is shared by the CPU, the disassembler and the
+ * assembler, so all 256 opcodes decode to something on every model:
+ * - MOS6502: the documented instructions plus the undocumented (illegal) ones, including the JAMs that lock up the CPU
+ * - WDC65C02: the CMOS instruction set with the Rockwell bit instructions, WAI and STP, where the unused opcodes are
+ *   NOPs of various lengths
+ */
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CpuModel {
+    #[default]
+    MOS6502,
+    WDC65C02
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mnemonic {
+    ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC,
+    CLD, CLI, CLV, CMP, CPX, CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP,
+    JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA, PHP, PLA, PLP, ROL, ROR, RTI,
+    RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA, TXS, TYA,
+
+    // WDC 65C02
+    BRA, PHX, PHY, PLX, PLY, STZ, TRB, TSB, WAI, STP,
+    BBR(u8), BBS(u8), RMB(u8), SMB(u8),
+
+    // Undocumented NMOS 6502
+    SLO, RLA, SRE, RRA, SAX, LAX, DCP, ISC, ANC, ALR, ARR, ANE, LXA, LAS,
+    TAS, SHA, SHX, SHY, SBX, JAM
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mnemonic::BBR(bit) => write!(f, "BBR{}", bit),
+            Mnemonic::BBS(bit) => write!(f, "BBS{}", bit),
+            Mnemonic::RMB(bit) => write!(f, "RMB{}", bit),
+            Mnemonic::SMB(bit) => write!(f, "SMB{}", bit),
+            _ => write!(f, "{:?}", self)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+    // 65C02: ($zp)
+    ZeroPageIndirect,
+    // 65C02: JMP ($abs,X)
+    AbsoluteIndexedIndirect,
+    // 65C02: BBR/BBS $zp,target
+    ZeroPageRelative
+}
+
+impl AddressingMode {
+    // The number of operand bytes following the opcode
+    pub fn operand_length(&self) -> u16 {
+        match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 0,
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect
+            | AddressingMode::AbsoluteIndexedIndirect
+            | AddressingMode::ZeroPageRelative => 2,
+            _ => 1
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Opcode {
+    pub mnemonic: Mnemonic,
+    pub mode: AddressingMode,
+    pub cycles: u8,
+    // Takes an extra cycle when indexing crosses a page boundary
+    pub page_cycle: bool,
+    // Undocumented on the NMOS part, or reserved on the 65C02
+    pub illegal: bool
+}
+
+impl Opcode {
+    const fn new(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8) -> Opcode {
+        Opcode { mnemonic, mode, cycles, page_cycle: false, illegal: false }
+    }
+
+    const fn paged(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8) -> Opcode {
+        Opcode { mnemonic, mode, cycles, page_cycle: true, illegal: false }
+    }
+
+    const fn undocumented(self) -> Opcode {
+        Opcode { illegal: true, ..self }
+    }
+
+    // The length of the whole instruction in bytes
+    pub fn length(&self) -> u16 {
+        1 + self.mode.operand_length()
+    }
+}
+
+pub fn decode(model: CpuModel, opcode: u8) -> Opcode {
+    match model {
+        CpuModel::MOS6502 => documented(opcode).unwrap_or_else(|| undocumented(opcode)),
+        CpuModel::WDC65C02 => cmos(opcode).or_else(|| documented(opcode)).unwrap_or_else(|| reserved(opcode))
+    }
+}
+
+// The 151 documented opcodes of the NMOS 6502
+fn documented(opcode: u8) -> Option<Opcode> {
+    use AddressingMode::*;
+    use Mnemonic::*;
+
+    let decoded = match opcode {
+        0x00 => Opcode::new(BRK, Implied, 7),
+        0x01 => Opcode::new(ORA, IndirectX, 6),
+        0x05 => Opcode::new(ORA, ZeroPage, 3),
+        0x06 => Opcode::new(ASL, ZeroPage, 5),
+        0x08 => Opcode::new(PHP, Implied, 3),
+        0x09 => Opcode::new(ORA, Immediate, 2),
+        0x0a => Opcode::new(ASL, Accumulator, 2),
+        0x0d => Opcode::new(ORA, Absolute, 4),
+        0x0e => Opcode::new(ASL, Absolute, 6),
+        0x10 => Opcode::new(BPL, Relative, 2),
+        0x11 => Opcode::paged(ORA, IndirectY, 5),
+        0x15 => Opcode::new(ORA, ZeroPageX, 4),
+        0x16 => Opcode::new(ASL, ZeroPageX, 6),
+        0x18 => Opcode::new(CLC, Implied, 2),
+        0x19 => Opcode::paged(ORA, AbsoluteY, 4),
+        0x1d => Opcode::paged(ORA, AbsoluteX, 4),
+        0x1e => Opcode::new(ASL, AbsoluteX, 7),
+        0x20 => Opcode::new(JSR, Absolute, 6),
+        0x21 => Opcode::new(AND, IndirectX, 6),
+        0x24 => Opcode::new(BIT, ZeroPage, 3),
+        0x25 => Opcode::new(AND, ZeroPage, 3),
+        0x26 => Opcode::new(ROL, ZeroPage, 5),
+        0x28 => Opcode::new(PLP, Implied, 4),
+        0x29 => Opcode::new(AND, Immediate, 2),
+        0x2a => Opcode::new(ROL, Accumulator, 2),
+        0x2c => Opcode::new(BIT, Absolute, 4),
+        0x2d => Opcode::new(AND, Absolute, 4),
+        0x2e => Opcode::new(ROL, Absolute, 6),
+        0x30 => Opcode::new(BMI, Relative, 2),
+        0x31 => Opcode::paged(AND, IndirectY, 5),
+        0x35 => Opcode::new(AND, ZeroPageX, 4),
+        0x36 => Opcode::new(ROL, ZeroPageX, 6),
+        0x38 => Opcode::new(SEC, Implied, 2),
+        0x39 => Opcode::paged(AND, AbsoluteY, 4),
+        0x3d => Opcode::paged(AND, AbsoluteX, 4),
+        0x3e => Opcode::new(ROL, AbsoluteX, 7),
+        0x40 => Opcode::new(RTI, Implied, 6),
+        0x41 => Opcode::new(EOR, IndirectX, 6),
+        0x45 => Opcode::new(EOR, ZeroPage, 3),
+        0x46 => Opcode::new(LSR, ZeroPage, 5),
+        0x48 => Opcode::new(PHA, Implied, 3),
+        0x49 => Opcode::new(EOR, Immediate, 2),
+        0x4a => Opcode::new(LSR, Accumulator, 2),
+        0x4c => Opcode::new(JMP, Absolute, 3),
+        0x4d => Opcode::new(EOR, Absolute, 4),
+        0x4e => Opcode::new(LSR, Absolute, 6),
+        0x50 => Opcode::new(BVC, Relative, 2),
+        0x51 => Opcode::paged(EOR, IndirectY, 5),
+        0x55 => Opcode::new(EOR, ZeroPageX, 4),
+        0x56 => Opcode::new(LSR, ZeroPageX, 6),
+        0x58 => Opcode::new(CLI, Implied, 2),
+        0x59 => Opcode::paged(EOR, AbsoluteY, 4),
+        0x5d => Opcode::paged(EOR, AbsoluteX, 4),
+        0x5e => Opcode::new(LSR, AbsoluteX, 7),
+        0x60 => Opcode::new(RTS, Implied, 6),
+        0x61 => Opcode::new(ADC, IndirectX, 6),
+        0x65 => Opcode::new(ADC, ZeroPage, 3),
+        0x66 => Opcode::new(ROR, ZeroPage, 5),
+        0x68 => Opcode::new(PLA, Implied, 4),
+        0x69 => Opcode::new(ADC, Immediate, 2),
+        0x6a => Opcode::new(ROR, Accumulator, 2),
+        0x6c => Opcode::new(JMP, Indirect, 5),
+        0x6d => Opcode::new(ADC, Absolute, 4),
+        0x6e => Opcode::new(ROR, Absolute, 6),
+        0x70 => Opcode::new(BVS, Relative, 2),
+        0x71 => Opcode::paged(ADC, IndirectY, 5),
+        0x75 => Opcode::new(ADC, ZeroPageX, 4),
+        0x76 => Opcode::new(ROR, ZeroPageX, 6),
+        0x78 => Opcode::new(SEI, Implied, 2),
+        0x79 => Opcode::paged(ADC, AbsoluteY, 4),
+        0x7d => Opcode::paged(ADC, AbsoluteX, 4),
+        0x7e => Opcode::new(ROR, AbsoluteX, 7),
+        0x81 => Opcode::new(STA, IndirectX, 6),
+        0x84 => Opcode::new(STY, ZeroPage, 3),
+        0x85 => Opcode::new(STA, ZeroPage, 3),
+        0x86 => Opcode::new(STX, ZeroPage, 3),
+        0x88 => Opcode::new(DEY, Implied, 2),
+        0x8a => Opcode::new(TXA, Implied, 2),
+        0x8c => Opcode::new(STY, Absolute, 4),
+        0x8d => Opcode::new(STA, Absolute, 4),
+        0x8e => Opcode::new(STX, Absolute, 4),
+        0x90 => Opcode::new(BCC, Relative, 2),
+        0x91 => Opcode::new(STA, IndirectY, 6),
+        0x94 => Opcode::new(STY, ZeroPageX, 4),
+        0x95 => Opcode::new(STA, ZeroPageX, 4),
+        0x96 => Opcode::new(STX, ZeroPageY, 4),
+        0x98 => Opcode::new(TYA, Implied, 2),
+        0x99 => Opcode::new(STA, AbsoluteY, 5),
+        0x9a => Opcode::new(TXS, Implied, 2),
+        0x9d => Opcode::new(STA, AbsoluteX, 5),
+        0xa0 => Opcode::new(LDY, Immediate, 2),
+        0xa1 => Opcode::new(LDA, IndirectX, 6),
+        0xa2 => Opcode::new(LDX, Immediate, 2),
+        0xa4 => Opcode::new(LDY, ZeroPage, 3),
+        0xa5 => Opcode::new(LDA, ZeroPage, 3),
+        0xa6 => Opcode::new(LDX, ZeroPage, 3),
+        0xa8 => Opcode::new(TAY, Implied, 2),
+        0xa9 => Opcode::new(LDA, Immediate, 2),
+        0xaa => Opcode::new(TAX, Implied, 2),
+        0xac => Opcode::new(LDY, Absolute, 4),
+        0xad => Opcode::new(LDA, Absolute, 4),
+        0xae => Opcode::new(LDX, Absolute, 4),
+        0xb0 => Opcode::new(BCS, Relative, 2),
+        0xb1 => Opcode::paged(LDA, IndirectY, 5),
+        0xb4 => Opcode::new(LDY, ZeroPageX, 4),
+        0xb5 => Opcode::new(LDA, ZeroPageX, 4),
+        0xb6 => Opcode::new(LDX, ZeroPageY, 4),
+        0xb8 => Opcode::new(CLV, Implied, 2),
+        0xb9 => Opcode::paged(LDA, AbsoluteY, 4),
+        0xba => Opcode::new(TSX, Implied, 2),
+        0xbc => Opcode::paged(LDY, AbsoluteX, 4),
+        0xbd => Opcode::paged(LDA, AbsoluteX, 4),
+        0xbe => Opcode::paged(LDX, AbsoluteY, 4),
+        0xc0 => Opcode::new(CPY, Immediate, 2),
+        0xc1 => Opcode::new(CMP, IndirectX, 6),
+        0xc4 => Opcode::new(CPY, ZeroPage, 3),
+        0xc5 => Opcode::new(CMP, ZeroPage, 3),
+        0xc6 => Opcode::new(DEC, ZeroPage, 5),
+        0xc8 => Opcode::new(INY, Implied, 2),
+        0xc9 => Opcode::new(CMP, Immediate, 2),
+        0xca => Opcode::new(DEX, Implied, 2),
+        0xcc => Opcode::new(CPY, Absolute, 4),
+        0xcd => Opcode::new(CMP, Absolute, 4),
+        0xce => Opcode::new(DEC, Absolute, 6),
+        0xd0 => Opcode::new(BNE, Relative, 2),
+        0xd1 => Opcode::paged(CMP, IndirectY, 5),
+        0xd5 => Opcode::new(CMP, ZeroPageX, 4),
+        0xd6 => Opcode::new(DEC, ZeroPageX, 6),
+        0xd8 => Opcode::new(CLD, Implied, 2),
+        0xd9 => Opcode::paged(CMP, AbsoluteY, 4),
+        0xdd => Opcode::paged(CMP, AbsoluteX, 4),
+        0xde => Opcode::new(DEC, AbsoluteX, 7),
+        0xe0 => Opcode::new(CPX, Immediate, 2),
+        0xe1 => Opcode::new(SBC, IndirectX, 6),
+        0xe4 => Opcode::new(CPX, ZeroPage, 3),
+        0xe5 => Opcode::new(SBC, ZeroPage, 3),
+        0xe6 => Opcode::new(INC, ZeroPage, 5),
+        0xe8 => Opcode::new(INX, Implied, 2),
+        0xe9 => Opcode::new(SBC, Immediate, 2),
+        0xea => Opcode::new(NOP, Implied, 2),
+        0xec => Opcode::new(CPX, Absolute, 4),
+        0xed => Opcode::new(SBC, Absolute, 4),
+        0xee => Opcode::new(INC, Absolute, 6),
+        0xf0 => Opcode::new(BEQ, Relative, 2),
+        0xf1 => Opcode::paged(SBC, IndirectY, 5),
+        0xf5 => Opcode::new(SBC, ZeroPageX, 4),
+        0xf6 => Opcode::new(INC, ZeroPageX, 6),
+        0xf8 => Opcode::new(SED, Implied, 2),
+        0xf9 => Opcode::paged(SBC, AbsoluteY, 4),
+        0xfd => Opcode::paged(SBC, AbsoluteX, 4),
+        0xfe => Opcode::new(INC, AbsoluteX, 7),
+        _ => return None
+    };
+
+    Some(decoded)
+}
+
+
+// The undocumented NMOS opcodes, which fill every gap in the documented table
+fn undocumented(opcode: u8) -> Opcode {
+    use AddressingMode::*;
+    use Mnemonic::*;
+
+    // Most of them combine a read-modify-write instruction with an ALU instruction, laid out in columns 3, 7 and F
+    let combined = [SLO, RLA, SRE, RRA, SAX, LAX, DCP, ISC];
+    let mnemonic = combined[(opcode >> 5) as usize];
+    let store_or_load = matches!(mnemonic, SAX | LAX);
+
+    let decoded = match opcode {
+        _ if opcode & 0x1f == 0x12 || matches!(opcode, 0x02 | 0x22 | 0x42 | 0x62) => Opcode::new(JAM, Implied, 2),
+        0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => Opcode::new(NOP, Implied, 2),
+        0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => Opcode::new(NOP, Immediate, 2),
+        0x04 | 0x44 | 0x64 => Opcode::new(NOP, ZeroPage, 3),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 => Opcode::new(NOP, ZeroPageX, 4),
+        0x0c => Opcode::new(NOP, Absolute, 4),
+        0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => Opcode::paged(NOP, AbsoluteX, 4),
+        0x0b | 0x2b => Opcode::new(ANC, Immediate, 2),
+        0x4b => Opcode::new(ALR, Immediate, 2),
+        0x6b => Opcode::new(ARR, Immediate, 2),
+        0x8b => Opcode::new(ANE, Immediate, 2),
+        0xab => Opcode::new(LXA, Immediate, 2),
+        0xcb => Opcode::new(SBX, Immediate, 2),
+        0xeb => Opcode::new(SBC, Immediate, 2),
+        0x93 => Opcode::new(SHA, IndirectY, 6),
+        0x9f => Opcode::new(SHA, AbsoluteY, 5),
+        0x9b => Opcode::new(TAS, AbsoluteY, 5),
+        0x9c => Opcode::new(SHY, AbsoluteX, 5),
+        0x9e => Opcode::new(SHX, AbsoluteY, 5),
+        0xbb => Opcode::paged(LAS, AbsoluteY, 4),
+        0xb3 => Opcode::paged(LAX, IndirectY, 5),
+        0xbf => Opcode::paged(LAX, AbsoluteY, 4),
+        0x97 => Opcode::new(SAX, ZeroPageY, 4),
+        0xb7 => Opcode::new(LAX, ZeroPageY, 4),
+        _ => match opcode & 0x1f {
+            0x03 => Opcode::new(mnemonic, IndirectX, if store_or_load { 6 } else { 8 }),
+            0x07 => Opcode::new(mnemonic, ZeroPage, if store_or_load { 3 } else { 5 }),
+            0x0f => Opcode::new(mnemonic, Absolute, if store_or_load { 4 } else { 6 }),
+            0x13 => Opcode::new(mnemonic, IndirectY, 8),
+            0x17 => Opcode::new(mnemonic, ZeroPageX, 6),
+            0x1b => Opcode::new(mnemonic, AbsoluteY, 7),
+            0x1f => Opcode::new(mnemonic, AbsoluteX, 7),
+            _ => unreachable!("Opcodes: ${:02x} is documented", opcode)
+        }
+    };
+
+    decoded.undocumented()
+}
+
+// Opcodes that are new or behave differently on the 65C02
+fn cmos(opcode: u8) -> Option<Opcode> {
+    use AddressingMode::*;
+    use Mnemonic::*;
+
+    let bit = (opcode >> 4) & 0x07;
+    let decoded = match opcode {
+        0x04 => Opcode::new(TSB, ZeroPage, 5),
+        0x0c => Opcode::new(TSB, Absolute, 6),
+        0x14 => Opcode::new(TRB, ZeroPage, 5),
+        0x1c => Opcode::new(TRB, Absolute, 6),
+        0x12 => Opcode::new(ORA, ZeroPageIndirect, 5),
+        0x32 => Opcode::new(AND, ZeroPageIndirect, 5),
+        0x52 => Opcode::new(EOR, ZeroPageIndirect, 5),
+        0x72 => Opcode::new(ADC, ZeroPageIndirect, 5),
+        0x92 => Opcode::new(STA, ZeroPageIndirect, 5),
+        0xb2 => Opcode::new(LDA, ZeroPageIndirect, 5),
+        0xd2 => Opcode::new(CMP, ZeroPageIndirect, 5),
+        0xf2 => Opcode::new(SBC, ZeroPageIndirect, 5),
+        0x1a => Opcode::new(INC, Accumulator, 2),
+        0x3a => Opcode::new(DEC, Accumulator, 2),
+        0x34 => Opcode::new(BIT, ZeroPageX, 4),
+        0x3c => Opcode::paged(BIT, AbsoluteX, 4),
+        0x89 => Opcode::new(BIT, Immediate, 2),
+        0x5a => Opcode::new(PHY, Implied, 3),
+        0x7a => Opcode::new(PLY, Implied, 4),
+        0xda => Opcode::new(PHX, Implied, 3),
+        0xfa => Opcode::new(PLX, Implied, 4),
+        0x64 => Opcode::new(STZ, ZeroPage, 3),
+        0x74 => Opcode::new(STZ, ZeroPageX, 4),
+        0x9c => Opcode::new(STZ, Absolute, 4),
+        0x9e => Opcode::new(STZ, AbsoluteX, 5),
+        0x80 => Opcode::new(BRA, Relative, 2),
+        0x6c => Opcode::new(JMP, Indirect, 6),
+        0x7c => Opcode::new(JMP, AbsoluteIndexedIndirect, 6),
+        0xcb => Opcode::new(WAI, Implied, 3),
+        0xdb => Opcode::new(STP, Implied, 3),
+        // Shifts and rotates with absolute,X indexing only take the extra cycle when they cross a page
+        0x1e => Opcode::paged(ASL, AbsoluteX, 6),
+        0x3e => Opcode::paged(ROL, AbsoluteX, 6),
+        0x5e => Opcode::paged(LSR, AbsoluteX, 6),
+        0x7e => Opcode::paged(ROR, AbsoluteX, 6),
+        _ if opcode & 0x8f == 0x07 => Opcode::new(RMB(bit), ZeroPage, 5),
+        _ if opcode & 0x8f == 0x87 => Opcode::new(SMB(bit), ZeroPage, 5),
+        _ if opcode & 0x8f == 0x0f => Opcode::new(BBR(bit), ZeroPageRelative, 5),
+        _ if opcode & 0x8f == 0x8f => Opcode::new(BBS(bit), ZeroPageRelative, 5),
+        _ => return None
+    };
+
+    Some(decoded)
+}
+
+// The unused 65C02 opcodes, which are all NOPs
+fn reserved(opcode: u8) -> Opcode {
+    use AddressingMode::*;
+    use Mnemonic::*;
+
+    let decoded = match opcode {
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 => Opcode::new(NOP, Immediate, 2),
+        0x44 => Opcode::new(NOP, ZeroPage, 3),
+        0x54 | 0xd4 | 0xf4 => Opcode::new(NOP, ZeroPageX, 4),
+        0x5c => Opcode::new(NOP, Absolute, 8),
+        0xdc | 0xfc => Opcode::new(NOP, Absolute, 4),
+        // Columns 3 and B are single cycle NOPs
+        _ => Opcode::new(NOP, Implied, 1)
+    };
+
+    decoded.undocumented()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_documented() {
+        // The NMOS 6502 has 151 documented opcodes
+        let legal = (0..=255).filter(|&opcode| !decode(CpuModel::MOS6502, opcode).illegal).count();
+        assert_eq!(legal, 151);
+
+        // The W65C02S has 212
+        let legal = (0..=255).filter(|&opcode| !decode(CpuModel::WDC65C02, opcode).illegal).count();
+        assert_eq!(legal, 212);
+    }
+
+    #[test]
+    fn opcodes_decode() {
+        let lda = decode(CpuModel::MOS6502, 0xbd);
+        assert_eq!(lda.mnemonic, Mnemonic::LDA);
+        assert_eq!(lda.mode, AddressingMode::AbsoluteX);
+        assert_eq!(lda.length(), 3);
+        assert!(lda.page_cycle);
+
+        assert_eq!(decode(CpuModel::MOS6502, 0x6c).length(), 3);
+        assert_eq!(decode(CpuModel::MOS6502, 0xd0).length(), 2);
+        assert_eq!(decode(CpuModel::MOS6502, 0x0a).length(), 1);
+    }
+
+    #[test]
+    fn opcodes_undocumented() {
+        let decoded = |opcode| {
+            let decoded = decode(CpuModel::MOS6502, opcode);
+            assert!(decoded.illegal);
+            (decoded.mnemonic, decoded.mode, decoded.cycles)
+        };
+        assert_eq!(decoded(0x02), (Mnemonic::JAM, AddressingMode::Implied, 2));
+        assert_eq!(decoded(0x03), (Mnemonic::SLO, AddressingMode::IndirectX, 8));
+        assert_eq!(decoded(0x87), (Mnemonic::SAX, AddressingMode::ZeroPage, 3));
+        assert_eq!(decoded(0xaf), (Mnemonic::LAX, AddressingMode::Absolute, 4));
+        assert_eq!(decoded(0xfb), (Mnemonic::ISC, AddressingMode::AbsoluteY, 7));
+        assert_eq!(decoded(0xdf), (Mnemonic::DCP, AddressingMode::AbsoluteX, 7));
+        assert_eq!(decoded(0xeb), (Mnemonic::SBC, AddressingMode::Immediate, 2));
+    }
+
+    #[test]
+    fn opcodes_65c02() {
+        let decoded = |opcode| {
+            let decoded = decode(CpuModel::WDC65C02, opcode);
+            (decoded.mnemonic, decoded.mode, decoded.length())
+        };
+        assert_eq!(decoded(0x12), (Mnemonic::ORA, AddressingMode::ZeroPageIndirect, 2));
+        assert_eq!(decoded(0x7c), (Mnemonic::JMP, AddressingMode::AbsoluteIndexedIndirect, 3));
+        assert_eq!(decoded(0xb7), (Mnemonic::SMB(3), AddressingMode::ZeroPage, 2));
+        assert_eq!(decoded(0x5f), (Mnemonic::BBR(5), AddressingMode::ZeroPageRelative, 3));
+        assert_eq!(decoded(0x5c), (Mnemonic::NOP, AddressingMode::Absolute, 3));
+        assert_eq!(decoded(0x03), (Mnemonic::NOP, AddressingMode::Implied, 1));
+        assert_eq!(decode(CpuModel::WDC65C02, 0x6c).cycles, 6);
+        assert_eq!(Mnemonic::BBS(7).to_string(), "BBS7");
+    }
+}
@@ -19,6 +19,12 @@ impl ByteRegister {
     }
 }
 
+impl Default for ByteRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
 pub struct WordRegister {
     value: u16
@@ -40,6 +46,12 @@ impl WordRegister {
     }
 }
 
+impl Default for WordRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,1080 @@
+/*!
+ * Debug Adapter Protocol Server
+ *
+ * Lets VS Code, or any other editor that speaks the Debug Adapter Protocol, debug an Emulator. Messages are JSON with
+ * a Content-Length header, read from one stream and written to another, normally stdin and stdout. Source level
+ * debugging uses the debug information ld65 writes with `--dbgfile`.
+ *
+ * The launch request loads the `program` ROM image into the ROM region and resets the CPU, while attach debugs the
+ * Emulator as it is. Both take `debugInfo`, the path of the .dbg file (or any other file the SymbolTable can load),
+ * `sourceRoot`, the directory source file names in it are relative to (the directory of the .dbg file by default),
+ * and `stopOnEntry`.
+ *
+ * The other supported requests are:
+ * - setBreakpoints, on source lines, and setFunctionBreakpoints, on symbols or addresses, both with conditions written
+ *   as monitor expressions and hit counts
+ * - continue, pause, next, stepIn and stepOut, by source line or by instruction
+ * - threads, stackTrace, scopes and variables: the call stack, the registers, the zero page symbols and the stack
+ * - setVariable, for registers and zero page symbols
+ * - readMemory, writeMemory and evaluate
+ * - disconnect and terminate
+ *
+ * The call stack is tracked as the program runs, by watching JSR and following the stack pointer back up.
+ */
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TryRecvError};
+
+use crate::cpu::cpu::*;
+use crate::emulator::breakpoints::*;
+use crate::emulator::emulator::*;
+use crate::emulator::expression::*;
+use crate::json::json::*;
+use crate::monitor::monitor::*;
+
+// How many instructions to run between checks for requests from the client
+const RUN_CHUNK: u32 = 10_000;
+
+const OPCODE_JSR: u8 = 0x20;
+
+const THREAD_ID: i64 = 1;
+
+// Variable references for the scopes, which are the same for every stack frame
+const REGISTERS: i64 = 1;
+const ZERO_PAGE: i64 = 2;
+const STACK: i64 = 3;
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, &byte)| bits | (byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes().filter(|&c| c != b'=' && !c.is_ascii_whitespace()) {
+        bits = bits << 6 | BASE64.iter().position(|&digit| digit == c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+// Read one message, or None at the end of the input
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// Memory references are the addresses we hand out, as 0x hex, but be generous in what is accepted
+fn parse_address(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let hex = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).or_else(|| text.strip_prefix('$'));
+    match hex {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+fn memory_reference(address: u16) -> Json {
+    Json::from(format!("0x{:04x}", address))
+}
+
+// The flags as letters, uppercase when set
+fn flags_string(flags: u8) -> String {
+    "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(index, c)| if flags & (0x80 >> index) != 0 { c } else { c.to_ascii_lowercase() })
+        .collect()
+}
+
+// A subroutine call in progress: where the JSR was, and the stack pointer before it
+#[derive(Debug, Clone, Copy)]
+struct Call {
+    address: u16,
+    sp: u8
+}
+
+// What the program is doing while it runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Run {
+    Continue,
+    // Step until the source line changes, or by one instruction when there is no line to leave
+    StepIn(Option<(u32, u32)>),
+    // Like StepIn, but over subroutine calls deeper than the depth given
+    Next(Option<(u32, u32)>, usize),
+    // Run until the call stack is shallower than the depth given
+    StepOut(usize)
+}
+
+// A breakpoint from the client, with the id it knows it by and the Emulator breakpoints that implement it
+#[derive(Debug)]
+struct ClientBreakpoint {
+    id: i64,
+    numbers: Vec<u32>
+}
+
+#[derive(Debug)]
+pub struct DapServer {
+    emulator: Emulator,
+    source_root: PathBuf,
+    // Source breakpoints by path, then function breakpoints
+    source_breakpoints: HashMap<String, Vec<ClientBreakpoint>>,
+    function_breakpoints: Vec<ClientBreakpoint>,
+    next_id: i64,
+    stop_on_entry: bool,
+    calls: Vec<Call>,
+    running: Option<Run>,
+    seq: i64,
+    outbox: Vec<Json>,
+    done: bool
+}
+
+impl DapServer {
+    pub fn new(emulator: Emulator) -> DapServer {
+        DapServer {
+            emulator,
+            source_root: PathBuf::new(),
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: Vec::new(),
+            next_id: 1,
+            stop_on_entry: false,
+            calls: Vec::new(),
+            running: None,
+            seq: 1,
+            outbox: Vec::new(),
+            done: false
+        }
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emulator
+    }
+
+    // Serve one session, until the client disconnects or the input ends. Requests are read on another thread, so
+    // that a pause can arrive while the program is running.
+    pub fn serve<R, W>(&mut self, input: R, mut output: W) -> io::Result<()>
+    where
+        R: BufRead + Send + 'static,
+        W: Write
+    {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut input = input;
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.done = false;
+        while !self.done {
+            let message = if self.running.is_some() {
+                self.run_chunk();
+                self.flush(&mut output)?;
+                match receiver.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => break
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => break
+                }
+            };
+
+            match Json::parse(&message) {
+                Ok(request) => self.handle(&request),
+                Err(error) => self.output(&format!("Bad message: {}\n", error))
+            }
+            self.flush(&mut output)?;
+        }
+        Ok(())
+    }
+
+    fn flush<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        for message in std::mem::take(&mut self.outbox) {
+            write_message(output, &message)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, kind: &str, mut fields: Vec<(&str, Json)>) {
+        fields.insert(0, ("seq", Json::from(self.seq)));
+        fields.insert(1, ("type", Json::from(kind)));
+        self.seq += 1;
+        self.outbox.push(Json::object(fields));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send("event", vec![("event", Json::from(event)), ("body", body)]);
+    }
+
+    fn output(&mut self, text: &str) {
+        self.event("output", Json::object(vec![("category", Json::from("console")), ("output", Json::from(text))]));
+    }
+
+    fn stopped(&mut self, reason: &str, fields: Vec<(&str, Json)>) {
+        self.running = None;
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true))
+        ];
+        body.extend(fields);
+        self.event("stopped", Json::object(body));
+    }
+
+    fn handle(&mut self, request: &Json) {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default().to_string();
+        let null = Json::object(vec![]);
+        let arguments = request.get("arguments").unwrap_or(&null);
+
+        let result = match command.as_str() {
+            "initialize" => Ok(self.initialize()),
+            "launch" => self.launch(arguments, true),
+            "attach" => self.launch(arguments, false),
+            "configurationDone" => Ok(Json::Null),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(arguments),
+            "setExceptionBreakpoints" => Ok(Json::object(vec![("breakpoints", Json::from(vec![]))])),
+            "threads" => {
+                let thread = Json::object(vec![("id", Json::from(THREAD_ID)), ("name", Json::from("CPU"))]);
+                Ok(Json::object(vec![("threads", Json::from(vec![thread]))]))
+            }
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(DapServer::scopes()),
+            "variables" => self.variables(arguments),
+            "setVariable" => self.set_variable(arguments),
+            "readMemory" => self.read_memory(arguments),
+            "writeMemory" => self.write_memory(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "continue" => {
+                self.running = Some(Run::Continue);
+                Ok(Json::object(vec![("allThreadsContinued", Json::from(true))]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.running = Some(self.step_mode(&command, arguments));
+                Ok(Json::Null)
+            }
+            "pause" => Ok(Json::Null),
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(Json::Null)
+            }
+            _ => Err(format!("Unsupported request: {}", command))
+        };
+
+        let mut fields = vec![
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(result.is_ok())),
+            ("command", Json::from(command.as_str()))
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => fields.push(("body", body)),
+            Err(message) => fields.push(("message", Json::from(message)))
+        }
+        self.send("response", fields);
+
+        // Events that have to follow the response
+        match command.as_str() {
+            "initialize" => self.event("initialized", Json::Null),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", vec![]);
+                } else {
+                    self.running = Some(Run::Continue);
+                }
+            }
+            "pause" if self.running.is_some() => self.stopped("pause", vec![]),
+            "terminate" => self.event("terminated", Json::Null),
+            _ => {}
+        }
+    }
+
+    fn initialize(&self) -> Json {
+        Json::object(vec![
+            ("supportsConfigurationDoneRequest", Json::from(true)),
+            ("supportsFunctionBreakpoints", Json::from(true)),
+            ("supportsConditionalBreakpoints", Json::from(true)),
+            ("supportsHitConditionalBreakpoints", Json::from(true)),
+            ("supportsSetVariable", Json::from(true)),
+            ("supportsSteppingGranularity", Json::from(true)),
+            ("supportsReadMemoryRequest", Json::from(true)),
+            ("supportsWriteMemoryRequest", Json::from(true)),
+            ("supportsTerminateRequest", Json::from(true))
+        ])
+    }
+
+    fn launch(&mut self, arguments: &Json, launch: bool) -> Result<Json, String> {
+        if let Some(path) = arguments.get("debugInfo").and_then(Json::as_str) {
+            self.emulator.symbols_mut().load(path).map_err(|error| error.to_string())?;
+            self.source_root = Path::new(path).parent().map(Path::to_path_buf).unwrap_or_default();
+        }
+        if let Some(root) = arguments.get("sourceRoot").and_then(Json::as_str) {
+            self.source_root = PathBuf::from(root);
+        }
+        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+
+        if launch {
+            if let Some(program) = arguments.get("program").and_then(Json::as_str) {
+                let data = std::fs::read(program).map_err(|error| format!("Unable to read {}: {}", program, error))?;
+                self.emulator
+                    .memory_map_mut()
+                    .load("ROM", data)
+                    .map_err(|error| format!("Unable to load {}: {:?}", program, error))?;
+            }
+            self.emulator.start().map_err(|error| self.error_message(error))?;
+            self.calls.clear();
+        }
+        Ok(Json::Null)
+    }
+
+    // The path a client should use for a source file in the debug information
+    fn source_path(&self, file: u32) -> Option<PathBuf> {
+        let name = &self.emulator.symbols().debug_info()?.file(file)?.name;
+        Some(self.source_root.join(name))
+    }
+
+    fn source(&self, file: u32) -> Json {
+        let path = self.source_path(file).unwrap_or_default();
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        Json::object(vec![("name", Json::from(name)), ("path", Json::from(path.to_string_lossy().into_owned()))])
+    }
+
+    fn line_at(&self, address: u16) -> Option<(u32, u32)> {
+        let info = self.emulator.symbols().debug_info()?.line_at(address)?;
+        Some((info.file, info.line))
+    }
+
+    fn symbol(&self, name: &str) -> Option<u16> {
+        self.emulator.symbols().address(name)
+    }
+
+    // Name an address after its symbol, as in main+3
+    fn symbolize(&self, address: u16) -> String {
+        self.emulator.symbols().symbolize(address).unwrap_or_else(|| format!("${:04x}", address))
+    }
+
+    fn error_message(&self, error: CpuError) -> String {
+        error_message(&MonitorError::Cpu(error), self.emulator.symbols())
+    }
+
+    // Create the Emulator breakpoints for one client breakpoint, applying its condition and hit count
+    fn add_breakpoints(&mut self, addresses: &[u16], options: &Json) -> Result<ClientBreakpoint, String> {
+        let condition = match options.get("condition").and_then(Json::as_str).filter(|text| !text.trim().is_empty()) {
+            Some(text) => Some(Expression::parse(text).map_err(|error| error.to_string())?),
+            None => None
+        };
+        let ignore = match options.get("hitCondition").and_then(Json::as_str).filter(|text| !text.trim().is_empty()) {
+            Some(text) => {
+                let hits = text.trim().parse::<u64>().map_err(|_| format!("Bad hit count: {}", text))?;
+                hits.saturating_sub(1)
+            }
+            None => 0
+        };
+
+        let mut numbers = Vec::new();
+        for &address in addresses {
+            let mut breakpoint = Breakpoint::execute(address).with_ignore(ignore);
+            if let Some(condition) = &condition {
+                breakpoint = breakpoint.with_condition(condition.clone());
+            }
+            numbers.push(self.emulator.breakpoints_mut().add(breakpoint));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        Ok(ClientBreakpoint { id, numbers })
+    }
+
+    fn remove_breakpoints(&mut self, breakpoints: Vec<ClientBreakpoint>) {
+        for number in breakpoints.into_iter().flat_map(|breakpoint| breakpoint.numbers) {
+            self.emulator.breakpoints_mut().remove(number);
+        }
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let source = arguments.get("source").cloned().unwrap_or(Json::Null);
+        let path = source.get("path").and_then(Json::as_str).unwrap_or_default().to_string();
+        let old = self.source_breakpoints.remove(&path).unwrap_or_default();
+        self.remove_breakpoints(old);
+
+        let file = self.emulator.symbols().debug_info().and_then(|info| info.find_file(&path)).map(|file| file.id);
+        let requested = arguments.get("breakpoints").and_then(Json::as_array).cloned().unwrap_or_default();
+        let mut added = Vec::new();
+        let mut results = Vec::new();
+        for options in &requested {
+            let line = options.get("line").and_then(Json::as_i64).unwrap_or(0) as u32;
+            // A line without code moves to the next line that has some
+            let found = file.and_then(|file| {
+                let info = self.emulator.symbols().debug_info()?;
+                let line = info
+                    .lines()
+                    .iter()
+                    .filter(|info| info.file == file && info.line >= line && !info.macro_expansion)
+                    .map(|info| info.line)
+                    .min()?;
+                Some((line, info.addresses(file, line)))
+            });
+
+            let mut result = vec![("verified", Json::from(false)), ("line", Json::from(line as i64))];
+            match found {
+                None => result.push(("message", Json::from("No code at this line"))),
+                Some((line, addresses)) => match self.add_breakpoints(&addresses, options) {
+                    Ok(breakpoint) => {
+                        result = vec![
+                            ("id", Json::from(breakpoint.id)),
+                            ("verified", Json::from(true)),
+                            ("line", Json::from(line as i64)),
+                            ("source", source.clone())
+                        ];
+                        added.push(breakpoint);
+                    }
+                    Err(message) => result.push(("message", Json::from(message)))
+                }
+            }
+            results.push(Json::object(result));
+        }
+        self.source_breakpoints.insert(path, added);
+        Ok(Json::object(vec![("breakpoints", Json::from(results))]))
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let old = std::mem::take(&mut self.function_breakpoints);
+        self.remove_breakpoints(old);
+
+        let requested = arguments.get("breakpoints").and_then(Json::as_array).cloned().unwrap_or_default();
+        let mut results = Vec::new();
+        for options in &requested {
+            let name = options.get("name").and_then(Json::as_str).unwrap_or_default();
+            let result = match self.symbol(name).or_else(|| parse_address(name)) {
+                None => Err(format!("Unknown symbol: {}", name)),
+                Some(address) => self.add_breakpoints(&[address], options)
+            };
+            results.push(match result {
+                Ok(breakpoint) => {
+                    let result = Json::object(vec![("id", Json::from(breakpoint.id)), ("verified", Json::from(true))]);
+                    self.function_breakpoints.push(breakpoint);
+                    result
+                }
+                Err(message) => Json::object(vec![("verified", Json::from(false)), ("message", Json::from(message))])
+            });
+        }
+        Ok(Json::object(vec![("breakpoints", Json::from(results))]))
+    }
+
+    // The id the client knows an Emulator breakpoint by
+    fn client_id(&self, number: u32) -> Option<i64> {
+        self.source_breakpoints
+            .values()
+            .flatten()
+            .chain(self.function_breakpoints.iter())
+            .find(|breakpoint| breakpoint.numbers.contains(&number))
+            .map(|breakpoint| breakpoint.id)
+    }
+
+    fn step_mode(&self, command: &str, arguments: &Json) -> Run {
+        let instruction = arguments.get("granularity").and_then(Json::as_str) == Some("instruction");
+        let line = if instruction { None } else { self.line_at(self.emulator.cpu().pc()) };
+        match command {
+            "stepIn" => Run::StepIn(line),
+            "next" => Run::Next(line, self.calls.len()),
+            _ => Run::StepOut(self.calls.len())
+        }
+    }
+
+    // Execute one instruction, keeping track of subroutine calls
+    fn execute(&mut self) -> Result<Option<StopReason>, CpuError> {
+        let pc = self.emulator.cpu().pc();
+        let sp = self.emulator.cpu().sp();
+        let opcode = self.emulator.memory_map().peek(pc).ok();
+        let stop = self.emulator.step_watched()?;
+
+        // Returns, and anything else that moves the stack pointer back up, unwind the calls
+        let sp_now = self.emulator.cpu().sp();
+        while self.calls.last().is_some_and(|call| sp_now >= call.sp) {
+            self.calls.pop();
+        }
+        if opcode == Some(OPCODE_JSR) {
+            self.calls.push(Call { address: pc, sp });
+        }
+        Ok(stop)
+    }
+
+    // Whether a step has finished, after an instruction has run
+    fn step_done(&self, run: Run) -> bool {
+        let line = self.line_at(self.emulator.cpu().pc());
+        match run {
+            Run::Continue => false,
+            Run::StepIn(None) => true,
+            Run::StepIn(start) => line.is_some() && line != start,
+            Run::Next(start, depth) => {
+                self.calls.len() <= depth && (start.is_none() || (line.is_some() && line != start))
+            }
+            Run::StepOut(depth) => self.calls.len() < depth
+        }
+    }
+
+    // Run up to a chunk of instructions, or until something stops the program
+    fn run_chunk(&mut self) {
+        let Some(run) = self.running else {
+            return;
+        };
+        for _ in 0..RUN_CHUNK {
+            match self.execute() {
+                Err(error) => {
+                    let text = self.error_message(error);
+                    let fields = vec![("description", Json::from(text.as_str())), ("text", Json::from(text))];
+                    self.stopped("exception", fields);
+                    return;
+                }
+                Ok(Some(stop)) => {
+                    self.stop(stop);
+                    return;
+                }
+                Ok(None) => {}
+            }
+            if self.step_done(run) {
+                self.stopped("step", vec![]);
+                return;
+            }
+            if let Some(stop) = self.emulator.check_breakpoints() {
+                self.stop(stop);
+                return;
+            }
+        }
+    }
+
+    fn stop(&mut self, stop: StopReason) {
+        match stop {
+            StopReason::Breakpoint(number) => {
+                let ids = self.client_id(number).map(|id| vec![Json::from(id)]).unwrap_or_default();
+                self.stopped("breakpoint", vec![("hitBreakpointIds", Json::from(ids))]);
+            }
+            StopReason::Watchpoint(_, access) => {
+                let text = format!("{:?} of ${:04x}", access.kind, access.address);
+                self.stopped("data breakpoint", vec![("description", Json::from(text))]);
+            }
+            StopReason::Limit => {}
+        }
+    }
+
+    fn frame(&self, id: i64, address: u16) -> Json {
+        let mut frame = vec![("id", Json::from(id)), ("name", Json::from(self.symbolize(address)))];
+        match self.line_at(address) {
+            Some((file, line)) => {
+                frame.push(("source", self.source(file)));
+                frame.push(("line", Json::from(line as i64)));
+                frame.push(("column", Json::from(1)));
+            }
+            None => {
+                // Code without debug information has no source to show
+                frame.push(("line", Json::from(0)));
+                frame.push(("column", Json::from(0)));
+                frame.push(("presentationHint", Json::from("subtle")));
+            }
+        }
+        frame.push(("instructionPointerReference", memory_reference(address)));
+        Json::object(frame)
+    }
+
+    fn stack_trace(&self) -> Json {
+        let mut frames = vec![self.frame(0, self.emulator.cpu().pc())];
+        for (index, call) in self.calls.iter().rev().enumerate() {
+            frames.push(self.frame(index as i64 + 1, call.address));
+        }
+        let total = frames.len() as i64;
+        Json::object(vec![("stackFrames", Json::from(frames)), ("totalFrames", Json::from(total))])
+    }
+
+    fn scopes() -> Json {
+        let scope = |name: &str, reference: i64| {
+            Json::object(vec![
+                ("name", Json::from(name)),
+                ("variablesReference", Json::from(reference)),
+                ("expensive", Json::from(false))
+            ])
+        };
+        let scopes = vec![scope("Registers", REGISTERS), scope("Zero Page", ZERO_PAGE), scope("Stack", STACK)];
+        Json::object(vec![("scopes", Json::from(scopes))])
+    }
+
+    // The labels in the zero page, in address order
+    fn zero_page_symbols(&self) -> Vec<(String, u16)> {
+        let labels = self.emulator.symbols().labels();
+        labels
+            .take_while(|(address, _)| *address < 0x100)
+            .map(|(address, name)| (String::from(name), address))
+            .collect()
+    }
+
+    fn variable(name: &str, value: String, address: Option<u16>) -> Json {
+        let mut fields = vec![
+            ("name", Json::from(name)),
+            ("value", Json::from(value)),
+            ("variablesReference", Json::from(0))
+        ];
+        if let Some(address) = address {
+            fields.push(("memoryReference", memory_reference(address)));
+        }
+        Json::object(fields)
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let cpu = self.emulator.cpu();
+        let memory = self.emulator.memory_map();
+        let byte = |address: u16| match memory.peek(address) {
+            Ok(value) => format!("${:02x}", value),
+            Err(_) => String::from("??")
+        };
+
+        let variables = match arguments.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS) => vec![
+                DapServer::variable("a", format!("${:02x}", cpu.a()), None),
+                DapServer::variable("x", format!("${:02x}", cpu.x()), None),
+                DapServer::variable("y", format!("${:02x}", cpu.y()), None),
+                DapServer::variable("sp", format!("${:02x}", cpu.sp()), Some(0x0100 | cpu.sp() as u16)),
+                DapServer::variable("pc", format!("${:04x}", cpu.pc()), Some(cpu.pc())),
+                DapServer::variable("p", format!("${:02x} {}", cpu.flags(), flags_string(cpu.flags())), None)
+            ],
+            Some(ZERO_PAGE) => self
+                .zero_page_symbols()
+                .iter()
+                .map(|(name, address)| DapServer::variable(name, byte(*address), Some(*address)))
+                .collect(),
+            Some(STACK) => (cpu.sp() as u16 + 1..=0xff)
+                .map(|offset| 0x0100 | offset)
+                .map(|address| DapServer::variable(&format!("${:04x}", address), byte(address), Some(address)))
+                .collect(),
+            _ => return Err(String::from("Unknown variables reference"))
+        };
+        Ok(Json::object(vec![("variables", Json::from(variables))]))
+    }
+
+    fn set_variable(&mut self, arguments: &Json) -> Result<Json, String> {
+        let name = arguments.get("name").and_then(Json::as_str).unwrap_or_default();
+        let text = arguments.get("value").and_then(Json::as_str).unwrap_or_default();
+        let expression = Expression::parse(text).map_err(|error| error.to_string())?;
+        let value = expression.evaluate(self.emulator.cpu(), self.emulator.memory_map());
+
+        let shown = match arguments.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS) => {
+                let cpu = self.emulator.cpu_mut();
+                match name {
+                    "a" => cpu.set_a(value as u8),
+                    "x" => cpu.set_x(value as u8),
+                    "y" => cpu.set_y(value as u8),
+                    "sp" => cpu.set_sp(value as u8),
+                    "p" => cpu.set_flags(value as u8),
+                    "pc" => cpu.set_pc(value as u16),
+                    _ => return Err(format!("Unknown register: {}", name))
+                }
+                if name == "pc" {
+                    format!("${:04x}", value as u16)
+                } else if name == "p" {
+                    format!("${:02x} {}", cpu.flags(), flags_string(cpu.flags()))
+                } else {
+                    format!("${:02x}", value as u8)
+                }
+            }
+            Some(ZERO_PAGE) => {
+                let address = self.symbol(name).ok_or_else(|| format!("Unknown symbol: {}", name))?;
+                self.emulator
+                    .memory_map_mut()
+                    .poke(address, value as u8)
+                    .map_err(|error| MonitorError::Memory(address, error).to_string())?;
+                format!("${:02x}", value as u8)
+            }
+            _ => return Err(String::from("Only registers and zero page symbols can be set"))
+        };
+        Ok(Json::object(vec![("value", Json::from(shown))]))
+    }
+
+    fn memory_address(arguments: &Json) -> Result<u16, String> {
+        let reference = arguments.get("memoryReference").and_then(Json::as_str).unwrap_or_default();
+        let address = parse_address(reference).ok_or_else(|| format!("Bad memory reference: {}", reference))?;
+        let offset = arguments.get("offset").and_then(Json::as_i64).unwrap_or(0);
+        Ok(address.wrapping_add(offset as u16))
+    }
+
+    fn read_memory(&self, arguments: &Json) -> Result<Json, String> {
+        let address = DapServer::memory_address(arguments)?;
+        let count = arguments.get("count").and_then(Json::as_i64).unwrap_or(0).clamp(0, 0x10000) as u32;
+
+        // Stop at the first byte that cannot be read
+        let memory = self.emulator.memory_map();
+        let data = (0..count)
+            .map_while(|offset| memory.peek(address.wrapping_add(offset as u16)).ok())
+            .collect::<Vec<u8>>();
+        Ok(Json::object(vec![
+            ("address", memory_reference(address)),
+            ("data", Json::from(base64_encode(&data))),
+            ("unreadableBytes", Json::from((count - data.len() as u32) as i64))
+        ]))
+    }
+
+    fn write_memory(&mut self, arguments: &Json) -> Result<Json, String> {
+        let address = DapServer::memory_address(arguments)?;
+        let text = arguments.get("data").and_then(Json::as_str).unwrap_or_default();
+        let data = base64_decode(text).ok_or_else(|| String::from("Bad base64 data"))?;
+        for (offset, &byte) in data.iter().enumerate() {
+            let address = address.wrapping_add(offset as u16);
+            self.emulator
+                .memory_map_mut()
+                .poke(address, byte)
+                .map_err(|error| MonitorError::Memory(address, error).to_string())?;
+        }
+        Ok(Json::object(vec![("bytesWritten", Json::from(data.len() as i64))]))
+    }
+
+    // Symbols evaluate to their address, anything else as a monitor expression
+    fn evaluate(&self, arguments: &Json) -> Result<Json, String> {
+        let text = arguments.get("expression").and_then(Json::as_str).unwrap_or_default().trim();
+        let (value, address) = match self.symbol(text) {
+            Some(address) => (address as i64, Some(address)),
+            None => {
+                let expression = Expression::parse(text).map_err(|error| error.to_string())?;
+                (expression.evaluate(self.emulator.cpu(), self.emulator.memory_map()), None)
+            }
+        };
+
+        let result = if (0..=0xff).contains(&value) {
+            format!("${:02x} ({})", value, value)
+        } else if (0..=0xffff).contains(&value) {
+            format!("${:04x} ({})", value, value)
+        } else {
+            value.to_string()
+        };
+        let mut fields = vec![("result", Json::from(result)), ("variablesReference", Json::from(0))];
+        if let Some(address) = address {
+            fields.push(("memoryReference", memory_reference(address)));
+        }
+        Ok(Json::object(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::opcodes::*;
+    use crate::devices::memory::*;
+    use crate::symbols::debug_info::*;
+    use std::io::Cursor;
+
+    // main.s, assembled at $8000:
+    //  2  main:   ldx #$00
+    //  3  loop:   jsr bump
+    //  4          bne loop
+    //  5          stp
+    //  7  bump:   inx
+    //  8          stx counter
+    //  9          rts
+    const PROGRAM: [u8; 13] = [0xa2, 0x00, 0x20, 0x08, 0x80, 0xd0, 0xfb, 0xdb, 0xe8, 0x86, 0x10, 0x60, 0x00];
+
+    const DEBUG_INFO: &str = "version\tmajor=2,minor=0
+file\tid=0,name=\"main.s\",size=100,mtime=0x65a1b2c3,mod=0
+seg\tid=0,name=\"CODE\",start=0x008000,size=0x000c,addrsize=absolute,type=ro
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=0,start=5,size=2
+span\tid=3,seg=0,start=7,size=1
+span\tid=4,seg=0,start=8,size=1
+span\tid=5,seg=0,start=9,size=2
+span\tid=6,seg=0,start=11,size=1
+line\tid=0,file=0,line=2,span=0
+line\tid=1,file=0,line=3,span=1
+line\tid=2,file=0,line=4,span=2
+line\tid=3,file=0,line=5,span=3
+line\tid=4,file=0,line=7,span=4
+line\tid=5,file=0,line=8,span=5
+line\tid=6,file=0,line=9,span=6
+sym\tid=0,name=\"main\",addrsize=absolute,val=0x8000,seg=0,type=lab
+sym\tid=1,name=\"loop\",addrsize=absolute,val=0x8002,seg=0,type=lab
+sym\tid=2,name=\"bump\",addrsize=absolute,val=0x8008,seg=0,type=lab
+sym\tid=3,name=\"counter\",addrsize=zeropage,val=0x10,type=lab
+";
+
+    fn server() -> DapServer {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x4000, 0x0000).unwrap();
+        emulator.memory_map_mut().create(String::from("ROM"), MemoryType::ROM, 0x8000, 0x8000).unwrap();
+        emulator.cpu_mut().set_model(CpuModel::WDC65C02);
+        let mut server = DapServer::new(emulator);
+        server.emulator_mut().symbols_mut().add_debug_info(DebugInfo::parse(DEBUG_INFO).unwrap());
+        server.source_root = PathBuf::from("/project");
+        server
+    }
+
+    fn rom_file(name: &str) -> PathBuf {
+        let mut rom = vec![0; 0x8000];
+        rom[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        rom[0x7ffd] = 0x80;
+        let path = std::env::temp_dir().join(format!("dap_{}_{}.bin", name, std::process::id()));
+        std::fs::write(&path, rom).unwrap();
+        path
+    }
+
+    // Play a recorded session, returning everything the server sent
+    fn session(server: &mut DapServer, requests: &[&str]) -> Vec<Json> {
+        let mut input = String::new();
+        for (index, request) in requests.iter().enumerate() {
+            let (command, arguments) = request.split_once(' ').unwrap_or((request, "{}"));
+            let body = format!("{{\"seq\":{},\"type\":\"request\",\"command\":\"{}\",\"arguments\":{}}}", index + 1,
+                command, arguments);
+            input += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        }
+
+        let mut output = Vec::new();
+        server.serve(Cursor::new(input.into_bytes()), &mut output).unwrap();
+
+        let mut output = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(Json::parse(&message).unwrap());
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Json], command: &str) -> Vec<&'a Json> {
+        messages
+            .iter()
+            .filter(|message| message.get("type").and_then(Json::as_str) == Some("response"))
+            .filter(|message| message.get("command").and_then(Json::as_str) == Some(command))
+            .collect()
+    }
+
+    fn events<'a>(messages: &'a [Json], event: &str) -> Vec<&'a Json> {
+        messages.iter().filter(|message| message.get("event").and_then(Json::as_str) == Some(event)).collect()
+    }
+
+    fn field<'a>(message: &'a Json, path: &[&str]) -> &'a Json {
+        path.iter().fold(message, |json, key| json.get(key).unwrap_or_else(|| panic!("No {} in {}", key, message)))
+    }
+
+    #[test]
+    fn dap_base64() {
+        for data in [&b""[..], b"a", b"ab", b"abc", b"\x00\xff\x10\x80"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_decode("TQ=="), Some(b"M".to_vec()));
+        assert_eq!(base64_decode("T!"), None);
+    }
+
+    #[test]
+    fn dap_launch_and_breakpoints() {
+        let rom = rom_file("launch");
+        let launch = format!("{{\"program\":{},\"stopOnEntry\":true}}", Json::from(rom.to_string_lossy().as_ref()));
+        let mut server = server();
+        let messages = session(&mut server, &[
+            "initialize {\"adapterID\":\"remu\"}",
+            &format!("launch {}", launch),
+            "setBreakpoints {\"source\":{\"path\":\"/project/main.s\"},\"breakpoints\":[{\"line\":6},{\"line\":1000}]}",
+            "configurationDone",
+            "continue",
+            "stackTrace {\"threadId\":1}",
+            "variables {\"variablesReference\":1}",
+            "variables {\"variablesReference\":2}",
+            "continue",
+            "variables {\"variablesReference\":2}",
+            "disconnect"
+        ]);
+        std::fs::remove_file(rom).unwrap();
+
+        // initialized follows the initialize response
+        assert_eq!(messages[0].get("command").and_then(Json::as_str), Some("initialize"));
+        assert_eq!(messages[1].get("event").and_then(Json::as_str), Some("initialized"));
+        assert_eq!(field(&messages[0], &["body", "supportsSetVariable"]), &Json::from(true));
+
+        // Line 6 has no code, so the breakpoint moves to line 7
+        let breakpoints = field(response(&messages, "setBreakpoints")[0], &["body", "breakpoints"]).as_array().unwrap();
+        assert_eq!(breakpoints[0].get("line"), Some(&Json::from(7)));
+        assert_eq!(breakpoints[0].get("verified"), Some(&Json::from(true)));
+        assert_eq!(breakpoints[1].get("verified"), Some(&Json::from(false)));
+
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped.len(), 3);
+        assert_eq!(field(stopped[0], &["body", "reason"]).as_str(), Some("entry"));
+        assert_eq!(field(stopped[1], &["body", "reason"]).as_str(), Some("breakpoint"));
+        assert_eq!(field(stopped[1], &["body", "hitBreakpointIds"]), &Json::from(vec![Json::from(1)]));
+
+        let frames = field(response(&messages, "stackTrace")[0], &["body", "stackFrames"]).as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("name").and_then(Json::as_str), Some("bump"));
+        assert_eq!(frames[0].get("line"), Some(&Json::from(7)));
+        assert_eq!(field(&frames[0], &["source", "path"]).as_str(), Some("/project/main.s"));
+        assert_eq!(frames[1].get("name").and_then(Json::as_str), Some("loop"));
+        assert_eq!(frames[1].get("line"), Some(&Json::from(3)));
+
+        let variables = response(&messages, "variables");
+        let registers = field(variables[0], &["body", "variables"]).as_array().unwrap();
+        assert_eq!(registers[1].get("value").and_then(Json::as_str), Some("$00"));
+        assert_eq!(registers[4].get("value").and_then(Json::as_str), Some("$8008"));
+        let zero_page = field(variables[1], &["body", "variables"]).as_array().unwrap();
+        assert_eq!(zero_page[0].get("name").and_then(Json::as_str), Some("counter"));
+        assert_eq!(zero_page[0].get("value").and_then(Json::as_str), Some("$00"));
+        assert_eq!(zero_page[0].get("memoryReference").and_then(Json::as_str), Some("0x0010"));
+        let zero_page = field(variables[2], &["body", "variables"]).as_array().unwrap();
+        assert_eq!(zero_page[0].get("value").and_then(Json::as_str), Some("$01"));
+    }
+
+    #[test]
+    fn dap_stepping() {
+        let mut server = server();
+        server.emulator_mut().memory_map_mut().set_debug(true);
+        for (offset, &byte) in PROGRAM.iter().enumerate() {
+            server.emulator_mut().memory_map_mut().poke(0x8000 + offset as u16, byte).unwrap();
+        }
+        server.emulator_mut().cpu_mut().set_pc(0x8000);
+        server.emulator_mut().cpu_mut().set_sp(0xff);
+
+        let messages = session(&mut server, &[
+            "attach {\"stopOnEntry\":true}",
+            "configurationDone",
+            "next {\"threadId\":1}",
+            "stepIn {\"threadId\":1}",
+            "stepIn {\"threadId\":1,\"granularity\":\"instruction\"}",
+            "stepOut {\"threadId\":1}",
+            "next {\"threadId\":1}",
+            "next {\"threadId\":1}",
+            "stackTrace {\"threadId\":1}",
+            "disconnect"
+        ]);
+        // The steps land on lines 3 (next), 7 (into bump), 8 (one instruction), 4 (out of bump, after the RTS), 3
+        // (the branch back) and 4 again (over the call)
+        assert_eq!(events(&messages, "stopped").len(), 7);
+        assert_eq!(server.emulator().cpu().pc(), 0x8005);
+        let frames = field(response(&messages, "stackTrace")[0], &["body", "stackFrames"]).as_array().unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].get("line"), Some(&Json::from(4)));
+        assert_eq!(server.emulator().memory_map().peek(0x0010).unwrap(), 0x02);
+    }
+
+    #[test]
+    fn dap_memory_and_variables() {
+        let mut server = server();
+        server.emulator_mut().memory_map_mut().write(0x0010, 0x42).unwrap();
+        let messages = session(&mut server, &[
+            "readMemory {\"memoryReference\":\"0x000e\",\"count\":4}",
+            "readMemory {\"memoryReference\":\"0x3ffe\",\"count\":4}",
+            "writeMemory {\"memoryReference\":\"0x0200\",\"offset\":1,\"data\":\"qlU=\"}",
+            "setVariable {\"variablesReference\":1,\"name\":\"a\",\"value\":\"$ff\"}",
+            "setVariable {\"variablesReference\":1,\"name\":\"p\",\"value\":\"%11000011\"}",
+            "setVariable {\"variablesReference\":2,\"name\":\"counter\",\"value\":\"a + 1\"}",
+            "setVariable {\"variablesReference\":1,\"name\":\"q\",\"value\":\"1\"}",
+            "evaluate {\"expression\":\"[$0201] + 1\"}",
+            "evaluate {\"expression\":\"bump\"}",
+            "evaluate {\"expression\":\"a +\"}",
+            "nonsense",
+            "disconnect"
+        ]);
+
+        let reads = response(&messages, "readMemory");
+        assert_eq!(field(reads[0], &["body", "data"]).as_str(), Some(base64_encode(&[0, 0, 0x42, 0]).as_str()));
+        assert_eq!(field(reads[1], &["body", "unreadableBytes"]), &Json::from(2));
+        assert_eq!(field(response(&messages, "writeMemory")[0], &["body", "bytesWritten"]), &Json::from(2));
+        assert_eq!(server.emulator().memory_map().peek(0x0201).unwrap(), 0xaa);
+        assert_eq!(server.emulator().memory_map().peek(0x0202).unwrap(), 0x55);
+
+        let sets = response(&messages, "setVariable");
+        assert_eq!(field(sets[1], &["body", "value"]).as_str(), Some("$e3 NV-bdiZC"));
+        assert_eq!(server.emulator().cpu().a(), 0xff);
+        assert_eq!(server.emulator().memory_map().peek(0x0010).unwrap(), 0x00);
+        assert_eq!(sets[3].get("success"), Some(&Json::from(false)));
+
+        let evaluations = response(&messages, "evaluate");
+        assert_eq!(field(evaluations[0], &["body", "result"]).as_str(), Some("$ab (171)"));
+        assert_eq!(field(evaluations[1], &["body", "result"]).as_str(), Some("$8008 (32776)"));
+        assert_eq!(field(evaluations[1], &["body", "memoryReference"]).as_str(), Some("0x8008"));
+        assert_eq!(evaluations[2].get("success"), Some(&Json::from(false)));
+
+        let unsupported = response(&messages, "nonsense");
+        assert_eq!(unsupported[0].get("message").and_then(Json::as_str), Some("Unsupported request: nonsense"));
+    }
+
+    #[test]
+    fn dap_exceptions_and_function_breakpoints() {
+        let mut server = server();
+        server.emulator_mut().memory_map_mut().set_debug(true);
+        for (offset, &byte) in PROGRAM.iter().enumerate() {
+            server.emulator_mut().memory_map_mut().poke(0x8000 + offset as u16, byte).unwrap();
+        }
+        server.emulator_mut().cpu_mut().set_pc(0x8000);
+
+        let breakpoints = "{\"breakpoints\":[{\"name\":\"bump\",\"hitCondition\":\"3\"},{\"name\":\"nowhere\"}]}";
+        let messages = session(&mut server, &[
+            &format!("setFunctionBreakpoints {}", breakpoints),
+            "attach {}",
+            "configurationDone",
+            "setFunctionBreakpoints {\"breakpoints\":[]}",
+            "continue",
+            "disconnect"
+        ]);
+
+        let breakpoints = field(response(&messages, "setFunctionBreakpoints")[0], &["body", "breakpoints"]);
+        let breakpoints = breakpoints.as_array().unwrap();
+        assert_eq!(breakpoints[0].get("verified"), Some(&Json::from(true)));
+        assert_eq!(breakpoints[1].get("message").and_then(Json::as_str), Some("Unknown symbol: nowhere"));
+
+        // The third call stops, then the loop runs until X wraps around and the STP stops the CPU
+        let stopped = events(&messages, "stopped");
+        assert_eq!(field(stopped[0], &["body", "reason"]).as_str(), Some("breakpoint"));
+        assert_eq!(server.emulator().breakpoints().iter().count(), 0);
+        assert_eq!(field(stopped[1], &["body", "reason"]).as_str(), Some("exception"));
+        assert_eq!(field(stopped[1], &["body", "text"]).as_str(), Some("CPU stopped at $8007 (loop+5)"));
+    }
+}
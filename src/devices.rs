@@ -1,2 +1,20 @@
 pub mod memory;
-pub mod memory_map;
\ No newline at end of file
+pub mod memory_map;
+pub mod banked_memory;
+pub mod save_state;
+pub mod framebuffer;
+pub mod tms9918;
+pub mod text_display;
+pub mod wav;
+pub mod sid;
+pub mod keyboard;
+pub mod disk_image;
+pub mod spi;
+pub mod sd_card;
+pub mod i2c;
+pub mod ds1307;
+pub mod eeprom;
+pub mod compact_flash;
+pub mod via;
+pub mod pia;
+pub mod riot;
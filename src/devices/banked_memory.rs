@@ -0,0 +1,179 @@
+/*!
+ * Device: Banked Memory
+ *
+ * A window of RAM or ROM onto a larger memory made of banks the size of the window. Which bank shows through the
+ * window is chosen by writing its number to a BankRegister, a one byte register that can be mapped anywhere and reads
+ * back the bank selected. Bank numbers wrap around the number of banks.
+ *
+ * Loading an image fills the banks in order, so a 32K image loaded into 8K banks fills the first four.
+ */
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const BANK_REGISTER_SIZE: u32 = 1;
+
+#[derive(Debug)]
+pub struct BankedMemory {
+    memory_type: MemoryType,
+    data: Vec<u8>,
+    banks: usize,
+    size: u32,
+    offset: u32,
+    bank: Rc<Cell<u8>>
+}
+
+impl BankedMemory {
+    pub fn new(memory_type: MemoryType, banks: usize, size: u32, offset: u32) -> BankedMemory {
+        let banks = banks.clamp(1, 256);
+        BankedMemory {
+            memory_type,
+            data: vec![0; banks * size as usize],
+            banks,
+            size,
+            offset,
+            bank: Rc::new(Cell::new(0))
+        }
+    }
+
+    // The register that selects the bank, to be mapped at `offset`
+    pub fn register(&self, offset: u32) -> BankRegister {
+        BankRegister { bank: Rc::clone(&self.bank), banks: self.banks, offset }
+    }
+
+    pub fn bank(&self) -> usize {
+        self.bank.get() as usize
+    }
+
+    pub fn banks(&self) -> usize {
+        self.banks
+    }
+
+    fn index(&self, address: u16) -> Result<usize, MemoryError> {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + self.size {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Ok(self.bank() * self.size as usize + (address - self.offset) as usize)
+    }
+}
+
+impl Memory for BankedMemory {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        Ok(self.data[self.index(address)?])
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let index = self.index(address)?;
+        if self.memory_type != MemoryType::ROM {
+            self.data[index] = value;
+        }
+        Ok(())
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let index = self.index(address)?;
+        self.data[index] = value;
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        self.memory_type
+    }
+
+    fn load(&mut self, data: Vec<u8>) -> MemoryWriteResult {
+        if data.len() > self.data.len() {
+            return Err(MemoryError::OutOfBounds);
+        }
+        self.data.fill(0);
+        self.data[..data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    // The bank selected and every bank's contents, which for ROM keeps any patches made while debugging
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.bank.get());
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let bank = state.read_u8()?;
+        if bank as usize >= self.banks {
+            return Err(StateError::Invalid(format!("Bank {} of {}", bank, self.banks)));
+        }
+        state.read_into(&mut self.data)?;
+        self.bank.set(bank);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct BankRegister {
+    bank: Rc<Cell<u8>>,
+    banks: usize,
+    offset: u32
+}
+
+impl Memory for BankRegister {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        if address as u32 != self.offset {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Ok(self.bank.get())
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        if address as u32 != self.offset {
+            return Err(MemoryError::OutOfBounds);
+        }
+        self.bank.set((value as usize % self.banks) as u8);
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banked_memory() {
+        let mut memory = BankedMemory::new(MemoryType::RAM, 4, 0x100, 0x8000);
+        let mut register = memory.register(0x7000);
+        memory.write(0x8000, 0x11).unwrap();
+        register.write(0x7000, 2).unwrap();
+        assert_eq!(memory.read(0x8000).unwrap(), 0x00);
+        memory.write(0x80ff, 0x22).unwrap();
+        register.write(0x7000, 6).unwrap();
+        assert_eq!(register.read(0x7000).unwrap(), 2);
+        assert_eq!(memory.read(0x80ff).unwrap(), 0x22);
+        register.write(0x7000, 0).unwrap();
+        assert_eq!(memory.read(0x8000).unwrap(), 0x11);
+        assert_eq!(memory.read(0x8100), Err(MemoryError::OutOfBounds));
+
+        // An image fills the banks in order, and ROM ignores writes
+        let mut rom = BankedMemory::new(MemoryType::ROM, 2, 0x10, 0xf000);
+        rom.load((0..0x20).collect()).unwrap();
+        rom.write(0xf000, 0xff).unwrap();
+        assert_eq!(rom.read(0xf000).unwrap(), 0x00);
+        rom.register(0).write(0, 1).unwrap();
+        assert_eq!(rom.read(0xf001).unwrap(), 0x11);
+        assert_eq!(rom.load(vec![0; 0x21]), Err(MemoryError::OutOfBounds));
+
+        let mut state = StateWriter::new();
+        rom.save_state(&mut state);
+        let mut copy = BankedMemory::new(MemoryType::ROM, 2, 0x10, 0xf000);
+        copy.load_state(&mut StateReader::new(&state.into_bytes())).unwrap();
+        assert_eq!((copy.bank(), copy.read(0xf001).unwrap()), (1, 0x11));
+    }
+}
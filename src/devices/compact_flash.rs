@@ -0,0 +1,548 @@
+/*!
+ * Device: Compact Flash (True IDE mode)
+ *
+ * A CF card wired up in true IDE mode with an 8 bit data bus, backed by a DiskImage. The ATA task file occupies eight
+ * registers:
+ * - offset + 0: data
+ * - offset + 1: error (read) / features (write)
+ * - offset + 2: sector count
+ * - offset + 3-5: LBA bits 0-7, 8-15 and 16-23
+ * - offset + 6: drive/head, with LBA bits 24-27 in the low nibble
+ * - offset + 7: status (read) / command (write)
+ *
+ * Supported commands are IDENTIFY DEVICE, READ SECTORS, WRITE SECTORS and SET FEATURES (to switch 8 bit transfers on
+ * and off). Until 8 bit mode is enabled the data register moves a 16 bit word per access and only the low byte is
+ * seen, as on the real card. After a command, and between sectors, the card reports BSY for a configurable number of
+ * CPU cycles before raising DRQ, so firmware has to poll the status register properly.
+ */
+
+use std::cell::Cell;
+
+use crate::devices::disk_image::*;
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const COMPACT_FLASH_SIZE: u32 = 8;
+
+pub const STATUS_BSY: u8 = 0x80;
+pub const STATUS_DRDY: u8 = 0x40;
+pub const STATUS_DSC: u8 = 0x10;
+pub const STATUS_DRQ: u8 = 0x08;
+pub const STATUS_ERR: u8 = 0x01;
+
+const ERROR_ABORTED: u8 = 0x04;
+const ERROR_ID_NOT_FOUND: u8 = 0x10;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_NO_RETRY: u8 = 0x21;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_NO_RETRY: u8 = 0x31;
+const COMMAND_IDENTIFY: u8 = 0xec;
+const COMMAND_SET_FEATURES: u8 = 0xef;
+
+const FEATURE_ENABLE_8BIT: u8 = 0x01;
+const FEATURE_DISABLE_8BIT: u8 = 0x81;
+
+const DRIVE_SLAVE: u8 = 0x10;
+
+const DEFAULT_COMMAND_CYCLES: u32 = 100;
+const DEFAULT_SECTOR_CYCLES: u32 = 50;
+
+// What the card does once it stops being busy
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pending {
+    None,
+    Identify,
+    LoadSector,
+    RequestData,
+    CommitSector
+}
+
+#[derive(Debug)]
+pub struct CompactFlash {
+    image: DiskImage,
+    offset: u32,
+    buffer: Vec<u8>,
+    index: Cell<usize>,
+    status: Cell<u8>,
+    error: u8,
+    feature: u8,
+    sector_count: Cell<u8>,
+    lba: Cell<u32>,
+    drive_head: u8,
+    eight_bit: bool,
+    writing: bool,
+    pending: Cell<Pending>,
+    busy: Cell<u32>,
+    command_cycles: u32,
+    sector_cycles: u32
+}
+
+impl CompactFlash {
+    pub fn new(image: DiskImage, offset: u32) -> CompactFlash {
+        CompactFlash {
+            image,
+            offset,
+            buffer: vec![0; BLOCK_SIZE],
+            index: Cell::new(0),
+            status: Cell::new(STATUS_DRDY | STATUS_DSC),
+            error: 0,
+            feature: 0,
+            sector_count: Cell::new(1),
+            lba: Cell::new(0),
+            drive_head: 0xe0,
+            eight_bit: false,
+            writing: false,
+            pending: Cell::new(Pending::None),
+            busy: Cell::new(0),
+            command_cycles: DEFAULT_COMMAND_CYCLES,
+            sector_cycles: DEFAULT_SECTOR_CYCLES
+        }
+    }
+
+    // How long the card stays busy after a command and between sectors, in CPU cycles
+    pub fn set_busy_cycles(&mut self, command: u32, sector: u32) {
+        self.command_cycles = command;
+        self.sector_cycles = sector;
+    }
+
+    pub fn image(&mut self) -> &mut DiskImage {
+        &mut self.image
+    }
+
+    fn become_busy(&self, pending: Pending, cycles: u32) {
+        self.status.set(STATUS_BSY);
+        self.pending.set(pending);
+        self.busy.set(cycles);
+    }
+
+    fn fail(&mut self, error: u8) {
+        self.error = error;
+        self.status.set(STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+        self.pending.set(Pending::None);
+    }
+
+    fn request_data(&self) {
+        self.index.set(0);
+        self.status.set(STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+    }
+
+    fn finish(&self) {
+        self.status.set(STATUS_DRDY | STATUS_DSC);
+    }
+
+    // Sector count 0 means 256 sectors
+    fn sectors(&self) -> u32 {
+        match self.sector_count.get() {
+            0 => 256,
+            count => count as u32
+        }
+    }
+
+    // Move on to the next sector of a multi-sector transfer
+    fn next_sector(&self) -> bool {
+        self.lba.set((self.lba.get() + 1) & 0x0fff_ffff);
+        self.sector_count.set(self.sector_count.get().wrapping_sub(1));
+        self.sector_count.get() != 0
+    }
+
+    fn execute(&mut self, command: u8) {
+        self.error = 0;
+
+        // There is no slave drive
+        if self.drive_head & DRIVE_SLAVE != 0 {
+            return;
+        }
+
+        match command {
+            COMMAND_IDENTIFY => {
+                self.buffer = self.identify();
+                self.writing = false;
+                self.become_busy(Pending::Identify, self.command_cycles);
+            }
+            COMMAND_READ_SECTORS
+            | COMMAND_READ_SECTORS_NO_RETRY
+            | COMMAND_WRITE_SECTORS
+            | COMMAND_WRITE_SECTORS_NO_RETRY => {
+                if self.lba.get() + self.sectors() > self.image.block_count() {
+                    self.fail(ERROR_ID_NOT_FOUND);
+                    return;
+                }
+                self.writing = command == COMMAND_WRITE_SECTORS || command == COMMAND_WRITE_SECTORS_NO_RETRY;
+                let pending = if self.writing { Pending::RequestData } else { Pending::LoadSector };
+                self.become_busy(pending, self.command_cycles);
+            }
+            COMMAND_SET_FEATURES => match self.feature {
+                FEATURE_ENABLE_8BIT => {
+                    self.eight_bit = true;
+                    self.finish();
+                }
+                FEATURE_DISABLE_8BIT => {
+                    self.eight_bit = false;
+                    self.finish();
+                }
+                _ => self.fail(ERROR_ABORTED)
+            },
+            _ => self.fail(ERROR_ABORTED)
+        }
+    }
+
+    fn identify(&self) -> Vec<u8> {
+        let mut words = [0u16; 256];
+        let sectors = self.image.block_count();
+
+        // CHS geometry is only reported for completeness; everything here is addressed by LBA
+        let (heads, sectors_per_track) = (16u32, 63u32);
+        let cylinders = (sectors / (heads * sectors_per_track)).clamp(1, 16383);
+
+        words[0] = 0x848a;
+        words[1] = cylinders as u16;
+        words[3] = heads as u16;
+        words[6] = sectors_per_track as u16;
+        words[7] = (sectors >> 16) as u16;
+        words[8] = sectors as u16;
+        set_string(&mut words[10..20], "REMU0001");
+        set_string(&mut words[23..27], "1.0");
+        set_string(&mut words[27..47], "MINI 6502 REMU CF CARD");
+        words[47] = 0x0001;
+        words[49] = 0x0200;
+        words[60] = sectors as u16;
+        words[61] = (sectors >> 16) as u16;
+
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+}
+
+// ATA strings are space padded with the first character of each pair in the high byte
+fn set_string(words: &mut [u16], text: &str) {
+    let mut bytes = text.bytes().chain(std::iter::repeat(b' '));
+    for word in words.iter_mut() {
+        let high = bytes.next().unwrap_or(b' ');
+        let low = bytes.next().unwrap_or(b' ');
+        *word = ((high as u16) << 8) | low as u16;
+    }
+}
+
+impl Memory for CompactFlash {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + COMPACT_FLASH_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        let lba = self.lba.get();
+        match address - self.offset {
+            0 => {
+                if self.status.get() & STATUS_DRQ == 0 || self.writing {
+                    return Ok(0xff);
+                }
+
+                let index = self.index.get();
+                let value = self.buffer[index];
+                self.index.set(index + if self.eight_bit { 1 } else { 2 });
+                if self.index.get() >= BLOCK_SIZE {
+                    if self.pending.get() == Pending::Identify || !self.next_sector() {
+                        self.finish();
+                    } else {
+                        self.become_busy(Pending::LoadSector, self.sector_cycles);
+                    }
+                }
+                Ok(value)
+            }
+            1 => Ok(self.error),
+            2 => Ok(self.sector_count.get()),
+            3 => Ok(lba as u8),
+            4 => Ok((lba >> 8) as u8),
+            5 => Ok((lba >> 16) as u8),
+            6 => Ok((self.drive_head & 0xf0) | ((lba >> 24) & 0x0f) as u8),
+            _ => Ok(if self.drive_head & DRIVE_SLAVE != 0 { 0x00 } else { self.status.get() })
+        }
+    }
+
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + COMPACT_FLASH_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        if address - self.offset != 0 {
+            return self.read(address as u16);
+        }
+        if self.status.get() & STATUS_DRQ == 0 || self.writing {
+            Ok(0xff)
+        } else {
+            Ok(self.buffer[self.index.get()])
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + COMPACT_FLASH_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        // The task file cannot be changed while the card is busy
+        let register = address - self.offset;
+        if self.status.get() & STATUS_BSY != 0 && register != 7 {
+            return Ok(());
+        }
+
+        let lba = self.lba.get();
+        match register {
+            0 => {
+                if self.status.get() & STATUS_DRQ == 0 || !self.writing {
+                    return Ok(());
+                }
+
+                let index = self.index.get();
+                self.buffer[index] = value;
+                if !self.eight_bit {
+                    self.buffer[index + 1] = 0;
+                }
+                self.index.set(index + if self.eight_bit { 1 } else { 2 });
+                if self.index.get() >= BLOCK_SIZE {
+                    self.become_busy(Pending::CommitSector, self.sector_cycles);
+                }
+            }
+            1 => self.feature = value,
+            2 => self.sector_count.set(value),
+            3 => self.lba.set((lba & !0x0000_00ff) | value as u32),
+            4 => self.lba.set((lba & !0x0000_ff00) | ((value as u32) << 8)),
+            5 => self.lba.set((lba & !0x00ff_0000) | ((value as u32) << 16)),
+            6 => {
+                self.drive_head = value;
+                self.lba.set((lba & 0x00ff_ffff) | (((value & 0x0f) as u32) << 24));
+            }
+            _ => {
+                if self.status.get() & STATUS_BSY == 0 {
+                    self.execute(value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.status.get() & STATUS_BSY == 0 {
+            return;
+        }
+        self.busy.set(self.busy.get().saturating_sub(cycles));
+        if self.busy.get() > 0 {
+            return;
+        }
+
+        match self.pending.replace(Pending::None) {
+            Pending::Identify => {
+                self.pending.set(Pending::Identify);
+                self.request_data();
+            }
+            Pending::LoadSector => match self.image.read_block(self.lba.get(), &mut self.buffer) {
+                Ok(()) => self.request_data(),
+                Err(_) => self.fail(ERROR_ID_NOT_FOUND)
+            },
+            Pending::RequestData => self.request_data(),
+            Pending::CommitSector => match self.image.write_block(self.lba.get(), &self.buffer) {
+                Ok(()) => {
+                    if self.next_sector() {
+                        self.request_data();
+                    } else {
+                        self.finish();
+                    }
+                }
+                Err(_) => self.fail(ERROR_ABORTED)
+            },
+            Pending::None => self.finish()
+        }
+    }
+
+    // The card's registers, sector buffer and the sectors written to the image
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.buffer);
+        state.write_u32(self.index.get() as u32);
+        state.write_u8(self.status.get());
+        state.write_u8(self.error);
+        state.write_u8(self.feature);
+        state.write_u8(self.sector_count.get());
+        state.write_u32(self.lba.get());
+        state.write_u8(self.drive_head);
+        state.write_bool(self.eight_bit);
+        state.write_bool(self.writing);
+        state.write_u8(self.pending.get() as u8);
+        state.write_u32(self.busy.get());
+        self.image.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.buffer)?;
+        let index = state.read_u32()? as usize;
+        if index > self.buffer.len() {
+            return Err(StateError::Invalid(format!("Buffer index {}", index)));
+        }
+        self.index.set(index);
+        self.status.set(state.read_u8()?);
+        self.error = state.read_u8()?;
+        self.feature = state.read_u8()?;
+        self.sector_count.set(state.read_u8()?);
+        self.lba.set(state.read_u32()?);
+        self.drive_head = state.read_u8()?;
+        self.eight_bit = state.read_bool()?;
+        self.writing = state.read_bool()?;
+        self.pending.set(match state.read_u8()? {
+            0 => Pending::None,
+            1 => Pending::Identify,
+            2 => Pending::LoadSector,
+            3 => Pending::RequestData,
+            4 => Pending::CommitSector,
+            value => return Err(StateError::Invalid(format!("Pending operation {}", value)))
+        });
+        self.busy.set(state.read_u32()?);
+        self.image.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u16 = 0x7f00;
+
+    fn card(blocks: usize) -> CompactFlash {
+        let mut image = vec![0; BLOCK_SIZE * blocks];
+        for (block, data) in image.chunks_mut(BLOCK_SIZE).enumerate() {
+            data.fill(block as u8);
+        }
+        let mut card = CompactFlash::new(DiskImage::from_bytes(image), BASE as u32);
+        card.set_busy_cycles(10, 5);
+        card
+    }
+
+    // Poll the status register until the card is no longer busy
+    fn wait(card: &mut CompactFlash) -> u8 {
+        for _ in 0..100 {
+            let status = card.read(BASE + 7).unwrap();
+            if status & STATUS_BSY == 0 {
+                return status;
+            }
+            card.tick(1);
+        }
+        panic!("CompactFlash: Card stayed busy");
+    }
+
+    fn command(card: &mut CompactFlash, lba: u32, count: u8, command: u8) {
+        card.write(BASE + 2, count).unwrap();
+        card.write(BASE + 3, lba as u8).unwrap();
+        card.write(BASE + 4, (lba >> 8) as u8).unwrap();
+        card.write(BASE + 5, (lba >> 16) as u8).unwrap();
+        card.write(BASE + 6, 0xe0 | ((lba >> 24) & 0x0f) as u8).unwrap();
+        card.write(BASE + 7, command).unwrap();
+    }
+
+    fn enable_8bit(card: &mut CompactFlash) {
+        card.write(BASE + 1, FEATURE_ENABLE_8BIT).unwrap();
+        card.write(BASE + 7, COMMAND_SET_FEATURES).unwrap();
+        assert_eq!(wait(card) & STATUS_ERR, 0);
+    }
+
+    #[test]
+    fn compact_flash_identify() {
+        let mut card = card(2048);
+        enable_8bit(&mut card);
+        command(&mut card, 0, 1, COMMAND_IDENTIFY);
+        assert_eq!(card.read(BASE + 7).unwrap(), STATUS_BSY);
+        assert_eq!(wait(&mut card) & STATUS_DRQ, STATUS_DRQ);
+
+        let data = (0..BLOCK_SIZE).map(|_| card.read(BASE).unwrap()).collect::<Vec<u8>>();
+        assert_eq!(&data[0..2], &[0x8a, 0x84]);
+        assert_eq!(u16::from_le_bytes([data[120], data[121]]), 2048);
+        assert_eq!(&data[54..58], b"IMIN");
+        assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC);
+    }
+
+    #[test]
+    fn compact_flash_read_sectors() {
+        let mut card = card(8);
+        enable_8bit(&mut card);
+        command(&mut card, 2, 2, COMMAND_READ_SECTORS);
+
+        // Data is not available while the card is busy
+        assert_eq!(card.read(BASE).unwrap(), 0xff);
+        for sector in 2..4 {
+            assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+            assert!((0..BLOCK_SIZE).all(|_| card.read(BASE).unwrap() == sector));
+        }
+        assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC);
+        assert_eq!(card.read(BASE + 3).unwrap(), 4);
+        assert_eq!(card.read(BASE + 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn compact_flash_write_sectors() {
+        let mut card = card(8);
+        enable_8bit(&mut card);
+        let mut state = StateWriter::new();
+        card.save_state(&mut state);
+        let state = state.into_bytes();
+        command(&mut card, 5, 2, COMMAND_WRITE_SECTORS);
+        for value in [0xa5, 0x5a] {
+            assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+            for _ in 0..BLOCK_SIZE {
+                card.write(BASE, value).unwrap();
+            }
+            assert_eq!(card.read(BASE + 7).unwrap(), STATUS_BSY);
+        }
+        assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC);
+
+        let mut data = [0; BLOCK_SIZE];
+        card.image().read_block(5, &mut data).unwrap();
+        assert_eq!(data, [0xa5; BLOCK_SIZE]);
+        card.image().read_block(6, &mut data).unwrap();
+        assert_eq!(data, [0x5a; BLOCK_SIZE]);
+        card.image().read_block(7, &mut data).unwrap();
+        assert_eq!(data, [0x07; BLOCK_SIZE]);
+
+        // Restoring a state from before the command brings back the old sectors
+        card.load_state(&mut StateReader::new(&state)).unwrap();
+        card.image().read_block(5, &mut data).unwrap();
+        assert_eq!(data, [0x05; BLOCK_SIZE]);
+        card.image().read_block(6, &mut data).unwrap();
+        assert_eq!(data, [0x06; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn compact_flash_16bit_mode() {
+        let mut card = card(4);
+        command(&mut card, 1, 1, COMMAND_READ_SECTORS);
+        wait(&mut card);
+
+        // Without 8 bit mode each access moves a whole word, so only 256 reads make up a sector
+        for _ in 0..BLOCK_SIZE / 2 {
+            assert_eq!(card.read(BASE).unwrap(), 1);
+        }
+        assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC);
+    }
+
+    #[test]
+    fn compact_flash_errors() {
+        let mut card = card(4);
+        command(&mut card, 3, 2, COMMAND_READ_SECTORS);
+        assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+        assert_eq!(card.read(BASE + 1).unwrap(), ERROR_ID_NOT_FOUND);
+
+        command(&mut card, 0, 1, 0x99);
+        assert_eq!(wait(&mut card), STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+        assert_eq!(card.read(BASE + 1).unwrap(), ERROR_ABORTED);
+
+        // Selecting the (missing) slave drive
+        card.write(BASE + 6, 0xf0).unwrap();
+        assert_eq!(card.read(BASE + 7).unwrap(), 0x00);
+    }
+}
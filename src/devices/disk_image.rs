@@ -0,0 +1,186 @@
+/*!
+ * Disk Image
+ *
+ * Block storage devices (SD cards, CF cards) keep their contents in a disk image: either a file on the host or a buffer
+ * in memory for tests. Images are addressed in 512 byte blocks.
+ *
+ * Blocks the emulated machine writes go to a copy-on-write overlay in memory rather than to the backing, so the overlay
+ * is all the machine's state needs to hold for the image, and restoring a state brings the image back exactly. The
+ * overlay is written back to the host file when the image is dropped, as the machine shuts down.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::devices::save_state::*;
+
+pub const BLOCK_SIZE: usize = 512;
+
+trait Backing: Read + Write + Seek + Debug {}
+
+impl<T: Read + Write + Seek + Debug> Backing for T {}
+
+#[derive(Debug)]
+pub struct DiskImage {
+    backing: Box<dyn Backing>,
+    size: u64,
+    overlay: BTreeMap<u32, Vec<u8>>
+}
+
+impl DiskImage {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<DiskImage> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(DiskImage {
+            backing: Box::new(file),
+            size,
+            overlay: BTreeMap::new()
+        })
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> DiskImage {
+        let size = data.len() as u64;
+        DiskImage {
+            backing: Box::new(Cursor::new(data)),
+            size,
+            overlay: BTreeMap::new()
+        }
+    }
+
+    pub fn block_count(&self) -> u32 {
+        (self.size / BLOCK_SIZE as u64) as u32
+    }
+
+    pub fn read_block(&mut self, block: u32, data: &mut [u8]) -> std::io::Result<()> {
+        self.check_block(block, data.len())?;
+        if let Some(written) = self.overlay.get(&block) {
+            data.copy_from_slice(written);
+            return Ok(());
+        }
+        self.backing.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+        self.backing.read_exact(data)
+    }
+
+    pub fn write_block(&mut self, block: u32, data: &[u8]) -> std::io::Result<()> {
+        self.check_block(block, data.len())?;
+        self.overlay.insert(block, data.to_vec());
+        Ok(())
+    }
+
+    // Write the overlay back to the backing. Only done once the image is finished with, as a state restored afterwards
+    // relies on the backing still holding the original contents.
+    fn write_back(&mut self) -> std::io::Result<()> {
+        for (&block, data) in &self.overlay {
+            self.backing.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+            self.backing.write_all(data)?;
+        }
+        self.backing.flush()
+    }
+
+    // The blocks written since the image was opened
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.overlay.len() as u32);
+        for (&block, data) in &self.overlay {
+            state.write_u32(block);
+            state.write_raw(data);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let count = state.read_u32()?;
+        let mut overlay = BTreeMap::new();
+        for _ in 0..count {
+            let block = state.read_u32()?;
+            if block >= self.block_count() {
+                return Err(StateError::Invalid(format!("Disk image block {}", block)));
+            }
+            overlay.insert(block, state.read_raw(BLOCK_SIZE)?.to_vec());
+        }
+        self.overlay = overlay;
+        Ok(())
+    }
+
+    fn check_block(&self, block: u32, length: usize) -> std::io::Result<()> {
+        if length != BLOCK_SIZE || block >= self.block_count() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("DiskImage: Block {} is out of range", block)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskImage {
+    fn drop(&mut self) {
+        if let Err(error) = self.write_back() {
+            eprintln!("DiskImage: Unable to write back to the backing file: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_image() -> std::io::Result<()> {
+        let mut image = DiskImage::from_bytes(vec![0; BLOCK_SIZE * 4]);
+        assert_eq!(image.block_count(), 4);
+
+        let block = [0x5a; BLOCK_SIZE];
+        image.write_block(2, &block)?;
+
+        let mut data = [0; BLOCK_SIZE];
+        image.read_block(2, &mut data)?;
+        assert_eq!(data, block);
+        image.read_block(1, &mut data)?;
+        assert_eq!(data, [0; BLOCK_SIZE]);
+
+        assert!(image.read_block(4, &mut data).is_err());
+        assert!(image.write_block(0, &[0; 16]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn disk_image_file() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join(format!("disk_image_{}.img", std::process::id()));
+        std::fs::write(&path, vec![0; BLOCK_SIZE * 2])?;
+
+        let mut image = DiskImage::open(&path)?;
+        image.write_block(1, &[0xa5; BLOCK_SIZE])?;
+
+        // Writes only reach the host file once the image is dropped
+        assert_eq!(std::fs::read(&path)?[BLOCK_SIZE], 0x00);
+        drop(image);
+        let contents = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(contents[BLOCK_SIZE - 1], 0x00);
+        assert_eq!(contents[BLOCK_SIZE], 0xa5);
+        Ok(())
+    }
+
+    #[test]
+    fn disk_image_state() -> std::io::Result<()> {
+        let mut image = DiskImage::from_bytes(vec![0; BLOCK_SIZE * 4]);
+        image.write_block(1, &[0x11; BLOCK_SIZE])?;
+        let mut state = StateWriter::new();
+        image.save_state(&mut state);
+        let state = state.into_bytes();
+
+        // Restoring undoes writes made since, including to blocks that had not been written before
+        image.write_block(1, &[0x22; BLOCK_SIZE])?;
+        image.write_block(3, &[0x33; BLOCK_SIZE])?;
+        image.load_state(&mut StateReader::new(&state)).unwrap();
+        let mut data = [0; BLOCK_SIZE];
+        image.read_block(1, &mut data)?;
+        assert_eq!(data, [0x11; BLOCK_SIZE]);
+        image.read_block(3, &mut data)?;
+        assert_eq!(data, [0; BLOCK_SIZE]);
+
+        // A state for a bigger image does not fit
+        let mut small = DiskImage::from_bytes(vec![0; BLOCK_SIZE]);
+        assert!(small.load_state(&mut StateReader::new(&state)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+/*!
+ * Device: DS1307 Real-Time Clock
+ *
+ * An I2C real-time clock at address $68 with 64 registers:
+ * - $00-$06: seconds (bit 7 halts the clock), minutes, hours (bit 6 selects 12 hour mode), day of week, date, month
+ *   and year, all in BCD
+ * - $07: control (square wave output, stored but not modelled)
+ * - $08-$3F: 56 bytes of battery-backed RAM
+ *
+ * The first byte written after the address sets the register pointer, and both reads and writes auto-increment it.
+ * As on the real chip, the time is copied into the registers at every START so that a multi-byte read is consistent,
+ * and time written by the CPU takes effect at the STOP.
+ *
+ * Time comes from a ClockSource: the host clock, or emulated time derived from the CPU cycle count for deterministic
+ * tests. When input is recorded or replayed the host clock is swapped for emulated time at DEFAULT_CLOCK_RATE.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::devices::i2c::*;
+use crate::devices::save_state::*;
+
+pub const DS1307_ADDRESS: u8 = 0x68;
+
+const REGISTERS: usize = 64;
+const TIME_REGISTERS: usize = 7;
+const CLOCK_HALT: u8 = 0x80;
+const HOUR_12: u8 = 0x40;
+const HOUR_PM: u8 = 0x20;
+
+// 2000-01-01 00:00:00 UTC, the start of the DS1307's calendar
+pub const Y2K: i64 = 946_684_800;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    // Wall clock time from the host
+    Host,
+    // Time that starts at `start` (seconds since the Unix epoch) and advances with the CPU cycle count
+    Emulated { clock_rate: u32, start: i64 }
+}
+
+#[derive(Debug)]
+pub struct DS1307 {
+    source: ClockSource,
+    registers: [u8; REGISTERS],
+    pointer: u8,
+    pointer_set: bool,
+    time_written: bool,
+    // Seconds added to the source time, set when the CPU writes the time
+    offset: i64,
+    halted_at: Option<i64>,
+    day_of_week_offset: i64,
+    cycles: u64
+}
+
+impl DS1307 {
+    pub fn new(source: ClockSource) -> DS1307 {
+        let mut rtc = DS1307 {
+            source,
+            registers: [0; REGISTERS],
+            pointer: 0,
+            pointer_set: false,
+            time_written: false,
+            offset: 0,
+            halted_at: None,
+            day_of_week_offset: 0,
+            cycles: 0
+        };
+        rtc.latch();
+        rtc
+    }
+
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+
+    // The current time in seconds since the Unix epoch
+    pub fn now(&self) -> i64 {
+        self.halted_at.unwrap_or_else(|| self.source_time() + self.offset)
+    }
+
+    fn source_time(&self) -> i64 {
+        match self.source {
+            ClockSource::Host => {
+                SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as i64).unwrap_or(0)
+            }
+            ClockSource::Emulated { clock_rate, start } => start + (self.cycles / clock_rate.max(1) as u64) as i64
+        }
+    }
+
+    // Copy the current time into the time registers
+    fn latch(&mut self) {
+        let now = self.now();
+        let days = now.div_euclid(86400);
+        let seconds = now.rem_euclid(86400);
+        let (year, month, date) = civil_from_days(days);
+        let hour = (seconds / 3600) as u8;
+
+        self.registers[0] = to_bcd((seconds % 60) as u8) | if self.halted_at.is_some() { CLOCK_HALT } else { 0 };
+        self.registers[1] = to_bcd((seconds / 60 % 60) as u8);
+        self.registers[2] = if self.registers[2] & HOUR_12 != 0 {
+            let pm = if hour >= 12 { HOUR_PM } else { 0 };
+            HOUR_12 | pm | to_bcd(match hour % 12 { 0 => 12, hour => hour })
+        } else {
+            to_bcd(hour)
+        };
+        self.registers[3] = ((days + 4 + self.day_of_week_offset).rem_euclid(7) + 1) as u8;
+        self.registers[4] = to_bcd(date as u8);
+        self.registers[5] = to_bcd(month as u8);
+        self.registers[6] = to_bcd(year.rem_euclid(100) as u8);
+    }
+
+    // Set the clock from the time registers after the CPU has written them
+    fn commit(&mut self) {
+        let seconds = from_bcd(self.registers[0] & 0x7f) as i64;
+        let minutes = from_bcd(self.registers[1] & 0x7f) as i64;
+        let hours = if self.registers[2] & HOUR_12 != 0 {
+            let hour = from_bcd(self.registers[2] & 0x1f) as i64 % 12;
+            if self.registers[2] & HOUR_PM != 0 { hour + 12 } else { hour }
+        } else {
+            from_bcd(self.registers[2] & 0x3f) as i64
+        };
+        let days = days_from_civil(
+            2000 + from_bcd(self.registers[6]) as i64,
+            from_bcd(self.registers[5] & 0x1f).max(1) as i64,
+            from_bcd(self.registers[4] & 0x3f).max(1) as i64
+        );
+        let time = days * 86400 + hours * 3600 + minutes * 60 + seconds;
+
+        self.day_of_week_offset = (self.registers[3] as i64 - 1) - (days + 4).rem_euclid(7);
+        self.offset = time - self.source_time();
+        self.halted_at = if self.registers[0] & CLOCK_HALT != 0 { Some(time) } else { None };
+    }
+}
+
+impl I2cDevice for DS1307 {
+    fn address(&self) -> u8 {
+        DS1307_ADDRESS
+    }
+
+    fn start(&mut self, read: bool) -> bool {
+        self.latch();
+        self.pointer_set = read;
+        true
+    }
+
+    fn write(&mut self, value: u8) -> bool {
+        if !self.pointer_set {
+            self.pointer = value % REGISTERS as u8;
+            self.pointer_set = true;
+            return true;
+        }
+
+        let register = self.pointer as usize;
+        self.registers[register] = value;
+        self.time_written |= register < TIME_REGISTERS;
+        self.pointer = (self.pointer + 1) % REGISTERS as u8;
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.registers[self.pointer as usize];
+        self.pointer = (self.pointer + 1) % REGISTERS as u8;
+        value
+    }
+
+    fn stop(&mut self) {
+        if std::mem::replace(&mut self.time_written, false) {
+            self.commit();
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    // Count emulated time from `time` onwards. The cycles already counted are taken off, so the clock does not jump.
+    fn make_deterministic(&mut self, time: i64, clock_rate: u32) {
+        if self.source == ClockSource::Host {
+            let clock_rate = clock_rate.max(1);
+            let elapsed = (self.cycles / clock_rate as u64) as i64;
+            self.source = ClockSource::Emulated { clock_rate, start: time - elapsed };
+        }
+    }
+
+    // The registers and the clock's offset from its source. Host time keeps running while a state is put away.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_raw(&self.registers);
+        state.write_u8(self.pointer);
+        state.write_bool(self.pointer_set);
+        state.write_bool(self.time_written);
+        state.write_i64(self.offset);
+        state.write_bool(self.halted_at.is_some());
+        state.write_i64(self.halted_at.unwrap_or(0));
+        state.write_i64(self.day_of_week_offset);
+        state.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.copy_from_slice(state.read_raw(REGISTERS)?);
+        self.pointer = state.read_u8()? % REGISTERS as u8;
+        self.pointer_set = state.read_bool()?;
+        self.time_written = state.read_bool()?;
+        self.offset = state.read_i64()?;
+        let halted = state.read_bool()?;
+        let halted_at = state.read_i64()?;
+        self.halted_at = if halted { Some(halted_at) } else { None };
+        self.day_of_week_offset = state.read_i64()?;
+        self.cycles = state.read_u64()?;
+        Ok(())
+    }
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Days since 1970-01-01 for a date in the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The date (year, month, day) for a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::i2c::testing::*;
+    use crate::devices::memory::*;
+
+    const CLOCK_RATE: u32 = 1_000_000;
+
+    fn port(start: i64) -> I2cPort {
+        let mut port = I2cPort::new(0);
+        port.attach(Box::new(DS1307::new(ClockSource::Emulated { clock_rate: CLOCK_RATE, start })));
+        port
+    }
+
+    fn read_registers(port: &mut I2cPort, register: u8, count: usize) -> Vec<u8> {
+        start(port);
+        assert!(write_byte(port, DS1307_ADDRESS << 1));
+        assert!(write_byte(port, register));
+        start(port);
+        assert!(write_byte(port, (DS1307_ADDRESS << 1) | 1));
+        let values = (0..count).map(|index| read_byte(port, index + 1 < count)).collect();
+        stop(port);
+        values
+    }
+
+    fn write_registers(port: &mut I2cPort, register: u8, values: &[u8]) {
+        start(port);
+        assert!(write_byte(port, DS1307_ADDRESS << 1));
+        assert!(write_byte(port, register));
+        for &value in values {
+            assert!(write_byte(port, value));
+        }
+        stop(port);
+    }
+
+    #[test]
+    fn ds1307_calendar() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 1, 1) * 86400, Y2K);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2099, 12, 31) + 1), (2100, 1, 1));
+    }
+
+    #[test]
+    fn ds1307_emulated_time() {
+        // 2024-02-28 23:59:58, a Wednesday
+        let mut port = port(days_from_civil(2024, 2, 28) * 86400 + 86398);
+        assert_eq!(read_registers(&mut port, 0, 7), [0x58, 0x59, 0x23, 0x04, 0x28, 0x02, 0x24]);
+
+        // Three seconds later it is the leap day
+        port.tick(3 * CLOCK_RATE);
+        assert_eq!(read_registers(&mut port, 0, 7), [0x01, 0x00, 0x00, 0x05, 0x29, 0x02, 0x24]);
+    }
+
+    #[test]
+    fn ds1307_set_time() {
+        let mut port = port(Y2K);
+
+        // 12 hour mode, 11:30:00 PM on 2031-12-31
+        write_registers(&mut port, 0, &[0x00, 0x30, HOUR_12 | HOUR_PM | 0x11, 0x03, 0x31, 0x12, 0x31]);
+        assert_eq!(read_registers(&mut port, 0, 7), [0x00, 0x30, 0x71, 0x03, 0x31, 0x12, 0x31]);
+
+        port.tick(1800 * CLOCK_RATE);
+        assert_eq!(read_registers(&mut port, 0, 7), [0x00, 0x00, HOUR_12 | 0x12, 0x04, 0x01, 0x01, 0x32]);
+    }
+
+    #[test]
+    fn ds1307_clock_halt() {
+        let mut port = port(Y2K);
+        write_registers(&mut port, 0, &[CLOCK_HALT | 0x10]);
+        port.tick(10 * CLOCK_RATE);
+        assert_eq!(read_registers(&mut port, 0, 1), [CLOCK_HALT | 0x10]);
+
+        write_registers(&mut port, 0, &[0x10]);
+        port.tick(10 * CLOCK_RATE);
+        assert_eq!(read_registers(&mut port, 0, 1), [0x20]);
+    }
+
+    #[test]
+    fn ds1307_ram() {
+        let mut port = port(Y2K);
+        write_registers(&mut port, 0x3e, &[0x12, 0x34]);
+        assert_eq!(read_registers(&mut port, 0x08, 1), [0x00]);
+        assert_eq!(read_registers(&mut port, 0x3e, 1), [0x12]);
+
+        // The register pointer wraps from $3F back to the seconds register
+        assert_eq!(read_registers(&mut port, 0x3f, 2), [0x34, 0x00]);
+        assert_eq!(read_registers(&mut port, 0x07, 1), [0x00]);
+    }
+
+    #[test]
+    fn ds1307_host_time() {
+        let rtc = DS1307::new(ClockSource::Host);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        assert!((rtc.now() - now).abs() <= 1);
+    }
+
+    #[test]
+    fn ds1307_deterministic() {
+        // Emulated time runs at the machine's clock rate, here 1.8432 MHz
+        let mut rtc = DS1307::new(ClockSource::Host);
+        rtc.tick(4_608_000);
+        rtc.make_deterministic(Y2K, 1_843_200);
+        assert_eq!(rtc.source(), ClockSource::Emulated { clock_rate: 1_843_200, start: Y2K - 2 });
+        assert_eq!(rtc.now(), Y2K);
+        rtc.tick(2_764_800);
+        assert_eq!(rtc.now(), Y2K + 2);
+
+        // Emulated time is already deterministic
+        let mut rtc = DS1307::new(ClockSource::Emulated { clock_rate: CLOCK_RATE, start: Y2K });
+        rtc.make_deterministic(0, 1_843_200);
+        assert_eq!(rtc.now(), Y2K);
+    }
+}
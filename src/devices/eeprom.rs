@@ -0,0 +1,280 @@
+/*!
+ * Device: 24LC256 Serial EEPROM
+ *
+ * 32K of I2C EEPROM at address $50-$57 (set by the A0-A2 pins). A write transaction starts with a two byte memory
+ * address, followed by up to 64 bytes of data that wrap around within the current page and are programmed when the
+ * master sends STOP. Programming takes a few milliseconds, during which the chip does not acknowledge its address, so
+ * firmware can poll for completion. Reads continue sequentially from the current address and wrap at the end of memory.
+ *
+ * The contents can be backed by a file on the host. As with disk images, the machine only changes the copy in memory,
+ * which is part of its state, and the file is written back when the EEPROM is dropped as the machine shuts down.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::devices::i2c::*;
+use crate::devices::save_state::*;
+
+pub const EEPROM_SIZE: usize = 0x8000;
+pub const EEPROM_BASE_ADDRESS: u8 = 0x50;
+
+const PAGE_SIZE: u16 = 64;
+const ADDRESS_MASK: u16 = EEPROM_SIZE as u16 - 1;
+
+// The 5ms write cycle at 1MHz
+const DEFAULT_WRITE_CYCLES: u32 = 5000;
+
+#[derive(Debug)]
+pub struct EEPROM {
+    data: Vec<u8>,
+    address: u8,
+    pointer: u16,
+    address_bytes: u8,
+    page: Vec<(u16, u8)>,
+    write_cycles: u32,
+    busy_cycles: u32,
+    file: Option<File>
+}
+
+impl EEPROM {
+    // `pins` is the value of the A0-A2 address pins
+    pub fn new(pins: u8) -> EEPROM {
+        EEPROM {
+            data: vec![0xff; EEPROM_SIZE],
+            address: EEPROM_BASE_ADDRESS | (pins & 0x07),
+            pointer: 0,
+            address_bytes: 0,
+            page: Vec::new(),
+            write_cycles: DEFAULT_WRITE_CYCLES,
+            busy_cycles: 0,
+            file: None
+        }
+    }
+
+    // Back the EEPROM with a host file, creating it (erased to $FF) if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P, pins: u8) -> std::io::Result<EEPROM> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut eeprom = EEPROM::new(pins);
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        contents.truncate(EEPROM_SIZE);
+        eeprom.data[..contents.len()].copy_from_slice(&contents);
+        if contents.len() < EEPROM_SIZE {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&eeprom.data)?;
+            file.flush()?;
+        }
+
+        eeprom.file = Some(file);
+        Ok(eeprom)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // How long programming a page keeps the chip busy, in CPU cycles
+    pub fn set_write_cycles(&mut self, cycles: u32) {
+        self.write_cycles = cycles;
+    }
+
+    // Write the contents back to the backing file
+    fn write_back(&mut self) -> std::io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&self.data)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EEPROM {
+    fn drop(&mut self) {
+        if let Err(error) = self.write_back() {
+            eprintln!("EEPROM: Unable to write back to the backing file: {}", error);
+        }
+    }
+}
+
+impl I2cDevice for EEPROM {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn start(&mut self, _read: bool) -> bool {
+        self.address_bytes = 0;
+        self.page.clear();
+        self.busy_cycles == 0
+    }
+
+    fn write(&mut self, value: u8) -> bool {
+        match self.address_bytes {
+            0 => self.pointer = ((value as u16) << 8) & ADDRESS_MASK,
+            1 => self.pointer |= value as u16,
+            _ => {
+                // Data is latched into the page buffer, wrapping around within the page
+                self.page.push((self.pointer, value));
+                let page = self.pointer & !(PAGE_SIZE - 1);
+                self.pointer = page | ((self.pointer + 1) & (PAGE_SIZE - 1));
+            }
+        }
+        self.address_bytes = self.address_bytes.saturating_add(1);
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.data[self.pointer as usize];
+        self.pointer = (self.pointer + 1) & ADDRESS_MASK;
+        value
+    }
+
+    fn stop(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        for (address, value) in std::mem::take(&mut self.page) {
+            self.data[address as usize] = value;
+        }
+        self.busy_cycles = self.write_cycles;
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+    }
+
+    // The contents and any page waiting to be programmed
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u16(self.pointer);
+        state.write_u8(self.address_bytes);
+        state.write_u32(self.page.len() as u32);
+        for &(address, value) in &self.page {
+            state.write_u16(address);
+            state.write_u8(value);
+        }
+        state.write_u32(self.busy_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.data)?;
+        self.pointer = state.read_u16()? & ADDRESS_MASK;
+        self.address_bytes = state.read_u8()?;
+        let count = state.read_u32()?;
+        self.page.clear();
+        for _ in 0..count {
+            let address = state.read_u16()? & ADDRESS_MASK;
+            self.page.push((address, state.read_u8()?));
+        }
+        self.busy_cycles = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::i2c::testing::*;
+    use crate::devices::memory::*;
+
+    fn select(port: &mut I2cPort, pins: u8, address: u16) -> bool {
+        start(port);
+        if !write_byte(port, (EEPROM_BASE_ADDRESS | pins) << 1) {
+            stop(port);
+            return false;
+        }
+        assert!(write_byte(port, (address >> 8) as u8));
+        assert!(write_byte(port, address as u8));
+        true
+    }
+
+    fn write_bytes(port: &mut I2cPort, address: u16, values: &[u8]) {
+        assert!(select(port, 0, address));
+        for &value in values {
+            assert!(write_byte(port, value));
+        }
+        stop(port);
+    }
+
+    fn read_bytes(port: &mut I2cPort, address: u16, count: usize) -> Vec<u8> {
+        assert!(select(port, 0, address));
+        start(port);
+        assert!(write_byte(port, (EEPROM_BASE_ADDRESS << 1) | 1));
+        let values = (0..count).map(|index| read_byte(port, index + 1 < count)).collect();
+        stop(port);
+        values
+    }
+
+    fn port(eeprom: EEPROM) -> I2cPort {
+        let mut port = I2cPort::new(0);
+        port.attach(Box::new(eeprom));
+        port
+    }
+
+    #[test]
+    fn eeprom_write_and_read() {
+        let mut port = port(EEPROM::new(0));
+        write_bytes(&mut port, 0x1234, &[0x11, 0x22, 0x33]);
+
+        // The chip does not answer while it is programming
+        assert!(!select(&mut port, 0, 0x1234));
+        port.tick(DEFAULT_WRITE_CYCLES);
+        assert_eq!(read_bytes(&mut port, 0x1233, 5), [0xff, 0x11, 0x22, 0x33, 0xff]);
+    }
+
+    #[test]
+    fn eeprom_page_wrap() {
+        let mut eeprom = EEPROM::new(0);
+        eeprom.set_write_cycles(0);
+        let mut port = port(eeprom);
+
+        // Writing past the end of a 64 byte page wraps to its start
+        write_bytes(&mut port, 0x007f, &[0xaa, 0xbb]);
+        assert_eq!(read_bytes(&mut port, 0x0040, 1), [0xbb]);
+        assert_eq!(read_bytes(&mut port, 0x007f, 2), [0xaa, 0xff]);
+
+        // Sequential reads wrap at the end of memory
+        write_bytes(&mut port, 0x0000, &[0x01]);
+        assert_eq!(read_bytes(&mut port, 0x7fff, 2), [0xff, 0x01]);
+    }
+
+    #[test]
+    fn eeprom_address_pins() {
+        let mut port = port(EEPROM::new(5));
+        assert!(!select(&mut port, 0, 0));
+        assert!(select(&mut port, 5, 0));
+        stop(&mut port);
+    }
+
+    #[test]
+    fn eeprom_file() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join(format!("eeprom_{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut port = port(EEPROM::open(&path, 0)?);
+        write_bytes(&mut port, 0x0100, &[0x5a, 0xa5]);
+        let mut state = StateWriter::new();
+        port.save_state(&mut state);
+        let state = state.into_bytes();
+        port.tick(DEFAULT_WRITE_CYCLES);
+        write_bytes(&mut port, 0x0100, &[0x00]);
+
+        // Restoring a state neither touches the file nor keeps later writes, which is what gets written back
+        port.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(std::fs::read(&path)?[0x0100], 0xff);
+        drop(port);
+
+        let contents = std::fs::read(&path)?;
+        assert_eq!(contents.len(), EEPROM_SIZE);
+        assert_eq!(&contents[0x00ff..0x0103], &[0xff, 0x5a, 0xa5, 0xff]);
+
+        // Reopening picks up the previous contents
+        let eeprom = EEPROM::open(&path, 0)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(eeprom.data()[0x0100], 0x5a);
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+/*!
+ * Framebuffer
+ *
+ * An in-memory RGB image that video devices render into. A frame can be written out as a binary PPM (P6) or as a PNG
+ * so that video output can be checked by headless tests. The PNG encoder only emits stored (uncompressed) deflate
+ * blocks, which keeps it free of external dependencies at the cost of larger files.
+ */
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+pub type Rgb = [u8; 3];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    PPM,
+    PNG
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::PPM => "ppm",
+            ImageFormat::PNG => "png"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![0; width * height * 3]
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Raw pixel data, three bytes (R, G, B) per pixel, row by row from the top left
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Rgb {
+        let index = (y * self.width + x) * 3;
+        [self.pixels[index], self.pixels[index + 1], self.pixels[index + 2]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        let index = (y * self.width + x) * 3;
+        self.pixels[index..index + 3].copy_from_slice(&color);
+    }
+
+    pub fn fill(&mut self, color: Rgb) {
+        for pixel in self.pixels.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.pixels)
+    }
+
+    pub fn write_png<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        out.write_all(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])?;
+
+        // IHDR: width, height, 8 bits per channel, colour type 2 (RGB), default compression, filter and interlace
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_png_chunk(out, b"IHDR", &header)?;
+
+        // Every scanline is prefixed with filter type 0 (none)
+        let stride = self.width * 3;
+        let mut raw = Vec::with_capacity((stride + 1) * self.height);
+        for row in self.pixels.chunks_exact(stride.max(1)).take(self.height) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        write_png_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+        write_png_chunk(out, b"IEND", &[])
+    }
+
+    pub fn save_ppm<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_ppm(&mut out)?;
+        out.flush()
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_png(&mut out)?;
+        out.flush()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> std::io::Result<()> {
+        match format {
+            ImageFormat::PPM => self.save_ppm(path),
+            ImageFormat::PNG => self.save_png(path)
+        }
+    }
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    // The CRC covers the chunk type and data but not the length
+    let crc = !crc32_update(crc32_update(0xffff_ffff, kind), data);
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// Wrap data in a zlib stream made of stored deflate blocks (at most 65535 bytes each)
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framebuffer_pixels() {
+        let mut framebuffer = Framebuffer::new(4, 2);
+        assert_eq!(framebuffer.pixels().len(), 4 * 2 * 3);
+        assert_eq!(framebuffer.get_pixel(3, 1), [0, 0, 0]);
+
+        framebuffer.set_pixel(3, 1, [0x12, 0x34, 0x56]);
+        assert_eq!(framebuffer.get_pixel(3, 1), [0x12, 0x34, 0x56]);
+        assert_eq!(framebuffer.get_pixel(2, 1), [0, 0, 0]);
+
+        framebuffer.fill([0xff, 0x00, 0xff]);
+        assert_eq!(framebuffer.get_pixel(0, 0), [0xff, 0x00, 0xff]);
+        assert_eq!(framebuffer.get_pixel(3, 1), [0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn framebuffer_ppm() {
+        let mut framebuffer = Framebuffer::new(2, 1);
+        framebuffer.set_pixel(1, 0, [1, 2, 3]);
+
+        let mut out = Vec::new();
+        framebuffer.write_ppm(&mut out).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\x00\x00\x00\x01\x02\x03");
+    }
+
+    #[test]
+    fn framebuffer_png() {
+        let framebuffer = Framebuffer::new(3, 2);
+
+        let mut out = Vec::new();
+        framebuffer.write_png(&mut out).unwrap();
+        assert_eq!(&out[0..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[16..20], &3u32.to_be_bytes());
+        assert_eq!(&out[20..24], &2u32.to_be_bytes());
+        assert_eq!(&out[out.len() - 8..out.len() - 4], b"IEND");
+
+        // IEND has no data, so its CRC is a well known constant
+        assert_eq!(&out[out.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn crc_and_adler() {
+        assert_eq!(!crc32_update(0xffff_ffff, b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+}
@@ -0,0 +1,485 @@
+/*!
+ * I2C Bus
+ *
+ * The I2cBus models the two open-drain lines (SCL and SDA) shared by a master and any number of slave devices. The
+ * master drives the lines through set_lines(), where true releases a line and false pulls it low; a slave can only pull
+ * SDA low, so the level seen on the bus is the AND of everything driving it. The bus decodes START and STOP conditions,
+ * shifts bytes in and out on the clock edges and hands complete bytes to the addressed slave, which implements
+ * I2cDevice.
+ *
+ * Device: I2C Port
+ *
+ * A dedicated MMIO port for bit-banging the bus from 6502 code, at offset + 0:
+ * - bit 0: SCL (write 0 to pull low, 1 to release; reads the line)
+ * - bit 1: SDA (write 0 to pull low, 1 to release; reads the line)
+ */
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const I2C_PORT_SIZE: u32 = 1;
+
+pub const PIN_SCL: u8 = 0x01;
+pub const PIN_SDA: u8 = 0x02;
+
+pub trait I2cDevice: std::fmt::Debug {
+    // The 7 bit bus address
+    fn address(&self) -> u8;
+
+    // Addressed after a START; returns whether the device acknowledges
+    fn start(&mut self, read: bool) -> bool;
+
+    // A byte written by the master; returns whether the device acknowledges
+    fn write(&mut self, value: u8) -> bool;
+
+    // The next byte to send to the master
+    fn read(&mut self) -> u8;
+
+    fn stop(&mut self) {}
+
+    fn tick(&mut self, _cycles: u32) {}
+
+    // Save and restore the device's internal state, as for a Memory device
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+
+    // As for a Memory device
+    fn make_deterministic(&mut self, _time: i64, _clock_rate: u32) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Address,
+    Write,
+    Read,
+    // The transfer was not acknowledged; wait for the next START or STOP
+    Ignore
+}
+
+#[derive(Debug)]
+pub struct I2cBus {
+    devices: Vec<Box<dyn I2cDevice>>,
+    active: Option<usize>,
+    phase: Phase,
+    scl: bool,
+    sda_master: bool,
+    sda_slave: bool,
+    reading: bool,
+    sampled: bool,
+    master_ack: bool,
+    shift: u8,
+    bit: u8
+}
+
+impl I2cBus {
+    pub fn new() -> I2cBus {
+        I2cBus {
+            devices: Vec::new(),
+            active: None,
+            phase: Phase::Idle,
+            scl: true,
+            sda_master: true,
+            sda_slave: true,
+            reading: false,
+            sampled: false,
+            master_ack: false,
+            shift: 0,
+            bit: 0
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn I2cDevice>) {
+        self.devices.push(device);
+    }
+
+    pub fn scl(&self) -> bool {
+        self.scl
+    }
+
+    pub fn sda(&self) -> bool {
+        self.sda_master && self.sda_slave
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for device in &mut self.devices {
+            device.tick(cycles);
+        }
+    }
+
+    pub fn make_deterministic(&mut self, time: i64, clock_rate: u32) {
+        for device in &mut self.devices {
+            device.make_deterministic(time, clock_rate);
+        }
+    }
+
+    // The state of the bus, then of each device attached to it in a block of its own
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.active.is_some());
+        state.write_u32(self.active.unwrap_or(0) as u32);
+        state.write_u8(self.phase as u8);
+        state.write_bool(self.scl);
+        state.write_bool(self.sda_master);
+        state.write_bool(self.sda_slave);
+        state.write_bool(self.reading);
+        state.write_bool(self.sampled);
+        state.write_bool(self.master_ack);
+        state.write_u8(self.shift);
+        state.write_u8(self.bit);
+        state.write_u32(self.devices.len() as u32);
+        for device in &self.devices {
+            let mut block = StateWriter::new();
+            device.save_state(&mut block);
+            state.write_bytes(&block.into_bytes());
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let active = state.read_bool()?;
+        let index = state.read_u32()? as usize;
+        self.active = if active { Some(index) } else { None };
+        self.phase = match state.read_u8()? {
+            0 => Phase::Idle,
+            1 => Phase::Address,
+            2 => Phase::Write,
+            3 => Phase::Read,
+            4 => Phase::Ignore,
+            value => return Err(StateError::Invalid(format!("I2C phase {}", value)))
+        };
+        self.scl = state.read_bool()?;
+        self.sda_master = state.read_bool()?;
+        self.sda_slave = state.read_bool()?;
+        self.reading = state.read_bool()?;
+        self.sampled = state.read_bool()?;
+        self.master_ack = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.bit = state.read_u8()?;
+        let count = state.read_u32()? as usize;
+        if count != self.devices.len() || self.active.is_some_and(|index| index >= count) {
+            let message = format!("{} I2C devices saved, but {} are attached", count, self.devices.len());
+            return Err(StateError::Mismatch(message));
+        }
+        for device in &mut self.devices {
+            device.load_state(&mut StateReader::new(state.read_bytes()?))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_lines(&mut self, scl: bool, sda: bool) {
+        // A change on SDA while SCL stays high is a START (falling) or STOP (rising) condition
+        let previous_sda = self.sda();
+        self.sda_master = sda;
+        if self.scl && scl && previous_sda != self.sda() {
+            if self.sda() {
+                self.stop();
+            } else {
+                self.start();
+            }
+        }
+
+        if scl != self.scl {
+            self.scl = scl;
+            if scl {
+                self.rising_edge();
+            } else {
+                self.falling_edge();
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        self.phase = Phase::Address;
+        self.active = None;
+        self.sda_slave = true;
+        self.sampled = false;
+        self.shift = 0;
+        self.bit = 0;
+    }
+
+    fn stop(&mut self) {
+        if let Some(index) = self.active.take() {
+            self.devices[index].stop();
+        }
+        self.phase = Phase::Idle;
+        self.sda_slave = true;
+    }
+
+    fn rising_edge(&mut self) {
+        self.sampled = true;
+        match self.phase {
+            Phase::Address | Phase::Write if self.bit < 8 => self.shift = (self.shift << 1) | self.sda() as u8,
+            Phase::Read if self.bit == 8 => self.master_ack = !self.sda(),
+            _ => {}
+        }
+    }
+
+    // Slaves change SDA while SCL is low, so all of the byte handling happens after a falling edge
+    fn falling_edge(&mut self) {
+        if !std::mem::replace(&mut self.sampled, false) {
+            return;
+        }
+
+        match self.phase {
+            Phase::Address | Phase::Write if self.bit < 8 => {
+                self.bit += 1;
+                if self.bit == 8 {
+                    self.sda_slave = !self.receive_byte();
+                }
+            }
+            Phase::Address | Phase::Write => {
+                // The acknowledge clock is over
+                self.sda_slave = true;
+                self.bit = 0;
+                self.shift = 0;
+                if self.phase == Phase::Address {
+                    self.phase = if self.reading { Phase::Read } else { Phase::Write };
+                    if self.reading {
+                        self.load_byte();
+                    }
+                }
+            }
+            Phase::Read if self.bit < 8 => {
+                self.bit += 1;
+                self.sda_slave = self.bit == 8 || self.shift & (0x80 >> self.bit) != 0;
+            }
+            Phase::Read => {
+                // Keep sending for as long as the master acknowledges
+                if self.master_ack {
+                    self.load_byte();
+                } else {
+                    self.phase = Phase::Ignore;
+                    self.sda_slave = true;
+                }
+            }
+            Phase::Idle | Phase::Ignore => {}
+        }
+    }
+
+    // Handle a complete byte from the master, returning whether it was acknowledged
+    fn receive_byte(&mut self) -> bool {
+        if self.phase == Phase::Address {
+            let address = self.shift >> 1;
+            self.reading = self.shift & 1 != 0;
+            self.active = self.devices.iter().position(|device| device.address() == address);
+            let acknowledged = match self.active {
+                Some(index) => self.devices[index].start(self.reading),
+                None => false
+            };
+            if !acknowledged {
+                self.active = None;
+                self.phase = Phase::Ignore;
+            }
+            return acknowledged;
+        }
+
+        match self.active {
+            Some(index) => self.devices[index].write(self.shift),
+            None => false
+        }
+    }
+
+    fn load_byte(&mut self) {
+        self.shift = match self.active {
+            Some(index) => self.devices[index].read(),
+            None => 0xff
+        };
+        self.bit = 0;
+        self.sda_slave = self.shift & 0x80 != 0;
+    }
+}
+
+impl Default for I2cBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct I2cPort {
+    bus: I2cBus,
+    offset: u32
+}
+
+impl I2cPort {
+    pub fn new(offset: u32) -> I2cPort {
+        I2cPort {
+            bus: I2cBus::new(),
+            offset
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn I2cDevice>) {
+        self.bus.attach(device);
+    }
+}
+
+impl Memory for I2cPort {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + I2C_PORT_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        let scl = if self.bus.scl() { PIN_SCL } else { 0 };
+        let sda = if self.bus.sda() { PIN_SDA } else { 0 };
+        Ok(scl | sda)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + I2C_PORT_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        self.bus.set_lines(value & PIN_SCL != 0, value & PIN_SDA != 0);
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.bus.tick(cycles);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.bus.load_state(state)
+    }
+
+    fn make_deterministic(&mut self, time: i64, clock_rate: u32) {
+        self.bus.make_deterministic(time, clock_rate);
+    }
+}
+
+// Bit-banging helpers shared by the tests of the I2C devices
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    pub fn start(port: &mut I2cPort) {
+        port.write(0, PIN_SCL | PIN_SDA).unwrap();
+        port.write(0, PIN_SCL).unwrap();
+        port.write(0, 0).unwrap();
+    }
+
+    pub fn stop(port: &mut I2cPort) {
+        port.write(0, 0).unwrap();
+        port.write(0, PIN_SCL).unwrap();
+        port.write(0, PIN_SCL | PIN_SDA).unwrap();
+    }
+
+    fn clock_bit(port: &mut I2cPort, sda: bool) -> bool {
+        let sda = if sda { PIN_SDA } else { 0 };
+        port.write(0, sda).unwrap();
+        port.write(0, sda | PIN_SCL).unwrap();
+        let line = port.read(0).unwrap() & PIN_SDA != 0;
+        port.write(0, sda).unwrap();
+        line
+    }
+
+    // Send a byte and return whether it was acknowledged
+    pub fn write_byte(port: &mut I2cPort, value: u8) -> bool {
+        for bit in (0..8).rev() {
+            clock_bit(port, value & (1 << bit) != 0);
+        }
+        !clock_bit(port, true)
+    }
+
+    pub fn read_byte(port: &mut I2cPort, ack: bool) -> u8 {
+        let mut value = 0;
+        for _ in 0..8 {
+            value = (value << 1) | clock_bit(port, true) as u8;
+        }
+        clock_bit(port, !ack);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    // A single register that increments every time it is read
+    #[derive(Debug)]
+    struct Register {
+        value: u8
+    }
+
+    impl I2cDevice for Register {
+        fn address(&self) -> u8 {
+            0x20
+        }
+
+        fn start(&mut self, _read: bool) -> bool {
+            true
+        }
+
+        fn write(&mut self, value: u8) -> bool {
+            self.value = value;
+            true
+        }
+
+        fn read(&mut self) -> u8 {
+            self.value = self.value.wrapping_add(1);
+            self.value.wrapping_sub(1)
+        }
+    }
+
+    fn port() -> I2cPort {
+        let mut port = I2cPort::new(0);
+        port.attach(Box::new(Register { value: 0 }));
+        port
+    }
+
+    #[test]
+    fn i2c_write_and_read() {
+        let mut port = port();
+        start(&mut port);
+        assert!(write_byte(&mut port, 0x20 << 1));
+        assert!(write_byte(&mut port, 0x5a));
+        stop(&mut port);
+
+        start(&mut port);
+        assert!(write_byte(&mut port, (0x20 << 1) | 1));
+        assert_eq!(read_byte(&mut port, true), 0x5a);
+        assert_eq!(read_byte(&mut port, false), 0x5b);
+        stop(&mut port);
+
+        // Both lines are released when the bus is idle
+        assert_eq!(port.read(0).unwrap(), PIN_SCL | PIN_SDA);
+    }
+
+    #[test]
+    fn i2c_no_device() {
+        let mut port = port();
+        start(&mut port);
+        assert!(!write_byte(&mut port, 0x21 << 1));
+        assert!(!write_byte(&mut port, 0x00));
+        stop(&mut port);
+    }
+
+    #[test]
+    fn i2c_repeated_start() {
+        let mut port = port();
+        start(&mut port);
+        assert!(write_byte(&mut port, 0x20 << 1));
+        assert!(write_byte(&mut port, 0x10));
+        start(&mut port);
+        assert!(write_byte(&mut port, (0x20 << 1) | 1));
+        assert_eq!(read_byte(&mut port, false), 0x10);
+        stop(&mut port);
+    }
+}
@@ -0,0 +1,569 @@
+/*!
+ * Device: Keyboard
+ *
+ * A keyboard that can present itself to the CPU in one of two ways:
+ * - ASCII: an Apple-1 style latch. Reading the data register returns the last key with bit 7 (the strobe) set the
+ *   first time, and the status register has bit 7 set while a key is waiting.
+ * - Matrix: an 8x8 key matrix scanned through two ports, like a C64 or VIC-20. The CPU drives the column select port
+ *   (active low) and reads the row port, where a 0 bit means a key in a selected column is down.
+ *
+ * A matrix keyboard can also be wired to the ports of a 6522 VIA instead, as a PortDevice: port A drives the column
+ * selects and port B reads the rows.
+ *
+ * Registers:
+ * - offset + 0: ASCII data (read) / matrix column select (read/write)
+ * - offset + 1: ASCII status (read) / matrix rows (read)
+ * - offset + 2: control: bit 0 enables the IRQ on a key press, bit 7 reads as the pending interrupt and acknowledges
+ *   it when written as 1
+ *
+ * Keys come from the host terminal (see RawTerminal and attach_host) or from a script of key presses timed in CPU
+ * cycles, which makes keyboard input reproducible in tests. Host keys are taken as input by the Emulator, so they can
+ * be recorded and replayed. Ctrl-] on the host is never typed, and asks the program running the machine to quit.
+ */
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+use crate::devices::via;
+
+pub const KEYBOARD_SIZE: u32 = 3;
+
+const STROBE: u8 = 0x80;
+const CONTROL_IRQ_ENABLE: u8 = 0x01;
+const CONTROL_IRQ_PENDING: u8 = 0x80;
+
+// Ctrl-], which quits rather than being typed when it comes from the host
+pub const QUIT_KEY: u8 = 0x1d;
+
+static QUIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// How long a key stays down in the matrix when it comes from the host or a script, in CPU cycles
+const DEFAULT_HOLD_CYCLES: u64 = 20_000;
+
+// The default matrix layout, indexed by column * 8 + row. Letters are matched case-insensitively.
+const DEFAULT_LAYOUT: [u8; 64] = [
+    b'A', b'B', b'C', b'D', b'E', b'F', b'G', b'H',
+    b'I', b'J', b'K', b'L', b'M', b'N', b'O', b'P',
+    b'Q', b'R', b'S', b'T', b'U', b'V', b'W', b'X',
+    b'Y', b'Z', b'0', b'1', b'2', b'3', b'4', b'5',
+    b'6', b'7', b'8', b'9', b' ', 0x0d, 0x08, 0x1b,
+    b',', b'.', b'/', b';', b'\'', b'[', b']', b'-',
+    b'=', b'\\', b'`', 0x09, b'!', b'"', b'#', b'$',
+    b'%', b'&', b'(', b')', b'*', b'+', b':', b'?'
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyboardMode {
+    Ascii,
+    Matrix
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KeyEvent {
+    cycle: u64,
+    key: u8,
+    pressed: bool
+}
+
+#[derive(Debug)]
+pub struct Keyboard {
+    mode: KeyboardMode,
+    offset: u32,
+    latch: Cell<u8>,
+    strobe: Cell<bool>,
+    control: Cell<u8>,
+    column_select: u8,
+    // One byte per column, with bit n set while the key in row n is down
+    matrix: [u8; 8],
+    layout: [u8; 64],
+    hold_cycles: u64,
+    cycles: u64,
+    script: VecDeque<KeyEvent>,
+    host: Option<Receiver<u8>>
+}
+
+impl Keyboard {
+    pub fn new(mode: KeyboardMode, offset: u32) -> Keyboard {
+        Keyboard {
+            mode,
+            offset,
+            latch: Cell::new(0),
+            strobe: Cell::new(false),
+            control: Cell::new(0),
+            column_select: 0xff,
+            matrix: [0; 8],
+            layout: DEFAULT_LAYOUT,
+            hold_cycles: DEFAULT_HOLD_CYCLES,
+            cycles: 0,
+            script: VecDeque::new(),
+            host: None
+        }
+    }
+
+    pub fn mode(&self) -> KeyboardMode {
+        self.mode
+    }
+
+    // Replace the matrix layout; entry column * 8 + row is the ASCII key at that position (0 for none)
+    pub fn set_layout(&mut self, layout: [u8; 64]) {
+        self.layout = layout;
+    }
+
+    pub fn set_hold_cycles(&mut self, cycles: u64) {
+        self.hold_cycles = cycles;
+    }
+
+    // Feed keys typed on the host terminal into the keyboard. Put the terminal in raw mode first with RawTerminal.
+    pub fn attach_host(&mut self) {
+        self.attach_receiver(host_keys());
+    }
+
+    // Feed keys from another thread into the keyboard, as if typed on the host
+    pub fn attach_receiver(&mut self, receiver: Receiver<u8>) {
+        self.host = Some(receiver);
+    }
+
+    // Queue a key press at an absolute CPU cycle. In matrix mode the key is released again after the hold time.
+    pub fn queue_key(&mut self, cycle: u64, key: u8) {
+        self.schedule(KeyEvent { cycle, key, pressed: true });
+        if self.mode == KeyboardMode::Matrix {
+            self.schedule(KeyEvent { cycle: cycle + self.hold_cycles, key, pressed: false });
+        }
+    }
+
+    // Queue a string, one key every `interval` cycles starting at `cycle`
+    pub fn queue_text(&mut self, cycle: u64, interval: u64, text: &str) {
+        for (index, key) in text.bytes().enumerate() {
+            self.queue_key(cycle + index as u64 * interval, key);
+        }
+    }
+
+    pub fn pending_keys(&self) -> usize {
+        self.script.iter().filter(|event| event.pressed).count()
+    }
+
+    // Press a key immediately
+    pub fn press(&mut self, key: u8) {
+        match self.mode {
+            KeyboardMode::Ascii => {
+                self.latch.set(key & 0x7f);
+                self.strobe.set(true);
+                self.raise_irq();
+            }
+            KeyboardMode::Matrix => {
+                if let Some((column, row)) = self.position(key) {
+                    if self.matrix[column] & (1 << row) == 0 {
+                        self.matrix[column] |= 1 << row;
+                        self.raise_irq();
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn release(&mut self, key: u8) {
+        if let Some((column, row)) = self.position(key) {
+            self.matrix[column] &= !(1 << row);
+        }
+    }
+
+    // The rows seen on the row port for a given column select value
+    pub fn scan(&self, column_select: u8) -> u8 {
+        let mut rows = 0xff;
+        for (column, keys) in self.matrix.iter().enumerate() {
+            if column_select & (1 << column) == 0 {
+                rows &= !keys;
+            }
+        }
+        rows
+    }
+
+    fn position(&self, key: u8) -> Option<(usize, usize)> {
+        let key = key.to_ascii_uppercase();
+        self.layout.iter().position(|&entry| entry != 0 && entry == key).map(|index| (index / 8, index % 8))
+    }
+
+    fn schedule(&mut self, event: KeyEvent) {
+        let index = self.script.partition_point(|queued| queued.cycle <= event.cycle);
+        self.script.insert(index, event);
+    }
+
+    fn raise_irq(&self) {
+        if self.control.get() & CONTROL_IRQ_ENABLE != 0 {
+            self.control.set(self.control.get() | CONTROL_IRQ_PENDING);
+        }
+    }
+}
+
+impl Memory for Keyboard {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + KEYBOARD_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        match (address - self.offset, self.mode) {
+            (0, KeyboardMode::Ascii) => {
+                // The strobe is only returned once; reading the key also acknowledges its interrupt
+                let value = if self.strobe.replace(false) { self.latch.get() | STROBE } else { self.latch.get() };
+                self.control.set(self.control.get() & !CONTROL_IRQ_PENDING);
+                Ok(value)
+            }
+            (1, KeyboardMode::Ascii) => Ok(if self.strobe.get() { STROBE } else { 0 }),
+            (0, KeyboardMode::Matrix) => Ok(self.column_select),
+            (1, KeyboardMode::Matrix) => Ok(self.scan(self.column_select)),
+            _ => Ok(self.control.get())
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + KEYBOARD_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        match (address - self.offset, self.mode) {
+            (0, KeyboardMode::Matrix) => self.column_select = value,
+            (2, _) => {
+                let pending = if value & CONTROL_IRQ_PENDING != 0 { 0 } else { self.control.get() & CONTROL_IRQ_PENDING };
+                self.control.set((value & CONTROL_IRQ_ENABLE) | pending);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + KEYBOARD_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        match (address - self.offset, self.mode) {
+            (0, KeyboardMode::Ascii) => {
+                Ok(if self.strobe.get() { self.latch.get() | STROBE } else { self.latch.get() })
+            }
+            _ => self.read(address as u16)
+        }
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+
+        while let Some(event) = self.script.front().copied() {
+            if event.cycle > self.cycles {
+                break;
+            }
+            self.script.pop_front();
+            if event.pressed {
+                self.press(event.key);
+            } else {
+                self.release(event.key);
+            }
+        }
+    }
+
+    // The next key pressed or released by the script
+    fn next_event(&self) -> Option<u64> {
+        self.script.front().map(|event| event.cycle.saturating_sub(self.cycles))
+    }
+
+    // The keys typed on the host since the last call
+    fn take_input(&mut self) -> Vec<u8> {
+        let mut keys = Vec::new();
+        if let Some(host) = &self.host {
+            loop {
+                match host.try_recv() {
+                    Ok(key) => keys.push(key),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.host = None;
+                        break;
+                    }
+                }
+            }
+        }
+        keys
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        for &key in data {
+            self.queue_key(self.cycles, key);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control.get() & CONTROL_IRQ_PENDING != 0
+    }
+
+    // The latch, the key matrix and any scripted keys still to come. Keys from the host are not part of the state.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.latch.get());
+        state.write_bool(self.strobe.get());
+        state.write_u8(self.control.get());
+        state.write_u8(self.column_select);
+        state.write_raw(&self.matrix);
+        state.write_u64(self.cycles);
+        state.write_u32(self.script.len() as u32);
+        for event in &self.script {
+            state.write_u64(event.cycle);
+            state.write_u8(event.key);
+            state.write_bool(event.pressed);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.latch.set(state.read_u8()?);
+        self.strobe.set(state.read_bool()?);
+        self.control.set(state.read_u8()?);
+        self.column_select = state.read_u8()?;
+        let matrix = state.read_raw(8)?;
+        self.matrix.copy_from_slice(matrix);
+        self.cycles = state.read_u64()?;
+        let count = state.read_u32()?;
+        self.script.clear();
+        for _ in 0..count {
+            let cycle = state.read_u64()?;
+            let key = state.read_u8()?;
+            let pressed = state.read_bool()?;
+            self.script.push_back(KeyEvent { cycle, key, pressed });
+        }
+        Ok(())
+    }
+}
+
+// On a VIA the columns are selected by port A and the rows are read from port B
+impl via::PortDevice for Keyboard {
+    fn write_pins(&mut self, port_a: u8, _port_b: u8) {
+        self.column_select = port_a;
+    }
+
+    fn read_pins(&self, port_a: u8, _port_b: u8) -> (u8, u8) {
+        (0xff, self.scan(port_a))
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        Memory::tick(self, cycles);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        Memory::next_event(self)
+    }
+
+    fn take_input(&mut self) -> Vec<u8> {
+        Memory::take_input(self)
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        Memory::input(self, data);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        Memory::save_state(self, state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        Memory::load_state(self, state)
+    }
+}
+
+// The keys typed on the host terminal, read from standard input on a thread of their own. QUIT_KEY is kept back and
+// asks to quit instead, as a raw terminal does not turn Ctrl-C into a signal.
+pub fn host_keys() -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut byte = [0u8; 1];
+        while let Ok(1) = stdin.read(&mut byte) {
+            if byte[0] == QUIT_KEY {
+                QUIT_REQUESTED.store(true, Ordering::Relaxed);
+            } else if sender.send(byte[0]).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// Whether QUIT_KEY has been typed on the host
+pub fn quit_requested() -> bool {
+    QUIT_REQUESTED.load(Ordering::Relaxed)
+}
+
+// Puts the host terminal in raw mode (no line buffering or echo) for as long as it is alive, using stty
+#[derive(Debug)]
+pub struct RawTerminal {
+    saved: String
+}
+
+impl RawTerminal {
+    pub fn enable() -> std::io::Result<RawTerminal> {
+        let output = Command::new("stty").arg("-g").stdin(Stdio::inherit()).output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other("stty: standard input is not a terminal"));
+        }
+        let saved = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Command::new("stty").args(["raw", "-echo"]).stdin(Stdio::inherit()).status()?;
+        Ok(RawTerminal { saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = Command::new("stty").arg(&self.saved).stdin(Stdio::inherit()).status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::via::VIA;
+
+    #[test]
+    fn keyboard_ascii() -> Result<(), MemoryError> {
+        let mut keyboard = Keyboard::new(KeyboardMode::Ascii, 0xd010);
+        assert_eq!(keyboard.read(0xd011)?, 0);
+
+        keyboard.press(b'A');
+        assert_eq!(keyboard.read(0xd011)?, STROBE);
+        assert_eq!(keyboard.read(0xd010)?, b'A' | STROBE);
+
+        // Reading the key clears the strobe
+        assert_eq!(keyboard.read(0xd011)?, 0);
+        assert_eq!(keyboard.read(0xd010)?, b'A');
+
+        assert!(keyboard.read(0xd013).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_ascii_irq() -> Result<(), MemoryError> {
+        let mut keyboard = Keyboard::new(KeyboardMode::Ascii, 0xd010);
+        keyboard.press(b'A');
+        assert!(!keyboard.irq());
+
+        keyboard.write(0xd012, CONTROL_IRQ_ENABLE)?;
+        keyboard.press(b'B');
+        assert!(keyboard.irq());
+        assert_eq!(keyboard.read(0xd012)?, CONTROL_IRQ_ENABLE | CONTROL_IRQ_PENDING);
+        assert_eq!(keyboard.read(0xd010)?, b'B' | STROBE);
+        assert!(!keyboard.irq());
+
+        // The interrupt can also be acknowledged through the control register
+        keyboard.press(b'C');
+        keyboard.write(0xd012, CONTROL_IRQ_ENABLE | CONTROL_IRQ_PENDING)?;
+        assert!(!keyboard.irq());
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_matrix() -> Result<(), MemoryError> {
+        let mut keyboard = Keyboard::new(KeyboardMode::Matrix, 0x6000);
+
+        // 'K' is column 1, row 2
+        keyboard.press(b'k');
+        keyboard.write(0x6000, 0xff)?;
+        assert_eq!(keyboard.read(0x6001)?, 0xff);
+        keyboard.write(0x6000, !0x01)?;
+        assert_eq!(keyboard.read(0x6001)?, 0xff);
+        keyboard.write(0x6000, !0x02)?;
+        assert_eq!(keyboard.read(0x6001)?, !0x04);
+
+        // Selecting every column at once sees every key
+        keyboard.press(b'A');
+        keyboard.write(0x6000, 0x00)?;
+        assert_eq!(keyboard.read(0x6001)?, !0x05);
+
+        keyboard.release(b'K');
+        assert_eq!(keyboard.read(0x6001)?, !0x01);
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_script() -> Result<(), MemoryError> {
+        let mut keyboard = Keyboard::new(KeyboardMode::Ascii, 0xd010);
+        keyboard.queue_text(100, 50, "HI");
+        assert_eq!(keyboard.pending_keys(), 2);
+
+        keyboard.tick(99);
+        assert_eq!(keyboard.read(0xd011)?, 0);
+        keyboard.tick(1);
+        assert_eq!(keyboard.read(0xd010)?, b'H' | STROBE);
+        keyboard.tick(50);
+        assert_eq!(keyboard.read(0xd010)?, b'I' | STROBE);
+        assert_eq!(keyboard.pending_keys(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_matrix_script() -> Result<(), MemoryError> {
+        let mut keyboard = Keyboard::new(KeyboardMode::Matrix, 0x6000);
+        keyboard.set_hold_cycles(10);
+        keyboard.write(0x6002, CONTROL_IRQ_ENABLE)?;
+        keyboard.write(0x6000, 0x00)?;
+        keyboard.queue_key(5, b' ');
+
+        keyboard.tick(5);
+        assert!(keyboard.irq());
+        assert_eq!(keyboard.read(0x6001)?, !0x10);
+
+        keyboard.tick(10);
+        assert_eq!(keyboard.read(0x6001)?, 0xff);
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_via() -> Result<(), MemoryError> {
+        let mut keyboard = Keyboard::new(KeyboardMode::Matrix, 0);
+        keyboard.set_hold_cycles(10);
+        keyboard.queue_key(5, b'k');
+        let mut via = VIA::new(0x6000);
+        via.attach(Box::new(keyboard));
+
+        // Port A selects the columns and port B reads the rows
+        via.write(0x6003, 0xff)?;
+        via.write(0x6001, !0x02)?;
+        assert_eq!(via.next_event(), Some(5));
+        via.tick(5);
+        assert_eq!(via.read(0x6000)?, !0x04);
+        via.write(0x6001, !0x01)?;
+        assert_eq!(via.read(0x6000)?, 0xff);
+
+        // Rows read as outputs where port B drives them
+        via.write(0x6001, 0x00)?;
+        via.write(0x6002, 0x0f)?;
+        via.write(0x6000, 0x0f)?;
+        assert_eq!(via.read(0x6000)?, 0xff);
+
+        // Keys typed on the host reach the keyboard through the VIA, so they can be recorded
+        let (sender, receiver) = channel();
+        let mut keyboard = Keyboard::new(KeyboardMode::Matrix, 0);
+        keyboard.attach_receiver(receiver);
+        let mut copy = VIA::new(0x6000);
+        copy.attach(Box::new(keyboard));
+        sender.send(b'A').unwrap();
+        let input = copy.take_input();
+        assert_eq!(input, [0, 1, b'A']);
+        via.tick(10);
+        via.input(&input);
+        via.write(0x6002, 0x00)?;
+        via.tick(1);
+        assert_eq!(via.read(0x6000)?, !0x01);
+        Ok(())
+    }
+}
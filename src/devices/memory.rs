@@ -1,4 +1,4 @@
-/**
+/*!
  * Device: Memory
  * 
  * This device is meant to emulate the memory of the computer. It provides two types of memory:
@@ -11,14 +11,16 @@
 
 use std::ops::{Index, IndexMut};
 
-#[derive(Debug)]
+use crate::devices::save_state::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemoryType {
     RAM,
     ROM,
     MMIO
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemoryError {
     OutOfBounds,
     Overlap,
@@ -35,6 +37,58 @@ pub trait Memory: std::fmt::Debug {
     fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult;
     fn load(&mut self, data: Vec<u8>) -> MemoryWriteResult;
     fn type_of(&self) -> MemoryType;
+
+    // Read a value without any of the side effects a read may have on a device, for debuggers and disassemblers.
+    // Most devices have none, so by default this is an ordinary read.
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        self.read(address)
+    }
+
+    // Write a value past any write protection, for patching ROM while debugging. By default this is an ordinary
+    // write.
+    fn poke(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        self.write(address, value)
+    }
+
+    // Advance the device by a number of CPU cycles. Plain memory has no notion of time, so by default this does nothing.
+    fn tick(&mut self, _cycles: u32) {}
+
+    // How many cycles from now the device next changes in a way that can be seen without accessing it, such as a
+    // timer raising an interrupt or a display drawing a frame. The Emulator only ticks a device when such an event is
+    // due or when it is accessed, so anything else a device does with time can wait until then. None, the default,
+    // means nothing happens until it is next accessed. Ticking in one go must do the same as ticking in parts.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    // Whether the device is currently asserting the IRQ line.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Write the device's contents and internal registers into a save state. Devices with nothing to keep write
+    // nothing, which is the default.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    // Restore what save_state wrote, in the same order.
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+
+    // Input from outside the machine, such as keys typed on the host, that arrived since the last call. The Emulator
+    // hands it straight back through input, which lets it record the input and replay it later. Most devices have
+    // none.
+    fn take_input(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Deliver input that was taken with take_input, either just now or from a recording
+    fn input(&mut self, _data: &[u8]) {}
+
+    // Replace any source of nondeterminism, such as the host clock, with one driven by the CPU cycle count at
+    // `clock_rate`, starting at `time` (seconds since the Unix epoch). Recorded input only replays exactly on a
+    // deterministic machine.
+    fn make_deterministic(&mut self, _time: i64, _clock_rate: u32) {}
 }
 
 #[derive(Debug)]
@@ -70,10 +124,28 @@ impl Memory for ROM {
         Ok(())
     }
 
+    fn poke(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address >= self.offset && address < self.offset + self.size {
+            self.data[(address - self.offset) as usize] = value;
+            Ok(())
+        } else {
+            Err(MemoryError::OutOfBounds)
+        }
+    }
+
     fn type_of(&self) -> MemoryType {
         MemoryType::ROM
     }
 
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.data)
+    }
+
     fn load(&mut self, data: Vec<u8>) -> MemoryWriteResult {
         if data.len() as u32 > self.size {
             //panic!("ROM: Data size does not match ROM size: {:#06x} != {:#06x}", data.len(), self.size);
@@ -81,9 +153,7 @@ impl Memory for ROM {
         }
         self.data.clear();
         self.data.resize(self.size as usize, 0);
-        for i in 0..data.len() {
-            self.data[i] = data[i];
-        }
+        self.data[..data.len()].copy_from_slice(&data);
 
         Ok(())
     }
@@ -139,6 +209,14 @@ impl Memory for RAM {
         MemoryType::RAM
     }
 
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.data)
+    }
+
     fn load(&mut self, data: Vec<u8>) -> MemoryWriteResult {
         if data.len() as u32 > self.size {
             //panic!("ROM: Data size does not match ROM size: {:#06x} != {:#06x}", data.len(), self.size);
@@ -146,9 +224,7 @@ impl Memory for RAM {
         }
         self.data.clear();
         self.data.resize(self.size as usize, 0);
-        for i in 0..data.len() {
-            self.data[i] = data[i];
-        }
+        self.data[..data.len()].copy_from_slice(&data);
 
         Ok(())
     }
@@ -257,6 +333,7 @@ mod tests {
 
     #[test]
     #[should_panic]
+    #[allow(clippy::no_effect)]
     fn rom_index_out_of_bounds() {
         let rom = ROM::new(vec![0x12, 0x34, 0x56, 0x78], 4, 0x1000);
         rom[0x1004];
@@ -273,6 +350,7 @@ mod tests {
 
     #[test]
     #[should_panic]
+    #[allow(clippy::no_effect)]
     fn ram_index_out_of_bounds() {
         let ram = RAM::new(vec![0x12, 0x34, 0x56, 0x78], 4, 0x1000);
         ram[0x1004];
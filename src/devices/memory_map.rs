@@ -1,12 +1,25 @@
-/**
+/*!
  * Memory Map for the 6502 Emulator
  * 
  * The MemoryMap struct is a wrapper around a collection of devices that implement the Memory trait. It provides a
  * unified interface to the CPU for reading and writing to memory. In addition, it provides some static utilities for
  * creating new instances of Memory devices and inserting them into the map. 
+ *
+ * Each device's interrupt output is wired to the IRQ line by default, and can be wired to NMI instead or left
+ * unconnected. A mirror makes a range of addresses show another range, repeating it if the mirror is larger, the way
+ * incomplete address decoding does on real boards.
+ *
+ * Devices are not ticked after every instruction. The map keeps the time, the CPU cycle count, and each device keeps
+ * the cycle it was last ticked up to. A device is caught up to the time just before it is accessed, or when the
+ * Emulator's scheduler says one of its events is due; otherwise it is left alone. Devices accessed or changed are
+ * marked as touched, so that the Emulator can ask them again when their next events are. Plain RAM and ROM have no
+ * notion of time, so they are never caught up or touched.
  */
 
+use std::cell::{Cell, RefCell, RefMut};
+
 use crate::devices::memory::*;
+use crate::devices::save_state::*;
 
 #[derive(Debug)]
 pub enum MemoryMapError {
@@ -16,32 +29,81 @@ pub enum MemoryMapError {
 
 pub type MemoryMapInsertResult = Result<(), MemoryMapError>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write
+}
+
+// A read or write made through the map, as recorded for watchpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub address: u16,
+    pub kind: AccessKind,
+    pub value: u8
+}
+
+// Which CPU input a device's interrupt output is wired to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InterruptLine {
+    #[default]
+    Irq,
+    Nmi,
+    None
+}
+
+// A range of addresses that shows `length` bytes from `target` onwards, over and over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mirror {
+    offset: u32,
+    size: u32,
+    target: u32,
+    length: u32
+}
+
 // MemoryMapEntry is a simple struct that holds a Memory device and the range of addresses that it occupies. It is private
 // to the module.
 #[derive(Debug)]
 struct MemoryMapEntry {
     name: String,
-    device: Box<dyn Memory>,
+    // Reads take &self, but catch the device up to the time first, hence the RefCell
+    device: RefCell<Box<dyn Memory>>,
     size: u32,
-    offset: u32
+    offset: u32,
+    line: InterruptLine,
+    // Whether the device keeps time, the cycle it has been ticked up to, and whether it was touched since the
+    // Emulator last asked
+    timed: bool,
+    synced: Cell<u64>,
+    touched: Cell<bool>
 }
 
 impl MemoryMapEntry {
-    fn new(name: String, device: Box<dyn Memory>, size: u32, offset: u32) -> MemoryMapEntry {
+    fn new(name: String, device: Box<dyn Memory>, size: u32, offset: u32, time: u64) -> MemoryMapEntry {
+        let timed = device.type_of() == MemoryType::MMIO;
         MemoryMapEntry {
             name,
-            device,
+            device: RefCell::new(device),
             size,
-            offset
+            offset,
+            line: InterruptLine::Irq,
+            timed,
+            synced: Cell::new(time),
+            touched: Cell::new(timed)
         }
     }
 
+    fn contains(&self, address: u16) -> bool {
+        let address = address as u32;
+        address >= self.offset && address < self.offset + self.size
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
     pub fn device_type(&self) -> String {
-        match self.device.type_of() {
+        match self.device.borrow().type_of() {
             MemoryType::RAM => String::from("RAM"),
             MemoryType::ROM => String::from("ROM"),
             MemoryType::MMIO => String::from("MMIO")
@@ -59,13 +121,28 @@ impl MemoryMapEntry {
 // methods for reading and writing to the devices in the map.
 #[derive(Debug)]
 pub struct MemoryMap {
-    devices: Vec<MemoryMapEntry>
+    devices: Vec<MemoryMapEntry>,
+    mirrors: Vec<Mirror>,
+    // In debug mode poke writes past write protection, so ROM can be patched
+    debug: bool,
+    // Reads and writes are only recorded while something is watching them. Reads take &self, hence the RefCell.
+    recording: bool,
+    accesses: RefCell<Vec<Access>>,
+    time: u64,
+    // The devices touched since the Emulator last asked, by index
+    touched: RefCell<Vec<usize>>
 }
 
 impl MemoryMap {
     pub fn new() -> MemoryMap {
         MemoryMap {
-            devices: Vec::new()
+            devices: Vec::new(),
+            mirrors: Vec::new(),
+            debug: false,
+            recording: false,
+            accesses: RefCell::new(Vec::new()),
+            time: 0,
+            touched: RefCell::new(Vec::new())
         }
     }
 
@@ -74,42 +151,145 @@ impl MemoryMap {
     }
 
     pub fn read(&self, address: u16) -> MemoryReadResult {
-        for entry in &self.devices {
-            let address = address as u32;
-            if address >= entry.offset && address < entry.offset + entry.size {
-                return entry.device.read(address as u16);
-            }
-        }
+        let address = self.resolve(address);
+        let index = self.find(address).ok_or(MemoryError::Unmapped)?;
+        let value = self.device(index, true).read(address)?;
+        self.record(address, AccessKind::Read, value);
+        Ok(value)
+    }
 
-        Err(MemoryError::Unmapped)
+    // Read without side effects, such as acknowledging a key or advancing a data port
+    pub fn peek(&self, address: u16) -> MemoryReadResult {
+        let address = self.resolve(address);
+        let index = self.find(address).ok_or(MemoryError::Unmapped)?;
+        self.device(index, false).peek(address)
     }
 
     pub fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
-        for entry in &mut self.devices {
-            let address = address as u32;
-            if address >= entry.offset && address < entry.offset + entry.size {
-                return entry.device.write(address as u16, value);
-            }
+        let address = self.resolve(address);
+        let index = self.find(address).ok_or(MemoryError::Unmapped)?;
+        self.device(index, true).write(address, value)?;
+        self.record(address, AccessKind::Write, value);
+        Ok(())
+    }
+
+    // The index of the device mapped at an address
+    fn find(&self, address: u16) -> Option<usize> {
+        self.devices.iter().position(|entry| entry.contains(address))
+    }
+
+    // The index of the device with a name
+    fn named(&self, name: &str) -> Option<usize> {
+        self.devices.iter().position(|entry| entry.name() == name)
+    }
+
+    // The device at an index, caught up to the time, and marked as touched if it is about to be accessed or changed
+    fn device(&self, index: usize, touch: bool) -> RefMut<'_, Box<dyn Memory>> {
+        let entry = &self.devices[index];
+        let mut device = entry.device.borrow_mut();
+        if !entry.timed {
+            return device;
+        }
+        let mut behind = self.time.saturating_sub(entry.synced.get());
+        while behind > 0 {
+            let cycles = behind.min(u32::MAX as u64);
+            device.tick(cycles as u32);
+            behind -= cycles;
         }
+        entry.synced.set(entry.synced.get().max(self.time));
+        if touch && !entry.touched.replace(true) {
+            self.touched.borrow_mut().push(index);
+        }
+        device
+    }
 
-        Err(MemoryError::Unmapped)
+    // The address a mirrored address stands for. Accesses are recorded at that address, so a watchpoint on memory
+    // also sees the accesses made through its mirrors.
+    fn resolve(&self, address: u16) -> u16 {
+        let offset = address as u32;
+        match self.mirrors.iter().find(|mirror| offset >= mirror.offset && offset < mirror.offset + mirror.size) {
+            Some(mirror) => (mirror.target + (offset - mirror.offset) % mirror.length) as u16,
+            None => address
+        }
     }
 
-    fn insert(&mut self, name: String, device: Box<dyn Memory>, size: u32, offset: u32) -> MemoryMapInsertResult {
-        // Verify that the device does not overlap with any existing devices
-        for entry in &self.devices {
-            if offset >= entry.offset && offset < entry.offset + entry.size {
-                //panic!("MemoryMap: Device overlaps with existing device: {:#06x} {}", offset, entry.name());
-                return Err(MemoryMapError::Overlap);
-            }
+    // Whether a range overlaps a device or a mirror already in the map
+    fn overlaps(&self, offset: u32, size: u32) -> bool {
+        let ranges = self.devices.iter().map(|entry| (entry.offset, entry.size));
+        let mut ranges = ranges.chain(self.mirrors.iter().map(|mirror| (mirror.offset, mirror.size)));
+        ranges.any(|(start, length)| offset < start + length && start < offset + size)
+    }
 
-            if offset + size > entry.offset && offset + size <= entry.offset + entry.size {
-                //panic!("MemoryMap: Device overlaps with existing device: {:#06x} {}", offset, entry.name());
-                return Err(MemoryMapError::Overlap);
-            }
+    fn record(&self, address: u16, kind: AccessKind, value: u8) {
+        if self.recording {
+            self.accesses.borrow_mut().push(Access { address, kind, value });
         }
+    }
 
-        self.devices.push(MemoryMapEntry::new(name, device, size, offset));
+    // Start or stop recording the reads and writes made through the map. Debugger accesses (peek and poke) are never
+    // recorded.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        if !recording {
+            self.accesses.borrow_mut().clear();
+        }
+    }
+
+    // The accesses recorded since the last call
+    pub fn take_accesses(&self) -> Vec<Access> {
+        self.accesses.take()
+    }
+
+    // Write on behalf of a debugger. In debug mode this patches ROM, otherwise it is an ordinary write.
+    pub fn poke(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = self.resolve(address);
+        let index = self.find(address).ok_or(MemoryError::Unmapped)?;
+        let mut device = self.device(index, true);
+        if self.debug { device.poke(address, value) } else { device.write(address, value) }
+    }
+
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    pub fn insert(&mut self, name: String, device: Box<dyn Memory>, size: u32, offset: u32) -> MemoryMapInsertResult {
+        // Verify that the device fits in the address space and does not overlap with any existing devices or mirrors
+        if size == 0 || size > 0x10000 || offset > 0x10000 - size {
+            return Err(MemoryMapError::OutOfBounds);
+        }
+        if self.overlaps(offset, size) {
+            return Err(MemoryMapError::Overlap);
+        }
+
+        let entry = MemoryMapEntry::new(name, device, size, offset, self.time);
+        if entry.timed {
+            self.touched.borrow_mut().push(self.devices.len());
+        }
+        self.devices.push(entry);
+        Ok(())
+    }
+
+    // Make `size` addresses from `offset` show the `length` addresses from `target`, repeated to fill the mirror
+    pub fn mirror(&mut self, offset: u32, size: u32, target: u32, length: u32) -> MemoryMapInsertResult {
+        if size == 0 || length == 0 || offset + size > 0x10000 || target + length > 0x10000 {
+            return Err(MemoryMapError::OutOfBounds);
+        }
+        if self.overlaps(offset, size) || (target < offset + size && offset < target + length) {
+            return Err(MemoryMapError::Overlap);
+        }
+
+        self.mirrors.push(Mirror { offset, size, target, length });
+        Ok(())
+    }
+
+    // Wire the interrupt output of the named device to IRQ, NMI or nothing
+    pub fn set_interrupt_line(&mut self, name: &str, line: InterruptLine) -> MemoryWriteResult {
+        let index = self.named(name).ok_or(MemoryError::Unmapped)?;
+        self.devices[index].line = line;
         Ok(())
     }
 
@@ -119,7 +299,155 @@ impl MemoryMap {
             MemoryType::ROM => Box::new(ROM::new(vec![0; size as usize], size, offset)) as Box<dyn Memory>
         };
         
-        return self.insert(name, memory, size, offset);
+        self.insert(name, memory, size, offset)
+    }
+
+    // Zero every RAM device, as at power on
+    pub fn clear_ram(&mut self) {
+        for entry in &self.devices {
+            let mut device = entry.device.borrow_mut();
+            if device.type_of() == MemoryType::RAM {
+                // A device that cannot be loaded with its own size is left as it is
+                let _ = device.load(vec![0; entry.size as usize]);
+            }
+        }
+    }
+
+    // Load the contents of the named device, such as a ROM image
+    pub fn load(&mut self, name: &str, data: Vec<u8>) -> MemoryWriteResult {
+        let index = self.named(name).ok_or(MemoryError::Unmapped)?;
+        self.device(index, true).load(data)
+    }
+
+    // The cycle the devices are caught up to when they are next accessed
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    // Move the time on to a cycle. No device is ticked until it is accessed or caught up.
+    pub fn advance(&mut self, cycle: u64) {
+        self.time = self.time.max(cycle);
+    }
+
+    // Start the time again from a cycle without ticking any device, as when the CPU's cycle count jumps
+    pub fn reset_time(&mut self, cycle: u64) {
+        self.time = cycle;
+        for entry in &self.devices {
+            entry.synced.set(cycle);
+        }
+    }
+
+    // Advance the time by a number of CPU cycles, and tick every device up to it
+    pub fn tick(&mut self, cycles: u32) {
+        self.time += cycles as u64;
+        for index in 0..self.devices.len() {
+            self.catch_up(index);
+        }
+    }
+
+    // Tick the device at an index up to the time, as when its next event is due
+    pub fn catch_up(&mut self, index: usize) {
+        if index < self.devices.len() {
+            self.device(index, false);
+        }
+    }
+
+    // The cycle of the next event of the device at an index, from what Memory::next_event says
+    pub fn next_event(&self, index: usize) -> Option<u64> {
+        let entry = self.devices.get(index)?;
+        let cycles = entry.device.borrow().next_event()?;
+        Some(entry.synced.get().saturating_add(cycles.max(1)))
+    }
+
+    // Swap the indexes of the devices accessed or changed since the last call, whose next events may have moved, into
+    // `touched`. The two lists take turns, so that nothing is allocated once they have grown.
+    pub fn take_touched(&mut self, touched: &mut Vec<usize>) {
+        touched.clear();
+        std::mem::swap(self.touched.get_mut(), touched);
+        for &index in touched.iter() {
+            self.devices[index].touched.set(false);
+        }
+    }
+
+    // The input from outside the machine that each device received since the last call, by device name
+    pub fn take_input(&mut self) -> Vec<(String, Vec<u8>)> {
+        let mut input = Vec::new();
+        for entry in &mut self.devices {
+            let data = entry.device.get_mut().take_input();
+            if !data.is_empty() {
+                input.push((entry.name.clone(), data));
+            }
+        }
+        input
+    }
+
+    // Deliver input to the device with a name
+    pub fn input(&mut self, name: &str, data: &[u8]) -> MemoryWriteResult {
+        let index = self.named(name).ok_or(MemoryError::Unmapped)?;
+        self.device(index, true).input(data);
+        Ok(())
+    }
+
+    pub fn make_deterministic(&mut self, time: i64, clock_rate: u32) {
+        for index in 0..self.devices.len() {
+            self.device(index, true).make_deterministic(time, clock_rate);
+        }
+    }
+
+    // The IRQ line is shared (wired-OR), so it is asserted if any device wired to it asserts it. Devices are not
+    // caught up for this, as their interrupts only change on an access or an event.
+    pub fn irq(&self) -> bool {
+        self.devices.iter().any(|entry| entry.line == InterruptLine::Irq && entry.device.borrow().irq())
+    }
+
+    // The NMI line is shared the same way. The CPU takes an NMI on the edge, when the line goes from high to low.
+    pub fn nmi(&self) -> bool {
+        self.devices.iter().any(|entry| entry.line == InterruptLine::Nmi && entry.device.borrow().irq())
+    }
+
+    // Save the state of every device, each in a block along with where it is mapped
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.devices.len() as u32);
+        for (index, entry) in self.devices.iter().enumerate() {
+            state.write_string(&entry.name);
+            state.write_u32(entry.offset);
+            state.write_u32(entry.size);
+            let mut device = StateWriter::new();
+            self.device(index, false).save_state(&mut device);
+            state.write_bytes(&device.into_bytes());
+        }
+    }
+
+    // Restore the state of every device. The map must hold the same devices at the same addresses as when the state
+    // was saved, which is checked before any device is touched.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let count = state.read_u32()? as usize;
+        if count != self.devices.len() {
+            let message = format!("{} devices saved, but {} are mapped", count, self.devices.len());
+            return Err(StateError::Mismatch(message));
+        }
+        let mut blocks = Vec::new();
+        for entry in &self.devices {
+            let name = state.read_string()?;
+            let offset = state.read_u32()?;
+            let size = state.read_u32()?;
+            if name != entry.name || offset != entry.offset || size != entry.size {
+                return Err(StateError::Mismatch(format!(
+                    "{} at {:#06x} saved where {} is mapped at {:#06x}",
+                    name, offset, entry.name, entry.offset
+                )));
+            }
+            blocks.push(state.read_bytes()?);
+        }
+
+        for (index, block) in blocks.into_iter().enumerate() {
+            let mut device = StateReader::new(block);
+            self.device(index, true).load_state(&mut device)?;
+            if !device.is_empty() {
+                return Err(StateError::Invalid(format!("{} did not read all of its state", self.devices[index].name)));
+            }
+        }
+        Ok(())
     }
 
     // Print a formatted table of the memory map in the following format:
@@ -130,14 +458,34 @@ impl MemoryMap {
         for entry in &self.devices {
             entry.print_row();
         }
+        for mirror in &self.mirrors {
+            let name = format!("Mirror {:04x}", mirror.target);
+            let end = mirror.offset + mirror.size - 1;
+            println!("{: <12} | {: <10} | {:#06x} | {:#06x}", name, "Mirror", mirror.offset, end);
+        }
     }
 
 }
 
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Devices that produce output as they are ticked, like the SID, render everything up to the end before they are dropped
+impl Drop for MemoryMap {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.tick(0);
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::via::*;
 
     #[test]
     fn memory_map() {
@@ -221,4 +569,85 @@ mod tests {
         assert_eq!(memory_map.read(0x8000).unwrap(), 0x00);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn memory_map_recording() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.create("RAM".to_string(), MemoryType::RAM, 0x4000, 0x0000).unwrap();
+
+        // Nothing is recorded until recording is turned on
+        memory_map.write(0x0010, 0x12).unwrap();
+        assert!(memory_map.take_accesses().is_empty());
+
+        memory_map.set_recording(true);
+        memory_map.write(0x0010, 0x34).unwrap();
+        memory_map.read(0x0010).unwrap();
+        memory_map.peek(0x0010).unwrap();
+        memory_map.read(0x8000).unwrap_err();
+        assert_eq!(
+            memory_map.take_accesses(),
+            vec![
+                Access { address: 0x0010, kind: AccessKind::Write, value: 0x34 },
+                Access { address: 0x0010, kind: AccessKind::Read, value: 0x34 }
+            ]
+        );
+        assert!(memory_map.take_accesses().is_empty());
+    }
+
+    #[test]
+    fn memory_map_mirror() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.create(String::from("RAM"), MemoryType::RAM, 0x0100, 0x0000).unwrap();
+        memory_map.mirror(0x1000, 0x0400, 0x0000, 0x0100).unwrap();
+        memory_map.write(0x13ff, 0x42).unwrap();
+        assert_eq!(memory_map.read(0x00ff).unwrap(), 0x42);
+        assert_eq!(memory_map.peek(0x10ff).unwrap(), 0x42);
+
+        // Accesses through a mirror are recorded at the address it stands for
+        memory_map.set_recording(true);
+        memory_map.read(0x1201).unwrap();
+        assert_eq!(memory_map.take_accesses()[0].address, 0x0001);
+
+        assert!(matches!(memory_map.mirror(0x1200, 0x0400, 0x0000, 0x0100), Err(MemoryMapError::Overlap)));
+        assert!(matches!(memory_map.mirror(0x2000, 0x0100, 0x2080, 0x0100), Err(MemoryMapError::Overlap)));
+        assert!(matches!(memory_map.mirror(0xff00, 0x0200, 0x0000, 0x0100), Err(MemoryMapError::OutOfBounds)));
+        let enclosing = memory_map.create(String::from("ROM"), MemoryType::ROM, 0x2000, 0x0000);
+        assert!(matches!(enclosing, Err(MemoryMapError::Overlap)));
+        let past_the_end = memory_map.create(String::from("ROM"), MemoryType::ROM, 0x0200, 0xff00);
+        assert!(matches!(past_the_end, Err(MemoryMapError::OutOfBounds)));
+        assert_eq!(memory_map.set_interrupt_line("ROM", InterruptLine::None), Err(MemoryError::Unmapped));
+    }
+
+    #[test]
+    fn memory_map_time() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.create(String::from("RAM"), MemoryType::RAM, 0x0100, 0x0000).unwrap();
+        memory_map.insert(String::from("VIA"), Box::new(VIA::new(0x6000)), VIA_SIZE, 0x6000).unwrap();
+        let mut touched = Vec::new();
+        memory_map.take_touched(&mut touched);
+        assert_eq!(touched, vec![1]);
+        memory_map.write(0x6004, 0xff).unwrap();
+        memory_map.write(0x6005, 0x00).unwrap();
+        memory_map.write(0x600e, 0x80 | INTERRUPT_T1).unwrap();
+        memory_map.read(0x0010).unwrap();
+        memory_map.take_touched(&mut touched);
+        assert_eq!(touched, vec![1]);
+        assert_eq!(memory_map.next_event(1), Some(0x100));
+        assert_eq!(memory_map.next_event(0), None);
+
+        // Devices are caught up when they are accessed, or asked to be
+        memory_map.advance(0x80);
+        assert_eq!(memory_map.peek(0x6004).unwrap(), 0x7f);
+        memory_map.take_touched(&mut touched);
+        assert!(touched.is_empty());
+        memory_map.advance(0x100);
+        assert!(!memory_map.irq());
+        memory_map.catch_up(1);
+        assert!(memory_map.irq());
+
+        // Going back in time does not tick anything
+        memory_map.reset_time(0x10);
+        memory_map.advance(0x20);
+        assert_eq!(memory_map.peek(0x6004).unwrap(), 0xef);
+        assert_eq!(memory_map.time(), 0x20);
+    }
+}
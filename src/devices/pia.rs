@@ -0,0 +1,360 @@
+/*!
+ * Device: 6821 PIA
+ *
+ * The Peripheral Interface Adapter: two 8-bit ports, each with a data direction register and a control register, and
+ * the control lines CA1 and CB1 that set an interrupt flag on an edge. Registers:
+ * - offset + 0: port A data, or DDRA when CRA bit 2 is clear
+ * - offset + 1: CRA
+ * - offset + 2: port B data, or DDRB when CRB bit 2 is clear
+ * - offset + 3: CRB
+ *
+ * Control register bit 0 enables the interrupt from C1, bit 1 chooses its rising edge, and bit 7 is its flag, which is
+ * cleared by reading the port's data. Bits 6 and 7 are read only. The C2 lines are not modelled.
+ *
+ * The PIA can also be wired up as the Apple-1 terminal: port A reads the keyboard, with bit 7 always set, and each key
+ * strobes CA1; port B writes characters to the display, and PB7 reads as 0 because the display is never busy. Keys are
+ * turned into what the Apple-1 expects (upper case, with Return as CR and Delete as the underscore Wozmon uses to rub
+ * out), and held back until the key before them has been read so that none are lost.
+ */
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+use crate::devices::keyboard::*;
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const PIA_SIZE: u32 = 4;
+
+pub const CONTROL_C1_IRQ_ENABLE: u8 = 0x01;
+pub const CONTROL_C1_RISING: u8 = 0x02;
+pub const CONTROL_DATA: u8 = 0x04;
+pub const CONTROL_IRQ1: u8 = 0x80;
+const CONTROL_READ_ONLY: u8 = 0xc0;
+
+#[derive(Debug, Default)]
+struct Port {
+    output: u8,
+    ddr: u8,
+    // What the outside world drives onto the pins
+    input: u8,
+    control: Cell<u8>,
+    c1: bool
+}
+
+impl Port {
+    fn new() -> Port {
+        Port { input: 0xff, c1: true, ..Default::default() }
+    }
+
+    fn pins(&self) -> u8 {
+        (self.output & self.ddr) | (self.input & !self.ddr)
+    }
+
+    fn set_c1(&mut self, level: bool) {
+        let control = self.control.get();
+        if level != self.c1 && level == (control & CONTROL_C1_RISING != 0) {
+            self.control.set(control | CONTROL_IRQ1);
+        }
+        self.c1 = level;
+    }
+
+    fn irq(&self) -> bool {
+        let control = self.control.get();
+        control & CONTROL_IRQ1 != 0 && control & CONTROL_C1_IRQ_ENABLE != 0
+    }
+}
+
+#[derive(Debug)]
+struct Terminal {
+    keys: VecDeque<u8>,
+    host: Option<Receiver<u8>>,
+    // Characters written to the display that have not been taken yet, when they are not printed on the host
+    output: Vec<u8>,
+    echo: bool
+}
+
+#[derive(Debug)]
+pub struct PIA {
+    offset: u32,
+    ports: [Port; 2],
+    terminal: Option<Terminal>
+}
+
+impl PIA {
+    pub fn new(offset: u32) -> PIA {
+        PIA { offset, ports: [Port::new(), Port::new()], terminal: None }
+    }
+
+    // Wire the PIA up as the Apple-1 keyboard and display. With `host`, keys typed on the host terminal are read and
+    // the display is printed to it; put the terminal in raw mode first with RawTerminal.
+    pub fn attach_terminal(&mut self, host: bool) {
+        let mut terminal = Terminal { keys: VecDeque::new(), host: None, output: Vec::new(), echo: host };
+        if host {
+            terminal.host = Some(host_keys());
+        }
+        self.terminal = Some(terminal);
+        self.ports[1].input = 0x7f;
+    }
+
+    // Feed keys from another thread to the Apple-1 keyboard, as if typed on the host
+    pub fn attach_receiver(&mut self, receiver: Receiver<u8>) {
+        if let Some(terminal) = &mut self.terminal {
+            terminal.host = Some(receiver);
+        }
+    }
+
+    // Type keys on the Apple-1 keyboard
+    pub fn type_text(&mut self, text: &str) {
+        self.input(text.as_bytes());
+    }
+
+    // The characters written to the Apple-1 display since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.terminal.as_mut().map(|terminal| std::mem::take(&mut terminal.output)).unwrap_or_default()
+    }
+
+    // The levels on the port pins: the outputs where the DDR bit is set, and the inputs elsewhere
+    pub fn port_a(&self) -> u8 {
+        self.ports[0].pins()
+    }
+
+    pub fn port_b(&self) -> u8 {
+        self.ports[1].pins()
+    }
+
+    pub fn set_input_a(&mut self, value: u8) {
+        self.ports[0].input = value;
+    }
+
+    pub fn set_input_b(&mut self, value: u8) {
+        self.ports[1].input = value;
+    }
+
+    pub fn set_ca1(&mut self, level: bool) {
+        self.ports[0].set_c1(level);
+    }
+
+    pub fn set_cb1(&mut self, level: bool) {
+        self.ports[1].set_c1(level);
+    }
+
+    fn register(&self, address: u16) -> Result<(usize, bool), MemoryError> {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + PIA_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+        let register = address - self.offset;
+        Ok(((register >> 1) as usize, register & 1 != 0))
+    }
+
+    // Present the next key once the one before it has been read, with a strobe on CA1
+    fn next_key(&mut self) {
+        let Some(terminal) = &mut self.terminal else { return };
+        if self.ports[0].control.get() & CONTROL_IRQ1 != 0 {
+            return;
+        }
+        if let Some(key) = terminal.keys.pop_front() {
+            self.ports[0].input = key | 0x80;
+            self.ports[0].set_c1(false);
+            self.ports[0].set_c1(true);
+        }
+    }
+
+    fn display(&mut self, value: u8) {
+        let Some(terminal) = &mut self.terminal else { return };
+        let character = match value & 0x7f {
+            0x0d => b'\n',
+            character => character
+        };
+        if terminal.echo {
+            // The Apple-1 display has no carriage return of its own, so a raw terminal needs one
+            let mut stdout = std::io::stdout();
+            let text: &[u8] = if character == b'\n' { b"\r\n" } else { std::slice::from_ref(&character) };
+            stdout.write_all(text).and_then(|_| stdout.flush()).ok();
+        } else {
+            terminal.output.push(character);
+        }
+    }
+}
+
+impl Memory for PIA {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let (index, control) = self.register(address)?;
+        let port = &self.ports[index];
+        if !control && port.control.get() & CONTROL_DATA != 0 {
+            port.control.set(port.control.get() & !CONTROL_READ_ONLY);
+        }
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        let (index, control) = self.register(address)?;
+        let port = &self.ports[index];
+        Ok(match control {
+            true => port.control.get(),
+            false if port.control.get() & CONTROL_DATA != 0 => port.pins(),
+            false => port.ddr
+        })
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let (index, control) = self.register(address)?;
+        let port = &mut self.ports[index];
+        if control {
+            port.control.set((port.control.get() & CONTROL_READ_ONLY) | (value & !CONTROL_READ_ONLY));
+        } else if port.control.get() & CONTROL_DATA != 0 {
+            port.output = value;
+            if index == 1 {
+                self.display(value);
+            }
+        } else {
+            port.ddr = value;
+        }
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, _cycles: u32) {
+        self.next_key();
+    }
+
+    // The next key waiting is presented once the last one has been read
+    fn next_event(&self) -> Option<u64> {
+        let terminal = self.terminal.as_ref()?;
+        let read = self.ports[0].control.get() & CONTROL_IRQ1 == 0;
+        (read && !terminal.keys.is_empty()).then_some(1)
+    }
+
+    // The keys typed on the host since the last call
+    fn take_input(&mut self) -> Vec<u8> {
+        let mut keys = Vec::new();
+        if let Some(terminal) = &mut self.terminal {
+            while let Some(host) = &terminal.host {
+                match host.try_recv() {
+                    Ok(key) => keys.push(key),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => terminal.host = None
+                }
+            }
+        }
+        keys
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        if let Some(terminal) = &mut self.terminal {
+            for &key in data {
+                terminal.keys.push_back(match key {
+                    b'\n' => 0x0d,
+                    0x7f | 0x08 => b'_',
+                    key => key.to_ascii_uppercase()
+                });
+            }
+        }
+        self.next_key();
+    }
+
+    fn irq(&self) -> bool {
+        self.ports.iter().any(Port::irq)
+    }
+
+    // Both ports and any keys waiting to be read. Keys still on their way from the host are not part of the state.
+    fn save_state(&self, state: &mut StateWriter) {
+        for port in &self.ports {
+            state.write_raw(&[port.output, port.ddr, port.input, port.control.get()]);
+            state.write_bool(port.c1);
+        }
+        let keys = self.terminal.as_ref().map(|terminal| Vec::from(terminal.keys.clone())).unwrap_or_default();
+        state.write_bytes(&keys);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for port in &mut self.ports {
+            let registers = state.read_raw(4)?;
+            (port.output, port.ddr, port.input) = (registers[0], registers[1], registers[2]);
+            port.control.set(registers[3]);
+            port.c1 = state.read_bool()?;
+        }
+        let keys = state.read_bytes()?;
+        if let Some(terminal) = &mut self.terminal {
+            terminal.keys = keys.iter().copied().collect();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pia() -> Result<(), MemoryError> {
+        let mut pia = PIA::new(0xd010);
+
+        // The data direction register is selected until CR bit 2 is set
+        pia.write(0xd012, 0xf0)?;
+        pia.write(0xd013, CONTROL_DATA)?;
+        pia.set_input_b(0x05);
+        pia.write(0xd012, 0xaa)?;
+        assert_eq!(pia.read(0xd012)?, 0xa5);
+
+        // CA1 sets the flag on the chosen edge, and reading port A clears it
+        pia.write(0xd011, CONTROL_DATA | CONTROL_C1_RISING | CONTROL_C1_IRQ_ENABLE | CONTROL_IRQ1)?;
+        assert_eq!(pia.read(0xd011)?, CONTROL_DATA | CONTROL_C1_RISING | CONTROL_C1_IRQ_ENABLE);
+        pia.set_ca1(false);
+        assert!(!pia.irq());
+        pia.set_ca1(true);
+        assert!(pia.irq());
+        assert_eq!(pia.peek(0xd011)? & CONTROL_IRQ1, CONTROL_IRQ1);
+        pia.read(0xd010)?;
+        assert!(!pia.irq());
+        assert!(pia.read(0xd014).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pia_terminal() -> Result<(), MemoryError> {
+        // Set up the way Wozmon does it
+        let mut pia = PIA::new(0xd010);
+        pia.attach_terminal(false);
+        pia.write(0xd012, 0x7f)?;
+        pia.write(0xd011, 0xa7)?;
+        pia.write(0xd013, 0xa7)?;
+
+        pia.type_text("a\n");
+        assert_eq!(pia.read(0xd011)? & CONTROL_IRQ1, CONTROL_IRQ1);
+        assert_eq!(pia.read(0xd010)?, b'A' | 0x80);
+        assert_eq!(pia.read(0xd011)? & CONTROL_IRQ1, 0);
+        pia.tick(1);
+        assert_eq!(pia.read(0xd010)?, 0x8d);
+        pia.tick(1);
+        assert_eq!(pia.read(0xd011)? & CONTROL_IRQ1, 0);
+
+        // The display is never busy, and takes characters with or without bit 7
+        assert_eq!(pia.read(0xd012)? & 0x80, 0);
+        for &character in b"HI\x8d" {
+            pia.write(0xd012, character)?;
+        }
+        assert_eq!(pia.take_output(), b"HI\n");
+
+        let mut state = StateWriter::new();
+        pia.type_text("XY");
+        pia.save_state(&mut state);
+        let mut copy = PIA::new(0xd010);
+        copy.attach_terminal(false);
+        copy.load_state(&mut StateReader::new(&state.into_bytes())).unwrap();
+        assert_eq!(copy.read(0xd010)?, b'X' | 0x80);
+        copy.tick(1);
+        assert_eq!(copy.read(0xd010)?, b'Y' | 0x80);
+        Ok(())
+    }
+}
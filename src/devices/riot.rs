@@ -0,0 +1,282 @@
+/*!
+ * Device: 6532 RIOT
+ *
+ * The RAM-I/O-Timer's I/O and timer half: two 8-bit ports with data direction registers, an 8-bit interval timer with a
+ * prescaler, and an interrupt on an edge of PA7. Its 128 bytes of RAM have a chip select of their own, so they are
+ * mapped as an ordinary RAM region. The registers take 32 addresses, decoded from A0-A4:
+ * - A2 = 0: port A data, DDRA, port B data, DDRB, chosen by A1 and A0
+ * - A2 = 1, A4 = 1, writing: start the timer counting down from the value every 1, 8, 64 or 1024 cycles, chosen by A1
+ *   and A0, with A3 enabling its interrupt
+ * - A2 = 1, A4 = 0, writing: A0 chooses the rising edge of PA7, and A1 enables its interrupt
+ * - A2 = 1, A0 = 0, reading: the timer, with A3 enabling its interrupt. Reading it clears the timer's flag.
+ * - A2 = 1, A0 = 1, reading: the flags, bit 7 for the timer and bit 6 for PA7. Reading them clears the PA7 flag.
+ *
+ * Once the timer passes zero it sets its flag and goes on counting down every cycle, until it is written again. The
+ * 6530 RRIOTs in the KIM-1 have the same ports and timer at the same offsets, and are modelled with this too.
+ */
+
+use std::cell::Cell;
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const RIOT_SIZE: u32 = 32;
+
+pub const FLAG_TIMER: u8 = 0x80;
+pub const FLAG_PA7: u8 = 0x40;
+
+const PRESCALERS: [u16; 4] = [1, 8, 64, 1024];
+
+#[derive(Debug)]
+pub struct RIOT {
+    offset: u32,
+    output_a: u8,
+    output_b: u8,
+    ddr_a: u8,
+    ddr_b: u8,
+    // What the outside world drives onto the pins
+    input_a: u8,
+    input_b: u8,
+    timer: u8,
+    prescaler: u16,
+    // Cycles left until the timer next counts down
+    countdown: u16,
+    timer_irq: Cell<bool>,
+    edge_rising: bool,
+    edge_irq: bool,
+    flags: Cell<u8>
+}
+
+impl RIOT {
+    pub fn new(offset: u32) -> RIOT {
+        RIOT {
+            offset,
+            output_a: 0,
+            output_b: 0,
+            ddr_a: 0,
+            ddr_b: 0,
+            input_a: 0xff,
+            input_b: 0xff,
+            timer: 0xff,
+            prescaler: 1024,
+            countdown: 1024,
+            timer_irq: Cell::new(false),
+            edge_rising: false,
+            edge_irq: false,
+            flags: Cell::new(0)
+        }
+    }
+
+    // The levels on the port pins: the outputs where the DDR bit is set, and the inputs elsewhere
+    pub fn port_a(&self) -> u8 {
+        (self.output_a & self.ddr_a) | (self.input_a & !self.ddr_a)
+    }
+
+    pub fn port_b(&self) -> u8 {
+        (self.output_b & self.ddr_b) | (self.input_b & !self.ddr_b)
+    }
+
+    // Drive port A, which sets the PA7 flag on the chosen edge of bit 7
+    pub fn set_input_a(&mut self, value: u8) {
+        let before = self.port_a() & 0x80;
+        self.input_a = value;
+        let after = self.port_a() & 0x80;
+        if before != after && (after != 0) == self.edge_rising {
+            self.flags.set(self.flags.get() | FLAG_PA7);
+        }
+    }
+
+    pub fn set_input_b(&mut self, value: u8) {
+        self.input_b = value;
+    }
+
+    pub fn timer(&self) -> u8 {
+        self.timer
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags.get()
+    }
+
+    fn register(&self, address: u16) -> Result<u32, MemoryError> {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + RIOT_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Ok(address - self.offset)
+    }
+
+    // A register's value without the side effects of reading it
+    fn value(&self, register: u32) -> u8 {
+        match register {
+            _ if register & 0x04 == 0 => match register & 0x03 {
+                0 => self.port_a(),
+                1 => self.ddr_a,
+                2 => self.port_b(),
+                _ => self.ddr_b
+            },
+            _ if register & 0x01 == 0 => self.timer,
+            _ => self.flags.get()
+        }
+    }
+}
+
+impl Memory for RIOT {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let register = self.register(address)?;
+        let value = self.value(register);
+        if register & 0x04 != 0 {
+            if register & 0x01 == 0 {
+                self.timer_irq.set(register & 0x08 != 0);
+                self.flags.set(self.flags.get() & !FLAG_TIMER);
+            } else {
+                self.flags.set(self.flags.get() & !FLAG_PA7);
+            }
+        }
+        Ok(value)
+    }
+
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        Ok(self.value(self.register(address)?))
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let register = self.register(address)?;
+        if register & 0x04 == 0 {
+            match register & 0x03 {
+                0 => self.output_a = value,
+                1 => self.ddr_a = value,
+                2 => self.output_b = value,
+                _ => self.ddr_b = value
+            }
+        } else if register & 0x10 != 0 {
+            self.timer = value;
+            self.prescaler = PRESCALERS[(register & 0x03) as usize];
+            self.countdown = self.prescaler;
+            self.timer_irq.set(register & 0x08 != 0);
+            self.flags.set(self.flags.get() & !FLAG_TIMER);
+        } else {
+            self.edge_rising = register & 0x01 != 0;
+            self.edge_irq = register & 0x02 != 0;
+        }
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let step = cycles.min(self.countdown as u32);
+            cycles -= step;
+            self.countdown -= step as u16;
+            if self.countdown > 0 {
+                break;
+            }
+            let (timer, underflow) = self.timer.overflowing_sub(1);
+            self.timer = timer;
+            if underflow {
+                self.flags.set(self.flags.get() | FLAG_TIMER);
+                self.prescaler = 1;
+            }
+            self.countdown = self.prescaler;
+
+            // Count down whole periods at once, as far as zero
+            let periods = (cycles / self.prescaler as u32).min(self.timer as u32);
+            self.timer -= periods as u8;
+            cycles -= periods * self.prescaler as u32;
+        }
+    }
+
+    // When the timer passes zero, if that interrupts and has not already
+    fn next_event(&self) -> Option<u64> {
+        if !self.timer_irq.get() || self.flags.get() & FLAG_TIMER != 0 {
+            return None;
+        }
+        Some(self.countdown as u64 + self.timer as u64 * self.prescaler as u64)
+    }
+
+    fn irq(&self) -> bool {
+        let flags = self.flags.get();
+        (flags & FLAG_TIMER != 0 && self.timer_irq.get()) || (flags & FLAG_PA7 != 0 && self.edge_irq)
+    }
+
+    // The ports, the timer with its prescaler, and the interrupt settings and flags
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_raw(&[self.output_a, self.output_b, self.ddr_a, self.ddr_b, self.input_a, self.input_b]);
+        state.write_u8(self.timer);
+        state.write_u16(self.prescaler);
+        state.write_u16(self.countdown);
+        state.write_bool(self.timer_irq.get());
+        state.write_bool(self.edge_rising);
+        state.write_bool(self.edge_irq);
+        state.write_u8(self.flags.get());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ports = state.read_raw(6)?;
+        (self.output_a, self.output_b, self.ddr_a, self.ddr_b) = (ports[0], ports[1], ports[2], ports[3]);
+        (self.input_a, self.input_b) = (ports[4], ports[5]);
+        self.timer = state.read_u8()?;
+        let prescaler = state.read_u16()?;
+        let countdown = state.read_u16()?;
+        if !PRESCALERS.contains(&prescaler) || countdown == 0 || countdown > prescaler {
+            return Err(StateError::Invalid(format!("Timer prescaler {} with {} cycles left", prescaler, countdown)));
+        }
+        (self.prescaler, self.countdown) = (prescaler, countdown);
+        self.timer_irq.set(state.read_bool()?);
+        self.edge_rising = state.read_bool()?;
+        self.edge_irq = state.read_bool()?;
+        self.flags.set(state.read_u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn riot() -> Result<(), MemoryError> {
+        let mut riot = RIOT::new(0x1740);
+        riot.write(0x1741, 0x0f)?;
+        riot.write(0x1740, 0x55)?;
+        riot.set_input_a(0x30);
+        assert_eq!(riot.read(0x1740)?, 0x35);
+
+        // A falling edge on PA7 by default, with its interrupt enabled
+        riot.write(0x1746, 0)?;
+        assert_eq!(riot.read(0x1747)?, FLAG_PA7);
+        riot.set_input_a(0xb0);
+        assert_eq!(riot.flags(), 0);
+        riot.set_input_a(0x30);
+        assert!(riot.irq());
+        assert_eq!(riot.read(0x1747)?, FLAG_PA7);
+        assert!(!riot.irq());
+
+        // Counting down from 2 every 8 cycles, then every cycle once it passes zero
+        riot.write(0x175d, 2)?;
+        assert_eq!(riot.next_event(), Some(24));
+        riot.tick(23);
+        assert_eq!((riot.timer(), riot.irq()), (0, false));
+        riot.tick(1);
+        assert_eq!((riot.peek(0x1744)?, riot.irq()), (0xff, true));
+        riot.tick(5);
+        assert_eq!(riot.read(0x174c)?, 0xfa);
+        assert!(!riot.irq());
+
+        let mut state = StateWriter::new();
+        riot.save_state(&mut state);
+        let mut copy = RIOT::new(0x1740);
+        copy.load_state(&mut StateReader::new(&state.into_bytes())).unwrap();
+        assert_eq!((copy.port_a(), copy.timer()), (0x35, 0xfa));
+        assert!(riot.read(0x1760).is_err());
+        Ok(())
+    }
+}
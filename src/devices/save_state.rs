@@ -0,0 +1,232 @@
+/*!
+ * Save States
+ *
+ * The building blocks for saving and restoring the state of the machine. Each part of the machine writes its own
+ * state with a StateWriter and reads it back with a StateReader, field by field and in the same order. Numbers are
+ * little endian, and byte strings are prefixed with their length as a u32.
+ *
+ * A device's state is kept in a block of its own, so the memory map can check that every device read back exactly
+ * what it wrote.
+ */
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(std::io::Error),
+    // The data is not a save state at all
+    BadMagic,
+    // The save state was written by a version of the format we cannot read
+    Version(u16),
+    // The data ended before everything was read
+    Truncated,
+    // The save state is for a different machine, such as one with other devices
+    Mismatch(String),
+    // A value that cannot be restored
+    Invalid(String)
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(error) => write!(f, "{}", error),
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::Version(version) => write!(f, "Unsupported save state version {}", version),
+            StateError::Truncated => write!(f, "The save state is truncated"),
+            StateError::Mismatch(message) => write!(f, "The save state is for another machine: {}", message),
+            StateError::Invalid(message) => write!(f, "Invalid save state: {}", message)
+        }
+    }
+}
+
+impl From<std::io::Error> for StateError {
+    fn from(error: std::io::Error) -> Self {
+        StateError::Io(error)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Bytes without a length, for headers and other fields of a fixed size
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_bytes(value.as_bytes());
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    // Whether everything has been read
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    pub fn read_raw(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < length {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.read_raw(N)?.try_into().unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_raw(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(StateError::Invalid(format!("{} is not a boolean", value)))
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, StateError> {
+        Ok(i64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.read_raw(length)
+    }
+
+    // Read bytes into a buffer of a fixed size, such as a device's memory, which must match the length saved
+    pub fn read_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            let message = format!("{} bytes saved for {} bytes of memory", bytes.len(), buffer.len());
+            return Err(StateError::Mismatch(message));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn read_string(&mut self) -> Result<String, StateError> {
+        String::from_utf8(self.read_bytes()?.to_vec()).map_err(|_| StateError::Invalid(String::from("Bad string")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_state_values() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789a_bcde);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_i64(-5);
+        writer.write_f32(0.5);
+        writer.write_f64(-1.25);
+        writer.write_string("RAM");
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+        assert_eq!(&data[..5], &[0x12, 0x01, 0x56, 0x34, 0xde]);
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789a_bcde);
+        assert_eq!(reader.read_u64().unwrap(), u64::MAX - 1);
+        assert_eq!(reader.read_i64().unwrap(), -5);
+        assert_eq!(reader.read_f32().unwrap(), 0.5);
+        assert_eq!(reader.read_f64().unwrap(), -1.25);
+        assert_eq!(reader.read_string().unwrap(), "RAM");
+        let mut buffer = [0; 2];
+        assert!(matches!(reader.read_into(&mut buffer), Err(StateError::Mismatch(_))));
+        assert!(reader.is_empty());
+        assert!(matches!(reader.read_u8(), Err(StateError::Truncated)));
+        assert!(matches!(StateReader::new(&[2]).read_bool(), Err(StateError::Invalid(_))));
+        assert!(matches!(StateReader::new(&[9, 0, 0, 0, 1]).read_bytes(), Err(StateError::Truncated)));
+    }
+}
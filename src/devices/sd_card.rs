@@ -0,0 +1,489 @@
+/*!
+ * Device: SD Card (SPI mode)
+ *
+ * An SDHC card on the SPI bus, backed by a DiskImage. It implements what a small FAT loader needs:
+ * - CMD0 (GO_IDLE_STATE), CMD8 (SEND_IF_COND), CMD55 + ACMD41 (SD_SEND_OP_COND) and CMD58 (READ_OCR) to initialise
+ * - CMD9 (SEND_CSD) and CMD16 (SET_BLOCKLEN, 512 only)
+ * - CMD17 (READ_SINGLE_BLOCK) and CMD24 (WRITE_BLOCK), addressed in 512 byte blocks
+ * - CMD59 (CRC_ON_OFF)
+ *
+ * As on a real card, CMD0 and CMD8 always need a valid CRC7 and everything else is only checked once CMD59 turns CRC
+ * checking on. Checking can also be switched off entirely for firmware that never sends real CRCs.
+ */
+
+use std::collections::VecDeque;
+
+use crate::devices::disk_image::*;
+use crate::devices::save_state::*;
+use crate::devices::spi::*;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_CRC_ERROR: u8 = 0x08;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_CRC_ERROR: u8 = 0x0b;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+// ACMD41 has to be polled this many times before the card leaves the idle state
+const INIT_POLLS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Command,
+    WriteToken(u32),
+    WriteData(u32)
+}
+
+#[derive(Debug)]
+pub struct SdCard {
+    image: DiskImage,
+    state: State,
+    command: Vec<u8>,
+    data: Vec<u8>,
+    output: VecDeque<u8>,
+    spi_mode: bool,
+    idle: bool,
+    app_command: bool,
+    init_polls: u32,
+    crc_enabled: bool,
+    crc_checking: bool
+}
+
+impl SdCard {
+    pub fn new(image: DiskImage) -> SdCard {
+        SdCard {
+            image,
+            state: State::Command,
+            command: Vec::with_capacity(6),
+            data: Vec::with_capacity(BLOCK_SIZE + 2),
+            output: VecDeque::new(),
+            spi_mode: false,
+            idle: true,
+            app_command: false,
+            init_polls: 0,
+            crc_enabled: false,
+            crc_checking: true
+        }
+    }
+
+    // Turn all CRC checks on or off, regardless of CMD59
+    pub fn set_crc_checking(&mut self, enabled: bool) {
+        self.crc_checking = enabled;
+    }
+
+    pub fn image(&mut self) -> &mut DiskImage {
+        &mut self.image
+    }
+
+    fn r1(&self) -> u8 {
+        if self.idle { R1_IDLE } else { 0 }
+    }
+
+    fn execute(&mut self) {
+        let index = self.command[0] & 0x3f;
+        let argument = u32::from_be_bytes([self.command[1], self.command[2], self.command[3], self.command[4]]);
+        let crc = self.command[5] >> 1;
+        let app_command = std::mem::replace(&mut self.app_command, false);
+
+        // Until CMD0 puts it in SPI mode the card ignores everything else
+        if !self.spi_mode && index != 0 {
+            return;
+        }
+
+        let crc_required = index == 0 || index == 8 || self.crc_enabled;
+        if self.crc_checking && crc_required && crc7(&self.command[0..5]) != crc {
+            self.respond(&[self.r1() | R1_CRC_ERROR]);
+            return;
+        }
+
+        match (app_command, index) {
+            (_, 0) => {
+                self.spi_mode = true;
+                self.idle = true;
+                self.init_polls = 0;
+                self.respond(&[R1_IDLE]);
+            }
+            (_, 8) => {
+                // Echo back the voltage range and check pattern
+                self.respond(&[self.r1(), 0x00, 0x00, ((argument >> 8) & 0x0f) as u8, argument as u8]);
+            }
+            (_, 55) => {
+                self.app_command = true;
+                self.respond(&[self.r1()]);
+            }
+            (true, 41) => {
+                self.init_polls += 1;
+                if self.init_polls >= INIT_POLLS {
+                    self.idle = false;
+                }
+                self.respond(&[self.r1()]);
+            }
+            (_, 58) => {
+                // Power up status and card capacity status (block addressed SDHC), 3.2-3.4V
+                let status = if self.idle { 0x40 } else { 0xc0 };
+                self.respond(&[self.r1(), status, 0xff, 0x80, 0x00]);
+            }
+            (_, 59) => {
+                self.crc_enabled = argument & 1 != 0;
+                self.respond(&[self.r1()]);
+            }
+            (_, 16) => {
+                let status = if argument as usize == BLOCK_SIZE { self.r1() } else { self.r1() | R1_PARAMETER_ERROR };
+                self.respond(&[status]);
+            }
+            (_, 9) if !self.idle => {
+                let csd = self.csd();
+                self.respond(&[self.r1()]);
+                self.respond_data(&csd);
+            }
+            (_, 17) if !self.idle => {
+                let mut block = vec![0; BLOCK_SIZE];
+                match self.image.read_block(argument, &mut block) {
+                    Ok(()) => {
+                        self.respond(&[self.r1()]);
+                        self.respond_data(&block);
+                    }
+                    Err(_) => self.respond(&[self.r1() | R1_ADDRESS_ERROR])
+                }
+            }
+            (_, 24) if !self.idle => {
+                if argument >= self.image.block_count() {
+                    self.respond(&[self.r1() | R1_ADDRESS_ERROR]);
+                } else {
+                    self.respond(&[self.r1()]);
+                    self.state = State::WriteToken(argument);
+                }
+            }
+            _ => self.respond(&[self.r1() | R1_ILLEGAL_COMMAND])
+        }
+    }
+
+    // Responses follow the command after one byte of NCR delay
+    fn respond(&mut self, response: &[u8]) {
+        self.output.push_back(0xff);
+        self.output.extend(response);
+    }
+
+    fn respond_data(&mut self, data: &[u8]) {
+        self.output.push_back(0xff);
+        self.output.push_back(TOKEN_START_BLOCK);
+        self.output.extend(data);
+        self.output.extend(crc16(data).to_be_bytes());
+    }
+
+    fn write_block(&mut self, block: u32) {
+        let crc = u16::from_be_bytes([self.data[BLOCK_SIZE], self.data[BLOCK_SIZE + 1]]);
+        let status = if self.crc_checking && self.crc_enabled && crc16(&self.data[..BLOCK_SIZE]) != crc {
+            DATA_CRC_ERROR
+        } else {
+            match self.image.write_block(block, &self.data[..BLOCK_SIZE]) {
+                Ok(()) => DATA_ACCEPTED,
+                Err(_) => DATA_WRITE_ERROR
+            }
+        };
+
+        // The data response is followed by a couple of busy bytes while the block is programmed
+        self.output.extend([status, 0x00, 0x00]);
+    }
+
+    // A version 2.0 CSD register describing the capacity of the image
+    fn csd(&self) -> [u8; 16] {
+        let size = (self.image.block_count() / 1024).saturating_sub(1);
+        let mut csd = [0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00, 0x00, 0x00, 0x00, 0x7f, 0x80, 0x0a, 0x40, 0x00, 0x00];
+        csd[7] = ((size >> 16) & 0x3f) as u8;
+        csd[8] = (size >> 8) as u8;
+        csd[9] = size as u8;
+        csd[15] = (crc7(&csd[..15]) << 1) | 1;
+        csd
+    }
+}
+
+impl SpiDevice for SdCard {
+    fn deselect(&mut self) {
+        self.command.clear();
+    }
+
+    fn output(&mut self) -> u8 {
+        self.output.pop_front().unwrap_or(0xff)
+    }
+
+    fn input(&mut self, value: u8) {
+        match self.state {
+            State::Command => {
+                // Commands start with the bits 01; anything else between commands is filler
+                if self.command.is_empty() && value & 0xc0 != 0x40 {
+                    return;
+                }
+                self.command.push(value);
+                if self.command.len() == 6 {
+                    self.execute();
+                    self.command.clear();
+                }
+            }
+            State::WriteToken(block) => {
+                if value == TOKEN_START_BLOCK {
+                    self.data.clear();
+                    self.state = State::WriteData(block);
+                }
+            }
+            State::WriteData(block) => {
+                self.data.push(value);
+                if self.data.len() == BLOCK_SIZE + 2 {
+                    self.write_block(block);
+                    self.state = State::Command;
+                }
+            }
+        }
+    }
+
+    // The card's protocol state, any response still to be clocked out and the blocks written to the image
+    fn save_state(&self, state: &mut StateWriter) {
+        let (tag, block) = match self.state {
+            State::Command => (0, 0),
+            State::WriteToken(block) => (1, block),
+            State::WriteData(block) => (2, block)
+        };
+        state.write_u8(tag);
+        state.write_u32(block);
+        state.write_bytes(&self.command);
+        state.write_bytes(&self.data);
+        state.write_bytes(&self.output.iter().copied().collect::<Vec<u8>>());
+        state.write_bool(self.spi_mode);
+        state.write_bool(self.idle);
+        state.write_bool(self.app_command);
+        state.write_u32(self.init_polls);
+        state.write_bool(self.crc_enabled);
+        self.image.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let tag = state.read_u8()?;
+        let block = state.read_u32()?;
+        self.state = match tag {
+            0 => State::Command,
+            1 => State::WriteToken(block),
+            2 => State::WriteData(block),
+            _ => return Err(StateError::Invalid(format!("SD card state {}", tag)))
+        };
+        let command = state.read_bytes()?;
+        let data = state.read_bytes()?;
+        if command.len() >= 6 || data.len() >= BLOCK_SIZE + 2 {
+            return Err(StateError::Invalid(String::from("SD card buffers")));
+        }
+        self.command = command.to_vec();
+        self.data = data.to_vec();
+        self.output = state.read_bytes()?.iter().copied().collect();
+        self.spi_mode = state.read_bool()?;
+        self.idle = state.read_bool()?;
+        self.app_command = state.read_bool()?;
+        self.init_polls = state.read_u32()?;
+        self.crc_enabled = state.read_bool()?;
+        self.image.load_state(state)
+    }
+}
+
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let input = (byte >> bit) & 1;
+            let feedback = ((crc >> 6) & 1) ^ input;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+// CRC-16/XMODEM, as used for SD data blocks
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(index: u8, argument: u32) -> [u8; 6] {
+        let mut command = [0x40 | index, 0, 0, 0, 0, 0];
+        command[1..5].copy_from_slice(&argument.to_be_bytes());
+        command[5] = (crc7(&command[..5]) << 1) | 1;
+        command
+    }
+
+    // Send a command and return the response bytes that follow it, skipping the NCR filler
+    fn send(bus: &mut SpiBus, index: u8, argument: u32, length: usize) -> Vec<u8> {
+        for byte in command(index, argument) {
+            bus.transfer(byte);
+        }
+        let mut response = Vec::new();
+        let mut first = bus.transfer(0xff);
+        while first == 0xff {
+            first = bus.transfer(0xff);
+        }
+        response.push(first);
+        for _ in 1..length {
+            response.push(bus.transfer(0xff));
+        }
+        response
+    }
+
+    fn initialise(bus: &mut SpiBus) {
+        assert_eq!(send(bus, 0, 0, 1), [R1_IDLE]);
+        assert_eq!(send(bus, 8, 0x1aa, 5), [R1_IDLE, 0x00, 0x00, 0x01, 0xaa]);
+        assert_eq!(send(bus, 55, 0, 1), [R1_IDLE]);
+        assert_eq!(send(bus, 41, 0x4000_0000, 1), [R1_IDLE]);
+        assert_eq!(send(bus, 55, 0, 1), [R1_IDLE]);
+        assert_eq!(send(bus, 41, 0x4000_0000, 1), [0x00]);
+        assert_eq!(send(bus, 58, 0, 5), [0x00, 0xc0, 0xff, 0x80, 0x00]);
+    }
+
+    fn card_bus(blocks: usize) -> SpiBus {
+        let mut image = vec![0; BLOCK_SIZE * blocks];
+        image[BLOCK_SIZE..BLOCK_SIZE * 2].iter_mut().enumerate().for_each(|(index, byte)| *byte = index as u8);
+        let mut bus = SpiBus::new();
+        bus.attach(0, Box::new(SdCard::new(DiskImage::from_bytes(image))));
+        bus.select(Some(0));
+        bus
+    }
+
+    #[test]
+    fn sd_card_crc() {
+        assert_eq!(command(0, 0)[5], 0x95);
+        assert_eq!(command(8, 0x1aa)[5], 0x87);
+        assert_eq!(crc16(&[0xff; BLOCK_SIZE]), 0x7fa1);
+    }
+
+    #[test]
+    fn sd_card_init() {
+        let mut bus = card_bus(4);
+
+        // Nothing happens before CMD0
+        for byte in command(55, 0) {
+            bus.transfer(byte);
+        }
+        assert!((0..8).all(|_| bus.transfer(0xff) == 0xff));
+
+        // Reads are refused while the card is idle
+        assert_eq!(send(&mut bus, 0, 0, 1), [R1_IDLE]);
+        assert_eq!(send(&mut bus, 17, 0, 1), [R1_IDLE | R1_ILLEGAL_COMMAND]);
+
+        initialise(&mut bus);
+        assert_eq!(send(&mut bus, 16, 512, 1), [0x00]);
+        assert_eq!(send(&mut bus, 16, 1024, 1), [R1_PARAMETER_ERROR]);
+        assert_eq!(send(&mut bus, 42, 0, 1), [R1_ILLEGAL_COMMAND]);
+    }
+
+    #[test]
+    fn sd_card_command_crc_checking() {
+        let mut bus = card_bus(4);
+        let mut bad = command(0, 0);
+        bad[5] ^= 0x02;
+        for byte in bad {
+            bus.transfer(byte);
+        }
+        bus.transfer(0xff);
+        assert_eq!(bus.transfer(0xff), R1_IDLE | R1_CRC_ERROR);
+
+        let mut bus = card_bus(4);
+        initialise(&mut bus);
+        assert_eq!(send(&mut bus, 59, 1, 1), [0x00]);
+        for byte in [0x40 | 16, 0x00, 0x00, 0x02, 0x00, 0x01] {
+            bus.transfer(byte);
+        }
+        bus.transfer(0xff);
+        assert_eq!(bus.transfer(0xff), R1_CRC_ERROR);
+
+        // With checking switched off the same command goes through
+        let mut card = SdCard::new(DiskImage::from_bytes(vec![0; BLOCK_SIZE]));
+        card.set_crc_checking(false);
+        for byte in [0x40, 0x00, 0x00, 0x00, 0x00, 0x01] {
+            card.transfer(byte);
+        }
+        card.transfer(0xff);
+        assert_eq!(card.transfer(0xff), R1_IDLE);
+    }
+
+    #[test]
+    fn sd_card_read_block() {
+        let mut bus = card_bus(4);
+        initialise(&mut bus);
+
+        let response = send(&mut bus, 17, 1, 1 + 1 + 1 + BLOCK_SIZE + 2);
+        assert_eq!(response[0], 0x00);
+        let token = response.iter().skip(1).position(|&byte| byte == TOKEN_START_BLOCK).unwrap() + 2;
+        let block = &response[token..token + BLOCK_SIZE];
+        assert_eq!(block[0], 0x00);
+        assert_eq!(block[0x1ff], 0xff);
+        let crc = u16::from_be_bytes([response[token + BLOCK_SIZE], response[token + BLOCK_SIZE + 1]]);
+        assert_eq!(crc, crc16(block));
+
+        assert_eq!(send(&mut bus, 17, 4, 1), [R1_ADDRESS_ERROR]);
+    }
+
+    #[test]
+    fn sd_card_write_block() {
+        let mut bus = card_bus(4);
+        initialise(&mut bus);
+        assert_eq!(send(&mut bus, 59, 1, 1), [0x00]);
+        let mut state = StateWriter::new();
+        bus.save_state(&mut state);
+        let state = state.into_bytes();
+        assert_eq!(send(&mut bus, 24, 2, 1), [0x00]);
+
+        let data = [0xc3; BLOCK_SIZE];
+        bus.transfer(0xff);
+        bus.transfer(TOKEN_START_BLOCK);
+        for byte in data {
+            bus.transfer(byte);
+        }
+        for byte in crc16(&data).to_be_bytes() {
+            bus.transfer(byte);
+        }
+        assert_eq!(bus.transfer(0xff) & 0x1f, DATA_ACCEPTED);
+        assert_eq!(bus.transfer(0xff), 0x00);
+
+        // A bad data CRC is rejected
+        assert_eq!(send(&mut bus, 24, 3, 1), [0x00]);
+        bus.transfer(TOKEN_START_BLOCK);
+        for _ in 0..BLOCK_SIZE + 1 {
+            bus.transfer(0x00);
+        }
+        bus.transfer(0x01);
+        assert_eq!(bus.transfer(0xff) & 0x1f, DATA_CRC_ERROR);
+
+        let response = send(&mut bus, 17, 2, 3 + BLOCK_SIZE);
+        let token = response.iter().skip(1).position(|&byte| byte == TOKEN_START_BLOCK).unwrap() + 2;
+        assert_eq!(&response[token..token + BLOCK_SIZE], &data);
+
+        // Restoring a state from before the write brings back the old block
+        bus.load_state(&mut StateReader::new(&state)).unwrap();
+        let response = send(&mut bus, 17, 2, 3 + BLOCK_SIZE);
+        let token = response.iter().skip(1).position(|&byte| byte == TOKEN_START_BLOCK).unwrap() + 2;
+        assert_eq!(&response[token..token + BLOCK_SIZE], &[0; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn sd_card_csd() {
+        let mut bus = card_bus(2048);
+        initialise(&mut bus);
+        let response = send(&mut bus, 9, 0, 3 + 16);
+        let token = response.iter().skip(1).position(|&byte| byte == TOKEN_START_BLOCK).unwrap() + 2;
+        let csd = &response[token..token + 16];
+        assert_eq!(csd[0] >> 6, 1);
+        assert_eq!(u32::from_be_bytes([0, csd[7], csd[8], csd[9]]), 1);
+    }
+}
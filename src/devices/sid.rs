@@ -0,0 +1,703 @@
+/*!
+ * Device: SID 6581/8580 Sound Interface Device
+ *
+ * Three voices, each with a 24 bit phase accumulator driving triangle, sawtooth, pulse and noise waveforms, hard sync
+ * and ring modulation, and an ADSR envelope generator. The voices are mixed through a multimode (low/band/high pass)
+ * state variable filter and the master volume.
+ *
+ * The SID is clocked from the CPU cycle count through tick(). Output is box-filtered down to the requested sample rate
+ * and collected as 16 bit samples, which are streamed to a WAV file when the SID has one to write to. Otherwise only
+ * the most recent samples are kept. The analogue parts (filter cutoff curve, DC offsets) are approximations, and
+ * combined waveforms are modelled as the AND of their components.
+ *
+ * Registers ($00-$1C, in a 32 byte window):
+ * - $00-$06, $07-$0D, $0E-$14: voice 1-3 frequency lo/hi, pulse width lo/hi, control, attack/decay, sustain/release
+ * - $15-$18: filter cutoff lo/hi, resonance/routing, mode/volume
+ * - $19-$1C (read only): paddle X, paddle Y, voice 3 oscillator, voice 3 envelope
+ */
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+use crate::devices::wav::*;
+
+pub const SID_SIZE: u32 = 0x20;
+
+const CONTROL_GATE: u8 = 0x01;
+const CONTROL_SYNC: u8 = 0x02;
+const CONTROL_RING: u8 = 0x04;
+const CONTROL_TEST: u8 = 0x08;
+const CONTROL_TRIANGLE: u8 = 0x10;
+const CONTROL_SAWTOOTH: u8 = 0x20;
+const CONTROL_PULSE: u8 = 0x40;
+const CONTROL_NOISE: u8 = 0x80;
+
+const MODE_LOW_PASS: u8 = 0x10;
+const MODE_BAND_PASS: u8 = 0x20;
+const MODE_HIGH_PASS: u8 = 0x40;
+const MODE_VOICE3_OFF: u8 = 0x80;
+
+const ACCUMULATOR_MASK: u32 = 0xff_ffff;
+const NOISE_SEED: u32 = 0x7f_fff8;
+
+// The number of cycles between envelope steps for each attack, decay and release setting
+const RATE_PERIODS: [u16; 16] = [9, 32, 63, 95, 149, 220, 267, 313, 392, 977, 1954, 3126, 3907, 11720, 19532, 31251];
+
+// How many samples are buffered before they are written out, or the oldest dropped when there is nowhere to write them
+const MAX_SAMPLES: usize = 0x10000;
+
+// The largest value a single voice can contribute to the mix: a 12 bit waveform times an 8 bit envelope
+const VOICE_MAX: f32 = (0x800 * 0xff) as f32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SidModel {
+    MOS6581,
+    MOS8580
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    DecaySustain,
+    Release
+}
+
+#[derive(Debug)]
+struct Voice {
+    frequency: u16,
+    pulse_width: u16,
+    control: u8,
+    attack_decay: u8,
+    sustain_release: u8,
+    accumulator: u32,
+    noise: u32,
+    msb_rising: bool,
+    envelope_state: EnvelopeState,
+    envelope: u8,
+    rate_counter: u16,
+    exponential_counter: u8
+}
+
+impl Voice {
+    fn new() -> Voice {
+        Voice {
+            frequency: 0,
+            pulse_width: 0,
+            control: 0,
+            attack_decay: 0,
+            sustain_release: 0,
+            accumulator: 0,
+            noise: NOISE_SEED,
+            msb_rising: false,
+            envelope_state: EnvelopeState::Release,
+            envelope: 0,
+            rate_counter: 0,
+            exponential_counter: 0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.frequency);
+        state.write_u16(self.pulse_width);
+        state.write_u8(self.control);
+        state.write_u8(self.attack_decay);
+        state.write_u8(self.sustain_release);
+        state.write_u32(self.accumulator);
+        state.write_u32(self.noise);
+        state.write_bool(self.msb_rising);
+        state.write_u8(self.envelope_state as u8);
+        state.write_u8(self.envelope);
+        state.write_u16(self.rate_counter);
+        state.write_u8(self.exponential_counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.frequency = state.read_u16()?;
+        self.pulse_width = state.read_u16()?;
+        self.control = state.read_u8()?;
+        self.attack_decay = state.read_u8()?;
+        self.sustain_release = state.read_u8()?;
+        self.accumulator = state.read_u32()? & ACCUMULATOR_MASK;
+        self.noise = state.read_u32()? & 0x7f_ffff;
+        self.msb_rising = state.read_bool()?;
+        self.envelope_state = match state.read_u8()? {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::DecaySustain,
+            2 => EnvelopeState::Release,
+            value => return Err(StateError::Invalid(format!("Envelope state {}", value)))
+        };
+        self.envelope = state.read_u8()?;
+        self.rate_counter = state.read_u16()?;
+        self.exponential_counter = state.read_u8()?;
+        Ok(())
+    }
+
+    fn set_control(&mut self, value: u8) {
+        let gate = value & CONTROL_GATE != 0;
+        if gate && self.control & CONTROL_GATE == 0 {
+            self.envelope_state = EnvelopeState::Attack;
+        } else if !gate && self.control & CONTROL_GATE != 0 {
+            self.envelope_state = EnvelopeState::Release;
+        }
+        self.control = value;
+    }
+
+    fn clock_oscillator(&mut self) {
+        let previous = self.accumulator;
+        if self.control & CONTROL_TEST != 0 {
+            self.accumulator = 0;
+            self.noise = NOISE_SEED;
+        } else {
+            self.accumulator = (self.accumulator + self.frequency as u32) & ACCUMULATOR_MASK;
+        }
+        self.msb_rising = previous & 0x80_0000 == 0 && self.accumulator & 0x80_0000 != 0;
+
+        // The noise shift register is clocked by bit 19 of the accumulator
+        if previous & 0x08_0000 == 0 && self.accumulator & 0x08_0000 != 0 {
+            let feedback = ((self.noise >> 22) ^ (self.noise >> 17)) & 1;
+            self.noise = ((self.noise << 1) | feedback) & 0x7f_ffff;
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        let rate = match self.envelope_state {
+            EnvelopeState::Attack => self.attack_decay >> 4,
+            EnvelopeState::DecaySustain => self.attack_decay & 0x0f,
+            EnvelopeState::Release => self.sustain_release & 0x0f
+        };
+
+        self.rate_counter += 1;
+        if self.rate_counter < RATE_PERIODS[rate as usize] {
+            return;
+        }
+        self.rate_counter = 0;
+
+        if self.envelope_state == EnvelopeState::Attack {
+            self.exponential_counter = 0;
+            self.envelope = self.envelope.saturating_add(1);
+            if self.envelope == 0xff {
+                self.envelope_state = EnvelopeState::DecaySustain;
+            }
+            return;
+        }
+
+        // Decay and release slow down as the level falls, approximating an exponential curve
+        self.exponential_counter += 1;
+        if self.exponential_counter < exponential_period(self.envelope) {
+            return;
+        }
+        self.exponential_counter = 0;
+
+        let floor = match self.envelope_state {
+            EnvelopeState::DecaySustain => (self.sustain_release >> 4) * 0x11,
+            _ => 0
+        };
+        if self.envelope > floor {
+            self.envelope -= 1;
+        }
+    }
+
+    // The 12 bit waveform output. `source_msb` is the top bit of the previous voice's accumulator, used for ring mod.
+    fn waveform(&self, source_msb: bool) -> u16 {
+        let mut output = 0xfff;
+        let mut selected = false;
+
+        if self.control & CONTROL_TRIANGLE != 0 {
+            let mut msb = self.accumulator & 0x80_0000 != 0;
+            if self.control & CONTROL_RING != 0 {
+                msb ^= source_msb;
+            }
+            let folded = if msb { !self.accumulator } else { self.accumulator };
+            output &= ((folded >> 11) & 0xfff) as u16;
+            selected = true;
+        }
+        if self.control & CONTROL_SAWTOOTH != 0 {
+            output &= (self.accumulator >> 12) as u16;
+            selected = true;
+        }
+        if self.control & CONTROL_PULSE != 0 {
+            let high = self.control & CONTROL_TEST != 0 || (self.accumulator >> 12) as u16 >= self.pulse_width;
+            output &= if high { 0xfff } else { 0 };
+            selected = true;
+        }
+        if self.control & CONTROL_NOISE != 0 {
+            let noise = self.noise;
+            let bits = ((noise >> 15) & 0x80)
+                | ((noise >> 14) & 0x40)
+                | ((noise >> 11) & 0x20)
+                | ((noise >> 9) & 0x10)
+                | ((noise >> 8) & 0x08)
+                | ((noise >> 5) & 0x04)
+                | ((noise >> 3) & 0x02)
+                | ((noise >> 2) & 0x01);
+            output &= (bits << 4) as u16;
+            selected = true;
+        }
+
+        if selected { output } else { 0 }
+    }
+}
+
+fn exponential_period(level: u8) -> u8 {
+    match level {
+        0x5e..=0xff => 1,
+        0x37..=0x5d => 2,
+        0x1b..=0x36 => 4,
+        0x0f..=0x1a => 8,
+        0x07..=0x0e => 16,
+        0x01..=0x06 => 30,
+        0x00 => 1
+    }
+}
+
+#[derive(Debug)]
+pub struct SID {
+    model: SidModel,
+    voices: [Voice; 3],
+    cutoff: u16,
+    resonance_filter: u8,
+    mode_volume: u8,
+    low_pass: f32,
+    band_pass: f32,
+    offset: u32,
+    clock_rate: u32,
+    sample_rate: u32,
+    sample_clock: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    samples: Vec<i16>,
+    output: Option<WavWriter<BufWriter<File>>>
+}
+
+impl SID {
+    pub fn new(model: SidModel, offset: u32, clock_rate: u32, sample_rate: u32) -> SID {
+        SID {
+            model,
+            voices: [Voice::new(), Voice::new(), Voice::new()],
+            cutoff: 0,
+            resonance_filter: 0,
+            mode_volume: 0,
+            low_pass: 0.0,
+            band_pass: 0.0,
+            offset,
+            clock_rate: clock_rate.max(1),
+            sample_rate: sample_rate.max(1),
+            sample_clock: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            samples: Vec::new(),
+            output: None
+        }
+    }
+
+    pub fn model(&self) -> SidModel {
+        self.model
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    // Hand over the samples rendered so far, e.g. to stream them out in chunks during a long run
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn write_wav<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write_wav(out, self.sample_rate, &self.samples)
+    }
+
+    pub fn save_wav<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        save_wav(path, self.sample_rate, &self.samples)
+    }
+
+    // Stream the output to a WAV file from now on, which is finished when the SID is dropped
+    pub fn set_output<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.output = Some(WavWriter::create(path, self.sample_rate)?);
+        Ok(())
+    }
+
+    fn write_samples(&mut self) {
+        if let Some(output) = &mut self.output {
+            if let Err(error) = output.write_samples(&self.samples) {
+                eprintln!("SID: Unable to write samples: {}", error);
+                self.output = None;
+            }
+            self.samples.clear();
+        }
+    }
+
+    // Filter cutoff in Hz for the 11 bit cutoff register. The 6581 curve is steep and non-linear, the 8580 is linear.
+    fn cutoff_frequency(&self) -> f32 {
+        let fraction = self.cutoff as f32 / 2047.0;
+        match self.model {
+            SidModel::MOS6581 => 220.0 + 17800.0 * fraction * fraction,
+            SidModel::MOS8580 => 30.0 + 12000.0 * fraction
+        }
+    }
+
+    fn clock(&mut self) {
+        for voice in &mut self.voices {
+            voice.clock_oscillator();
+            voice.clock_envelope();
+        }
+
+        // Each voice is synced by the one before it: voice 1 by voice 3, voice 2 by voice 1, voice 3 by voice 2
+        for index in 0..3 {
+            let source = (index + 2) % 3;
+            if self.voices[index].control & CONTROL_SYNC != 0 && self.voices[source].msb_rising {
+                self.voices[index].accumulator = 0;
+            }
+        }
+
+        let mut direct = 0.0;
+        let mut filtered = 0.0;
+        for index in 0..3 {
+            let source_msb = self.voices[(index + 2) % 3].accumulator & 0x80_0000 != 0;
+            let voice = &self.voices[index];
+            let output = (voice.waveform(source_msb) as f32 - 2048.0) * voice.envelope as f32;
+
+            if self.resonance_filter & (1 << index) != 0 {
+                filtered += output;
+            } else if index != 2 || self.mode_volume & MODE_VOICE3_OFF == 0 {
+                direct += output;
+            }
+        }
+
+        // Chamberlin state variable filter, run at the chip clock
+        let w = 2.0 * (std::f32::consts::PI * self.cutoff_frequency() / self.clock_rate as f32).sin();
+        let damping = 1.0 / (0.707 + 1.7 * (self.resonance_filter >> 4) as f32 / 15.0);
+        let high_pass = filtered - self.low_pass - damping * self.band_pass;
+        self.band_pass += w * high_pass;
+        self.low_pass += w * self.band_pass;
+
+        let mut mix = direct;
+        if self.mode_volume & MODE_LOW_PASS != 0 {
+            mix += self.low_pass;
+        }
+        if self.mode_volume & MODE_BAND_PASS != 0 {
+            mix += self.band_pass;
+        }
+        if self.mode_volume & MODE_HIGH_PASS != 0 {
+            mix += high_pass;
+        }
+
+        // The 6581 mixer has a DC offset, which is why volume register writes are audible (and used to play samples)
+        if self.model == SidModel::MOS6581 {
+            mix += VOICE_MAX * 0.35;
+        }
+
+        let volume = (self.mode_volume & 0x0f) as f32 / 15.0;
+        self.sample_sum += mix * volume / (VOICE_MAX * 3.0);
+        self.sample_count += 1;
+    }
+
+    fn emit_sample(&mut self) {
+        let average = if self.sample_count > 0 { self.sample_sum / self.sample_count as f32 } else { 0.0 };
+        let sample = (average * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32);
+        self.samples.push(sample as i16);
+        self.sample_sum = 0.0;
+        self.sample_count = 0;
+
+        if self.samples.len() >= MAX_SAMPLES {
+            self.write_samples();
+            if self.samples.len() >= MAX_SAMPLES {
+                self.samples.drain(..MAX_SAMPLES / 2);
+            }
+        }
+    }
+}
+
+impl Drop for SID {
+    fn drop(&mut self) {
+        self.write_samples();
+    }
+}
+
+impl Memory for SID {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + SID_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        // The paddle inputs are not connected and the write-only registers read back as 0
+        match (address - self.offset) & 0x1f {
+            0x1b => Ok((self.voices[2].waveform(self.voices[1].accumulator & 0x80_0000 != 0) >> 4) as u8),
+            0x1c => Ok(self.voices[2].envelope),
+            _ => Ok(0)
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + SID_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        let register = ((address - self.offset) & 0x1f) as usize;
+        if register < 0x15 {
+            let voice = &mut self.voices[register / 7];
+            match register % 7 {
+                0 => voice.frequency = (voice.frequency & 0xff00) | value as u16,
+                1 => voice.frequency = (voice.frequency & 0x00ff) | ((value as u16) << 8),
+                2 => voice.pulse_width = (voice.pulse_width & 0x0f00) | value as u16,
+                3 => voice.pulse_width = (voice.pulse_width & 0x00ff) | (((value & 0x0f) as u16) << 8),
+                4 => voice.set_control(value),
+                5 => voice.attack_decay = value,
+                _ => voice.sustain_release = value
+            }
+            return Ok(());
+        }
+
+        match register {
+            0x15 => self.cutoff = (self.cutoff & 0x7f8) | (value & 0x07) as u16,
+            0x16 => self.cutoff = (self.cutoff & 0x007) | ((value as u16) << 3),
+            0x17 => self.resonance_filter = value,
+            0x18 => self.mode_volume = value,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let cycles_per_sample = self.clock_rate as f64 / self.sample_rate as f64;
+        for _ in 0..cycles {
+            self.clock();
+            self.sample_clock += 1.0;
+            if self.sample_clock >= cycles_per_sample {
+                self.sample_clock -= cycles_per_sample;
+                self.emit_sample();
+            }
+        }
+    }
+
+    // The voices and the filter. Samples already produced but not yet taken are output, not state, and are not saved.
+    fn save_state(&self, state: &mut StateWriter) {
+        for voice in &self.voices {
+            voice.save_state(state);
+        }
+        state.write_u16(self.cutoff);
+        state.write_u8(self.resonance_filter);
+        state.write_u8(self.mode_volume);
+        state.write_f32(self.low_pass);
+        state.write_f32(self.band_pass);
+        state.write_f64(self.sample_clock);
+        state.write_f32(self.sample_sum);
+        state.write_u32(self.sample_count);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for voice in &mut self.voices {
+            voice.load_state(state)?;
+        }
+        self.cutoff = state.read_u16()?;
+        self.resonance_filter = state.read_u8()?;
+        self.mode_volume = state.read_u8()?;
+        self.low_pass = state.read_f32()?;
+        self.band_pass = state.read_f32()?;
+        self.sample_clock = state.read_f64()?;
+        self.sample_sum = state.read_f32()?;
+        self.sample_count = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sid() -> SID {
+        SID::new(SidModel::MOS8580, 0xd400, 1_000_000, 50_000)
+    }
+
+    #[test]
+    fn sid_sample_rate() {
+        let mut sid = sid();
+        sid.tick(10_000);
+        assert_eq!(sid.samples().len(), 500);
+
+        // Silence when the volume is zero
+        assert!(sid.samples().iter().all(|&sample| sample == 0));
+
+        let mut out = Vec::new();
+        sid.write_wav(&mut out).unwrap();
+        assert_eq!(out.len(), 44 + 500 * 2);
+        assert_eq!(sid.take_samples().len(), 500);
+        assert!(sid.samples().is_empty());
+
+        // Without an output only the most recent samples are kept
+        sid.tick(MAX_SAMPLES as u32 * 20 + 20);
+        assert_eq!(sid.samples().len(), MAX_SAMPLES / 2 + 1);
+    }
+
+    #[test]
+    fn sid_output() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join(format!("sid_{}.wav", std::process::id()));
+        let mut sid = sid();
+        sid.set_output(&path)?;
+        sid.tick(MAX_SAMPLES as u32 * 30);
+        assert!(sid.samples().len() < MAX_SAMPLES);
+        drop(sid);
+
+        // Every sample ends up in the file, with the sizes in the header
+        let contents = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let data_size = MAX_SAMPLES as u32 * 3 / 2 * 2;
+        assert_eq!(contents.len(), 44 + data_size as usize);
+        assert_eq!(&contents[40..44], &data_size.to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn sid_oscillator() -> Result<(), MemoryError> {
+        let mut sid = sid();
+
+        // A frequency of $1000 wraps the 24 bit accumulator every 4096 cycles
+        sid.write(0xd40e, 0x00)?;
+        sid.write(0xd40f, 0x10)?;
+        sid.write(0xd412, CONTROL_SAWTOOTH)?;
+        sid.tick(2048);
+        assert_eq!(sid.read(0xd41b)?, 0x80);
+        sid.tick(2048);
+        assert_eq!(sid.read(0xd41b)?, 0x00);
+
+        // The test bit holds the oscillator at zero
+        sid.tick(1024);
+        sid.write(0xd412, CONTROL_SAWTOOTH | CONTROL_TEST)?;
+        sid.tick(1);
+        assert_eq!(sid.read(0xd41b)?, 0x00);
+        Ok(())
+    }
+
+    #[test]
+    fn sid_pulse_and_triangle() -> Result<(), MemoryError> {
+        let mut sid = sid();
+        sid.write(0xd40f, 0x10)?;
+        sid.write(0xd410, 0x00)?;
+        sid.write(0xd411, 0x08)?;
+        sid.write(0xd412, CONTROL_PULSE)?;
+        sid.tick(1024);
+        assert_eq!(sid.read(0xd41b)?, 0x00);
+        sid.tick(2048);
+        assert_eq!(sid.read(0xd41b)?, 0xff);
+
+        // The triangle peaks halfway through the cycle
+        sid.write(0xd412, CONTROL_TRIANGLE)?;
+        sid.tick(4096 - 3072 + 2048);
+        assert_eq!(sid.read(0xd41b)?, 0xff);
+        Ok(())
+    }
+
+    #[test]
+    fn sid_noise() -> Result<(), MemoryError> {
+        let mut sid = sid();
+        sid.write(0xd40f, 0x40)?;
+        sid.write(0xd412, CONTROL_NOISE)?;
+
+        let mut values = Vec::new();
+        for _ in 0..16 {
+            sid.tick(256);
+            values.push(sid.read(0xd41b)?);
+        }
+        values.sort();
+        values.dedup();
+        assert!(values.len() > 4);
+        Ok(())
+    }
+
+    #[test]
+    fn sid_sync_and_ring() {
+        let mut voice = Voice::new();
+        voice.accumulator = 0x40_0000;
+        voice.control = CONTROL_TRIANGLE;
+        assert_eq!(voice.waveform(false), 0x800);
+
+        // Ring modulation inverts the triangle whenever the source voice's top bit is set
+        voice.control |= CONTROL_RING;
+        assert_eq!(voice.waveform(true), 0x7ff);
+
+        let mut sid = sid();
+        sid.voices[0].frequency = 0x0100;
+        sid.voices[0].accumulator = 0x12_3456;
+        sid.voices[0].control = CONTROL_SYNC;
+        sid.voices[2].accumulator = 0x7f_ffff;
+        sid.voices[2].frequency = 0x0001;
+        sid.clock();
+        assert_eq!(sid.voices[0].accumulator, 0);
+    }
+
+    #[test]
+    fn sid_envelope() -> Result<(), MemoryError> {
+        let mut sid = sid();
+
+        // Fastest attack (9 cycles per step) and decay to a sustain level of $88
+        sid.write(0xd413, 0x00)?;
+        sid.write(0xd414, 0x80)?;
+        sid.write(0xd412, CONTROL_GATE)?;
+        sid.tick(9 * 0x80);
+        assert_eq!(sid.read(0xd41c)?, 0x80);
+        sid.tick(9 * 0x7f);
+        assert_eq!(sid.read(0xd41c)?, 0xff);
+        sid.tick(10_000);
+        assert_eq!(sid.read(0xd41c)?, 0x88);
+
+        // Release falls all the way to zero
+        sid.write(0xd412, 0)?;
+        sid.tick(20_000);
+        assert_eq!(sid.read(0xd41c)?, 0x00);
+        Ok(())
+    }
+
+    #[test]
+    fn sid_output_and_filter() -> Result<(), MemoryError> {
+        // A high pitched square wave through the low pass filter is much quieter than the same wave unfiltered
+        let peak = |routing: u8| -> Result<i16, MemoryError> {
+            let mut sid = sid();
+            sid.write(0xd401, 0xc0)?;
+            sid.write(0xd403, 0x08)?;
+            sid.write(0xd405, 0x00)?;
+            sid.write(0xd406, 0xf0)?;
+            sid.write(0xd404, CONTROL_PULSE | CONTROL_GATE)?;
+            sid.write(0xd415, 0x00)?;
+            sid.write(0xd416, 0x04)?;
+            sid.write(0xd417, routing)?;
+            sid.write(0xd418, MODE_LOW_PASS | 0x0f)?;
+            sid.tick(20_000);
+            Ok(sid.samples()[500..].iter().map(|sample| sample.abs()).max().unwrap())
+        };
+
+        let unfiltered = peak(0x00)?;
+        let filtered = peak(0x01)?;
+        assert!(unfiltered > 5000);
+        assert!(filtered < unfiltered / 4);
+        Ok(())
+    }
+
+    #[test]
+    fn sid_memory_map() {
+        let mut memory_map = crate::devices::memory_map::MemoryMap::new();
+        memory_map.insert(String::from("SID"), Box::new(sid()), SID_SIZE, 0xd400).unwrap();
+        memory_map.write(0xd413, 0x00).unwrap();
+        memory_map.write(0xd412, CONTROL_GATE).unwrap();
+        memory_map.tick(100);
+        assert!(memory_map.read(0xd41c).unwrap() > 0);
+        assert!(memory_map.write(0xd420, 0).is_err());
+    }
+}
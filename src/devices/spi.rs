@@ -0,0 +1,438 @@
+/*!
+ * SPI Bus
+ *
+ * The SpiBus connects a master to up to eight slave devices, each on its own chip select line. It can be driven a whole
+ * byte at a time, or bit by bit through its pins the way 6502 firmware bit-bangs SPI (mode 0: SCLK idles low, data is
+ * sampled on the rising edge and shifted out on the falling edge).
+ *
+ * Slaves implement SpiDevice. Because a slave's reply is always determined by the bytes it has already received, the
+ * byte it shifts out is fetched with output() at the start of each byte and the received byte is delivered with input()
+ * once all eight bits are in.
+ *
+ * The bus's pins are laid out as: bit 0 SCLK, bit 1 MOSI, bits 2-4 /CS0-/CS2 (active low), bit 7 MISO. Firmware
+ * usually bit-bangs them on port B of a 6522 VIA, which the bus is wired to by attaching it to the VIA as a
+ * PortDevice.
+ *
+ * Device: SPI Port
+ *
+ * A dedicated MMIO port for the bus, for boards without a VIA to spare:
+ * - offset + 0 (pins): the pins, where MISO is read only
+ * - offset + 1 (data): writing exchanges a whole byte with the selected device, reading returns the last byte received
+ */
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+use crate::devices::via;
+
+pub const SPI_PORT_SIZE: u32 = 2;
+
+pub const PIN_SCLK: u8 = 0x01;
+pub const PIN_MOSI: u8 = 0x02;
+pub const PIN_CS0: u8 = 0x04;
+pub const PIN_MISO: u8 = 0x80;
+
+const CHIP_SELECTS: usize = 8;
+const PORT_CHIP_SELECTS: usize = 3;
+
+pub trait SpiDevice: std::fmt::Debug {
+    fn select(&mut self) {}
+    fn deselect(&mut self) {}
+
+    // The byte to shift out on MISO during the next exchange
+    fn output(&mut self) -> u8;
+
+    // A complete byte received on MOSI
+    fn input(&mut self, value: u8);
+
+    fn transfer(&mut self, value: u8) -> u8 {
+        let output = self.output();
+        self.input(value);
+        output
+    }
+
+    // Save and restore the device's internal state, as for a Memory device
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct SpiBus {
+    devices: Vec<Option<Box<dyn SpiDevice>>>,
+    selected: Option<usize>,
+    sclk: bool,
+    mosi: bool,
+    shift_in: u8,
+    shift_out: u8,
+    next_out: Option<u8>,
+    bits: u8
+}
+
+impl SpiBus {
+    pub fn new() -> SpiBus {
+        SpiBus {
+            devices: (0..CHIP_SELECTS).map(|_| None).collect(),
+            selected: None,
+            sclk: false,
+            mosi: false,
+            shift_in: 0,
+            shift_out: 0xff,
+            next_out: None,
+            bits: 0
+        }
+    }
+
+    pub fn attach(&mut self, chip_select: usize, device: Box<dyn SpiDevice>) {
+        self.devices[chip_select] = Some(device);
+    }
+
+    pub fn device(&self, chip_select: usize) -> Option<&dyn SpiDevice> {
+        self.devices[chip_select].as_deref()
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    // The state of the bus, then a block for each chip select that has a device
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.selected.is_some());
+        state.write_u32(self.selected.unwrap_or(0) as u32);
+        state.write_bool(self.sclk);
+        state.write_bool(self.mosi);
+        state.write_u8(self.shift_in);
+        state.write_u8(self.shift_out);
+        state.write_bool(self.next_out.is_some());
+        state.write_u8(self.next_out.unwrap_or(0));
+        state.write_u8(self.bits);
+        for device in &self.devices {
+            state.write_bool(device.is_some());
+            if let Some(device) = device {
+                let mut block = StateWriter::new();
+                device.save_state(&mut block);
+                state.write_bytes(&block.into_bytes());
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let selected = state.read_bool()?;
+        let index = state.read_u32()? as usize;
+        if index >= CHIP_SELECTS {
+            return Err(StateError::Invalid(format!("Chip select {}", index)));
+        }
+        self.selected = if selected { Some(index) } else { None };
+        self.sclk = state.read_bool()?;
+        self.mosi = state.read_bool()?;
+        self.shift_in = state.read_u8()?;
+        self.shift_out = state.read_u8()?;
+        let next = state.read_bool()?;
+        let next_out = state.read_u8()?;
+        self.next_out = if next { Some(next_out) } else { None };
+        self.bits = state.read_u8()? % 8;
+        for (chip_select, device) in self.devices.iter_mut().enumerate() {
+            match (state.read_bool()?, device) {
+                (true, Some(device)) => device.load_state(&mut StateReader::new(state.read_bytes()?))?,
+                (false, None) => {}
+                _ => return Err(StateError::Mismatch(format!("SPI chip select {} has another device", chip_select)))
+            }
+        }
+        Ok(())
+    }
+
+    // Select a device (or none). Selecting a device starts a new byte.
+    pub fn select(&mut self, chip_select: Option<usize>) {
+        if chip_select == self.selected {
+            return;
+        }
+
+        if let Some(device) = self.selected.and_then(|index| self.devices[index].as_mut()) {
+            device.deselect();
+        }
+        self.selected = chip_select;
+        self.bits = 0;
+        self.next_out = None;
+        self.shift_out = 0xff;
+
+        if let Some(device) = chip_select.and_then(|index| self.devices[index].as_mut()) {
+            device.select();
+            self.shift_out = device.output();
+        }
+    }
+
+    // Exchange a whole byte with the selected device. MISO floats high when nothing is selected.
+    pub fn transfer(&mut self, value: u8) -> u8 {
+        match self.selected.and_then(|index| self.devices[index].as_mut()) {
+            Some(device) => {
+                // Finish the byte that was pre-loaded for bit-banging before starting a new one
+                let output = self.next_out.take().unwrap_or(self.shift_out);
+                device.input(value);
+                self.shift_out = device.output();
+                self.bits = 0;
+                output
+            }
+            None => 0xff
+        }
+    }
+
+    pub fn set_sclk(&mut self, level: bool) {
+        if level == self.sclk {
+            return;
+        }
+        self.sclk = level;
+
+        let Some(device) = self.selected.and_then(|index| self.devices[index].as_mut()) else {
+            return;
+        };
+
+        if level {
+            // Rising edge: sample MOSI, and hand over the byte once all eight bits are in
+            self.shift_in = (self.shift_in << 1) | self.mosi as u8;
+            self.bits += 1;
+            if self.bits == 8 {
+                self.bits = 0;
+                device.input(self.shift_in);
+                self.next_out = Some(device.output());
+            }
+        } else {
+            // Falling edge: present the next bit on MISO. Before the first rising edge of a byte, as when SCLK falls
+            // while a device is being selected, the first bit is already there.
+            self.shift_out = match self.next_out.take() {
+                Some(value) => value,
+                None if self.bits > 0 => self.shift_out << 1,
+                None => self.shift_out
+            };
+        }
+    }
+
+    pub fn set_mosi(&mut self, level: bool) {
+        self.mosi = level;
+    }
+
+    pub fn miso(&self) -> bool {
+        match self.selected.and_then(|index| self.devices[index].as_ref()) {
+            Some(_) => self.shift_out & 0x80 != 0,
+            None => true
+        }
+    }
+
+    // Drive all the master's pins at once. Only one device can be selected; the lowest numbered chip select wins.
+    pub fn set_pins(&mut self, value: u8) {
+        let chip_select = (0..PORT_CHIP_SELECTS).find(|index| value & (PIN_CS0 << index) == 0);
+        self.select(chip_select);
+        self.set_mosi(value & PIN_MOSI != 0);
+        self.set_sclk(value & PIN_SCLK != 0);
+    }
+}
+
+// On a VIA the bus is wired to port B
+impl via::PortDevice for SpiBus {
+    fn write_pins(&mut self, _port_a: u8, port_b: u8) {
+        self.set_pins(port_b);
+    }
+
+    fn read_pins(&self, _port_a: u8, _port_b: u8) -> (u8, u8) {
+        (0xff, if self.miso() { 0xff } else { !PIN_MISO })
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        SpiBus::save_state(self, state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        SpiBus::load_state(self, state)
+    }
+}
+
+impl Default for SpiBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct SpiPort {
+    bus: SpiBus,
+    pins: u8,
+    data: u8,
+    offset: u32
+}
+
+impl SpiPort {
+    pub fn new(offset: u32) -> SpiPort {
+        SpiPort::with_bus(SpiBus::new(), offset)
+    }
+
+    pub fn with_bus(bus: SpiBus, offset: u32) -> SpiPort {
+        SpiPort {
+            bus,
+            pins: 0x1c | PIN_MOSI,
+            data: 0xff,
+            offset
+        }
+    }
+
+    pub fn attach(&mut self, chip_select: usize, device: Box<dyn SpiDevice>) {
+        self.bus.attach(chip_select, device);
+    }
+
+    pub fn bus(&self) -> &SpiBus {
+        &self.bus
+    }
+
+    fn set_pins(&mut self, value: u8) {
+        self.pins = value & !PIN_MISO;
+        self.bus.set_pins(value);
+    }
+}
+
+impl Memory for SpiPort {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + SPI_PORT_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        match address - self.offset {
+            0 => Ok(self.pins | if self.bus.miso() { PIN_MISO } else { 0 }),
+            _ => Ok(self.data)
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + SPI_PORT_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        match address - self.offset {
+            0 => self.set_pins(value),
+            _ => self.data = self.bus.transfer(value)
+        }
+
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.pins);
+        state.write_u8(self.data);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pins = state.read_u8()?;
+        self.data = state.read_u8()?;
+        self.bus.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::via::VIA;
+
+    // Replies with the previous byte it received plus one
+    #[derive(Debug)]
+    struct Echo {
+        last: u8
+    }
+
+    impl SpiDevice for Echo {
+        fn output(&mut self) -> u8 {
+            self.last.wrapping_add(1)
+        }
+
+        fn input(&mut self, value: u8) {
+            self.last = value;
+        }
+    }
+
+    fn echo() -> Box<Echo> {
+        Box::new(Echo { last: 0x10 })
+    }
+
+    fn bit_bang(port: &mut dyn Memory, address: u16, chip_select: u8, value: u8) -> u8 {
+        let mut received = 0;
+        for bit in (0..8).rev() {
+            let mosi = if value & (1 << bit) != 0 { PIN_MOSI } else { 0 };
+            received = (received << 1) | (port.read(address).unwrap() >> 7);
+            port.write(address, chip_select | mosi).unwrap();
+            port.write(address, chip_select | mosi | PIN_SCLK).unwrap();
+            port.write(address, chip_select | mosi).unwrap();
+        }
+        received
+    }
+
+    #[test]
+    fn spi_bus_transfer() {
+        let mut bus = SpiBus::new();
+        bus.attach(1, echo());
+        assert_eq!(bus.transfer(0x55), 0xff);
+
+        bus.select(Some(1));
+        assert_eq!(bus.transfer(0x20), 0x11);
+        assert_eq!(bus.transfer(0x30), 0x21);
+        bus.select(None);
+        assert!(bus.miso());
+    }
+
+    #[test]
+    fn spi_port_bit_bang() -> Result<(), MemoryError> {
+        let mut port = SpiPort::new(0x6000);
+        port.attach(0, echo());
+        port.attach(1, echo());
+
+        // Select device 1 only (/CS0 and /CS2 high)
+        let chip_select = PIN_CS0 | (PIN_CS0 << 2);
+        port.write(0x6000, chip_select)?;
+        assert_eq!(port.bus().selected(), Some(1));
+
+        assert_eq!(bit_bang(&mut port, 0x6000, chip_select, 0xa5), 0x11);
+        assert_eq!(bit_bang(&mut port, 0x6000, chip_select, 0x3c), 0xa6);
+
+        // Byte transfers through the data register continue the same stream
+        port.write(0x6001, 0x00)?;
+        assert_eq!(port.read(0x6001)?, 0x3d);
+
+        // Deselecting everything lets MISO float high
+        port.write(0x6000, 0x1c)?;
+        assert_eq!(port.bus().selected(), None);
+        assert_eq!(port.read(0x6000)? & PIN_MISO, PIN_MISO);
+        Ok(())
+    }
+
+    #[test]
+    fn spi_via() -> Result<(), MemoryError> {
+        let mut bus = SpiBus::new();
+        bus.attach(1, echo());
+        let mut via = VIA::new(0x6000);
+        via.attach(Box::new(bus));
+
+        // The same bit-banging on port B, with MISO an input
+        let chip_select = PIN_CS0 | (PIN_CS0 << 2);
+        via.write(0x6000, chip_select)?;
+        via.write(0x6002, !PIN_MISO)?;
+        assert_eq!(bit_bang(&mut via, 0x6000, chip_select, 0xa5), 0x11);
+        assert_eq!(bit_bang(&mut via, 0x6000, chip_select, 0x3c), 0xa6);
+
+        let mut state = StateWriter::new();
+        via.save_state(&mut state);
+        assert_eq!(bit_bang(&mut via, 0x6000, chip_select, 0x00), 0x3d);
+        via.load_state(&mut StateReader::new(&state.into_bytes())).unwrap();
+        assert_eq!(bit_bang(&mut via, 0x6000, chip_select, 0x00), 0x3d);
+
+        via.write(0x6000, 0x1c)?;
+        assert_eq!(via.read(0x6000)? & PIN_MISO, PIN_MISO);
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+/*!
+ * Device: Text Display
+ *
+ * A character-cell display that maps a block of memory (40x25 by default) as the screen buffer, followed by three
+ * registers:
+ * - Cursor column (offset + columns * rows)
+ * - Cursor row (offset + columns * rows + 1)
+ * - Control (offset + columns * rows + 2)
+ *
+ * Each screen byte is an ASCII character. Bit 7 shows the character in inverse video, and non-printable characters are
+ * shown as spaces. The display can be rendered to a terminal with ANSI escape codes, in which case only the cells that
+ * changed since the previous render are redrawn, or dumped as plain text for tests.
+ */
+
+use std::io::Write;
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const CONTROL_CURSOR_ENABLE: u8 = 0x01;
+pub const CONTROL_CLEAR: u8 = 0x02;
+
+#[derive(Debug)]
+pub struct TextDisplay {
+    screen: Vec<u8>,
+    columns: u8,
+    rows: u8,
+    cursor_column: u8,
+    cursor_row: u8,
+    control: u8,
+    offset: u32,
+    // What the terminal currently shows, or None if it has to be redrawn from scratch
+    drawn: Option<Vec<u8>>,
+    refresh_cycles: Option<u32>,
+    cycles: u32
+}
+
+impl TextDisplay {
+    // A display needs at least one column and one row, so zero is taken as one
+    pub fn new(columns: u8, rows: u8, offset: u32) -> TextDisplay {
+        let (columns, rows) = (columns.max(1), rows.max(1));
+        TextDisplay {
+            screen: vec![b' '; columns as usize * rows as usize],
+            columns,
+            rows,
+            cursor_column: 0,
+            cursor_row: 0,
+            control: CONTROL_CURSOR_ENABLE,
+            offset,
+            drawn: None,
+            refresh_cycles: None,
+            cycles: 0
+        }
+    }
+
+    // The number of bytes the display occupies in the memory map: the screen buffer plus three registers
+    pub fn size(&self) -> u32 {
+        self.screen.len() as u32 + 3
+    }
+
+    pub fn cursor(&self) -> (u8, u8) {
+        (self.cursor_column, self.cursor_row)
+    }
+
+    // Redraw the display on stdout every `cycles` CPU cycles
+    pub fn set_refresh(&mut self, cycles: u32) {
+        self.refresh_cycles = Some(cycles.max(1));
+    }
+
+    // Dump the screen as plain text, one line per row with trailing spaces removed
+    pub fn screen_string(&self) -> String {
+        self.screen
+            .chunks(self.columns as usize)
+            .map(|row| row.iter().map(|&byte| printable(byte)).collect::<String>().trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    // Build the ANSI escape sequence that brings the terminal up to date with the screen buffer
+    pub fn render_ansi(&mut self) -> String {
+        let mut out = String::new();
+        let previous = match self.drawn.take() {
+            Some(previous) => previous,
+            None => {
+                out.push_str("\x1b[2J");
+                vec![!0; self.screen.len()]
+            }
+        };
+
+        // Only move the terminal cursor when the next changed cell is not the one right after the last one written
+        let mut position = None;
+        for (index, (&byte, &old)) in self.screen.iter().zip(previous.iter()).enumerate() {
+            if byte == old {
+                continue;
+            }
+            if position != Some(index) {
+                let (row, column) = (index / self.columns as usize, index % self.columns as usize);
+                out.push_str(&format!("\x1b[{};{}H", row + 1, column + 1));
+            }
+            if byte & 0x80 != 0 {
+                out.push_str(&format!("\x1b[7m{}\x1b[0m", printable(byte)));
+            } else {
+                out.push(printable(byte));
+            }
+            position = Some(index + 1).filter(|next| next % self.columns as usize != 0);
+        }
+
+        out.push_str(&format!("\x1b[{};{}H", self.cursor_row as u32 + 1, self.cursor_column as u32 + 1));
+        out.push_str(if self.control & CONTROL_CURSOR_ENABLE != 0 { "\x1b[?25h" } else { "\x1b[?25l" });
+
+        self.drawn = Some(self.screen.clone());
+        out
+    }
+
+    pub fn render<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        let ansi = self.render_ansi();
+        out.write_all(ansi.as_bytes())?;
+        out.flush()
+    }
+
+    // Force the next render to redraw every cell, e.g. after something else has written to the terminal
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    fn clear(&mut self) {
+        self.screen.fill(b' ');
+        self.cursor_column = 0;
+        self.cursor_row = 0;
+    }
+}
+
+fn printable(byte: u8) -> char {
+    match byte & 0x7f {
+        0x20..=0x7e => (byte & 0x7f) as char,
+        _ => ' '
+    }
+}
+
+impl Memory for TextDisplay {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + self.size() {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        let index = (address - self.offset) as usize;
+        match index.checked_sub(self.screen.len()) {
+            None => Ok(self.screen[index]),
+            Some(0) => Ok(self.cursor_column),
+            Some(1) => Ok(self.cursor_row),
+            Some(_) => Ok(self.control)
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + self.size() {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        let index = (address - self.offset) as usize;
+        match index.checked_sub(self.screen.len()) {
+            None => self.screen[index] = value,
+            Some(0) => self.cursor_column = value.min(self.columns - 1),
+            Some(1) => self.cursor_row = value.min(self.rows - 1),
+            Some(_) => {
+                // The clear bit is a strobe and always reads back as zero
+                if value & CONTROL_CLEAR != 0 {
+                    self.clear();
+                }
+                self.control = value & !CONTROL_CLEAR;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    // Loading a text display fills the screen buffer from the top left
+    fn load(&mut self, data: Vec<u8>) -> MemoryWriteResult {
+        if data.len() > self.screen.len() {
+            return Err(MemoryError::OutOfBounds);
+        }
+        self.screen.fill(b' ');
+        self.screen[..data.len()].copy_from_slice(&data);
+
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let Some(refresh_cycles) = self.refresh_cycles else {
+            return;
+        };
+
+        self.cycles += cycles;
+        if self.cycles >= refresh_cycles {
+            self.cycles %= refresh_cycles;
+            if let Err(error) = self.render(&mut std::io::stdout()) {
+                eprintln!("TextDisplay: Unable to render to the terminal: {}", error);
+                self.refresh_cycles = None;
+            }
+        }
+    }
+
+    // The next time the screen is drawn on the terminal
+    fn next_event(&self) -> Option<u64> {
+        self.refresh_cycles.map(|refresh_cycles| refresh_cycles.saturating_sub(self.cycles) as u64)
+    }
+
+    // The screen and registers. The terminal is redrawn in full after a restore.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.screen);
+        state.write_u8(self.cursor_column);
+        state.write_u8(self.cursor_row);
+        state.write_u8(self.control);
+        state.write_u32(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.screen)?;
+        self.cursor_column = state.read_u8()?.min(self.columns - 1);
+        self.cursor_row = state.read_u8()?.min(self.rows - 1);
+        self.control = state.read_u8()?;
+        self.cycles = state.read_u32()?;
+        self.drawn = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_string(display: &mut TextDisplay, address: u16, text: &str) {
+        for (index, byte) in text.bytes().enumerate() {
+            display.write(address + index as u16, byte).unwrap();
+        }
+    }
+
+    #[test]
+    fn text_display() -> Result<(), MemoryError> {
+        let mut display = TextDisplay::new(40, 25, 0x4000);
+        assert_eq!(display.size(), 40 * 25 + 3);
+
+        write_string(&mut display, 0x4000, "HELLO");
+        write_string(&mut display, 0x4000 + 40 + 2, "WORLD");
+        assert_eq!(display.read(0x4000)?, b'H');
+        let lines = display.screen_string().split('\n').map(String::from).collect::<Vec<String>>();
+        assert_eq!(lines.len(), 25);
+        assert_eq!(lines[..3], ["HELLO", "  WORLD", ""]);
+
+        assert!(display.read(0x4000 + 40 * 25 + 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn text_display_registers() -> Result<(), MemoryError> {
+        let mut display = TextDisplay::new(40, 25, 0x4000);
+        let registers = 0x4000 + 40 * 25;
+
+        display.write(registers, 12)?;
+        display.write(registers + 1, 30)?;
+        assert_eq!(display.cursor(), (12, 24));
+        assert_eq!(display.read(registers + 1)?, 24);
+        assert_eq!(display.read(registers + 2)?, CONTROL_CURSOR_ENABLE);
+
+        // Clearing resets the screen and cursor, and the strobe bit does not stick
+        write_string(&mut display, 0x4000, "HELLO");
+        display.write(registers + 2, CONTROL_CLEAR)?;
+        assert_eq!(display.screen_string().trim(), "");
+        assert_eq!(display.cursor(), (0, 0));
+        assert_eq!(display.read(registers + 2)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn text_display_inverse_and_unprintable() {
+        let mut display = TextDisplay::new(4, 1, 0x0000);
+        display.load(vec![b'A' | 0x80, 0x00, b'B', 0x0d]).unwrap();
+        assert_eq!(display.screen_string(), "A B");
+        assert!(display.render_ansi().contains("\x1b[7mA\x1b[0m"));
+    }
+
+    #[test]
+    fn text_display_empty() {
+        // Zero columns or rows make a single cell rather than a display that cannot be drawn
+        let mut display = TextDisplay::new(0, 0, 0x0000);
+        assert_eq!(display.size(), 4);
+        display.write(0x0001, 5).unwrap();
+        display.write(0x0000, b'A').unwrap();
+        assert_eq!((display.cursor(), display.screen_string()), ((0, 0), String::from("A")));
+    }
+
+    #[test]
+    fn text_display_render_changes_only() {
+        let mut display = TextDisplay::new(4, 2, 0x0000);
+        display.load(b"ABCDEFGH".to_vec()).unwrap();
+
+        let first = display.render_ansi();
+        assert!(first.starts_with("\x1b[2J\x1b[1;1HABCD\x1b[2;1HEFGH"));
+
+        // Nothing changed, so only the cursor is positioned
+        assert_eq!(display.render_ansi(), "\x1b[1;1H\x1b[?25h");
+
+        display.write(0x0006, b'x').unwrap();
+        display.write(0x000a, 0).unwrap();
+        assert_eq!(display.render_ansi(), "\x1b[2;3Hx\x1b[1;1H\x1b[?25l");
+
+        display.invalidate();
+        assert!(display.render_ansi().starts_with("\x1b[2J"));
+    }
+
+    #[test]
+    fn text_display_memory_map() {
+        let mut memory_map = crate::devices::memory_map::MemoryMap::new();
+        let display = TextDisplay::new(40, 25, 0x4000);
+        let size = display.size();
+        memory_map.insert(String::from("Display"), Box::new(display), size, 0x4000).unwrap();
+        memory_map.write(0x4000, b'!').unwrap();
+        assert_eq!(memory_map.read(0x4000).unwrap(), b'!');
+    }
+}
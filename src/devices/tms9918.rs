@@ -0,0 +1,715 @@
+/*!
+ * Device: TMS9918A Video Display Processor
+ *
+ * The VDP owns 16K of VRAM that the CPU can only reach through two ports:
+ * - Data port (offset + 0): reads and writes VRAM at the current address, which auto-increments
+ * - Control port (offset + 1): two byte writes set the VRAM address or a register, reads return the status register
+ *
+ * All four graphics modes (Graphics I, Graphics II, Multicolor and Text) and sprites are rendered once per frame into
+ * a 256x192 RGB Framebuffer. At the end of every frame the F flag is set in the status register and, if enabled in
+ * register 1, the IRQ line is asserted until the status register is read.
+ *
+ * Reads from the VDP have side effects (the address pointer moves, status flags clear), so that state lives in Cells
+ * to fit the Memory trait.
+ */
+
+use std::cell::Cell;
+use std::path::PathBuf;
+
+use crate::devices::framebuffer::*;
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const TMS9918_SIZE: u32 = 2;
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 192;
+
+const VRAM_SIZE: usize = 0x4000;
+const VRAM_MASK: u16 = 0x3fff;
+
+const STATUS_INT: u8 = 0x80;
+const STATUS_FIFTH_SPRITE: u8 = 0x40;
+const STATUS_COLLISION: u8 = 0x20;
+const STATUS_SPRITE_NUMBER: u8 = 0x1f;
+
+const SPRITE_TERMINATOR: u8 = 0xd0;
+const SPRITES_PER_LINE: usize = 4;
+
+// Colour 0 is transparent and is replaced by the backdrop colour before it reaches the palette
+const PALETTE: [Rgb; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0x21, 0xc8, 0x42],
+    [0x5e, 0xdc, 0x78],
+    [0x54, 0x55, 0xed],
+    [0x7d, 0x76, 0xfc],
+    [0xd4, 0x52, 0x4d],
+    [0x42, 0xeb, 0xf5],
+    [0xfc, 0x55, 0x54],
+    [0xff, 0x79, 0x78],
+    [0xd4, 0xc1, 0x54],
+    [0xe6, 0xce, 0x80],
+    [0x21, 0xb0, 0x3b],
+    [0xc9, 0x5b, 0xba],
+    [0xcc, 0xcc, 0xcc],
+    [0xff, 0xff, 0xff]
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VdpMode {
+    Graphics1,
+    Graphics2,
+    Multicolor,
+    Text
+}
+
+#[derive(Debug)]
+struct FrameDump {
+    directory: PathBuf,
+    format: ImageFormat
+}
+
+#[derive(Debug)]
+pub struct TMS9918 {
+    vram: Vec<u8>,
+    registers: [u8; 8],
+    status: Cell<u8>,
+    address: Cell<u16>,
+    read_buffer: Cell<u8>,
+    latch: Cell<Option<u8>>,
+    offset: u32,
+    cycles_per_frame: u32,
+    cycles: u32,
+    frame_count: u64,
+    framebuffer: Framebuffer,
+    frame_dump: Option<FrameDump>
+}
+
+impl TMS9918 {
+    pub fn new(offset: u32, cycles_per_frame: u32) -> TMS9918 {
+        TMS9918 {
+            vram: vec![0; VRAM_SIZE],
+            registers: [0; 8],
+            status: Cell::new(0),
+            address: Cell::new(0),
+            read_buffer: Cell::new(0),
+            latch: Cell::new(None),
+            offset,
+            cycles_per_frame: cycles_per_frame.max(1),
+            cycles: 0,
+            frame_count: 0,
+            framebuffer: Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            frame_dump: None
+        }
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn register(&self, index: usize) -> u8 {
+        self.registers[index & 7]
+    }
+
+    pub fn frame(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Save every completed frame to `directory` as frame_00000.png, frame_00001.png, ...
+    pub fn set_frame_dump(&mut self, directory: PathBuf, format: ImageFormat) {
+        self.frame_dump = Some(FrameDump { directory, format });
+    }
+
+    pub fn mode(&self) -> VdpMode {
+        if self.registers[1] & 0x10 != 0 {
+            VdpMode::Text
+        } else if self.registers[1] & 0x08 != 0 {
+            VdpMode::Multicolor
+        } else if self.registers[0] & 0x02 != 0 {
+            VdpMode::Graphics2
+        } else {
+            VdpMode::Graphics1
+        }
+    }
+
+    // Render the current VRAM contents into the framebuffer. This happens automatically at the end of every frame.
+    pub fn render(&mut self) {
+        let backdrop = self.color(self.registers[7] & 0x0f);
+
+        // Register 1 bit 6 clear blanks the display to the backdrop colour and disables sprites
+        if self.registers[1] & 0x40 == 0 {
+            self.framebuffer.fill(backdrop);
+            return;
+        }
+
+        match self.mode() {
+            VdpMode::Graphics1 => self.render_graphics1(),
+            VdpMode::Graphics2 => self.render_graphics2(),
+            VdpMode::Multicolor => self.render_multicolor(),
+            VdpMode::Text => self.render_text()
+        }
+
+        if self.mode() != VdpMode::Text {
+            self.render_sprites();
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.render();
+        self.status.set(self.status.get() | STATUS_INT);
+
+        if let Some(dump) = &self.frame_dump {
+            let path = dump.directory.join(format!("frame_{:05}.{}", self.frame_count, dump.format.extension()));
+            if let Err(error) = self.framebuffer.save(&path, dump.format) {
+                eprintln!("TMS9918: Unable to save frame to {}: {}", path.display(), error);
+                self.frame_dump = None;
+            }
+        }
+
+        self.frame_count += 1;
+    }
+
+    fn color(&self, index: u8) -> Rgb {
+        match index & 0x0f {
+            0 => PALETTE[(self.registers[7] & 0x0f) as usize],
+            index => PALETTE[index as usize]
+        }
+    }
+
+    fn vram_at(&self, address: usize) -> u8 {
+        self.vram[address & (VRAM_SIZE - 1)]
+    }
+
+    fn name_table(&self) -> usize {
+        ((self.registers[2] & 0x0f) as usize) << 10
+    }
+
+    fn draw_pattern_row(&mut self, x: usize, y: usize, pattern: u8, width: usize, fg: u8, bg: u8) {
+        let (fg, bg) = (self.color(fg), self.color(bg));
+        for bit in 0..width {
+            let color = if pattern & (0x80 >> bit) != 0 { fg } else { bg };
+            self.framebuffer.set_pixel(x + bit, y, color);
+        }
+    }
+
+    fn render_graphics1(&mut self) {
+        let name_table = self.name_table();
+        let color_table = (self.registers[3] as usize) << 6;
+        let pattern_table = ((self.registers[4] & 0x07) as usize) << 11;
+
+        for row in 0..24 {
+            for column in 0..32 {
+                let name = self.vram_at(name_table + row * 32 + column) as usize;
+                let colors = self.vram_at(color_table + name / 8);
+                for line in 0..8 {
+                    let pattern = self.vram_at(pattern_table + name * 8 + line);
+                    self.draw_pattern_row(column * 8, row * 8 + line, pattern, 8, colors >> 4, colors & 0x0f);
+                }
+            }
+        }
+    }
+
+    fn render_graphics2(&mut self) {
+        let name_table = self.name_table();
+
+        // In Graphics II the low bits of registers 3 and 4 act as masks on the name index rather than as addresses
+        let color_table = ((self.registers[3] & 0x80) as usize) << 6;
+        let pattern_table = ((self.registers[4] & 0x04) as usize) << 11;
+        let color_mask = (((self.registers[3] & 0x7f) as usize) << 3) | 0x07;
+        let pattern_mask = (((self.registers[4] & 0x03) as usize) << 8) | (color_mask & 0xff);
+
+        for row in 0..24 {
+            for column in 0..32 {
+                let index = (row / 8) * 256 + self.vram_at(name_table + row * 32 + column) as usize;
+                for line in 0..8 {
+                    let pattern = self.vram_at(pattern_table + ((index & pattern_mask) << 3) + line);
+                    let colors = self.vram_at(color_table + ((index & color_mask) << 3) + line);
+                    self.draw_pattern_row(column * 8, row * 8 + line, pattern, 8, colors >> 4, colors & 0x0f);
+                }
+            }
+        }
+    }
+
+    fn render_multicolor(&mut self) {
+        let name_table = self.name_table();
+        let pattern_table = ((self.registers[4] & 0x07) as usize) << 11;
+
+        for row in 0..24 {
+            for column in 0..32 {
+                let name = self.vram_at(name_table + row * 32 + column) as usize;
+                for line in 0..8 {
+                    // Each pattern byte holds two 4x4 blocks; the character row picks which pair of bytes is used
+                    let colors = self.vram_at(pattern_table + name * 8 + (row & 0x03) * 2 + line / 4);
+                    self.draw_pattern_row(column * 8, row * 8 + line, 0xf0, 8, colors >> 4, colors & 0x0f);
+                }
+            }
+        }
+    }
+
+    fn render_text(&mut self) {
+        let name_table = self.name_table();
+        let pattern_table = ((self.registers[4] & 0x07) as usize) << 11;
+        let (fg, bg) = (self.registers[7] >> 4, self.registers[7] & 0x0f);
+
+        // 40 columns of 6 pixels leave an 8 pixel border on either side
+        self.framebuffer.fill(self.color(bg));
+        for row in 0..24 {
+            for column in 0..40 {
+                let name = self.vram_at(name_table + row * 40 + column) as usize;
+                for line in 0..8 {
+                    let pattern = self.vram_at(pattern_table + name * 8 + line);
+                    self.draw_pattern_row(8 + column * 6, row * 8 + line, pattern, 6, fg, bg);
+                }
+            }
+        }
+    }
+
+    fn render_sprites(&mut self) {
+        let attribute_table = ((self.registers[5] & 0x7f) as usize) << 7;
+        let pattern_table = ((self.registers[6] & 0x07) as usize) << 11;
+        let size = if self.registers[1] & 0x02 != 0 { 16 } else { 8 };
+        let scale = if self.registers[1] & 0x01 != 0 { 2 } else { 1 };
+
+        // Find the active sprites, which end at the first Y coordinate of $D0
+        let mut sprites = 0;
+        while sprites < 32 && self.vram_at(attribute_table + sprites * 4) != SPRITE_TERMINATOR {
+            sprites += 1;
+        }
+
+        let mut status = self.status.get();
+        for y in 0..SCREEN_HEIGHT {
+            let mut covered = [false; SCREEN_WIDTH];
+            let mut painted = [false; SCREEN_WIDTH];
+            let mut on_line = 0;
+
+            for sprite in 0..sprites {
+                let attributes = attribute_table + sprite * 4;
+
+                // Sprites are displayed one line below their Y coordinate, and values past $D0 wrap to the top
+                let mut top = self.vram_at(attributes) as i32 + 1;
+                if top > SPRITE_TERMINATOR as i32 {
+                    top -= 256;
+                }
+                if (y as i32) < top || (y as i32) >= top + size * scale {
+                    continue;
+                }
+
+                // Only four sprites fit on a line; the first one past that is reported in the status register
+                on_line += 1;
+                if on_line > SPRITES_PER_LINE {
+                    if status & STATUS_FIFTH_SPRITE == 0 {
+                        status = (status & !STATUS_SPRITE_NUMBER) | STATUS_FIFTH_SPRITE | sprite as u8;
+                    }
+                    break;
+                }
+
+                let row = ((y as i32 - top) / scale) as usize;
+                let name = self.vram_at(attributes + 2) as usize;
+                let name = if size == 16 { name & 0xfc } else { name };
+                let flags = self.vram_at(attributes + 3);
+                let left = self.vram_at(attributes + 1) as i32 - if flags & 0x80 != 0 { 32 } else { 0 };
+                let color = flags & 0x0f;
+
+                for pixel in 0..size * scale {
+                    let x = left + pixel;
+                    if !(0..SCREEN_WIDTH as i32).contains(&x) {
+                        continue;
+                    }
+                    let x = x as usize;
+
+                    // 16x16 sprites are four 8x8 blocks: the left half comes first, then the right half
+                    let column = (pixel / scale) as usize;
+                    let pattern = self.vram_at(pattern_table + name * 8 + row + if column >= 8 { 16 } else { 0 });
+                    if pattern & (0x80 >> (column & 7)) == 0 {
+                        continue;
+                    }
+
+                    // Collisions count every set pattern bit, even in transparent sprites
+                    if covered[x] {
+                        status |= STATUS_COLLISION;
+                    }
+                    covered[x] = true;
+
+                    // Lower numbered sprites have priority
+                    if !painted[x] && color != 0 {
+                        painted[x] = true;
+                        self.framebuffer.set_pixel(x, y, PALETTE[color as usize]);
+                    }
+                }
+            }
+        }
+
+        if status & STATUS_FIFTH_SPRITE == 0 {
+            status = (status & !STATUS_SPRITE_NUMBER) | (sprites.min(31) as u8);
+        }
+        self.status.set(status);
+    }
+}
+
+impl Memory for TMS9918 {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + TMS9918_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        // Any read resets the control port's byte latch
+        self.latch.set(None);
+
+        if (address - self.offset) & 1 == 0 {
+            // VRAM reads come from a read-ahead buffer that is refilled from the next address
+            let value = self.read_buffer.get();
+            let vram_address = self.address.get();
+            self.read_buffer.set(self.vram[vram_address as usize]);
+            self.address.set(vram_address.wrapping_add(1) & VRAM_MASK);
+            Ok(value)
+        } else {
+            // Reading the status register clears the interrupt, fifth sprite and collision flags
+            let value = self.status.get();
+            self.status.set(value & STATUS_SPRITE_NUMBER);
+            Ok(value)
+        }
+    }
+
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + TMS9918_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        if (address - self.offset) & 1 == 0 { Ok(self.read_buffer.get()) } else { Ok(self.status.get()) }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + TMS9918_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+
+        if (address - self.offset) & 1 == 0 {
+            self.latch.set(None);
+            let vram_address = self.address.get();
+            self.vram[vram_address as usize] = value;
+            self.read_buffer.set(value);
+            self.address.set(vram_address.wrapping_add(1) & VRAM_MASK);
+            return Ok(());
+        }
+
+        match self.latch.take() {
+            None => self.latch.set(Some(value)),
+            Some(low) => {
+                if value & 0x80 != 0 {
+                    // Register write: the first byte is the value, the second holds the register number
+                    self.registers[(value & 0x07) as usize] = low;
+                } else {
+                    // Address setup: bit 6 of the second byte selects a write, otherwise VRAM is pre-fetched for reading
+                    let vram_address = (((value & 0x3f) as u16) << 8) | low as u16;
+                    self.address.set(vram_address);
+                    if value & 0x40 == 0 {
+                        self.read_buffer.set(self.vram[vram_address as usize]);
+                        self.address.set(vram_address.wrapping_add(1) & VRAM_MASK);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    // Loading a VDP fills VRAM from address 0
+    fn load(&mut self, data: Vec<u8>) -> MemoryWriteResult {
+        if data.len() > VRAM_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+        self.vram.clear();
+        self.vram.resize(VRAM_SIZE, 0);
+        self.vram[..data.len()].copy_from_slice(&data);
+
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        while self.cycles >= self.cycles_per_frame {
+            self.cycles -= self.cycles_per_frame;
+            self.end_frame();
+        }
+    }
+
+    // The end of the frame, when the frame is drawn and the vblank interrupt raised
+    fn next_event(&self) -> Option<u64> {
+        Some((self.cycles_per_frame - self.cycles) as u64)
+    }
+
+    fn irq(&self) -> bool {
+        self.status.get() & STATUS_INT != 0 && self.registers[1] & 0x20 != 0
+    }
+
+    // VRAM and the registers. The framebuffer is rendered again from VRAM, so it is not saved.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.vram);
+        state.write_raw(&self.registers);
+        state.write_u8(self.status.get());
+        state.write_u16(self.address.get());
+        state.write_u8(self.read_buffer.get());
+        state.write_bool(self.latch.get().is_some());
+        state.write_u8(self.latch.get().unwrap_or(0));
+        state.write_u32(self.cycles);
+        state.write_u64(self.frame_count);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.vram)?;
+        let registers = state.read_raw(8)?;
+        self.registers.copy_from_slice(registers);
+        self.status.set(state.read_u8()?);
+        self.address.set(state.read_u16()? & VRAM_MASK);
+        self.read_buffer.set(state.read_u8()?);
+        let latched = state.read_bool()?;
+        let latch = state.read_u8()?;
+        self.latch.set(if latched { Some(latch) } else { None });
+        self.cycles = state.read_u32()?;
+        self.frame_count = state.read_u64()?;
+        self.render();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: u16 = 0x4000;
+    const CONTROL: u16 = 0x4001;
+
+    fn set_register(vdp: &mut TMS9918, register: u8, value: u8) {
+        vdp.write(CONTROL, value).unwrap();
+        vdp.write(CONTROL, 0x80 | register).unwrap();
+    }
+
+    fn write_vram(vdp: &mut TMS9918, address: u16, data: &[u8]) {
+        vdp.write(CONTROL, (address & 0xff) as u8).unwrap();
+        vdp.write(CONTROL, 0x40 | (address >> 8) as u8).unwrap();
+        for &value in data {
+            vdp.write(DATA, value).unwrap();
+        }
+    }
+
+    #[test]
+    fn tms9918_registers() {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 1, 0xe0);
+        set_register(&mut vdp, 7, 0xf4);
+        assert_eq!(vdp.register(1), 0xe0);
+        assert_eq!(vdp.register(7), 0xf4);
+        assert_eq!(vdp.mode(), VdpMode::Graphics1);
+
+        set_register(&mut vdp, 0, 0x02);
+        assert_eq!(vdp.mode(), VdpMode::Graphics2);
+        set_register(&mut vdp, 0, 0x00);
+        set_register(&mut vdp, 1, 0x08);
+        assert_eq!(vdp.mode(), VdpMode::Multicolor);
+        set_register(&mut vdp, 1, 0x10);
+        assert_eq!(vdp.mode(), VdpMode::Text);
+    }
+
+    #[test]
+    fn tms9918_vram_ports() -> Result<(), MemoryError> {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        write_vram(&mut vdp, 0x1234, &[0x12, 0x34, 0x56]);
+        assert_eq!(&vdp.vram()[0x1234..0x1237], &[0x12, 0x34, 0x56]);
+
+        // Set up a read, which pre-fetches the first byte
+        vdp.write(CONTROL, 0x34)?;
+        vdp.write(CONTROL, 0x12)?;
+        assert_eq!(vdp.read(DATA)?, 0x12);
+        assert_eq!(vdp.read(DATA)?, 0x34);
+        assert_eq!(vdp.read(DATA)?, 0x56);
+
+        assert!(vdp.read(0x4002).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tms9918_vblank_interrupt() -> Result<(), MemoryError> {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        vdp.tick(999);
+        assert_eq!(vdp.frame_count(), 0);
+        assert_eq!(vdp.read(CONTROL)? & STATUS_INT, 0);
+
+        // The frame flag is set even with interrupts disabled
+        vdp.tick(1);
+        assert_eq!(vdp.frame_count(), 1);
+        assert!(!vdp.irq());
+
+        set_register(&mut vdp, 1, 0x20);
+        assert!(vdp.irq());
+
+        // Reading the status register acknowledges the interrupt
+        assert_eq!(vdp.read(CONTROL)? & STATUS_INT, STATUS_INT);
+        assert!(!vdp.irq());
+        Ok(())
+    }
+
+    #[test]
+    fn tms9918_graphics1() {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 1, 0x40);
+        set_register(&mut vdp, 2, 0x06);
+        set_register(&mut vdp, 3, 0x80);
+        set_register(&mut vdp, 4, 0x00);
+        set_register(&mut vdp, 7, 0x04);
+
+        // Character 1 is a solid top line, white on transparent, placed at the top left
+        write_vram(&mut vdp, 0x0008, &[0xff, 0x00]);
+        write_vram(&mut vdp, 0x2000, &[0xf0]);
+        write_vram(&mut vdp, 0x1800, &[0x01]);
+        vdp.render();
+
+        let frame = vdp.frame();
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[15]);
+        assert_eq!(frame.get_pixel(7, 0), PALETTE[15]);
+        assert_eq!(frame.get_pixel(0, 1), PALETTE[4]);
+        assert_eq!(frame.get_pixel(8, 0), PALETTE[4]);
+    }
+
+    #[test]
+    fn tms9918_graphics2() {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 0, 0x02);
+        set_register(&mut vdp, 1, 0x40);
+        set_register(&mut vdp, 2, 0x0e);
+        set_register(&mut vdp, 3, 0xff);
+        set_register(&mut vdp, 4, 0x03);
+
+        // Name 0 in the second third of the screen uses pattern and colour entry 256
+        write_vram(&mut vdp, 0x0800, &[0x80]);
+        write_vram(&mut vdp, 0x2800, &[0x6f]);
+        vdp.render();
+
+        let frame = vdp.frame();
+        assert_eq!(frame.get_pixel(0, 64), PALETTE[6]);
+        assert_eq!(frame.get_pixel(1, 64), PALETTE[15]);
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[0]);
+    }
+
+    #[test]
+    fn tms9918_multicolor() {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 1, 0x48);
+        set_register(&mut vdp, 2, 0x06);
+        set_register(&mut vdp, 4, 0x00);
+
+        write_vram(&mut vdp, 0x0000, &[0x2d, 0x5a]);
+        vdp.render();
+
+        let frame = vdp.frame();
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[2]);
+        assert_eq!(frame.get_pixel(4, 3), PALETTE[13]);
+        assert_eq!(frame.get_pixel(0, 4), PALETTE[5]);
+        assert_eq!(frame.get_pixel(7, 7), PALETTE[10]);
+    }
+
+    #[test]
+    fn tms9918_text() {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 1, 0x50);
+        set_register(&mut vdp, 2, 0x02);
+        set_register(&mut vdp, 4, 0x00);
+        set_register(&mut vdp, 7, 0xf1);
+
+        write_vram(&mut vdp, 0x0008, &[0xfc]);
+        write_vram(&mut vdp, 0x0801, &[0x01]);
+        vdp.render();
+
+        let frame = vdp.frame();
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[1]);
+        assert_eq!(frame.get_pixel(14, 0), PALETTE[15]);
+        assert_eq!(frame.get_pixel(19, 0), PALETTE[15]);
+        assert_eq!(frame.get_pixel(20, 0), PALETTE[1]);
+    }
+
+    #[test]
+    fn tms9918_sprites() -> Result<(), MemoryError> {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 1, 0x40);
+        set_register(&mut vdp, 2, 0x06);
+        set_register(&mut vdp, 5, 0x36);
+        set_register(&mut vdp, 6, 0x07);
+        set_register(&mut vdp, 7, 0x01);
+
+        // Two overlapping solid sprites; sprite 0 is in front
+        write_vram(&mut vdp, 0x3800, &[0xff; 8]);
+        write_vram(&mut vdp, 0x1b00, &[0x0f, 0x10, 0x00, 0x06, 0x0f, 0x14, 0x00, 0x0f, SPRITE_TERMINATOR]);
+        vdp.render();
+
+        let frame = vdp.frame();
+        assert_eq!(frame.get_pixel(0x10, 0x10), PALETTE[6]);
+        assert_eq!(frame.get_pixel(0x17, 0x10), PALETTE[6]);
+        assert_eq!(frame.get_pixel(0x18, 0x10), PALETTE[15]);
+        assert_eq!(frame.get_pixel(0x10, 0x0f), PALETTE[1]);
+
+        let status = vdp.read(CONTROL)?;
+        assert_eq!(status & STATUS_COLLISION, STATUS_COLLISION);
+        assert_eq!(status & STATUS_FIFTH_SPRITE, 0);
+        assert_eq!(vdp.read(CONTROL)? & STATUS_COLLISION, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn tms9918_fifth_sprite() -> Result<(), MemoryError> {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 1, 0x40);
+        set_register(&mut vdp, 5, 0x36);
+        set_register(&mut vdp, 6, 0x07);
+
+        // Six sprites on the same line, spread out so they do not collide
+        let mut attributes = Vec::new();
+        for sprite in 0..6 {
+            attributes.extend_from_slice(&[0x20, sprite * 0x20, 0x00, 0x0f]);
+        }
+        attributes.push(SPRITE_TERMINATOR);
+        write_vram(&mut vdp, 0x3800, &[0xff; 8]);
+        write_vram(&mut vdp, 0x1b00, &attributes);
+        vdp.render();
+
+        assert_eq!(vdp.frame().get_pixel(0x60, 0x21), PALETTE[15]);
+        assert_eq!(vdp.frame().get_pixel(0x80, 0x21), PALETTE[0]);
+
+        let status = vdp.read(CONTROL)?;
+        assert_eq!(status & STATUS_FIFTH_SPRITE, STATUS_FIFTH_SPRITE);
+        assert_eq!(status & STATUS_SPRITE_NUMBER, 4);
+        assert_eq!(status & STATUS_COLLISION, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn tms9918_blank() {
+        let mut vdp = TMS9918::new(0x4000, 1000);
+        set_register(&mut vdp, 7, 0x07);
+        write_vram(&mut vdp, 0x0000, &[0xff; 8]);
+        vdp.render();
+        assert_eq!(vdp.frame().get_pixel(0, 0), PALETTE[7]);
+        assert_eq!(vdp.frame().get_pixel(255, 191), PALETTE[7]);
+    }
+
+    #[test]
+    fn tms9918_memory_map() {
+        let mut memory_map = crate::devices::memory_map::MemoryMap::new();
+        memory_map.insert(String::from("VDP"), Box::new(TMS9918::new(0x4000, 1000)), TMS9918_SIZE, 0x4000).unwrap();
+        memory_map.write(0x4001, 0x20).unwrap();
+        memory_map.write(0x4001, 0x81).unwrap();
+        memory_map.tick(1000);
+        assert!(memory_map.irq());
+        assert_eq!(memory_map.read(0x4001).unwrap() & STATUS_INT, STATUS_INT);
+        assert!(!memory_map.irq());
+    }
+}
@@ -0,0 +1,581 @@
+/*!
+ * Device: 6522 VIA
+ *
+ * The Versatile Interface Adapter: two 8-bit ports with data direction registers, two 16-bit timers, and the control
+ * lines CA1 and CB1 that raise interrupts on an edge. Registers:
+ * - offset + 0: ORB / IRB, offset + 1: ORA / IRA, offset + 2: DDRB, offset + 3: DDRA
+ * - offset + 4, 5: timer 1 counter (reading the low byte clears its interrupt, writing the high byte starts it)
+ * - offset + 6, 7: timer 1 latches
+ * - offset + 8, 9: timer 2 counter (reading the low byte clears its interrupt, writing the high byte starts it)
+ * - offset + 10: shift register, offset + 11: ACR, offset + 12: PCR
+ * - offset + 13: IFR, where bit 7 is set while any enabled interrupt is pending and writing 1s clears flags
+ * - offset + 14: IER, where writing with bit 7 set enables the other bits written as 1, and with it clear disables them
+ * - offset + 15: ORA / IRA without handshake
+ *
+ * Timer 1 is one-shot, or free-running when ACR bit 6 is set, in which case it reloads from its latches and interrupts
+ * every latch + 2 cycles. Timer 2 is always one-shot, and does not count when ACR bit 5 selects counting PB6 pulses.
+ * Input pins that are not driven read as 1, as the VIA's inputs are pulled up.
+ *
+ * The shift register is a plain register, and the CA2/CB2 handshakes and PB7 timer output are not modelled.
+ *
+ * Peripherals wired to the port pins, such as a key matrix or a bit-banged SPI bus, implement PortDevice and are
+ * attached to the VIA. They see the levels on the pins whenever the CPU changes them, and pull the pins they drive low.
+ */
+
+use std::cell::Cell;
+
+use crate::devices::memory::*;
+use crate::devices::save_state::*;
+
+pub const VIA_SIZE: u32 = 16;
+
+pub const INTERRUPT_CA2: u8 = 0x01;
+pub const INTERRUPT_CA1: u8 = 0x02;
+pub const INTERRUPT_SR: u8 = 0x04;
+pub const INTERRUPT_CB2: u8 = 0x08;
+pub const INTERRUPT_CB1: u8 = 0x10;
+pub const INTERRUPT_T2: u8 = 0x20;
+pub const INTERRUPT_T1: u8 = 0x40;
+const INTERRUPT_ANY: u8 = 0x80;
+
+const ACR_T2_PULSE_COUNT: u8 = 0x20;
+const ACR_T1_FREE_RUN: u8 = 0x40;
+const PCR_CA1_RISING: u8 = 0x01;
+const PCR_CB1_RISING: u8 = 0x10;
+
+// Something wired to the port pins
+pub trait PortDevice: std::fmt::Debug {
+    // The levels on the pins of ports A and B, after the CPU wrote to a port or a data direction register
+    fn write_pins(&mut self, _port_a: u8, _port_b: u8) {}
+
+    // The levels the device drives the pins of ports A and B to, given the levels on them. Pins it leaves alone are 1.
+    fn read_pins(&self, port_a: u8, port_b: u8) -> (u8, u8);
+
+    // As for a Memory device
+    fn tick(&mut self, _cycles: u32) {}
+
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    fn take_input(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn input(&mut self, _data: &[u8]) {}
+
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+
+    fn make_deterministic(&mut self, _time: i64, _clock_rate: u32) {}
+}
+
+#[derive(Debug)]
+pub struct VIA {
+    offset: u32,
+    output_a: u8,
+    output_b: u8,
+    ddr_a: u8,
+    ddr_b: u8,
+    // What the outside world drives onto the pins
+    input_a: u8,
+    input_b: u8,
+    ca1: bool,
+    cb1: bool,
+    t1_counter: u16,
+    t1_latch: u16,
+    // Whether timer 1 interrupts the next time it passes zero, and whether it reloads on the cycle after
+    t1_armed: bool,
+    t1_reload: bool,
+    t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+    shift: u8,
+    acr: u8,
+    pcr: u8,
+    ifr: Cell<u8>,
+    ier: u8,
+    devices: Vec<Box<dyn PortDevice>>
+}
+
+impl VIA {
+    pub fn new(offset: u32) -> VIA {
+        VIA {
+            offset,
+            output_a: 0,
+            output_b: 0,
+            ddr_a: 0,
+            ddr_b: 0,
+            input_a: 0xff,
+            input_b: 0xff,
+            ca1: true,
+            cb1: true,
+            t1_counter: 0xffff,
+            t1_latch: 0xffff,
+            t1_armed: false,
+            t1_reload: false,
+            t2_counter: 0xffff,
+            t2_latch_low: 0xff,
+            t2_armed: false,
+            shift: 0,
+            acr: 0,
+            pcr: 0,
+            ifr: Cell::new(0),
+            ier: 0,
+            devices: Vec::new()
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn PortDevice>) {
+        self.devices.push(device);
+    }
+
+    // The levels on the port pins: the outputs where the DDR bit is set, and elsewhere the inputs, pulled low by any
+    // attached device driving them
+    pub fn port_a(&self) -> u8 {
+        self.pins().0
+    }
+
+    pub fn port_b(&self) -> u8 {
+        self.pins().1
+    }
+
+    fn pins(&self) -> (u8, u8) {
+        let driven_a = (self.output_a & self.ddr_a) | !self.ddr_a;
+        let driven_b = (self.output_b & self.ddr_b) | !self.ddr_b;
+        let (mut input_a, mut input_b) = (self.input_a, self.input_b);
+        for device in &self.devices {
+            let (a, b) = device.read_pins(driven_a & input_a, driven_b & input_b);
+            (input_a, input_b) = (input_a & a, input_b & b);
+        }
+        let port_a = (self.output_a & self.ddr_a) | (input_a & !self.ddr_a);
+        (port_a, (self.output_b & self.ddr_b) | (input_b & !self.ddr_b))
+    }
+
+    // Tell the attached devices about new levels on the pins
+    fn update_pins(&mut self) {
+        if self.devices.is_empty() {
+            return;
+        }
+        let (port_a, port_b) = self.pins();
+        for device in &mut self.devices {
+            device.write_pins(port_a, port_b);
+        }
+    }
+
+    pub fn set_input_a(&mut self, value: u8) {
+        self.input_a = value;
+        self.update_pins();
+    }
+
+    pub fn set_input_b(&mut self, value: u8) {
+        self.input_b = value;
+        self.update_pins();
+    }
+
+    // Drive the CA1 line, which sets its interrupt flag on the edge chosen by PCR bit 0
+    pub fn set_ca1(&mut self, level: bool) {
+        if level != self.ca1 && level == (self.pcr & PCR_CA1_RISING != 0) {
+            self.set_flags(INTERRUPT_CA1);
+        }
+        self.ca1 = level;
+    }
+
+    // Drive the CB1 line, which sets its interrupt flag on the edge chosen by PCR bit 4
+    pub fn set_cb1(&mut self, level: bool) {
+        if level != self.cb1 && level == (self.pcr & PCR_CB1_RISING != 0) {
+            self.set_flags(INTERRUPT_CB1);
+        }
+        self.cb1 = level;
+    }
+
+    pub fn timer1(&self) -> u16 {
+        self.t1_counter
+    }
+
+    pub fn timer2(&self) -> u16 {
+        self.t2_counter
+    }
+
+    // The interrupt flags, with bit 7 set while any enabled interrupt is pending
+    pub fn flags(&self) -> u8 {
+        let flags = self.ifr.get() & !INTERRUPT_ANY;
+        if flags & self.ier != 0 { flags | INTERRUPT_ANY } else { flags }
+    }
+
+    fn set_flags(&self, flags: u8) {
+        self.ifr.set(self.ifr.get() | flags);
+    }
+
+    fn clear_flags(&self, flags: u8) {
+        self.ifr.set(self.ifr.get() & !flags);
+    }
+
+    fn register(&self, address: u16) -> Result<u32, MemoryError> {
+        let address = address as u32;
+        if address < self.offset || address >= self.offset + VIA_SIZE {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Ok(address - self.offset)
+    }
+
+    // A register's value without the side effects of reading it
+    fn value(&self, register: u32) -> u8 {
+        match register {
+            0 => self.port_b(),
+            1 | 15 => self.port_a(),
+            2 => self.ddr_b,
+            3 => self.ddr_a,
+            4 => self.t1_counter as u8,
+            5 => (self.t1_counter >> 8) as u8,
+            6 => self.t1_latch as u8,
+            7 => (self.t1_latch >> 8) as u8,
+            8 => self.t2_counter as u8,
+            9 => (self.t2_counter >> 8) as u8,
+            10 => self.shift,
+            11 => self.acr,
+            12 => self.pcr,
+            13 => self.flags(),
+            _ => self.ier | INTERRUPT_ANY
+        }
+    }
+
+    // Count timer 1 down, a period at a time. Each cycle it either reloads from the latches, on the cycle after it
+    // passes zero in free-running mode, or counts down by one.
+    fn count_t1(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.t1_reload {
+                self.t1_reload = false;
+                self.t1_counter = self.t1_latch;
+                cycles -= 1;
+                continue;
+            }
+            let underflow = self.t1_counter as u32 + 1;
+            if cycles < underflow {
+                self.t1_counter -= cycles as u16;
+                return;
+            }
+            cycles -= underflow;
+            self.t1_counter = 0xffff;
+            let free_run = self.acr & ACR_T1_FREE_RUN != 0;
+            if self.t1_armed {
+                self.set_flags(INTERRUPT_T1);
+                self.t1_armed = free_run;
+            }
+            self.t1_reload = free_run;
+            if !free_run {
+                // Nothing more happens until it is written, so it just wraps around
+                self.t1_counter = self.t1_counter.wrapping_sub(cycles as u16);
+                return;
+            }
+        }
+    }
+
+    fn count_t2(&mut self, cycles: u32) {
+        if self.acr & ACR_T2_PULSE_COUNT != 0 {
+            return;
+        }
+        if self.t2_armed && cycles > self.t2_counter as u32 {
+            self.set_flags(INTERRUPT_T2);
+            self.t2_armed = false;
+        }
+        self.t2_counter = self.t2_counter.wrapping_sub(cycles as u16);
+    }
+}
+
+impl Memory for VIA {
+    fn read(&self, address: u16) -> MemoryReadResult {
+        let register = self.register(address)?;
+        match register {
+            0 => self.clear_flags(INTERRUPT_CB1 | INTERRUPT_CB2),
+            1 => self.clear_flags(INTERRUPT_CA1 | INTERRUPT_CA2),
+            4 => self.clear_flags(INTERRUPT_T1),
+            8 => self.clear_flags(INTERRUPT_T2),
+            _ => {}
+        }
+        Ok(self.value(register))
+    }
+
+    fn peek(&self, address: u16) -> MemoryReadResult {
+        Ok(self.value(self.register(address)?))
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> MemoryWriteResult {
+        let register = self.register(address)?;
+        match register {
+            0 => {
+                self.output_b = value;
+                self.clear_flags(INTERRUPT_CB1 | INTERRUPT_CB2);
+            }
+            1 => {
+                self.output_a = value;
+                self.clear_flags(INTERRUPT_CA1 | INTERRUPT_CA2);
+            }
+            2 => self.ddr_b = value,
+            3 => self.ddr_a = value,
+            4 | 6 => self.t1_latch = (self.t1_latch & 0xff00) | value as u16,
+            5 => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | (value as u16) << 8;
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.t1_reload = false;
+                self.clear_flags(INTERRUPT_T1);
+            }
+            7 => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | (value as u16) << 8;
+                self.clear_flags(INTERRUPT_T1);
+            }
+            8 => self.t2_latch_low = value,
+            9 => {
+                self.t2_counter = (value as u16) << 8 | self.t2_latch_low as u16;
+                self.t2_armed = true;
+                self.clear_flags(INTERRUPT_T2);
+            }
+            10 => self.shift = value,
+            11 => self.acr = value,
+            12 => self.pcr = value,
+            13 => self.clear_flags(value & !INTERRUPT_ANY),
+            14 if value & INTERRUPT_ANY != 0 => self.ier |= value & !INTERRUPT_ANY,
+            14 => self.ier &= !value,
+            _ => self.output_a = value
+        }
+        if matches!(register, 0..=3 | 15) {
+            self.update_pins();
+        }
+        Ok(())
+    }
+
+    fn type_of(&self) -> MemoryType {
+        MemoryType::MMIO
+    }
+
+    fn load(&mut self, _data: Vec<u8>) -> MemoryWriteResult {
+        Err(MemoryError::ReadOnly)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.count_t1(cycles);
+        self.count_t2(cycles);
+        for device in &mut self.devices {
+            device.tick(cycles);
+        }
+    }
+
+    // When the next enabled timer interrupt is raised. A timer that cannot interrupt changes nothing that is not seen
+    // by reading it, which catches it up.
+    fn next_event(&self) -> Option<u64> {
+        let t1 = (self.t1_armed && self.ier & INTERRUPT_T1 != 0).then(|| match self.t1_reload {
+            true => self.t1_latch as u64 + 2,
+            false => self.t1_counter as u64 + 1
+        });
+        let t2 = self.t2_armed && self.ier & INTERRUPT_T2 != 0 && self.acr & ACR_T2_PULSE_COUNT == 0;
+        let t2 = t2.then_some(self.t2_counter as u64 + 1);
+        let devices = self.devices.iter().filter_map(|device| device.next_event());
+        t1.into_iter().chain(t2).chain(devices).min()
+    }
+
+    fn irq(&self) -> bool {
+        self.flags() & INTERRUPT_ANY != 0
+    }
+
+    // The input of each attached device in chunks of up to 255 bytes, each after the device's index and its length
+    fn take_input(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (index, device) in self.devices.iter_mut().enumerate() {
+            for chunk in device.take_input().chunks(u8::MAX as usize) {
+                data.extend_from_slice(&[index as u8, chunk.len() as u8]);
+                data.extend_from_slice(chunk);
+            }
+        }
+        data
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        let mut data = data;
+        while let [index, length, rest @ ..] = data {
+            let length = (*length as usize).min(rest.len());
+            if let Some(device) = self.devices.get_mut(*index as usize) {
+                device.input(&rest[..length]);
+            }
+            data = &rest[length..];
+        }
+    }
+
+    fn make_deterministic(&mut self, time: i64, clock_rate: u32) {
+        for device in &mut self.devices {
+            device.make_deterministic(time, clock_rate);
+        }
+    }
+
+    // The registers, the timers and the levels driven onto the pins, then each attached device in a block of its own
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_raw(&[self.output_a, self.output_b, self.ddr_a, self.ddr_b, self.input_a, self.input_b]);
+        state.write_bool(self.ca1);
+        state.write_bool(self.cb1);
+        state.write_u16(self.t1_counter);
+        state.write_u16(self.t1_latch);
+        state.write_bool(self.t1_armed);
+        state.write_bool(self.t1_reload);
+        state.write_u16(self.t2_counter);
+        state.write_u8(self.t2_latch_low);
+        state.write_bool(self.t2_armed);
+        state.write_raw(&[self.shift, self.acr, self.pcr, self.ifr.get(), self.ier]);
+        state.write_u32(self.devices.len() as u32);
+        for device in &self.devices {
+            let mut block = StateWriter::new();
+            device.save_state(&mut block);
+            state.write_bytes(&block.into_bytes());
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ports = state.read_raw(6)?;
+        (self.output_a, self.output_b, self.ddr_a, self.ddr_b) = (ports[0], ports[1], ports[2], ports[3]);
+        (self.input_a, self.input_b) = (ports[4], ports[5]);
+        self.ca1 = state.read_bool()?;
+        self.cb1 = state.read_bool()?;
+        self.t1_counter = state.read_u16()?;
+        self.t1_latch = state.read_u16()?;
+        self.t1_armed = state.read_bool()?;
+        self.t1_reload = state.read_bool()?;
+        self.t2_counter = state.read_u16()?;
+        self.t2_latch_low = state.read_u8()?;
+        self.t2_armed = state.read_bool()?;
+        let registers = state.read_raw(5)?;
+        (self.shift, self.acr, self.pcr, self.ier) = (registers[0], registers[1], registers[2], registers[4]);
+        self.ifr.set(registers[3]);
+        let count = state.read_u32()? as usize;
+        if count != self.devices.len() {
+            let message = format!("{} VIA port devices saved, but {} are attached", count, self.devices.len());
+            return Err(StateError::Mismatch(message));
+        }
+        for device in &mut self.devices {
+            device.load_state(&mut StateReader::new(state.read_bytes()?))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn via_ports() -> Result<(), MemoryError> {
+        let mut via = VIA::new(0x6000);
+        via.set_input_b(0x0f);
+        via.write(0x6002, 0xf0)?;
+        via.write(0x6000, 0xaa)?;
+        assert_eq!(via.read(0x6000)?, 0xaf);
+        assert_eq!(via.port_b(), 0xaf);
+
+        // Undriven inputs are pulled up
+        via.write(0x6003, 0x0f)?;
+        via.write(0x600f, 0x05)?;
+        assert_eq!(via.read(0x6001)?, 0xf5);
+
+        // CA1 interrupts on the falling edge by default, until it is disabled or the port is read
+        via.write(0x600e, INTERRUPT_ANY | INTERRUPT_CA1)?;
+        via.set_ca1(false);
+        assert!(via.irq());
+        assert_eq!(via.read(0x600d)?, INTERRUPT_ANY | INTERRUPT_CA1);
+        assert_eq!(via.read(0x600e)?, INTERRUPT_ANY | INTERRUPT_CA1);
+        via.read(0x6001)?;
+        assert!(!via.irq());
+        via.set_ca1(true);
+        assert!(!via.irq());
+        assert!(via.read(0x6010).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn via_timers() -> Result<(), MemoryError> {
+        let mut via = VIA::new(0x6000);
+        via.write(0x600e, INTERRUPT_ANY | INTERRUPT_T1 | INTERRUPT_T2)?;
+
+        // One shot: the interrupt comes once, N + 1 cycles after the timer is started
+        via.write(0x6004, 10)?;
+        via.write(0x6005, 0)?;
+        via.tick(10);
+        assert!(!via.irq());
+        via.tick(1);
+        assert!(via.irq());
+        assert_eq!(via.read(0x6004)?, 0xff);
+        assert!(!via.irq());
+        via.tick(0x10000);
+        assert!(!via.irq());
+
+        // Free running: every N + 2 cycles
+        via.write(0x600b, ACR_T1_FREE_RUN)?;
+        via.write(0x6005, 0)?;
+        via.tick(11);
+        assert!(via.irq());
+        via.write(0x600d, INTERRUPT_T1)?;
+        via.tick(11);
+        assert!(!via.irq());
+        via.tick(1);
+        assert!(via.irq());
+        via.write(0x600e, INTERRUPT_T1)?;
+        assert!(!via.irq());
+
+        // Timer 2 starts from its latched low byte
+        via.write(0x6008, 3)?;
+        via.write(0x6009, 0)?;
+        assert_eq!(via.peek(0x6008)?, 3);
+        via.tick(4);
+        assert_eq!(via.flags() & (INTERRUPT_ANY | INTERRUPT_T2), INTERRUPT_ANY | INTERRUPT_T2);
+        via.read(0x6008)?;
+        assert!(!via.irq());
+
+        let mut state = StateWriter::new();
+        via.save_state(&mut state);
+        let mut copy = VIA::new(0x6000);
+        copy.load_state(&mut StateReader::new(&state.into_bytes())).unwrap();
+        assert_eq!((copy.timer1(), copy.timer2(), copy.flags()), (via.timer1(), via.timer2(), via.flags()));
+        Ok(())
+    }
+
+    #[test]
+    fn via_events() -> Result<(), MemoryError> {
+        // Timer 1 free running every 7 cycles and timer 2 one-shot, ticked a cycle at a time and in one go
+        let mut vias = [VIA::new(0x6000), VIA::new(0x6000)];
+        for via in &mut vias {
+            via.write(0x600b, ACR_T1_FREE_RUN)?;
+            via.write(0x6004, 5)?;
+            via.write(0x6005, 0)?;
+            via.write(0x6008, 40)?;
+            via.write(0x6009, 0)?;
+        }
+        let [cycle_by_cycle, in_one_go] = &mut vias;
+        for cycles in [1, 3, 7, 20, 100, 0x10003] {
+            for _ in 0..cycles {
+                cycle_by_cycle.tick(1);
+            }
+            in_one_go.tick(cycles);
+            let state = |via: &VIA| (via.timer1(), via.timer2(), via.flags());
+            assert_eq!(state(cycle_by_cycle), state(in_one_go));
+        }
+
+        // Only enabled interrupts make events
+        let mut via = VIA::new(0x6000);
+        via.write(0x6004, 10)?;
+        via.write(0x6005, 0)?;
+        via.write(0x6008, 3)?;
+        via.write(0x6009, 0)?;
+        assert_eq!(via.next_event(), None);
+        via.write(0x600e, INTERRUPT_ANY | INTERRUPT_T1)?;
+        assert_eq!(via.next_event(), Some(11));
+        via.write(0x600e, INTERRUPT_ANY | INTERRUPT_T2)?;
+        assert_eq!(via.next_event(), Some(4));
+        via.tick(4);
+        assert_eq!(via.next_event(), Some(7));
+        via.tick(7);
+        assert!(via.irq());
+        assert_eq!(via.next_event(), None);
+        Ok(())
+    }
+}
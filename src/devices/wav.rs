@@ -0,0 +1,135 @@
+/*!
+ * WAV Writer
+ *
+ * Audio devices render 16 bit mono samples into memory; this writes them out as a canonical PCM WAV file so that
+ * rendered audio can be compared offline. A WavWriter streams samples to a file as they are produced, for runs too long
+ * to keep all of their audio in memory, and fills in the sizes in the header when it is finished.
+ */
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+fn write_header<W: Write>(out: &mut W, sample_rate: u32, data_size: u32) -> std::io::Result<()> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    // fmt chunk: PCM, one channel, 16 bits per sample
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())
+}
+
+pub fn write_wav<W: Write>(out: &mut W, sample_rate: u32, samples: &[i16]) -> std::io::Result<()> {
+    write_header(out, sample_rate, (samples.len() * 2) as u32)?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+pub fn save_wav<P: AsRef<Path>>(path: P, sample_rate: u32, samples: &[i16]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_wav(&mut out, sample_rate, samples)?;
+    out.flush()
+}
+
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    data_size: u32
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> std::io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> std::io::Result<Self> {
+        write_header(&mut out, sample_rate, 0)?;
+        Ok(WavWriter { out, sample_rate, data_size: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> std::io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size = self.data_size.saturating_add((samples.len() * 2) as u32);
+        Ok(())
+    }
+
+    // Rewrite the header with the size of the samples written so far, leaving the file ready for more
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.out, self.sample_rate, self.data_size)?;
+        self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 + self.data_size as u64))?;
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            eprintln!("WavWriter: Unable to finish the file: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_header() {
+        let mut out = Vec::new();
+        write_wav(&mut out, 44100, &[0x1234, -2]).unwrap();
+
+        assert_eq!(out.len(), 44 + 4);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &40u32.to_le_bytes());
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(&out[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&out[36..40], b"data");
+        assert_eq!(&out[40..44], &4u32.to_le_bytes());
+        assert_eq!(&out[44..], &[0x34, 0x12, 0xfe, 0xff]);
+    }
+
+    #[test]
+    fn wav_writer() -> std::io::Result<()> {
+        let mut writer = WavWriter::new(std::io::Cursor::new(Vec::new()), 44100)?;
+        writer.write_samples(&[0x1234])?;
+        writer.write_samples(&[-2])?;
+        writer.finish()?;
+
+        // The same file as writing all the samples at once
+        let mut out = Vec::new();
+        write_wav(&mut out, 44100, &[0x1234, -2])?;
+        assert_eq!(writer.get_ref().get_ref(), &out);
+
+        // More samples can follow a finish
+        writer.write_samples(&[0x0001])?;
+        writer.finish()?;
+        assert_eq!(&writer.get_ref().get_ref()[40..44], &6u32.to_le_bytes());
+        assert_eq!(writer.get_ref().get_ref().len(), 44 + 6);
+        Ok(())
+    }
+}
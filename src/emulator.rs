@@ -1 +1,9 @@
-pub mod emulator;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod emulator;
+pub mod breakpoints;
+pub mod expression;
+pub mod history;
+pub mod replay;
+pub mod scheduler;
+pub mod throttle;
+pub mod trace;
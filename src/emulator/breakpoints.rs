@@ -0,0 +1,317 @@
+/*!
+ * Breakpoints and Watchpoints
+ *
+ * The Emulator keeps a set of breakpoints, each of which stops execution on one kind of event:
+ * - Execute: the program counter reaches an address, checked before the instruction runs
+ * - Opcode: an instruction with a given opcode is about to run
+ * - Watch: a read, a write or either touches an address in a range, checked after the instruction that made the access
+ *
+ * Any breakpoint can have a condition, in which case it only counts as hit when the condition is true. Every hit is
+ * counted, and a breakpoint can be told to ignore its first hits. Temporary breakpoints delete themselves once hit.
+ */
+
+use std::fmt;
+
+use crate::cpu::cpu::*;
+use crate::devices::memory_map::*;
+use crate::emulator::expression::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access
+}
+
+impl WatchKind {
+    fn matches(&self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => true
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    Execute(u16),
+    Opcode(u8),
+    // An inclusive range of addresses
+    Watch(u16, u16, WatchKind)
+}
+
+impl fmt::Display for BreakpointKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakpointKind::Execute(address) => write!(f, "exec ${:04x}", address),
+            BreakpointKind::Opcode(opcode) => write!(f, "opcode ${:02x}", opcode),
+            BreakpointKind::Watch(start, end, kind) => {
+                let kind = match kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access"
+                };
+                write!(f, "{} ${:04x}", kind, start)?;
+                if start != end {
+                    write!(f, "-${:04x}", end)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    pub condition: Option<Expression>,
+    pub enabled: bool,
+    pub temporary: bool,
+    // The number of hits to let pass before stopping
+    pub ignore: u64,
+    pub hits: u64
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakpointKind) -> Breakpoint {
+        Breakpoint { kind, condition: None, enabled: true, temporary: false, ignore: 0, hits: 0 }
+    }
+
+    pub fn execute(address: u16) -> Breakpoint {
+        Breakpoint::new(BreakpointKind::Execute(address))
+    }
+
+    pub fn opcode(opcode: u8) -> Breakpoint {
+        Breakpoint::new(BreakpointKind::Opcode(opcode))
+    }
+
+    pub fn watch(start: u16, end: u16, kind: WatchKind) -> Breakpoint {
+        Breakpoint::new(BreakpointKind::Watch(start, end, kind))
+    }
+
+    pub fn with_condition(self, condition: Expression) -> Breakpoint {
+        Breakpoint { condition: Some(condition), ..self }
+    }
+
+    pub fn with_ignore(self, ignore: u64) -> Breakpoint {
+        Breakpoint { ignore, ..self }
+    }
+
+    pub fn temporary(self) -> Breakpoint {
+        Breakpoint { temporary: true, ..self }
+    }
+}
+
+// Why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // An execution or opcode breakpoint, with its number
+    Breakpoint(u32),
+    // A watchpoint, with its number and the access that triggered it
+    Watchpoint(u32, Access),
+    // The requested number of instructions ran without anything stopping them
+    Limit
+}
+
+#[derive(Debug)]
+pub struct Breakpoints {
+    breakpoints: Vec<(u32, Breakpoint)>,
+    next: u32
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints { breakpoints: Vec::new(), next: 1 }
+    }
+
+    // Add a breakpoint, returning the number it is known by
+    pub fn add(&mut self, breakpoint: Breakpoint) -> u32 {
+        let number = self.next;
+        self.next += 1;
+        self.breakpoints.push((number, breakpoint));
+        number
+    }
+
+    pub fn remove(&mut self, number: u32) -> Option<Breakpoint> {
+        let index = self.breakpoints.iter().position(|(n, _)| *n == number)?;
+        Some(self.breakpoints.remove(index).1)
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn get(&self, number: u32) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|(n, _)| *n == number).map(|(_, breakpoint)| breakpoint)
+    }
+
+    pub fn get_mut(&mut self, number: u32) -> Option<&mut Breakpoint> {
+        self.breakpoints.iter_mut().find(|(n, _)| *n == number).map(|(_, breakpoint)| breakpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Breakpoint)> {
+        self.breakpoints.iter().map(|(number, breakpoint)| (*number, breakpoint))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    // Whether any watchpoints are enabled, and so memory accesses need to be recorded
+    pub fn watching(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(_, breakpoint)| breakpoint.enabled && matches!(breakpoint.kind, BreakpointKind::Watch(..)))
+    }
+
+    // Count a hit on every breakpoint that matches and whose condition holds, returning the first that should stop
+    // execution. Temporary breakpoints are removed once they stop it.
+    fn hit<F>(&mut self, cpu: &CPU, memory: &MemoryMap, matches: F) -> Option<u32>
+    where
+        F: Fn(&BreakpointKind) -> bool
+    {
+        let mut stop = None;
+        for (number, breakpoint) in &mut self.breakpoints {
+            if !breakpoint.enabled || !matches(&breakpoint.kind) {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition.test(cpu, memory) {
+                    continue;
+                }
+            }
+            breakpoint.hits += 1;
+            if breakpoint.hits > breakpoint.ignore && stop.is_none() {
+                stop = Some(*number);
+            }
+        }
+
+        if let Some(number) = stop {
+            if self.get(number).is_some_and(|breakpoint| breakpoint.temporary) {
+                self.remove(number);
+            }
+        }
+        stop
+    }
+
+    // Check the execution and opcode breakpoints against the instruction about to run
+    pub fn check_execute(&mut self, cpu: &CPU, memory: &MemoryMap) -> Option<StopReason> {
+        let pc = cpu.pc();
+        let opcode = memory.peek(pc).ok();
+        self.hit(cpu, memory, |kind| match kind {
+            BreakpointKind::Execute(address) => *address == pc,
+            BreakpointKind::Opcode(code) => Some(*code) == opcode,
+            _ => false
+        })
+        .map(StopReason::Breakpoint)
+    }
+
+    // Check the watchpoints against the memory accesses made by the last instruction
+    pub fn check_accesses(&mut self, cpu: &CPU, memory: &MemoryMap, accesses: &[Access]) -> Option<StopReason> {
+        for access in accesses {
+            let number = self.hit(cpu, memory, |kind| match kind {
+                BreakpointKind::Watch(start, end, watch) => {
+                    (*start..=*end).contains(&access.address) && watch.matches(access.kind)
+                }
+                _ => false
+            });
+            if let Some(number) = number {
+                return Some(StopReason::Watchpoint(number, *access));
+            }
+        }
+        None
+    }
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::memory::*;
+
+    fn machine() -> (CPU, MemoryMap) {
+        let mut memory = MemoryMap::new();
+        memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        memory.write(0xc000, 0xea).unwrap();
+        let mut cpu = CPU::new();
+        cpu.set_pc(0xc000);
+        (cpu, memory)
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (cpu, memory) = machine();
+        let mut breakpoints = Breakpoints::new();
+        let first = breakpoints.add(Breakpoint::execute(0xc000));
+        let second = breakpoints.add(Breakpoint::opcode(0xea));
+        assert_eq!((first, second), (1, 2));
+
+        // Both match, and both count the hit, but the first one is reported
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), Some(StopReason::Breakpoint(1)));
+        assert_eq!(breakpoints.get(first).unwrap().hits, 1);
+        assert_eq!(breakpoints.get(second).unwrap().hits, 1);
+
+        breakpoints.get_mut(first).unwrap().enabled = false;
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), Some(StopReason::Breakpoint(2)));
+        assert!(breakpoints.remove(second).is_some());
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), None);
+        assert_eq!(breakpoints.iter().count(), 1);
+    }
+
+    #[test]
+    fn breakpoints_conditions() {
+        let (mut cpu, memory) = machine();
+        let mut breakpoints = Breakpoints::new();
+        let condition = Expression::parse("a == $ff").unwrap();
+        breakpoints.add(Breakpoint::execute(0xc000).with_condition(condition));
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), None);
+        assert_eq!(breakpoints.get(1).unwrap().hits, 0);
+
+        cpu.set_a(0xff);
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), Some(StopReason::Breakpoint(1)));
+    }
+
+    #[test]
+    fn breakpoints_ignore_and_temporary() {
+        let (cpu, memory) = machine();
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.add(Breakpoint::execute(0xc000).with_ignore(2).temporary());
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), None);
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), None);
+        assert_eq!(breakpoints.check_execute(&cpu, &memory), Some(StopReason::Breakpoint(1)));
+        assert!(breakpoints.is_empty());
+
+        // Numbers are not reused
+        assert_eq!(breakpoints.add(Breakpoint::opcode(0x00)), 2);
+    }
+
+    #[test]
+    fn breakpoints_watch() {
+        let (cpu, memory) = machine();
+        let mut breakpoints = Breakpoints::new();
+        assert!(!breakpoints.watching());
+        breakpoints.add(Breakpoint::watch(0x0200, 0x02ff, WatchKind::Write));
+        breakpoints.add(Breakpoint::watch(0x0010, 0x0010, WatchKind::Access));
+        assert!(breakpoints.watching());
+
+        let read = |address| Access { address, kind: AccessKind::Read, value: 0 };
+        let write = |address| Access { address, kind: AccessKind::Write, value: 0 };
+        assert_eq!(breakpoints.check_accesses(&cpu, &memory, &[read(0x0200), write(0x0300)]), None);
+        assert_eq!(
+            breakpoints.check_accesses(&cpu, &memory, &[read(0x0200), write(0x02ff)]),
+            Some(StopReason::Watchpoint(1, write(0x02ff)))
+        );
+        assert_eq!(
+            breakpoints.check_accesses(&cpu, &memory, &[read(0x0010)]),
+            Some(StopReason::Watchpoint(2, read(0x0010)))
+        );
+        assert_eq!(breakpoints.get(2).unwrap().kind.to_string(), "access $0010");
+        assert_eq!(breakpoints.get(1).unwrap().kind.to_string(), "write $0200-$02ff");
+    }
+}
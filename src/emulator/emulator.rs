@@ -1,26 +1,467 @@
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use crate::cpu::cpu::*;
-use crate::devices::memory::*;
 use crate::devices::memory_map::*;
+use crate::devices::save_state::*;
+use crate::emulator::breakpoints::*;
+use crate::emulator::history::*;
+use crate::emulator::replay::*;
+use crate::emulator::scheduler::*;
+use crate::emulator::throttle::*;
+use crate::emulator::trace::*;
+use crate::machine::machine::*;
+use crate::symbols::symbols::*;
+
+// A save state file starts with the magic and the version of the format, followed by the CPU, the NMI line and then
+// every device in the memory map. Bump the version whenever anything saved changes.
+pub const STATE_MAGIC: &[u8; 8] = b"REMUSAVE";
+pub const STATE_VERSION: u16 = 2;
+
+// The CPU clock when nothing says otherwise, in Hz
+pub const DEFAULT_CLOCK_RATE: u32 = 1_000_000;
 
 #[derive(Debug)]
 pub struct Emulator {
     cpu: CPU,
-    memory_map: MemoryMap
+    memory_map: MemoryMap,
+    clock_rate: u32,
+    throttle: Throttle,
+    scheduler: Scheduler,
+    // The devices the memory map says were touched, kept to be reused
+    touched: Vec<usize>,
+    // Whether NMI was asserted after the last instruction, since the CPU only takes it when it is first asserted
+    nmi: bool,
+    breakpoints: Breakpoints,
+    tracer: Option<Tracer>,
+    symbols: SymbolTable,
+    history: Option<History>,
+    // The reads and writes made by the last instruction, when something needed them recorded
+    accesses: Vec<Access>,
+    input: InputMode
 }
 
 impl Emulator {
     pub fn new() -> Emulator {
         Emulator {
             cpu: CPU::new(),
-            memory_map: MemoryMap::new()
+            memory_map: MemoryMap::new(),
+            clock_rate: DEFAULT_CLOCK_RATE,
+            throttle: Throttle::new(DEFAULT_CLOCK_RATE, Speed::REAL_TIME),
+            scheduler: Scheduler::new(),
+            touched: Vec::new(),
+            nmi: false,
+            breakpoints: Breakpoints::new(),
+            tracer: None,
+            symbols: SymbolTable::new(),
+            history: None,
+            accesses: Vec::new(),
+            input: InputMode::Live
         }
     }
 
-    pub fn init(&mut self) {
-        // Create a MemoryMap and add the RAM and ROM to it
-        self.memory_map.create(String::from("RAM"), MemoryType::RAM, 0x4000, 0x0000).unwrap();
-        self.memory_map.create(String::from("IO"), MemoryType::MMIO, 0x4000, 0x4000).unwrap();
-        self.memory_map.create(String::from("ROM"), MemoryType::ROM, 0x8000, 0x8000).unwrap();
+    // Set up the BE6502: 16K of RAM, a 6522 VIA at $6000 and a 32K ROM at $8000
+    pub fn init(&mut self) -> Result<(), MachineError> {
+        let config = MachineConfig::preset("be6502")
+            .ok_or_else(|| MachineError::Config(String::from("There is no be6502 preset")))?;
+        self.memory_map = config.memory_map()?;
+        self.cpu = CPU::with_model(config.model);
+        self.scheduler.clear();
+        self.set_clock_rate(config.clock_rate);
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn memory_map_mut(&mut self) -> &mut MemoryMap {
+        &mut self.memory_map
+    }
+
+    // Build the machine a configuration describes, with its ROM images loaded
+    pub fn from_config(config: &MachineConfig) -> Result<Emulator, MachineError> {
+        let mut emulator = Emulator::new();
+        emulator.cpu = CPU::with_model(config.model);
+        emulator.memory_map = config.memory_map()?;
+        emulator.set_clock_rate(config.clock_rate);
+        Ok(emulator)
+    }
+
+    // The frequency of the CPU clock in Hz
+    pub fn clock_rate(&self) -> u32 {
+        self.clock_rate
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = clock_rate.max(1);
+        self.throttle.set_clock_rate(self.clock_rate);
+    }
+
+    // How fast to run against the clock when paced
+    pub fn speed(&self) -> Speed {
+        self.throttle.speed()
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.throttle.set_speed(speed);
+    }
+
+    // Sleep until real time catches up with the cycles run, for a frontend to call between batches of instructions.
+    // Pausing for long, or restoring a state, starts the pacing again rather than rushing to catch up.
+    pub fn pace(&mut self) {
+        let delay = self.pace_delay(Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    // How long pace would sleep at `now`
+    pub fn pace_delay(&mut self, now: Instant) -> Duration {
+        self.throttle.delay(self.cpu.cycles(), now)
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    // Start tracing every instruction executed, or stop with None. The old tracer is flushed and handed back.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        let mut old = std::mem::replace(&mut self.tracer, tracer);
+        if let Some(old) = &mut old {
+            // A trace that cannot be written should not stop the machine
+            old.flush().ok();
+        }
+        old
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // Start keeping a history of the instructions executed so they can be gone back to, or stop with None
+    pub fn set_history(&mut self, history: Option<History>) -> Option<History> {
+        std::mem::replace(&mut self.history, history)
+    }
+
+    pub fn input_mode(&self) -> &InputMode {
+        &self.input
+    }
+
+    // Start recording the input the machine receives, from a snapshot of it as it is now. The devices are made
+    // deterministic first, with emulated time starting from the host's time.
+    pub fn start_recording(&mut self) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as i64).unwrap_or(0);
+        self.memory_map.make_deterministic(time, self.clock_rate);
+        self.input = InputMode::Recording(Recording::new(time, self.snapshot()));
+    }
+
+    // Stop recording or replaying and go back to live input, handing back the recording
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        match std::mem::take(&mut self.input) {
+            InputMode::Live => None,
+            InputMode::Recording(recording) | InputMode::Replaying(recording, _) => Some(recording)
+        }
+    }
+
+    // Go back to where a recording started and replay its input from there. The machine must have the same devices
+    // as the one recorded.
+    pub fn start_replay(&mut self, recording: Recording) -> Result<(), StateError> {
+        self.memory_map.make_deterministic(recording.time, self.clock_rate);
+        self.restore(&recording.snapshot)?;
+        self.input = InputMode::Replaying(recording, 0);
+        Ok(())
+    }
+
+    // Whether a replay has delivered all of its input
+    pub fn replay_finished(&self) -> bool {
+        match &self.input {
+            InputMode::Replaying(recording, next) => *next >= recording.events.len(),
+            _ => false
+        }
+    }
+
+    // Run the CPU's reset sequence, which picks up the program counter from the reset vector
+    pub fn start(&mut self) -> CpuStepResult {
+        self.cpu.start(&self.memory_map)
+    }
+
+    // Execute one instruction, servicing a pending IRQ first, and tick the devices with events due by the end of it.
+    // The instruction is traced before it runs, and journalled in the history after.
+    pub fn step(&mut self) -> CpuStepResult {
+        if self.history.as_ref().is_some_and(History::checkpoint_due) {
+            let snapshot = self.snapshot();
+            if let Some(history) = &mut self.history {
+                history.add_checkpoint(snapshot);
+            }
+        }
+        if let Some(history) = &mut self.history {
+            history.begin(&self.cpu);
+        }
+
+        let recording = self.history.is_some() || self.breakpoints.watching();
+        self.memory_map.set_recording(recording);
+        let result = self.execute();
+        self.accesses = self.memory_map.take_accesses();
+        self.memory_map.set_recording(false);
+
+        if let (Ok(_), Some(history)) = (&result, &mut self.history) {
+            history.end(&self.accesses);
+        }
+        result
+    }
+
+    fn execute(&mut self) -> CpuStepResult {
+        self.schedule();
+        let mut cycles = 0;
+        let was_asserted = std::mem::replace(&mut self.nmi, self.memory_map.nmi());
+        if self.nmi && !was_asserted {
+            cycles += self.cpu.nmi(&mut self.memory_map)?;
+        } else if self.memory_map.irq() {
+            cycles += self.cpu.irq(&mut self.memory_map)?;
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.cpu, &self.memory_map, Some(&self.symbols)).ok();
+        }
+        match self.cpu.step(&mut self.memory_map) {
+            Ok(step) => cycles += step,
+            Err(error) => {
+                // Write out the instructions that led up to the failure
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.flush().ok();
+                }
+                return Err(error);
+            }
+        }
+        self.run_events();
+        self.deliver_input();
+        Ok(cycles)
+    }
+
+    // Bring the scheduler up to date before an instruction. When the CPU's cycle count has jumped, as it does when a
+    // state is restored, the devices start again from it; otherwise only the devices touched since the last
+    // instruction, such as by a debugger or new input, are asked for their next events again.
+    fn schedule(&mut self) {
+        let cycle = self.cpu.cycles();
+        if cycle != self.memory_map.time() {
+            self.memory_map.reset_time(cycle);
+            self.scheduler.clear();
+            for device in 0..self.memory_map.count() {
+                self.scheduler.schedule(device, self.memory_map.next_event(device));
+            }
+        }
+        self.reschedule_touched();
+    }
+
+    // Move the time on to the end of the instruction just run, and tick each device whose event came due during it.
+    // A device is ticked up to the end of the instruction, as if it had been ticked after every instruction.
+    fn run_events(&mut self) {
+        let cycle = self.cpu.cycles();
+        self.memory_map.advance(cycle);
+        self.reschedule_touched();
+        while let Some(device) = self.scheduler.pop_due(cycle) {
+            self.memory_map.catch_up(device);
+            self.scheduler.schedule(device, self.memory_map.next_event(device));
+        }
+    }
+
+    // Writing a register can move a device's next event, such as restarting a timer, and so can reading one
+    fn reschedule_touched(&mut self) {
+        self.memory_map.take_touched(&mut self.touched);
+        for &device in &self.touched {
+            self.scheduler.schedule(device, self.memory_map.next_event(device));
+        }
+    }
+
+    // Hand the input that arrived during an instruction to the devices, recording it if need be. When replaying, the
+    // input comes from the recording instead, and anything from the host is dropped.
+    fn deliver_input(&mut self) {
+        let cycle = self.cpu.cycles();
+        let host = self.memory_map.take_input();
+        match &mut self.input {
+            InputMode::Replaying(recording, next) => {
+                while let Some(event) = recording.events.get(*next).filter(|event| event.cycle <= cycle) {
+                    // The devices were checked to be the same when the snapshot was restored
+                    self.memory_map.input(&event.device, &event.data).ok();
+                    *next += 1;
+                }
+            }
+            input => {
+                for (device, data) in host {
+                    self.memory_map.input(&device, &data).ok();
+                    if let InputMode::Recording(recording) = input {
+                        recording.events.push(InputEvent { cycle, device, data });
+                    }
+                }
+            }
+        }
+    }
+
+    // Check the execution and opcode breakpoints against the instruction about to run
+    pub fn check_breakpoints(&mut self) -> Option<StopReason> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        self.breakpoints.check_execute(&self.cpu, &self.memory_map)
+    }
+
+    // Execute one instruction like step, then check the watchpoints against the memory it accessed
+    pub fn step_watched(&mut self) -> Result<Option<StopReason>, CpuError> {
+        self.step()?;
+        if !self.breakpoints.watching() {
+            return Ok(None);
+        }
+        Ok(self.breakpoints.check_accesses(&self.cpu, &self.memory_map, &self.accesses))
+    }
+
+    // Run up to `instructions` instructions, stopping early on a breakpoint or watchpoint. Breakpoints are not checked
+    // before the first instruction, so that running again after a breakpoint does not stop at once.
+    pub fn run(&mut self, instructions: u64) -> Result<StopReason, CpuError> {
+        for count in 0..instructions {
+            if count > 0 {
+                if let Some(stop) = self.check_breakpoints() {
+                    return Ok(stop);
+                }
+            }
+            if let Some(stop) = self.step_watched()? {
+                return Ok(stop);
+            }
+        }
+        Ok(StopReason::Limit)
+    }
+
+    // Run until at least `cycles` more CPU cycles have gone by, stopping early like run
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<StopReason, CpuError> {
+        let end = self.cpu.cycles().saturating_add(cycles);
+        let mut first = true;
+        while self.cpu.cycles() < end {
+            if !first {
+                if let Some(stop) = self.check_breakpoints() {
+                    return Ok(stop);
+                }
+            }
+            if let Some(stop) = self.step_watched()? {
+                return Ok(stop);
+            }
+            first = false;
+        }
+        Ok(StopReason::Limit)
+    }
+
+    // Capture the state of the machine: the CPU, including its cycle count and whether it is waiting for an
+    // interrupt, and every device in the memory map. A device asserting IRQ is still asserting it once restored.
+    // Breakpoints, symbols and the tracer belong to the debugger and are not part of the state.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_raw(STATE_MAGIC);
+        state.write_u16(STATE_VERSION);
+        self.cpu.save_state(&mut state);
+        state.write_bool(self.nmi);
+        self.memory_map.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // Go back to a snapshot taken of this machine. Nothing is changed if it was taken of a machine with other devices.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        if state.read_raw(STATE_MAGIC.len()).ok() != Some(&STATE_MAGIC[..]) {
+            return Err(StateError::BadMagic);
+        }
+        let version = state.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::Version(version));
+        }
+
+        let mut cpu = CPU::new();
+        cpu.load_state(&mut state)?;
+        let nmi = state.read_bool()?;
+        self.memory_map.load_state(&mut state)?;
+        if !state.is_empty() {
+            return Err(StateError::Invalid(String::from("Trailing data")));
+        }
+        self.cpu = cpu;
+        self.nmi = nmi;
+        self.throttle.reset();
+        // The history leads up to the old state, not this one
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    // Go back to just before an instruction in the history ran, by restoring the checkpoint before it and running
+    // forward again. The instructions replayed are not traced and do not stop at breakpoints.
+    pub fn rewind(&mut self, instruction: u64) -> Result<(), HistoryError> {
+        let mut history = self.history.take().ok_or(HistoryError::Disabled)?;
+        let result = self.replay(&history, instruction);
+        if result.is_ok() {
+            history.truncate(instruction);
+        }
+        self.history = Some(history);
+        result
+    }
+
+    fn replay(&mut self, history: &History, instruction: u64) -> Result<(), HistoryError> {
+        if instruction < history.earliest() || instruction > history.count() {
+            return Err(HistoryError::OutOfRange(instruction));
+        }
+        let checkpoint = history.checkpoint_before(instruction).ok_or(HistoryError::OutOfRange(instruction))?;
+        self.restore(&checkpoint.snapshot)?;
+        let tracer = self.tracer.take();
+        let result = (checkpoint.instruction..instruction).try_for_each(|_| self.step().map(|_| ()));
+        self.tracer = tracer;
+        Ok(result?)
+    }
+
+    // Step backwards over the last `count` instructions
+    pub fn step_back(&mut self, count: u64) -> Result<(), HistoryError> {
+        let current = self.history.as_ref().ok_or(HistoryError::Disabled)?.count();
+        self.rewind(current.checked_sub(count).ok_or(HistoryError::OutOfRange(0))?)
+    }
+
+    // Go back to just before the last instruction that wrote to an address in a range, and return its journal entry.
+    // Returns None, without going anywhere, if no instruction in the history wrote there.
+    pub fn rewind_to_write(&mut self, start: u16, end: u16) -> Result<Option<JournalEntry>, HistoryError> {
+        let history = self.history.as_ref().ok_or(HistoryError::Disabled)?;
+        let Some(entry) = history.last_write(start, end).cloned() else {
+            return Ok(None);
+        };
+        self.rewind(entry.instruction)?;
+        Ok(Some(entry))
+    }
+
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), StateError> {
+        std::fs::write(path, self.snapshot())?;
+        Ok(())
+    }
+
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), StateError> {
+        let data = std::fs::read(path)?;
+        self.restore(&data)
     }
 
     pub fn warm_reset(&mut self) {
@@ -28,26 +469,38 @@ impl Emulator {
     }
 
     pub fn cold_reset(&mut self) {
-        // Zero out the RAM
-        for i in 0..0x4000 {
-            self.memory_map.write(i, 0).unwrap();
-        }
+        // Zero out the RAM, wherever the machine has it
+        self.memory_map.clear_ram();
 
         // Reset the CPU
         self.cpu.reset();
     }
 }
 
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::memory::*;
+    use crate::devices::eeprom::*;
+    use crate::devices::ds1307::*;
+    use crate::devices::i2c::*;
+    use crate::devices::keyboard::*;
+    use crate::devices::text_display::*;
+    use crate::devices::via::*;
+    use crate::emulator::expression::*;
 
     #[test]
     fn emulator() {
         let mut emulator = Emulator::new();
         
         // Initialize the emulator
-        emulator.init();
+        emulator.init().unwrap();
 
         // Load test data in to RAM
         emulator.memory_map.write(0x0000, 0x12).unwrap();
@@ -80,4 +533,377 @@ mod tests {
         assert_eq!(emulator.memory_map.read(0x0003).unwrap(), 0x00);
         
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn emulator_step() {
+        let mut emulator = Emulator::new();
+        emulator.init().unwrap();
+
+        // Reset vector to $C000: LDA #$42; STA $0200
+        let mut rom = vec![0; 0x8000];
+        rom[0x4000..0x4005].copy_from_slice(&[0xa9, 0x42, 0x8d, 0x00, 0x02]);
+        rom[0x7ffc] = 0x00;
+        rom[0x7ffd] = 0xc0;
+        emulator.memory_map.load("ROM", rom).unwrap();
+
+        emulator.start().unwrap();
+        assert_eq!(emulator.cpu().pc(), 0xc000);
+        assert_eq!(emulator.step().unwrap(), 2);
+        assert_eq!(emulator.step().unwrap(), 4);
+        assert_eq!(emulator.memory_map().read(0x0200).unwrap(), 0x42);
+        assert_eq!(emulator.cpu().cycles(), 13);
+    }
+
+    #[test]
+    fn emulator_breakpoints() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+
+        // $0200: LDX #$00; loop: INX; TXA; STA $10,X; BNE loop
+        let program = [0xa2, 0x00, 0xe8, 0x8a, 0x95, 0x10, 0xd0, 0xfa];
+        for (offset, &byte) in program.iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.cpu_mut().set_pc(0x0200);
+
+        let condition = Expression::parse("x == 3").unwrap();
+        let breakpoint = emulator.breakpoints_mut().add(Breakpoint::execute(0x0206).with_condition(condition));
+        assert_eq!(emulator.run(100).unwrap(), StopReason::Breakpoint(breakpoint));
+        assert_eq!(emulator.cpu().x(), 3);
+        assert_eq!(emulator.breakpoints().get(breakpoint).unwrap().hits, 1);
+        emulator.breakpoints_mut().clear();
+
+        // A write to $0020 happens when X is $10
+        let watchpoint = emulator.breakpoints_mut().add(Breakpoint::watch(0x0020, 0x0020, WatchKind::Write));
+        let access = Access { address: 0x0020, kind: AccessKind::Write, value: 0x10 };
+        assert_eq!(emulator.run(100).unwrap(), StopReason::Watchpoint(watchpoint, access));
+        assert_eq!(emulator.cpu().pc(), 0x0206);
+        emulator.breakpoints_mut().clear();
+
+        assert_eq!(emulator.run(3).unwrap(), StopReason::Limit);
+
+        // Reads by the debugger do not trigger watchpoints
+        emulator.breakpoints_mut().add(Breakpoint::watch(0x0000, 0xffff, WatchKind::Read));
+        emulator.memory_map().peek(0x0200).unwrap();
+        emulator.memory_map().read(0x0200).unwrap();
+        assert!(emulator.memory_map().take_accesses().is_empty());
+    }
+
+    #[test]
+    fn emulator_trace() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+
+        // $0200: INX; INX; JAM
+        for (offset, &byte) in [0xe8, 0xe8, 0x02].iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.cpu_mut().set_pc(0x0200);
+
+        let path = std::env::temp_dir().join(format!("mini-6502-remu-trace-{}.log", std::process::id()));
+        emulator.set_tracer(Some(Tracer::file(&path, Some(2)).unwrap()));
+        emulator.step().unwrap();
+        emulator.step().unwrap();
+        assert!(emulator.step().is_err());
+
+        // The ring buffer was written out when the CPU jammed
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0201  E8        INX"));
+        assert!(lines[1].starts_with("0202  02       *JAM"));
+
+        assert!(emulator.set_tracer(None).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn emulator_save_state() {
+        let machine = || {
+            let mut emulator = Emulator::new();
+            emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x8000, 0x0000).unwrap();
+            let display = TextDisplay::new(16, 2, 0x8000);
+            let size = display.size();
+            emulator.memory_map_mut().insert(String::from("Display"), Box::new(display), size, 0x8000).unwrap();
+            let mut port = I2cPort::new(0x8100);
+            port.attach(Box::new(EEPROM::new(0)));
+            emulator.memory_map_mut().insert(String::from("I2C"), Box::new(port), 1, 0x8100).unwrap();
+            emulator
+        };
+        let mut emulator = machine();
+
+        // $0200: loop: INX; STX $8000; TXA; STA $10,X; JMP loop
+        let program = [0xe8, 0x8e, 0x00, 0x80, 0x8a, 0x95, 0x10, 0x4c, 0x00, 0x02];
+        for (offset, &byte) in program.iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.cpu_mut().set_pc(0x0200);
+        emulator.run(10).unwrap();
+        let snapshot = emulator.snapshot();
+        assert_eq!(&snapshot[..10], b"REMUSAVE\x02\x00");
+
+        emulator.run(25).unwrap();
+        let registers = (emulator.cpu().pc(), emulator.cpu().x(), emulator.cpu().cycles());
+        let later = emulator.snapshot();
+
+        // Going back and running again ends up in exactly the same place, in a new machine too
+        emulator.restore(&snapshot).unwrap();
+        assert_eq!(emulator.cpu().x(), 2);
+        assert_eq!(emulator.memory_map().read(0x8000).unwrap(), 0x02);
+        emulator.run(25).unwrap();
+        assert_eq!((emulator.cpu().pc(), emulator.cpu().x(), emulator.cpu().cycles()), registers);
+        assert_eq!(emulator.snapshot(), later);
+
+        let path = std::env::temp_dir().join(format!("mini-6502-remu-state-{}.sav", std::process::id()));
+        emulator.save_state(&path).unwrap();
+        let mut copy = machine();
+        copy.load_state(&path).unwrap();
+        assert_eq!(copy.snapshot(), later);
+        std::fs::remove_file(&path).unwrap();
+
+        // States that cannot be restored leave the machine alone
+        let mut other = Emulator::new();
+        other.init().unwrap();
+        assert!(matches!(other.restore(&later), Err(StateError::Mismatch(_))));
+        assert_eq!(other.cpu().x(), 0);
+        assert!(matches!(copy.restore(b"not a save state"), Err(StateError::BadMagic)));
+        let mut version = later.clone();
+        version[8] = 1;
+        assert!(matches!(copy.restore(&version), Err(StateError::Version(1))));
+        assert!(matches!(copy.restore(&later[..later.len() - 1]), Err(StateError::Truncated)));
+        assert_eq!(copy.snapshot(), later);
+    }
+
+    #[test]
+    fn emulator_run_cycles() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+
+        // $0200: loop: INX (2 cycles); JMP loop (3 cycles)
+        for (offset, &byte) in [0xe8, 0x4c, 0x00, 0x02].iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.cpu_mut().set_pc(0x0200);
+        assert_eq!(emulator.run_cycles(11).unwrap(), StopReason::Limit);
+        assert_eq!((emulator.cpu().cycles(), emulator.cpu().x()), (12, 3));
+
+        // Breakpoints stop it early, but not on the instruction it starts on
+        let number = emulator.breakpoints_mut().add(Breakpoint::execute(0x0201));
+        assert_eq!(emulator.run_cycles(100).unwrap(), StopReason::Breakpoint(number));
+        assert_eq!((emulator.cpu().cycles(), emulator.cpu().pc()), (17, 0x0201));
+        assert_eq!(emulator.run_cycles(6).unwrap(), StopReason::Breakpoint(number));
+        assert_eq!(emulator.cpu().cycles(), 22);
+    }
+
+    #[test]
+    fn emulator_scheduler() {
+        // $0200: start timer 1 free running every $22 cycles with its interrupt enabled, then read timer 2 in a loop.
+        // $0300: the handler acknowledges the interrupt and lengthens the period by a cycle each time.
+        let program = [
+            0xa9, 0x40, 0x8d, 0x0b, 0x60, 0xa9, 0x20, 0x8d, 0x04, 0x60, 0xa9, 0x00, 0x8d, 0x05, 0x60, 0xa9, 0xc0, 0x8d,
+            0x0e, 0x60, 0x58, 0xad, 0x08, 0x60, 0x85, 0x10, 0x4c, 0x15, 0x02
+        ];
+        let handler = [0xad, 0x04, 0x60, 0xee, 0x06, 0x60, 0xe6, 0x11, 0x40];
+        let machine = || {
+            let mut emulator = Emulator::new();
+            let memory_map = emulator.memory_map_mut();
+            memory_map.create(String::from("RAM"), MemoryType::RAM, 0x6000, 0x0000).unwrap();
+            memory_map.insert(String::from("VIA"), Box::new(VIA::new(0x6000)), VIA_SIZE, 0x6000).unwrap();
+            memory_map.create(String::from("High RAM"), MemoryType::RAM, 0xa000 - VIA_SIZE, 0x6010).unwrap();
+            for (offset, &byte) in program.iter().enumerate() {
+                memory_map.write(0x0200 + offset as u16, byte).unwrap();
+            }
+            for (offset, &byte) in handler.iter().enumerate() {
+                memory_map.write(0x0300 + offset as u16, byte).unwrap();
+            }
+            memory_map.write(0xffff, 0x03).unwrap();
+            emulator.cpu_mut().set_pc(0x0200);
+            emulator
+        };
+
+        // The interrupts are taken on the same cycles as when the VIA is ticked after every instruction
+        let interrupts = |tick_every_instruction: bool| {
+            let mut emulator = machine();
+            let mut cycles = Vec::new();
+            for _ in 0..5000 {
+                emulator.step().unwrap();
+                if tick_every_instruction {
+                    emulator.memory_map_mut().tick(0);
+                }
+                // The interrupt is taken in the same step as the first instruction of the handler
+                if emulator.cpu().pc() == 0x0303 {
+                    cycles.push(emulator.cpu().cycles());
+                }
+            }
+            (cycles, emulator.memory_map().peek(0x0011).unwrap(), emulator.memory_map().peek(0x6004).unwrap())
+        };
+        let (cycles, count, timer) = interrupts(false);
+        assert!(cycles.len() > 100);
+        assert_eq!(count as usize, cycles.len() % 256);
+        assert_eq!((cycles, count, timer), interrupts(true));
+    }
+
+    #[test]
+    fn emulator_pace() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        for (offset, &byte) in [0xe8, 0x4c, 0x00, 0x02].iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.cpu_mut().set_pc(0x0200);
+
+        // Batches of 1000 cycles at 100 kHz take 10ms each, measured from the end of the first
+        emulator.set_clock_rate(100_000);
+        let start = Instant::now();
+        let at = |milliseconds: u64| start + Duration::from_millis(milliseconds);
+        emulator.run_cycles(1000).unwrap();
+        assert_eq!(emulator.pace_delay(at(0)), Duration::ZERO);
+        emulator.run_cycles(1000).unwrap();
+        assert_eq!(emulator.pace_delay(at(3)), Duration::from_millis(7));
+        emulator.run_cycles(1000).unwrap();
+        assert_eq!(emulator.pace_delay(at(25)), Duration::ZERO);
+
+        // Changing the speed starts measuring again
+        emulator.set_speed(Speed::Scaled(2.0));
+        assert_eq!(emulator.pace_delay(at(25)), Duration::ZERO);
+        emulator.run_cycles(1000).unwrap();
+        assert_eq!(emulator.pace_delay(at(25)), Duration::from_millis(5));
+
+        emulator.set_speed(Speed::Unthrottled);
+        assert_eq!(emulator.speed(), Speed::Unthrottled);
+        emulator.run_cycles(1000).unwrap();
+        assert_eq!(emulator.pace_delay(at(25)), Duration::ZERO);
+    }
+
+    #[test]
+    fn emulator_history() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+
+        // $0200: loop: INX; STX $10; TXA; AND #$07; BNE skip; STX $0300; skip: JMP loop
+        let program = [0xe8, 0x86, 0x10, 0x8a, 0x29, 0x07, 0xd0, 0x03, 0x8e, 0x00, 0x03, 0x4c, 0x00, 0x02];
+        for (offset, &byte) in program.iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.memory_map_mut().write(0x0300, 0xff).unwrap();
+        emulator.cpu_mut().set_pc(0x0200);
+        assert!(matches!(emulator.step_back(1), Err(HistoryError::Disabled)));
+
+        emulator.set_history(Some(History::new(100, 8)));
+        emulator.run(20).unwrap();
+        let snapshot = emulator.snapshot();
+        emulator.run(30).unwrap();
+        assert_eq!(emulator.history().unwrap().count(), 50);
+
+        // Back to instruction 20, and forward again to where we were
+        let later = emulator.snapshot();
+        emulator.step_back(30).unwrap();
+        assert_eq!(emulator.snapshot(), snapshot);
+        emulator.run(30).unwrap();
+        assert_eq!(emulator.snapshot(), later);
+
+        // One instruction back undoes the STX
+        emulator.run(1).unwrap();
+        let x = emulator.cpu().x();
+        emulator.step_back(1).unwrap();
+        assert_eq!(emulator.cpu().pc(), 0x0201);
+        assert_eq!(emulator.memory_map().read(0x0010).unwrap(), x.wrapping_sub(1));
+
+        // Who last wrote $0300? The STX when X was 8
+        let entry = emulator.rewind_to_write(0x0300, 0x0300).unwrap().unwrap();
+        assert_eq!((entry.pc, entry.x, entry.writes.clone()), (0x0208, 0x08, vec![(0x0300, 0x08)]));
+        assert_eq!(emulator.cpu().pc(), 0x0208);
+        assert_eq!(emulator.history().unwrap().count(), entry.instruction);
+        assert_eq!(emulator.memory_map().read(0x0300).unwrap(), 0xff);
+        assert_eq!(emulator.rewind_to_write(0x4000, 0x4000).unwrap(), None);
+
+        // The window bounds how far back we can go
+        emulator.run(500).unwrap();
+        let history = emulator.history().unwrap();
+        assert!(history.count() - history.earliest() < 100 + 8);
+        assert!(history.journal().count() < 100 + 8);
+        assert!(matches!(emulator.rewind(0), Err(HistoryError::OutOfRange(0))));
+        assert!(matches!(emulator.step_back(1000), Err(HistoryError::OutOfRange(_))));
+    }
+
+    #[test]
+    fn emulator_replay() {
+        let machine = || {
+            let mut emulator = Emulator::new();
+            emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x8000, 0x0000).unwrap();
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let mut keyboard = Keyboard::new(KeyboardMode::Ascii, 0x8000);
+            keyboard.attach_receiver(receiver);
+            emulator.memory_map_mut().insert(String::from("KBD"), Box::new(keyboard), KEYBOARD_SIZE, 0x8000).unwrap();
+            let mut port = I2cPort::new(0x8100);
+            port.attach(Box::new(DS1307::new(ClockSource::Host)));
+            emulator.memory_map_mut().insert(String::from("I2C"), Box::new(port), 1, 0x8100).unwrap();
+
+            // $0200: loop: LDA $8001; BPL loop; LDA $8000; STA $0300,X; INX; JMP loop
+            let program = [0xad, 0x01, 0x80, 0x10, 0xfb, 0xad, 0x00, 0x80, 0x9d, 0x00, 0x03, 0xe8, 0x4c, 0x00, 0x02];
+            for (offset, &byte) in program.iter().enumerate() {
+                emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+            }
+            emulator.cpu_mut().set_pc(0x0200);
+            (emulator, sender)
+        };
+
+        // Keys arrive from the host at times that depend on the host
+        let (mut emulator, sender) = machine();
+        emulator.run(10).unwrap();
+        emulator.start_recording();
+        for (steps, keys) in [(37, "A"), (100, "B"), (15, "C"), (60, "D"), (200, "")] {
+            emulator.run(steps).unwrap();
+            keys.bytes().for_each(|key| sender.send(key).unwrap());
+        }
+        let keys = (0..4).map(|index| emulator.memory_map().read(0x0300 + index).unwrap()).collect::<Vec<u8>>();
+        assert_eq!(keys, b"ABCD".map(|key| key | 0x80));
+        let end = emulator.snapshot();
+        let recording = emulator.stop_recording().unwrap();
+        assert_eq!(recording.events.iter().map(|event| event.data.len()).collect::<Vec<usize>>(), vec![1; 4]);
+        assert!(emulator.stop_recording().is_none());
+
+        // Replaying in another machine ends up in exactly the same state, without what the host types there
+        let (mut copy, sender) = machine();
+        copy.start_replay(Recording::from_bytes(&recording.to_bytes()).unwrap()).unwrap();
+        sender.send(b'X').unwrap();
+        copy.run(412).unwrap();
+        assert!(copy.replay_finished());
+        assert_eq!(copy.snapshot(), end);
+    }
+
+    #[test]
+    fn emulator_nmi() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x8000, 0x0000).unwrap();
+        emulator.memory_map_mut().create(String::from("Vectors"), MemoryType::RAM, 0x1000, 0xf000).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut keyboard = Keyboard::new(KeyboardMode::Ascii, 0x8000);
+        keyboard.attach_receiver(receiver);
+        emulator.memory_map_mut().insert(String::from("KBD"), Box::new(keyboard), KEYBOARD_SIZE, 0x8000).unwrap();
+        emulator.memory_map_mut().set_interrupt_line("KBD", InterruptLine::Nmi).unwrap();
+
+        // $0200: LDA #$01; STA $8002; loop: JMP loop, with an NMI handler at $0300 that counts in X: INX; RTI
+        let program = [0xa9, 0x01, 0x8d, 0x02, 0x80, 0x4c, 0x05, 0x02];
+        for (offset, &byte) in program.iter().chain(&[0; 0xf8]).chain(&[0xe8, 0x40]).enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.memory_map_mut().write(0xfffa, 0x00).unwrap();
+        emulator.memory_map_mut().write(0xfffb, 0x03).unwrap();
+        emulator.cpu_mut().set_pc(0x0200);
+
+        // The key holds NMI down, which is only taken once, even with interrupts disabled
+        emulator.cpu_mut().set_flags(0x04);
+        emulator.run(5).unwrap();
+        sender.send(b'A').unwrap();
+        emulator.run(20).unwrap();
+        assert_eq!(emulator.cpu().x(), 1);
+        assert!(emulator.memory_map().nmi() && !emulator.memory_map().irq());
+
+        // Once acknowledged, the next key is another NMI
+        emulator.memory_map_mut().write(0x8002, 0x81).unwrap();
+        sender.send(b'B').unwrap();
+        emulator.run(20).unwrap();
+        assert_eq!(emulator.cpu().x(), 2);
+    }
+}
@@ -0,0 +1,383 @@
+/*!
+ * Break Conditions
+ *
+ * Small C-like expressions over the state of the machine, such as `a == $ff && [$10] > 3`, used to make breakpoints
+ * and watchpoints conditional. The names that can be used are:
+ * - the registers a, x, y, sp, pc and p (the status register)
+ * - the flags n, v, d, i, z and c, which are 0 or 1
+ * - cycles, the number of cycles the CPU has executed
+ *
+ * `[address]` is the byte in memory at an address, read without side effects. Numbers are decimal unless prefixed
+ * with `$` for hexadecimal or `%` for binary, since a bare hexadecimal number would be ambiguous with the register
+ * names. The operators and their precedence follow C: `||`, `&&`, `|`, `^`, `&`, `==` `!=`, `<` `<=` `>` `>=`,
+ * `<<` `>>`, `+` `-`, `*` `/` `%`, and the unary `!` `-` `~`. Comparisons and logical operators give 0 or 1, and any
+ * value other than 0 is true.
+ */
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::cpu::cpu::*;
+use crate::devices::memory_map::*;
+
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    UnexpectedEnd,
+    UnexpectedToken(String),
+    UnknownName(String),
+    BadNumber(String)
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpressionError::UnexpectedEnd => write!(f, "Unexpected end of expression"),
+            ExpressionError::UnexpectedToken(token) => write!(f, "Unexpected {}", token),
+            ExpressionError::UnknownName(name) => write!(f, "Unknown name: {}", name),
+            ExpressionError::BadNumber(number) => write!(f, "Bad number: {}", number)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Name {
+    A,
+    X,
+    Y,
+    SP,
+    PC,
+    P,
+    Flag(u8),
+    Cycles
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(Name),
+    Operator(&'static str)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(i64),
+    Name(Name),
+    Memory(Box<Node>),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>)
+}
+
+// Longer operators come first so that `<=` is not read as `<`
+const OPERATORS: [&str; 23] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~", "(",
+    ")", "["
+];
+
+// Binary operators from the loosest binding to the tightest
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"]
+];
+
+fn name(text: &str) -> Result<Name, ExpressionError> {
+    Ok(match text {
+        "a" => Name::A,
+        "x" => Name::X,
+        "y" => Name::Y,
+        "sp" => Name::SP,
+        "pc" => Name::PC,
+        "p" => Name::P,
+        "n" => Name::Flag(FLAG_NEGATIVE),
+        "v" => Name::Flag(FLAG_OVERFLOW),
+        "d" => Name::Flag(FLAG_DECIMAL),
+        "i" => Name::Flag(FLAG_INTERRUPT),
+        "z" => Name::Flag(FLAG_ZERO),
+        "c" => Name::Flag(FLAG_CARRY),
+        "cycles" => Name::Cycles,
+        _ => return Err(ExpressionError::UnknownName(String::from(text)))
+    })
+}
+
+fn word(chars: &mut Peekable<Chars>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_alphanumeric() && c != '_' {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let radix = match c {
+            '$' => Some(16),
+            '%' if matches!(tokens.last(), None | Some(Token::Operator(_))) => Some(2),
+            _ if c.is_ascii_digit() => Some(10),
+            _ => None
+        };
+        if let Some(radix) = radix {
+            if radix != 10 {
+                chars.next();
+            }
+            let digits = word(&mut chars);
+            let value = i64::from_str_radix(&digits, radix).map_err(|_| ExpressionError::BadNumber(digits))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() {
+            tokens.push(Token::Name(name(&word(&mut chars).to_lowercase())?));
+        } else if c == ']' {
+            chars.next();
+            tokens.push(Token::Operator("]"));
+        } else {
+            let rest = chars.clone().collect::<String>();
+            let operator = OPERATORS
+                .iter()
+                .find(|operator| rest.starts_with(*operator))
+                .ok_or_else(|| ExpressionError::UnexpectedToken(c.to_string()))?;
+            for _ in 0..operator.len() {
+                chars.next();
+            }
+            tokens.push(Token::Operator(operator));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) => Some(operator),
+            _ => None
+        }
+    }
+
+    fn expect(&mut self, operator: &str) -> Result<(), ExpressionError> {
+        match self.next() {
+            Some(Token::Operator(found)) if found == operator => Ok(()),
+            Some(token) => Err(unexpected(token)),
+            None => Err(ExpressionError::UnexpectedEnd)
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, ExpressionError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(operator) = self.peek_operator().filter(|operator| PRECEDENCE[level].contains(operator)) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Node::Number(value)),
+            Some(Token::Name(name)) => Ok(Node::Name(name)),
+            Some(Token::Operator(operator @ ("!" | "-" | "~"))) => Ok(Node::Unary(operator, Box::new(self.unary()?))),
+            Some(Token::Operator("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Operator("[")) => {
+                let node = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Memory(Box::new(node)))
+            }
+            Some(token) => Err(unexpected(token)),
+            None => Err(ExpressionError::UnexpectedEnd)
+        }
+    }
+}
+
+fn unexpected(token: Token) -> ExpressionError {
+    ExpressionError::UnexpectedToken(match token {
+        Token::Number(value) => value.to_string(),
+        Token::Name(name) => format!("{:?}", name).to_lowercase(),
+        Token::Operator(operator) => String::from(operator)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    root: Node
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ExpressionError> {
+        let mut parser = Parser { tokens: tokenize(source)?, position: 0 };
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.next() {
+            return Err(unexpected(token));
+        }
+        Ok(Expression { source: String::from(source.trim()), root })
+    }
+
+    pub fn evaluate(&self, cpu: &CPU, memory: &MemoryMap) -> i64 {
+        evaluate(&self.root, cpu, memory)
+    }
+
+    // Whether the expression is true, meaning it evaluates to anything but 0
+    pub fn test(&self, cpu: &CPU, memory: &MemoryMap) -> bool {
+        self.evaluate(cpu, memory) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn evaluate(node: &Node, cpu: &CPU, memory: &MemoryMap) -> i64 {
+    match node {
+        Node::Number(value) => *value,
+        Node::Name(name) => match name {
+            Name::A => cpu.a() as i64,
+            Name::X => cpu.x() as i64,
+            Name::Y => cpu.y() as i64,
+            Name::SP => cpu.sp() as i64,
+            Name::PC => cpu.pc() as i64,
+            Name::P => cpu.flags() as i64,
+            Name::Flag(flag) => cpu.flag(*flag) as i64,
+            Name::Cycles => cpu.cycles() as i64
+        },
+        Node::Memory(address) => {
+            let address = evaluate(address, cpu, memory) as u16;
+            memory.peek(address).unwrap_or(0xff) as i64
+        }
+        Node::Unary(operator, operand) => {
+            let value = evaluate(operand, cpu, memory);
+            match *operator {
+                "!" => (value == 0) as i64,
+                "-" => value.wrapping_neg(),
+                _ => !value
+            }
+        }
+        Node::Binary(operator, left, right) => {
+            let left = evaluate(left, cpu, memory);
+
+            // The logical operators short-circuit, so a false guard keeps the right side from being evaluated
+            match *operator {
+                "&&" => return (left != 0 && evaluate(right, cpu, memory) != 0) as i64,
+                "||" => return (left != 0 || evaluate(right, cpu, memory) != 0) as i64,
+                _ => {}
+            }
+
+            let right = evaluate(right, cpu, memory);
+            match *operator {
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                "<=" => (left <= right) as i64,
+                ">" => (left > right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "/" => left.checked_div(right).unwrap_or(0),
+                _ => left.checked_rem(right).unwrap_or(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::memory::*;
+
+    fn machine() -> (CPU, MemoryMap) {
+        let mut memory = MemoryMap::new();
+        memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        memory.write(0x0010, 0x05).unwrap();
+        memory.write(0x0011, 0x12).unwrap();
+
+        let mut cpu = CPU::new();
+        cpu.set_a(0xff);
+        cpu.set_x(0x01);
+        cpu.set_pc(0xc000);
+        cpu.set_flags(FLAG_CARRY);
+        cpu.set_cycles(1000);
+        (cpu, memory)
+    }
+
+    fn evaluate(source: &str) -> i64 {
+        let (cpu, memory) = machine();
+        Expression::parse(source).unwrap().evaluate(&cpu, &memory)
+    }
+
+    #[test]
+    fn expression() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9);
+        assert_eq!(evaluate("$10 | %0101"), 0x15);
+        assert_eq!(evaluate("7 % 4 - -1"), 4);
+        assert_eq!(evaluate("1 << 4 >> 2"), 4);
+        assert_eq!(evaluate("!0 + ~0"), 0);
+        assert_eq!(evaluate("5 / 0"), 0);
+        assert_eq!(evaluate("1 < 2 == 1"), 1);
+    }
+
+    #[test]
+    fn expression_machine() {
+        assert_eq!(evaluate("a == $ff && [$10] > 3"), 1);
+        assert_eq!(evaluate("A == $ff && [$10] > 5"), 0);
+        assert_eq!(evaluate("[$10 + x]"), 0x12);
+        assert_eq!(evaluate("pc"), 0xc000);
+        assert_eq!(evaluate("c && !z"), 1);
+        assert_eq!(evaluate("p & $01"), 1);
+        assert_eq!(evaluate("cycles >= 1000"), 1);
+        assert_eq!(evaluate("x % 2 == 1"), 1);
+    }
+
+    #[test]
+    fn expression_errors() {
+        assert_eq!(Expression::parse("a =="), Err(ExpressionError::UnexpectedEnd));
+        assert_eq!(Expression::parse("q > 1"), Err(ExpressionError::UnknownName(String::from("q"))));
+        assert_eq!(Expression::parse("$zz"), Err(ExpressionError::BadNumber(String::from("zz"))));
+        assert_eq!(Expression::parse("(1"), Err(ExpressionError::UnexpectedEnd));
+        assert_eq!(Expression::parse("1 2"), Err(ExpressionError::UnexpectedToken(String::from("2"))));
+        assert_eq!(Expression::parse("a = 1"), Err(ExpressionError::UnexpectedToken(String::from("="))));
+        assert_eq!(Expression::parse("[1"), Err(ExpressionError::UnexpectedEnd));
+        assert_eq!(Expression::parse("a == $ff").unwrap().to_string(), "a == $ff");
+    }
+}
@@ -0,0 +1,263 @@
+/*!
+ * Execution History
+ *
+ * While a History is attached to the Emulator, every instruction is numbered and journalled: the registers before it
+ * ran and the memory writes it made. Every so many instructions a checkpoint (a snapshot of the whole machine) is
+ * taken as well. Going back to any instruction in the history restores the checkpoint before it and runs forward
+ * again, which lands in exactly the same state as long as the machine is deterministic. Input from the host, such as
+ * keys typed on the terminal, and changes made by a debugger between instructions are not replayed.
+ *
+ * Only the last `window` instructions are kept, give or take one checkpoint interval, which bounds the memory used.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::cpu::cpu::*;
+use crate::devices::memory_map::*;
+use crate::devices::save_state::*;
+
+pub const DEFAULT_WINDOW: u64 = 100_000;
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000;
+
+#[derive(Debug)]
+pub enum HistoryError {
+    // No history is being kept
+    Disabled,
+    // The instruction is before the start of the history, or has not run yet
+    OutOfRange(u64),
+    State(StateError),
+    Cpu(CpuError)
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Disabled => write!(f, "History is off"),
+            HistoryError::OutOfRange(instruction) => write!(f, "Instruction {} is not in the history", instruction),
+            HistoryError::State(error) => write!(f, "{}", error),
+            HistoryError::Cpu(error) => write!(f, "{:?}", error)
+        }
+    }
+}
+
+impl From<StateError> for HistoryError {
+    fn from(error: StateError) -> Self {
+        HistoryError::State(error)
+    }
+}
+
+impl From<CpuError> for HistoryError {
+    fn from(error: CpuError) -> Self {
+        HistoryError::Cpu(error)
+    }
+}
+
+// One instruction in the journal
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    // The number of the instruction, counting from when the history was attached
+    pub instruction: u64,
+    // The registers before the instruction ran
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+    pub cycles: u64,
+    // The addresses written by the instruction and the values written
+    pub writes: Vec<(u16, u8)>
+}
+
+impl JournalEntry {
+    fn new(instruction: u64, cpu: &CPU) -> JournalEntry {
+        JournalEntry {
+            instruction,
+            pc: cpu.pc(),
+            a: cpu.a(),
+            x: cpu.x(),
+            y: cpu.y(),
+            sp: cpu.sp(),
+            p: cpu.flags(),
+            cycles: cpu.cycles(),
+            writes: Vec::new()
+        }
+    }
+
+    pub fn wrote(&self, start: u16, end: u16) -> bool {
+        self.writes.iter().any(|&(address, _)| address >= start && address <= end)
+    }
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    // The state before this instruction ran
+    pub instruction: u64,
+    pub snapshot: Vec<u8>
+}
+
+#[derive(Debug)]
+pub struct History {
+    window: u64,
+    interval: u64,
+    // The number of the next instruction to run
+    count: u64,
+    checkpoints: VecDeque<Checkpoint>,
+    journal: VecDeque<JournalEntry>,
+    // The entry for the instruction that is running
+    pending: Option<JournalEntry>
+}
+
+impl History {
+    // Keep the last `window` instructions, with a checkpoint every `interval` instructions
+    pub fn new(window: u64, interval: u64) -> History {
+        History {
+            window: window.max(1),
+            interval: interval.max(1),
+            count: 0,
+            checkpoints: VecDeque::new(),
+            journal: VecDeque::new(),
+            pending: None
+        }
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    // The number of the next instruction to run, which is also how many have run
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // The first instruction that can still be gone back to
+    pub fn earliest(&self) -> u64 {
+        self.checkpoints.front().map_or(self.count, |checkpoint| checkpoint.instruction)
+    }
+
+    pub fn journal(&self) -> impl DoubleEndedIterator<Item = &JournalEntry> {
+        self.journal.iter()
+    }
+
+    pub fn entry(&self, instruction: u64) -> Option<&JournalEntry> {
+        let first = self.journal.front()?.instruction;
+        self.journal.get(instruction.checked_sub(first)? as usize)
+    }
+
+    // The most recent instruction that wrote to an address in a range
+    pub fn last_write(&self, start: u16, end: u16) -> Option<&JournalEntry> {
+        self.journal.iter().rev().find(|entry| entry.wrote(start, end))
+    }
+
+    // Forget everything, as when the machine is changed from outside
+    pub fn clear(&mut self) {
+        self.count = 0;
+        self.checkpoints.clear();
+        self.journal.clear();
+        self.pending = None;
+    }
+
+    pub(crate) fn checkpoint_due(&self) -> bool {
+        self.checkpoints.back().is_none_or(|checkpoint| self.count - checkpoint.instruction >= self.interval)
+    }
+
+    pub(crate) fn add_checkpoint(&mut self, snapshot: Vec<u8>) {
+        self.checkpoints.push_back(Checkpoint { instruction: self.count, snapshot });
+    }
+
+    // The checkpoint to replay from to get back to an instruction
+    pub(crate) fn checkpoint_before(&self, instruction: u64) -> Option<&Checkpoint> {
+        self.checkpoints.iter().rev().find(|checkpoint| checkpoint.instruction <= instruction)
+    }
+
+    // Note the registers before an instruction runs
+    pub(crate) fn begin(&mut self, cpu: &CPU) {
+        self.pending = Some(JournalEntry::new(self.count, cpu));
+    }
+
+    // Journal the instruction that began, now that it ran without an error
+    pub(crate) fn end(&mut self, accesses: &[Access]) {
+        let Some(mut entry) = self.pending.take() else {
+            return;
+        };
+        entry.writes = accesses
+            .iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .map(|access| (access.address, access.value))
+            .collect();
+        self.journal.push_back(entry);
+        self.count += 1;
+        self.trim();
+    }
+
+    // Drop what is older than the window, keeping the checkpoint the oldest instruction in the window replays from
+    fn trim(&mut self) {
+        let oldest = self.count.saturating_sub(self.window);
+        while self.checkpoints.get(1).is_some_and(|checkpoint| checkpoint.instruction <= oldest) {
+            self.checkpoints.pop_front();
+        }
+        let earliest = self.earliest();
+        while self.journal.front().is_some_and(|entry| entry.instruction < earliest) {
+            self.journal.pop_front();
+        }
+    }
+
+    // Forget the instructions from `instruction` on, after going back to it
+    pub(crate) fn truncate(&mut self, instruction: u64) {
+        self.count = instruction;
+        self.pending = None;
+        while self.checkpoints.back().is_some_and(|checkpoint| checkpoint.instruction > instruction) {
+            self.checkpoints.pop_back();
+        }
+        while self.journal.back().is_some_and(|entry| entry.instruction >= instruction) {
+            self.journal.pop_back();
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW, DEFAULT_CHECKPOINT_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_window() {
+        let mut history = History::new(10, 4);
+        let cpu = CPU::new();
+        for _ in 0..30 {
+            if history.checkpoint_due() {
+                history.add_checkpoint(vec![history.count() as u8]);
+            }
+            history.begin(&cpu);
+            let write = Access { address: history.count() as u16, kind: AccessKind::Write, value: 0x55 };
+            let read = Access { address: 0x1234, kind: AccessKind::Read, value: 0x00 };
+            history.end(&[read, write]);
+        }
+
+        // Instructions 20 to 29 are in the window, which needs the checkpoint at 20 and nothing before it
+        assert_eq!(history.count(), 30);
+        assert_eq!(history.earliest(), 20);
+        assert_eq!(history.journal().count(), 10);
+        assert_eq!(history.checkpoint_before(23).unwrap().instruction, 20);
+        assert_eq!(history.entry(25).unwrap().writes, vec![(25, 0x55)]);
+        assert_eq!(history.last_write(0x0000, 0x0016).unwrap().instruction, 22);
+        assert!(history.last_write(0x1234, 0x1234).is_none());
+
+        history.truncate(25);
+        assert_eq!(history.count(), 25);
+        assert_eq!(history.checkpoint_before(29).unwrap().instruction, 24);
+        assert!(history.entry(25).is_none());
+        history.clear();
+        assert_eq!(history.earliest(), 0);
+    }
+}
@@ -0,0 +1,126 @@
+/*!
+ * Input Recording and Replay
+ *
+ * A Recording captures everything that reaches the machine from outside while it runs: a snapshot of the machine
+ * when recording started, and every input event after that (such as keys typed on the host), stamped with the CPU
+ * cycle at which it was delivered. Devices are made deterministic first, so the host clock is replaced with emulated
+ * time starting at the time the recording began.
+ *
+ * Replaying restores the snapshot and delivers each event at the end of the first instruction at or past its cycle,
+ * which is the same instruction it was delivered after when recorded, so the run is reproduced exactly. Input from the
+ * host is ignored while replaying. Disk images are not part of the snapshot and must be the same as when recorded.
+ *
+ * A replay file is the magic and version, the start time, the snapshot and then the events.
+ */
+
+use std::path::Path;
+
+use crate::devices::save_state::*;
+
+pub const REPLAY_MAGIC: &[u8; 8] = b"REMUPLAY";
+pub const REPLAY_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputEvent {
+    // The CPU cycle count when the input was delivered
+    pub cycle: u64,
+    // The name of the device in the memory map that received it
+    pub device: String,
+    pub data: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    // The time the emulated clocks started from, in seconds since the Unix epoch
+    pub time: i64,
+    pub snapshot: Vec<u8>,
+    pub events: Vec<InputEvent>
+}
+
+impl Recording {
+    pub fn new(time: i64, snapshot: Vec<u8>) -> Recording {
+        Recording { time, snapshot, events: Vec::new() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_raw(REPLAY_MAGIC);
+        state.write_u16(REPLAY_VERSION);
+        state.write_i64(self.time);
+        state.write_bytes(&self.snapshot);
+        state.write_u32(self.events.len() as u32);
+        for event in &self.events {
+            state.write_u64(event.cycle);
+            state.write_string(&event.device);
+            state.write_bytes(&event.data);
+        }
+        state.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Recording, StateError> {
+        let mut state = StateReader::new(data);
+        if state.read_raw(REPLAY_MAGIC.len()).ok() != Some(&REPLAY_MAGIC[..]) {
+            return Err(StateError::BadMagic);
+        }
+        let version = state.read_u16()?;
+        if version != REPLAY_VERSION {
+            return Err(StateError::Version(version));
+        }
+
+        let mut recording = Recording::new(state.read_i64()?, state.read_bytes()?.to_vec());
+        let count = state.read_u32()?;
+        for _ in 0..count {
+            let cycle = state.read_u64()?;
+            let device = state.read_string()?;
+            let data = state.read_bytes()?.to_vec();
+            if recording.events.last().is_some_and(|last| last.cycle > cycle) {
+                return Err(StateError::Invalid(String::from("Input events out of order")));
+            }
+            recording.events.push(InputEvent { cycle, device, data });
+        }
+        if !state.is_empty() {
+            return Err(StateError::Invalid(String::from("Trailing data")));
+        }
+        Ok(recording)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), StateError> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Recording, StateError> {
+        Recording::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+// Where the Emulator's input comes from
+#[derive(Debug, Default)]
+pub enum InputMode {
+    // From the host, as it arrives
+    #[default]
+    Live,
+    // From the host, and recorded
+    Recording(Recording),
+    // From a recording, with the index of the next event to deliver
+    Replaying(Recording, usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_file() {
+        let mut recording = Recording::new(1_700_000_000, vec![1, 2, 3]);
+        recording.events.push(InputEvent { cycle: 10, device: String::from("KBD"), data: vec![b'A'] });
+        recording.events.push(InputEvent { cycle: 25, device: String::from("KBD"), data: vec![b'B', b'C'] });
+        let data = recording.to_bytes();
+        assert_eq!(Recording::from_bytes(&data).unwrap(), recording);
+
+        assert!(matches!(Recording::from_bytes(b"REMUSAVE"), Err(StateError::BadMagic)));
+        assert!(matches!(Recording::from_bytes(&data[..data.len() - 1]), Err(StateError::Truncated)));
+        recording.events.swap(0, 1);
+        assert!(matches!(Recording::from_bytes(&recording.to_bytes()), Err(StateError::Invalid(_))));
+    }
+}
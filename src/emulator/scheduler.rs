@@ -0,0 +1,135 @@
+/*!
+ * Scheduler
+ *
+ * The Emulator's queue of device events. Each device in the memory map has at most one event: the CPU cycle it next
+ * needs ticking by, such as when a VIA timer underflows, a byte finishes shifting out, or a video chip reaches vblank.
+ * After each instruction the Emulator ticks the devices whose events are due and asks them for their next ones, and
+ * the memory map catches up any other device the CPU accesses. Devices left alone cost nothing, however many there are.
+ *
+ * Scheduling a device again replaces its event. The old one stays in the heap until it reaches the front, where it is
+ * thrown away because it no longer matches the device's event.
+ */
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+#[derive(Debug)]
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<(u64, usize)>>,
+    // The cycle of each device's event, by its index in the memory map
+    events: Vec<Option<u64>>
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { queue: BinaryHeap::new(), events: Vec::new() }
+    }
+
+    // Replace a device's event with one at a cycle, or with none
+    pub fn schedule(&mut self, device: usize, cycle: Option<u64>) {
+        if device >= self.events.len() {
+            self.events.resize(device + 1, None);
+        }
+        if self.events[device] == cycle {
+            return;
+        }
+        self.events[device] = cycle;
+        if let Some(cycle) = cycle {
+            self.queue.push(Reverse((cycle, device)));
+            // Rebuild the heap before the events replaced pile up in it
+            if self.queue.len() > 2 * self.events.len() + 16 {
+                self.queue = self.pending().map(|(device, cycle)| Reverse((cycle, device))).collect();
+            }
+        }
+    }
+
+    // The cycle of a device's event
+    pub fn event(&self, device: usize) -> Option<u64> {
+        self.events.get(device).copied().flatten()
+    }
+
+    // The cycle of the next event of any device
+    pub fn next_event(&mut self) -> Option<u64> {
+        self.discard_replaced();
+        self.queue.peek().map(|&Reverse((cycle, _))| cycle)
+    }
+
+    // Take the earliest event due by a cycle, returning its device
+    pub fn pop_due(&mut self, cycle: u64) -> Option<usize> {
+        self.discard_replaced();
+        match self.queue.peek() {
+            Some(&Reverse((due, device))) if due <= cycle => {
+                self.queue.pop();
+                self.events[device] = None;
+                Some(device)
+            }
+            _ => None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.events.clear();
+    }
+
+    // Every device with an event, and its cycle
+    fn pending(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.events.iter().enumerate().filter_map(|(device, cycle)| cycle.map(|cycle| (device, cycle)))
+    }
+
+    fn discard_replaced(&mut self) {
+        while let Some(&Reverse((cycle, device))) = self.queue.peek() {
+            if self.events[device] == Some(cycle) {
+                break;
+            }
+            self.queue.pop();
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduler() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(2, Some(300));
+        scheduler.schedule(0, Some(100));
+        scheduler.schedule(1, Some(200));
+        assert_eq!(scheduler.next_event(), Some(100));
+        assert_eq!(scheduler.pop_due(99), None);
+
+        // Events come out in order, and only once they are due
+        assert_eq!(scheduler.pop_due(250), Some(0));
+        assert_eq!(scheduler.pop_due(250), Some(1));
+        assert_eq!(scheduler.pop_due(250), None);
+        assert_eq!(scheduler.event(2), Some(300));
+
+        // Rescheduling replaces the event, earlier or later, and None cancels it
+        scheduler.schedule(2, Some(400));
+        scheduler.schedule(0, Some(350));
+        scheduler.schedule(0, Some(500));
+        scheduler.schedule(1, Some(320));
+        scheduler.schedule(1, None);
+        assert_eq!(scheduler.next_event(), Some(400));
+        assert_eq!(scheduler.pop_due(1000), Some(2));
+        assert_eq!(scheduler.pop_due(1000), Some(0));
+        assert_eq!(scheduler.pop_due(1000), None);
+
+        // Replaced events do not pile up
+        for cycle in 0..1000 {
+            scheduler.schedule(cycle as usize % 3, Some(2000 - cycle));
+        }
+        assert!(scheduler.queue.len() <= 2 * 3 + 16);
+        assert_eq!(scheduler.pop_due(u64::MAX), Some(0));
+        scheduler.clear();
+        assert_eq!(scheduler.next_event(), None);
+    }
+}
@@ -0,0 +1,129 @@
+/*!
+ * Throttle
+ *
+ * Paces the Emulator to its CPU clock. After each batch of instructions, the throttle works out how long the cycles run
+ * since its anchor should have taken at the clock rate, scaled by the speed, and the Emulator sleeps for whatever of
+ * that has not gone by yet. Measuring from the anchor rather than adding up the batches means oversleeping once is made
+ * up for in the next batch, so the drift over a long run stays within one batch.
+ *
+ * When the host cannot keep up, or the machine was paused (in the debugger, say), the emulated time falls behind real
+ * time. Rather than running flat out to catch up, the throttle starts again from where it is once it is more than
+ * MAX_LAG behind. It does the same when the cycle count goes backwards, such as when a state is restored.
+ */
+
+use std::time::{Duration, Instant};
+
+pub const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    // As fast as the host can go
+    Unthrottled,
+    // A multiple of the clock rate: 1 is real time, more fast forwards and less is slow motion
+    Scaled(f64)
+}
+
+impl Speed {
+    pub const REAL_TIME: Speed = Speed::Scaled(1.0);
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::REAL_TIME
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    clock_rate: u32,
+    speed: Speed,
+    // The moment and the cycle count that time is measured from
+    anchor: Option<(Instant, u64)>
+}
+
+impl Throttle {
+    pub fn new(clock_rate: u32, speed: Speed) -> Throttle {
+        Throttle { clock_rate: clock_rate.max(1), speed, anchor: None }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.reset();
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = clock_rate.max(1);
+        self.reset();
+    }
+
+    // Start measuring again from the next batch
+    pub fn reset(&mut self) {
+        self.anchor = None;
+    }
+
+    // How long to wait at `now` for real time to catch up with the cycle count
+    pub fn delay(&mut self, cycles: u64, now: Instant) -> Duration {
+        let Speed::Scaled(scale) = self.speed else {
+            return Duration::ZERO;
+        };
+        let (start, start_cycles) = match self.anchor {
+            Some((start, start_cycles)) if cycles >= start_cycles => (start, start_cycles),
+            _ => {
+                self.anchor = Some((now, cycles));
+                return Duration::ZERO;
+            }
+        };
+
+        let nanoseconds = (cycles - start_cycles) as f64 * 1e9 / (self.clock_rate as f64 * scale);
+        let target = Duration::from_nanos(nanoseconds.round() as u64);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed > target + MAX_LAG {
+            self.anchor = Some((now, cycles));
+            return Duration::ZERO;
+        }
+        target.saturating_sub(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle() {
+        let start = Instant::now();
+        let at = |milliseconds: u64| start + Duration::from_millis(milliseconds);
+        let mut throttle = Throttle::new(1_000_000, Speed::REAL_TIME);
+        assert_eq!(throttle.delay(0, at(0)), Duration::ZERO);
+        assert_eq!(throttle.delay(10_000, at(2)), Duration::from_millis(8));
+
+        // Oversleeping is made up for in the next batch instead of adding up
+        assert_eq!(throttle.delay(20_000, at(21)), Duration::ZERO);
+        assert_eq!(throttle.delay(30_000, at(25)), Duration::from_millis(5));
+
+        // Falling too far behind starts again instead of running flat out to catch up
+        assert_eq!(throttle.delay(40_000, at(500)), Duration::ZERO);
+        assert_eq!(throttle.delay(50_000, at(500)), Duration::from_millis(10));
+        assert_eq!(throttle.delay(100, at(600)), Duration::ZERO);
+        assert_eq!(throttle.delay(1_100, at(600)), Duration::from_millis(1));
+
+        // Fast forward, slow motion and no throttle at all
+        throttle.set_speed(Speed::Scaled(4.0));
+        throttle.delay(0, at(0));
+        assert_eq!(throttle.delay(40_000, at(0)), Duration::from_millis(10));
+        throttle.set_speed(Speed::Scaled(0.5));
+        throttle.delay(0, at(0));
+        assert_eq!(throttle.delay(40_000, at(0)), Duration::from_millis(80));
+        throttle.set_speed(Speed::Unthrottled);
+        assert_eq!(throttle.delay(1_000_000, at(0)), Duration::ZERO);
+
+        // A 1.8432 MHz clock runs a second's worth of cycles in a second
+        let mut throttle = Throttle::new(1_843_200, Speed::REAL_TIME);
+        throttle.delay(0, at(0));
+        assert_eq!(throttle.delay(1_843_200, at(900)), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,378 @@
+/*!
+ * Execution Trace
+ *
+ * Writes one line per instruction, before it executes, in the layout of the well known nestest.log:
+ *
+ * C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+ *
+ * Undocumented opcodes are marked with a `*` before the mnemonic, and operands that touch memory are annotated with
+ * the effective address and the value found there, the way nestest does it. There is no PPU column; diff against a
+ * nestest log with `first_difference`, which leaves it out. When the address of an instruction has a symbol, it is
+ * added at the end of the line as a comment, `; main+3`, which `first_difference` leaves out too.
+ *
+ * A tracer either writes every line as it goes, or keeps only the last lines in a ring buffer and writes them out when
+ * it is flushed, which the Emulator does when the CPU fails. That keeps the lead-up to a crash without the cost of
+ * tracing a long run to disk.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::cpu::*;
+use crate::cpu::disassembler::*;
+use crate::cpu::opcodes::*;
+use crate::devices::memory_map::*;
+
+// Format the trace line for the instruction the CPU is about to execute
+pub fn trace_line(cpu: &CPU, memory: &MemoryMap) -> String {
+    let pc = cpu.pc();
+    let instruction = disassemble(memory, cpu.model(), pc);
+    let bytes = instruction.bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(" ");
+    let marker = if instruction.opcode.illegal { '*' } else { ' ' };
+    let mnemonic = instruction.mnemonic(Syntax::Tass64).to_uppercase();
+    let operand = operand(&instruction, cpu, memory);
+    let text = if operand.is_empty() { mnemonic } else { format!("{} {}", mnemonic, operand) };
+
+    format!(
+        "{:04X}  {:<9}{}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        pc,
+        bytes,
+        marker,
+        text,
+        cpu.a(),
+        cpu.x(),
+        cpu.y(),
+        cpu.flags(),
+        cpu.sp(),
+        cpu.cycles()
+    )
+}
+
+// Format the trace line, followed by the name of the instruction's address when it has one
+pub fn trace_line_with_symbols(cpu: &CPU, memory: &MemoryMap, symbols: &dyn SymbolLookup) -> String {
+    let line = trace_line(cpu, memory);
+    match symbols.symbol(cpu.pc()) {
+        Some(name) => format!("{}  ; {}", line, name),
+        None => line
+    }
+}
+
+// The operand with nestest's annotations of effective addresses and values
+fn operand(instruction: &Instruction, cpu: &CPU, memory: &MemoryMap) -> String {
+    let byte = |address: u16| memory.peek(address).unwrap_or(0xff);
+    let zero_page_word = |address: u8| u16::from_le_bytes([byte(address as u16), byte(address.wrapping_add(1) as u16)]);
+    let low = instruction.bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([low, instruction.bytes.get(2).copied().unwrap_or(0)]);
+    let jump = matches!(instruction.opcode.mnemonic, Mnemonic::JMP | Mnemonic::JSR);
+
+    match instruction.opcode.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => String::from("A"),
+        AddressingMode::Immediate => format!("#${:02X}", low),
+        AddressingMode::ZeroPage => format!("${:02X} = {:02X}", low, byte(low as u16)),
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let (index, name) = if instruction.opcode.mode == AddressingMode::ZeroPageX {
+                (cpu.x(), 'X')
+            } else {
+                (cpu.y(), 'Y')
+            };
+            let address = low.wrapping_add(index);
+            format!("${:02X},{} @ {:02X} = {:02X}", low, name, address, byte(address as u16))
+        }
+        AddressingMode::Absolute if jump => format!("${:04X}", word),
+        AddressingMode::Absolute => format!("${:04X} = {:02X}", word, byte(word)),
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let (index, name) = if instruction.opcode.mode == AddressingMode::AbsoluteX {
+                (cpu.x(), 'X')
+            } else {
+                (cpu.y(), 'Y')
+            };
+            let address = word.wrapping_add(index as u16);
+            format!("${:04X},{} @ {:04X} = {:02X}", word, name, address, byte(address))
+        }
+        AddressingMode::Indirect => {
+            // The NMOS part does not carry into the high byte of the pointer
+            let high = if cpu.model() == CpuModel::MOS6502 {
+                (word & 0xff00) | (word.wrapping_add(1) & 0x00ff)
+            } else {
+                word.wrapping_add(1)
+            };
+            format!("(${:04X}) = {:04X}", word, u16::from_le_bytes([byte(word), byte(high)]))
+        }
+        AddressingMode::IndirectX => {
+            let pointer = low.wrapping_add(cpu.x());
+            let address = zero_page_word(pointer);
+            format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", low, pointer, address, byte(address))
+        }
+        AddressingMode::IndirectY => {
+            let base = zero_page_word(low);
+            let address = base.wrapping_add(cpu.y() as u16);
+            format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", low, base, address, byte(address))
+        }
+        AddressingMode::ZeroPageIndirect => {
+            let address = zero_page_word(low);
+            format!("(${:02X}) = {:04X} = {:02X}", low, address, byte(address))
+        }
+        AddressingMode::AbsoluteIndexedIndirect => {
+            let pointer = word.wrapping_add(cpu.x() as u16);
+            let address = u16::from_le_bytes([byte(pointer), byte(pointer.wrapping_add(1))]);
+            format!("(${:04X},X) = {:04X}", word, address)
+        }
+        AddressingMode::Relative => format!("${:04X}", instruction.branch_target().unwrap_or(0)),
+        AddressingMode::ZeroPageRelative => {
+            format!("${:02X} = {:02X},${:04X}", low, byte(low as u16), instruction.branch_target().unwrap_or(0))
+        }
+    }
+}
+
+pub struct Tracer {
+    output: Box<dyn Write>,
+    // In ring buffer mode, the most recent lines and how many to keep
+    ring: Option<(VecDeque<String>, usize)>
+}
+
+impl Tracer {
+    // Write every line to `output` as it is traced
+    pub fn new(output: Box<dyn Write>) -> Tracer {
+        Tracer { output, ring: None }
+    }
+
+    // Keep only the last `lines` lines, which are written to `output` when the tracer is flushed
+    pub fn ring(output: Box<dyn Write>, lines: usize) -> Tracer {
+        Tracer { output, ring: Some((VecDeque::with_capacity(lines), lines)) }
+    }
+
+    // Trace to a file, either every line or, given a number of lines, in ring buffer mode
+    pub fn file<P: AsRef<Path>>(path: P, lines: Option<usize>) -> io::Result<Tracer> {
+        let output = Box::new(BufWriter::new(File::create(path)?));
+        Ok(match lines {
+            Some(lines) => Tracer::ring(output, lines),
+            None => Tracer::new(output)
+        })
+    }
+
+    pub fn trace(&mut self, cpu: &CPU, memory: &MemoryMap, symbols: Option<&dyn SymbolLookup>) -> io::Result<()> {
+        let line = match symbols {
+            Some(symbols) => trace_line_with_symbols(cpu, memory, symbols),
+            None => trace_line(cpu, memory)
+        };
+        match &mut self.ring {
+            Some((lines, capacity)) => {
+                if *capacity > 0 {
+                    if lines.len() == *capacity {
+                        lines.pop_front();
+                    }
+                    lines.push_back(line);
+                }
+                Ok(())
+            }
+            None => writeln!(self.output, "{}", line)
+        }
+    }
+
+    // The lines held in the ring buffer, oldest first
+    pub fn lines(&self) -> impl Iterator<Item = &String> {
+        self.ring.iter().flat_map(|(lines, _)| lines.iter())
+    }
+
+    // Write out anything buffered. The ring buffer is emptied into the output.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some((lines, _)) = &mut self.ring {
+            for line in lines.drain(..) {
+                writeln!(self.output, "{}", line)?;
+            }
+        }
+        self.output.flush()
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.ring {
+            Some((lines, capacity)) => write!(f, "Tracer {{ ring: {}/{} }}", lines.len(), capacity),
+            None => write!(f, "Tracer")
+        }
+    }
+}
+
+// Nestest logs carry the PPU's position, which has no equivalent here, and symbols are ours alone
+fn normalize(line: &str) -> String {
+    let line = line.split("  ; ").next().unwrap_or_default();
+    match (line.find(" PPU:"), line.find(" CYC:")) {
+        (Some(start), Some(end)) if start < end => format!("{}{}", &line[..start], &line[end..]),
+        _ => String::from(line)
+    }
+    .trim_end()
+    .to_string()
+}
+
+// Compare a trace with a reference, returning the line number (from 1) and both lines of the first difference. A
+// trace that stops early differs at the line where it ends.
+pub fn first_difference<A: BufRead, B: BufRead>(
+    trace: A,
+    reference: B
+) -> io::Result<Option<(usize, String, String)>> {
+    let mut trace = trace.lines();
+    let mut reference = reference.lines();
+    let mut number = 0;
+    loop {
+        number += 1;
+        match (trace.next().transpose()?, reference.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (ours, theirs) => {
+                let ours = ours.map(|line| normalize(&line)).unwrap_or_default();
+                let theirs = theirs.map(|line| normalize(&line)).unwrap_or_default();
+                if ours != theirs {
+                    return Ok(Some((number, ours, theirs)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::memory::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A writer whose contents can still be read after it has been handed to a tracer
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn machine(program: &[u8]) -> (CPU, MemoryMap) {
+        let mut memory = MemoryMap::new();
+        memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        for (offset, &byte) in program.iter().enumerate() {
+            memory.write(0xc000 + offset as u16, byte).unwrap();
+        }
+        memory.write(RESET_VECTOR, 0x00).unwrap();
+        memory.write(RESET_VECTOR + 1, 0xc0).unwrap();
+        let mut cpu = CPU::new();
+        cpu.start(&memory).unwrap();
+        (cpu, memory)
+    }
+
+    #[test]
+    fn trace() {
+        let (mut cpu, memory) = machine(&[0x4c, 0xf5, 0xc5]);
+        assert_eq!(
+            trace_line(&cpu, &memory),
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7"
+        );
+
+        // Annotated operands, as they appear in nestest.log
+        let mut memory = memory;
+        let mut line = |program: &[u8]| {
+            for (offset, &byte) in program.iter().enumerate() {
+                memory.write(0xc000 + offset as u16, byte).unwrap();
+            }
+            trace_line(&cpu, &memory)[15..48].trim().to_string()
+        };
+        assert_eq!(line(&[0x86, 0x00]), "STX $00 = 00");
+        assert_eq!(line(&[0xad, 0x00, 0x02]), "LDA $0200 = 00");
+        assert_eq!(line(&[0x4a]), "LSR A");
+        assert_eq!(line(&[0xb0, 0x04]), "BCS $C006");
+        assert_eq!(line(&[0x04, 0xa9]), "*NOP $A9 = 00");
+        assert_eq!(line(&[0xe7, 0x10]), "*ISB $10 = 00");
+
+        cpu.set_x(0x02);
+        cpu.set_y(0x01);
+        memory.write(0x0082, 0x00).unwrap();
+        memory.write(0x0083, 0x03).unwrap();
+        memory.write(0x0300, 0x89).unwrap();
+        memory.write(0x0301, 0x5a).unwrap();
+        memory.write(0x02ff, 0x7e).unwrap();
+        memory.write(0x0200, 0xdb).unwrap();
+        let mut line = |program: &[u8]| {
+            for (offset, &byte) in program.iter().enumerate() {
+                memory.write(0xc000 + offset as u16, byte).unwrap();
+            }
+            trace_line(&cpu, &memory)[15..48].trim().to_string()
+        };
+        assert_eq!(line(&[0xa1, 0x80]), "LDA ($80,X) @ 82 = 0300 = 89");
+        assert_eq!(line(&[0xb1, 0x82]), "LDA ($82),Y = 0300 @ 0301 = 5A");
+        assert_eq!(line(&[0xb5, 0x80]), "LDA $80,X @ 82 = 00");
+        assert_eq!(line(&[0xbd, 0x00, 0x03]), "LDA $0300,X @ 0302 = 00");
+        assert_eq!(line(&[0x6c, 0xff, 0x02]), "JMP ($02FF) = DB7E");
+    }
+
+    #[test]
+    fn trace_symbols() {
+        let (cpu, memory) = machine(&[0xe8]);
+        let mut symbols = std::collections::HashMap::new();
+        assert!(!trace_line_with_symbols(&cpu, &memory, &symbols).contains(';'));
+        symbols.insert(0xc000, String::from("main"));
+        assert!(trace_line_with_symbols(&cpu, &memory, &symbols).ends_with("CYC:7  ; main"));
+    }
+
+    #[test]
+    fn trace_ring() {
+        let (mut cpu, mut memory) = machine(&[0xe8, 0xe8, 0xe8, 0xe8]);
+        let output = Shared::default();
+        let mut tracer = Tracer::ring(Box::new(output.clone()), 2);
+        for _ in 0..4 {
+            tracer.trace(&cpu, &memory, None).unwrap();
+            cpu.step(&mut memory).unwrap();
+        }
+
+        // Only the last two instructions are kept, and nothing is written until the tracer is flushed
+        assert_eq!(tracer.lines().count(), 2);
+        assert!(output.text().is_empty());
+        tracer.flush().unwrap();
+        let text = output.text();
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("C002  E8        INX"));
+        assert!(lines[1].ends_with("A:00 X:03 Y:00 P:24 SP:FD CYC:13"));
+        assert_eq!(tracer.lines().count(), 0);
+    }
+
+    #[test]
+    fn trace_difference() {
+        let reference = "\
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+";
+        let trace = "\
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD CYC:10
+";
+        assert_eq!(first_difference(trace.as_bytes(), reference.as_bytes()).unwrap(), None);
+
+        // Symbols are left out of the comparison
+        let symbolic = trace.replace("CYC:7", "CYC:7  ; main");
+        assert_eq!(first_difference(symbolic.as_bytes(), reference.as_bytes()).unwrap(), None);
+
+        let trace = trace.replace("CYC:10", "CYC:11");
+        let (line, ours, theirs) = first_difference(trace.as_bytes(), reference.as_bytes()).unwrap().unwrap();
+        assert_eq!(line, 2);
+        assert!(ours.ends_with("CYC:11"));
+        assert!(theirs.ends_with("CYC:10"));
+
+        // A trace that ends early differs where it ends
+        let trace = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7\n";
+        let (line, ours, _) = first_difference(trace.as_bytes(), reference.as_bytes()).unwrap().unwrap();
+        assert_eq!((line, ours.as_str()), (2, ""));
+    }
+}
@@ -0,0 +1,693 @@
+/*!
+ * GDB Remote Serial Protocol Stub
+ *
+ * Lets gdb, or any other client that speaks the remote serial protocol, debug a running Emulator over a local TCP
+ * socket (`target remote localhost:port`). There is no 6502 architecture in gdb itself, so the register layout is
+ * described by a target description that the client can read with qXfer:features:read: a, x, y, p and sp are 8 bits
+ * wide and pc is 16, in that order, which is also the layout of the g and G packets.
+ *
+ * The supported packets are:
+ * - ? for the reason the target stopped
+ * - g, G, p and P to read and write registers
+ * - m and M to read and write memory through the MemoryMap (ROM is patched when the map is in debug mode)
+ * - s and c to step and continue, where a continue can be interrupted with Ctrl-C
+ * - bs and bc to step and continue backwards through the Emulator's history, if it keeps one. A reverse continue
+ *   stops at breakpoints and write or access watchpoints, but not at read watchpoints, since only writes are journalled.
+ *   Breakpoint conditions and ignore counts are left to the client when going backwards
+ * - Z0/z0 and Z1/z1 for software and hardware breakpoints, which are the same thing here
+ * - Z2/z2, Z3/z3 and Z4/z4 for write, read and access watchpoints
+ * - qSupported, QStartNoAckMode, qAttached, the thread queries, D to detach and k to kill
+ *
+ * Anything else gets the empty reply, which tells the client it is not supported.
+ */
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::cpu::*;
+use crate::devices::memory_map::*;
+use crate::emulator::breakpoints::*;
+use crate::emulator::emulator::*;
+use crate::emulator::history::*;
+
+// How many instructions to run between checks for an interrupt from the client
+const CONTINUE_CHUNK: u64 = 10_000;
+
+// The largest packet the stub accepts, as advertised in qSupported
+const PACKET_SIZE: u32 = 0x4000;
+
+// The most bytes an m packet returns. Each byte is two hex digits, and the reply must fit in a packet with its framing.
+const MAX_READ: u32 = (PACKET_SIZE - 4) / 2;
+
+const INTERRUPT: u8 = 0x03;
+
+// The signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\">\
+<feature name=\"org.mini6502.cpu\">\
+<reg name=\"a\" bitsize=\"8\" regnum=\"0\" type=\"uint8\"/>\
+<reg name=\"x\" bitsize=\"8\" regnum=\"1\" type=\"uint8\"/>\
+<reg name=\"y\" bitsize=\"8\" regnum=\"2\" type=\"uint8\"/>\
+<reg name=\"p\" bitsize=\"8\" regnum=\"3\" type=\"uint8\"/>\
+<reg name=\"sp\" bitsize=\"8\" regnum=\"4\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" regnum=\"5\" type=\"code_ptr\"/>\
+</feature>\
+</target>";
+
+// The connection to the client. Besides reading and writing packets, the server needs to notice a Ctrl-C while the
+// emulator is running without blocking on it.
+pub trait Connection: Read + Write {
+    fn interrupted(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Ok(false),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error)
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+// Parse "address,length" as used by the m, M and Z packets
+fn parse_range(text: &str) -> Option<(u16, u32)> {
+    let (address, length) = text.split_once(',')?;
+    let address = parse_hex(address)?;
+    if address > 0xffff {
+        return None;
+    }
+    Some((address as u16, parse_hex(length)?))
+}
+
+// The last address of a range of `length` bytes from `address`, stopping at the top of memory. A length of zero is
+// taken to mean a single byte.
+fn range_end(address: u16, length: u32) -> u16 {
+    (address as u32 + length.max(1) - 1).min(0xffff) as u16
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+#[derive(Debug)]
+pub struct GdbServer {
+    emulator: Emulator,
+    no_ack: bool,
+    // Breakpoints set by the client, by packet type and address, and the numbers they have in the Emulator
+    breakpoints: HashMap<(u8, u16, u32), u32>,
+    last_stop: String
+}
+
+impl GdbServer {
+    pub fn new(emulator: Emulator) -> GdbServer {
+        GdbServer { emulator, no_ack: false, breakpoints: HashMap::new(), last_stop: format!("S{:02x}", SIGTRAP) }
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emulator
+    }
+
+    pub fn into_emulator(self) -> Emulator {
+        self.emulator
+    }
+
+    // Wait for a client on `address` and serve it until it detaches or disconnects
+    pub fn listen<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+
+    // Serve one client until it detaches, kills the target or disconnects
+    pub fn serve<C: Connection>(&mut self, mut connection: C) -> io::Result<()> {
+        self.no_ack = false;
+        while let Some(packet) = self.receive(&mut connection)? {
+            let (reply, done) = self.handle(&packet, &mut connection)?;
+            self.send(&mut connection, &reply)?;
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Read the next packet, acknowledging it unless the client turned acknowledgements off. Returns None when the
+    // connection is closed.
+    fn receive<C: Connection>(&mut self, connection: &mut C) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            if connection.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] != b'$' {
+                // Acknowledgements from the client, and stray interrupts while the target is already stopped
+                continue;
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if connection.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut sum = [0; 2];
+            connection.read_exact(&mut sum)?;
+
+            let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if !self.no_ack {
+                if expected != Some(checksum(&data)) {
+                    connection.write_all(b"-")?;
+                    continue;
+                }
+                connection.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send<C: Connection>(&mut self, connection: &mut C, reply: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", reply, checksum(reply.as_bytes()));
+        connection.write_all(packet.as_bytes())?;
+        connection.flush()
+    }
+
+    // Handle one packet, returning the reply and whether the session is over
+    fn handle<C: Connection>(&mut self, packet: &str, connection: &mut C) -> io::Result<(String, bool)> {
+        let (command, rest) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => hex(&self.registers()),
+            "G" => match parse_hex_bytes(rest) {
+                Some(bytes) if bytes.len() == 7 => {
+                    for (index, &byte) in bytes.iter().enumerate().take(5) {
+                        self.set_register(index, byte as u16);
+                    }
+                    self.set_register(5, u16::from_le_bytes([bytes[5], bytes[6]]));
+                    String::from("OK")
+                }
+                _ => error(1)
+            },
+            "p" => match parse_hex(rest) {
+                Some(5) => hex(&self.emulator.cpu().pc().to_le_bytes()),
+                Some(register) if register < 5 => hex(&self.registers()[register as usize..=register as usize]),
+                _ => error(1)
+            },
+            "P" => {
+                let parsed = rest.split_once('=').and_then(|(register, value)| {
+                    Some((parse_hex(register)?, parse_hex_bytes(value)?))
+                });
+                match parsed {
+                    Some((register, value)) if register < 5 && value.len() == 1 => {
+                        self.set_register(register as usize, value[0] as u16);
+                        String::from("OK")
+                    }
+                    Some((5, value)) if value.len() == 2 => {
+                        self.set_register(5, u16::from_le_bytes([value[0], value[1]]));
+                        String::from("OK")
+                    }
+                    _ => error(1)
+                }
+            }
+            "m" => match parse_range(rest) {
+                Some((address, length)) => {
+                    // Longer reads are cut short, which the client handles by asking for the rest
+                    let memory = self.emulator.memory_map();
+                    let bytes = (0..length.min(MAX_READ))
+                        .map(|offset| memory.peek(address.wrapping_add(offset as u16)).ok())
+                        .collect::<Option<Vec<u8>>>();
+                    bytes.map(|bytes| hex(&bytes)).unwrap_or_else(|| error(14))
+                }
+                None => error(1)
+            },
+            "M" => {
+                let parsed = rest
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, parse_hex_bytes(data)?)));
+                match parsed {
+                    Some(((address, length), data)) if data.len() == length as usize => {
+                        let memory = self.emulator.memory_map_mut();
+                        let written = data
+                            .iter()
+                            .enumerate()
+                            .all(|(offset, &byte)| memory.poke(address.wrapping_add(offset as u16), byte).is_ok());
+                        if written { String::from("OK") } else { error(14) }
+                    }
+                    _ => error(1)
+                }
+            }
+            "s" => {
+                let reply = self.step();
+                self.last_stop = reply.clone();
+                reply
+            }
+            "c" => {
+                let reply = self.resume(connection)?;
+                self.last_stop = reply.clone();
+                reply
+            }
+            "b" => {
+                let reply = match rest {
+                    "s" => self.step_back(),
+                    "c" => self.resume_back(),
+                    _ => return Ok((String::new(), false))
+                };
+                if !reply.starts_with('E') {
+                    self.last_stop = reply.clone();
+                }
+                reply
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", rest),
+            "H" => String::from("OK"),
+            "T" => String::from("OK"),
+            "D" => return Ok((String::from("OK"), true)),
+            "k" => return Ok((String::from("OK"), true)),
+            "q" | "Q" => self.query(packet),
+            _ => String::new()
+        };
+        Ok((reply, false))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(annex) {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length as usize).min(TARGET_XML.len());
+                    let kind = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", kind, &TARGET_XML[offset..end])
+                }
+                None => error(1)
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                String::from("OK")
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new()
+        }
+    }
+
+    // The registers in the order of the target description, with the program counter in little endian
+    fn registers(&self) -> Vec<u8> {
+        let cpu = self.emulator.cpu();
+        let [low, high] = cpu.pc().to_le_bytes();
+        vec![cpu.a(), cpu.x(), cpu.y(), cpu.flags(), cpu.sp(), low, high]
+    }
+
+    fn set_register(&mut self, register: usize, value: u16) {
+        let cpu = self.emulator.cpu_mut();
+        match register {
+            0 => cpu.set_a(value as u8),
+            1 => cpu.set_x(value as u8),
+            2 => cpu.set_y(value as u8),
+            3 => cpu.set_flags(value as u8),
+            4 => cpu.set_sp(value as u8),
+            _ => cpu.set_pc(value)
+        }
+    }
+
+    fn stop_reply(&self, stop: StopReason) -> String {
+        match stop {
+            StopReason::Watchpoint(number, access) => {
+                let watch = self.emulator.breakpoints().get(number).map(|breakpoint| breakpoint.kind);
+                let kind = match watch {
+                    Some(BreakpointKind::Watch(_, _, WatchKind::Access)) => "awatch",
+                    _ if access.kind == AccessKind::Read => "rwatch",
+                    _ => "watch"
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, access.address)
+            }
+            _ => format!("S{:02x}", SIGTRAP)
+        }
+    }
+
+    fn error_reply(error: CpuError) -> String {
+        match error {
+            CpuError::IllegalOpcode(..) | CpuError::Stopped(_) => format!("S{:02x}", SIGILL),
+            CpuError::Memory(..) => format!("S{:02x}", SIGSEGV)
+        }
+    }
+
+    fn step(&mut self) -> String {
+        match self.emulator.step_watched() {
+            Ok(Some(stop)) => self.stop_reply(stop),
+            Ok(None) => format!("S{:02x}", SIGTRAP),
+            Err(error) => GdbServer::error_reply(error)
+        }
+    }
+
+    // Run until a breakpoint, a watchpoint, an error or an interrupt from the client
+    fn resume<C: Connection>(&mut self, connection: &mut C) -> io::Result<String> {
+        let mut first = true;
+        loop {
+            // Breakpoints are skipped on the very first instruction so that continuing from one does not stop at once
+            if !first {
+                if let Some(stop) = self.emulator.check_breakpoints() {
+                    return Ok(self.stop_reply(stop));
+                }
+            }
+            match self.emulator.run(CONTINUE_CHUNK) {
+                Ok(StopReason::Limit) => {}
+                Ok(stop) => return Ok(self.stop_reply(stop)),
+                Err(error) => return Ok(GdbServer::error_reply(error))
+            }
+            first = false;
+            if connection.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn step_back(&mut self) -> String {
+        match self.emulator.step_back(1) {
+            Ok(()) => format!("S{:02x}", SIGTRAP),
+            Err(HistoryError::OutOfRange(_)) => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Err(_) => error(1)
+        }
+    }
+
+    // Run backwards to the last instruction that hit a breakpoint or a write watchpoint, or to the start of history.
+    // Only the client's own breakpoints are searched, and their conditions and ignore counts are not evaluated, since
+    // that would mean replaying every instruction. gdb keeps the conditions and ignore counts of its breakpoints itself,
+    // and continues backwards again when one is not met.
+    fn resume_back(&mut self) -> String {
+        let Some(history) = self.emulator.history() else {
+            return error(1);
+        };
+        let hit = history.journal().rev().find_map(|entry| {
+            self.breakpoints.keys().find_map(|&(kind, address, length)| {
+                let end = range_end(address, length);
+                let watch = if kind == 4 { "awatch" } else { "watch" };
+                match kind {
+                    0 | 1 if entry.pc == address => Some((entry.instruction, format!("S{:02x}", SIGTRAP))),
+                    2 | 4 => entry.writes.iter().find(|&&(written, _)| written >= address && written <= end).map(
+                        |&(written, _)| (entry.instruction, format!("T{:02x}{}:{:04x};", SIGTRAP, watch, written))
+                    ),
+                    _ => None
+                }
+            })
+        });
+        let (instruction, reply) = hit.unwrap_or((history.earliest(), format!("T{:02x}replaylog:begin;", SIGTRAP)));
+        match self.emulator.rewind(instruction) {
+            Ok(()) => reply,
+            Err(_) => error(1)
+        }
+    }
+
+    // Insert (Z) or remove (z) a breakpoint or watchpoint: "type,address,kind"
+    fn breakpoint(&mut self, insert: bool, rest: &str) -> String {
+        let parsed = rest.split_once(',').and_then(|(kind, range)| Some((parse_hex(kind)?, parse_range(range)?)));
+        let (kind, address, length) = match parsed {
+            Some((kind, (address, length))) if kind <= 4 => (kind as u8, address, length),
+            Some(_) => return String::new(),
+            None => return error(1)
+        };
+        if length > 0x10000 {
+            return error(1);
+        }
+
+        let key = (kind, address, length);
+        if !insert {
+            return match self.breakpoints.remove(&key) {
+                Some(number) => {
+                    self.emulator.breakpoints_mut().remove(number);
+                    String::from("OK")
+                }
+                None => error(1)
+            };
+        }
+        if self.breakpoints.contains_key(&key) {
+            return String::from("OK");
+        }
+
+        let end = range_end(address, length);
+        let breakpoint = match kind {
+            0 | 1 => Breakpoint::execute(address),
+            2 => Breakpoint::watch(address, end, WatchKind::Write),
+            3 => Breakpoint::watch(address, end, WatchKind::Read),
+            _ => Breakpoint::watch(address, end, WatchKind::Access)
+        };
+        let number = self.emulator.breakpoints_mut().add(breakpoint);
+        self.breakpoints.insert(key, number);
+        String::from("OK")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::memory::*;
+    use std::io::Cursor;
+
+    // A scripted client: everything it will send is queued up front, and everything the server sends is kept
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.output.write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn interrupted(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    fn server() -> GdbServer {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+
+        // $0200: LDX #$00; loop: INX; TXA; STA $10,X; BNE loop
+        for (offset, &byte) in [0xa2, 0x00, 0xe8, 0x8a, 0x95, 0x10, 0xd0, 0xfa].iter().enumerate() {
+            emulator.memory_map_mut().write(0x0200 + offset as u16, byte).unwrap();
+        }
+        emulator.cpu_mut().set_pc(0x0200);
+        emulator.cpu_mut().set_sp(0xfd);
+        GdbServer::new(emulator)
+    }
+
+    // Run a session and return the replies, without the acknowledgements
+    fn session(server: &mut GdbServer, packets: &[&str]) -> Vec<String> {
+        let input = packets.iter().map(|data| packet(data)).collect::<String>();
+        let mut script = Script { input: Cursor::new(input.into_bytes()), output: Vec::new() };
+        server.serve(&mut script).unwrap();
+
+        let output = String::from_utf8(script.output).unwrap();
+        output
+            .split('$')
+            .skip(1)
+            .map(|reply| {
+                let (data, sum) = reply.split_once('#').unwrap();
+                assert_eq!(&sum[..2], format!("{:02x}", checksum(data.as_bytes())));
+                String::from(data)
+            })
+            .collect()
+    }
+
+    impl<T: Connection> Connection for &mut T {
+        fn interrupted(&mut self) -> io::Result<bool> {
+            (**self).interrupted()
+        }
+    }
+
+    #[test]
+    fn gdb_registers() {
+        let mut server = server();
+        let replies = session(&mut server, &["?", "g", "P0=ff", "P5=0003", "p0", "p5", "G01020304fc0002", "g", "p6"]);
+        assert_eq!(replies, vec!["S05", "00000000fd0002", "OK", "OK", "ff", "0003", "OK", "01020324fc0002", "E01"]);
+        assert_eq!(server.emulator().cpu().pc(), 0x0200);
+    }
+
+    #[test]
+    fn gdb_memory() {
+        let mut server = server();
+        let replies = session(&mut server, &["m200,3", "M300,2:aa55", "m2ff,3", "mfffff,1", "M300,2:aa"]);
+        assert_eq!(replies, vec!["a200e8", "OK", "00aa55", "E01", "E01"]);
+
+        // Reads too long for a packet are cut short
+        let replies = session(&mut server, &["m0,10000"]);
+        assert_eq!(replies[0].len(), 2 * MAX_READ as usize);
+
+        // Unmapped memory is an error
+        let mut server = GdbServer::new(Emulator::new());
+        assert_eq!(session(&mut server, &["m0,1", "M0,1:00"]), vec!["E0e", "E0e"]);
+    }
+
+    #[test]
+    fn gdb_step_and_continue() {
+        let mut server = server();
+        let replies = session(&mut server, &["s", "p1", "Z0,206,1", "c", "p1", "c", "p1", "z0,206,1", "z0,206,1"]);
+        assert_eq!(replies, vec!["S05", "00", "OK", "S05", "01", "S05", "02", "OK", "E01"]);
+        assert!(server.emulator().breakpoints().is_empty());
+    }
+
+    #[test]
+    fn gdb_watchpoints() {
+        let mut server = server();
+        let replies = session(&mut server, &["Z2,20,2", "c", "p1", "c", "z2,20,2", "Z3,0,1", "?"]);
+        assert_eq!(replies, vec!["OK", "T05watch:0020;", "10", "T05watch:0021;", "OK", "OK", "T05watch:0021;"]);
+
+        // Access watchpoints say so, whatever the access was
+        server = self::server();
+        assert_eq!(session(&mut server, &["Z4,20,2", "c"]), vec!["OK", "T05awatch:0020;"]);
+
+        // A range can cover all of memory, or run off the top of it, but no further
+        let replies = session(&mut server, &["Z2,0,10000", "Z3,ff00,1000", "Z2,0,10001", "z2,0,10001"]);
+        assert_eq!(replies, vec!["OK", "OK", "E01", "E01"]);
+        let watches = server.emulator().breakpoints().iter().map(|(_, breakpoint)| breakpoint.kind).collect::<Vec<_>>();
+        assert!(watches.contains(&BreakpointKind::Watch(0x0000, 0xffff, WatchKind::Write)));
+        assert!(watches.contains(&BreakpointKind::Watch(0xff00, 0xffff, WatchKind::Read)));
+    }
+
+    #[test]
+    fn gdb_reverse() {
+        let mut server = server();
+        assert_eq!(session(&mut server, &["bs"]), vec!["E01"]);
+
+        server.emulator_mut().set_history(Some(History::new(100, 10)));
+        let replies = session(&mut server, &["Z2,20,2", "c", "c", "p1", "bs", "p1", "m21,1", "bc", "p1", "m20,1"]);
+        let expected = ["OK", "T05watch:0020;", "T05watch:0021;", "11", "S05", "11", "00", "T05watch:0020;", "10", "00"];
+        assert_eq!(replies, expected);
+
+        // With nothing more to stop at, a reverse continue goes back to the start of the history
+        let replies = session(&mut server, &["bc", "p5", "bs"]);
+        assert_eq!(replies, vec!["T05replaylog:begin;", "0002", "T05replaylog:begin;"]);
+
+        // A watchpoint can cover all of memory
+        server = self::server();
+        server.emulator_mut().set_history(Some(History::new(100, 10)));
+        let replies = session(&mut server, &["s", "s", "s", "s", "s", "Z4,0,10000", "bc", "p5"]);
+        assert_eq!(replies[5..], ["OK", "T05awatch:0011;", "0402"]);
+    }
+
+    #[test]
+    fn gdb_errors() {
+        let mut server = server();
+        server.emulator_mut().memory_map_mut().write(0x0200, 0x02).unwrap();
+        assert_eq!(session(&mut server, &["c", "?"]), vec!["S04", "S04"]);
+    }
+
+    #[test]
+    fn gdb_queries() {
+        let mut server = server();
+        let replies = session(&mut server, &["qSupported:multiprocess+", "qAttached", "Hg0", "vMustReplyEmpty", "D"]);
+        assert_eq!(replies[0], "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+");
+        assert_eq!(replies[1..], ["1", "OK", "", "OK"]);
+
+        // The target description can be read in pieces
+        let packets = ["qXfer:features:read:target.xml:0,10", "qXfer:features:read:target.xml:10,fff"];
+        let replies = session(&mut server, &packets);
+        assert_eq!(replies[0], format!("m{}", &TARGET_XML[..16]));
+        assert_eq!(replies[1], format!("l{}", &TARGET_XML[16..]));
+    }
+
+    #[test]
+    fn gdb_no_ack() {
+        let mut server = server();
+        let input = format!("+{}+{}{}", packet("QStartNoAckMode"), packet("g"), "$g#00");
+        let mut script = Script { input: Cursor::new(input.into_bytes()), output: Vec::new() };
+        server.serve(&mut script).unwrap();
+
+        // The first packet is acknowledged, and after that even a bad checksum is not
+        let output = String::from_utf8(script.output).unwrap();
+        assert_eq!(output, format!("+{}{}{}", packet("OK"), packet("00000000fd0002"), packet("00000000fd0002")));
+    }
+
+    #[test]
+    fn gdb_bad_checksum() {
+        let mut server = server();
+        let input = format!("$g#00{}", packet("g"));
+        let mut script = Script { input: Cursor::new(input.into_bytes()), output: Vec::new() };
+        server.serve(&mut script).unwrap();
+        assert_eq!(String::from_utf8(script.output).unwrap(), format!("-+{}", packet("00000000fd0002")));
+    }
+
+    #[test]
+    fn gdb_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(packet("m200,2").as_bytes()).unwrap();
+            stream.write_all(packet("k").as_bytes()).unwrap();
+            let mut output = String::new();
+            stream.read_to_string(&mut output).unwrap();
+            output
+        });
+
+        let mut server = server();
+        let (stream, _) = listener.accept().unwrap();
+        server.serve(stream).unwrap();
+        assert_eq!(client.join().unwrap(), format!("+{}+{}", packet("a200"), packet("OK")));
+    }
+}
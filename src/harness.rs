@@ -0,0 +1,2 @@
+pub mod klaus;
+pub mod tom_harte;
@@ -0,0 +1,250 @@
+/*!
+ * Klaus Dormann Test Suites
+ *
+ * Runs Klaus Dormann's 6502 and 65C02 test programs (https://github.com/Klaus2m5/6502_65C02_functional_tests) in a
+ * flat 64K of RAM. Each program loads at a fixed address and runs until the program counter traps in a loop that
+ * jumps or branches to itself. Trapping at the success address passes; trapping anywhere else fails, and the test
+ * case number the program keeps in memory says which test it was in.
+ *
+ * The decimal test has no success address. It ends with an error flag in memory, which is zero when it passed.
+ *
+ * The binaries are expected in test-roms/klaus, built with the default options of the sources. The tests at the end
+ * of this file fail when they are missing.
+ */
+
+use std::fmt;
+use std::path::Path;
+
+use crate::cpu::cpu::*;
+use crate::cpu::opcodes::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+
+pub const ROM_DIRECTORY: &str = "test-roms/klaus";
+
+const OPCODE_BRK: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KlausTest {
+    pub name: &'static str,
+    pub file: &'static str,
+    pub model: CpuModel,
+    pub load: u16,
+    pub start: u16,
+    // Where the program traps when every test passed, or None if the result is in memory instead
+    pub success: Option<u16>,
+    // The test case number, or for the decimal test the error flag
+    pub test_case: u16,
+    // How many instructions to allow before giving up on the program ever trapping
+    pub limit: u64
+}
+
+pub const FUNCTIONAL_TEST: KlausTest = KlausTest {
+    name: "6502 functional test",
+    file: "6502_functional_test.bin",
+    model: CpuModel::MOS6502,
+    load: 0x0000,
+    start: 0x0400,
+    success: Some(0x3469),
+    test_case: 0x0200,
+    limit: 100_000_000
+};
+
+pub const EXTENDED_OPCODES_TEST: KlausTest = KlausTest {
+    name: "65C02 extended opcodes test",
+    file: "65C02_extended_opcodes_test.bin",
+    model: CpuModel::WDC65C02,
+    load: 0x0000,
+    start: 0x0400,
+    success: Some(0x24f1),
+    test_case: 0x0202,
+    limit: 100_000_000
+};
+
+pub const DECIMAL_TEST: KlausTest = KlausTest {
+    name: "6502 decimal test",
+    file: "6502_decimal_test.bin",
+    model: CpuModel::MOS6502,
+    load: 0x0200,
+    start: 0x0200,
+    success: None,
+    test_case: 0x000b,
+    limit: 100_000_000
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KlausOutcome {
+    Passed { instructions: u64, cycles: u64 },
+    // The program trapped somewhere other than the success address, in the given test case
+    Failed { pc: u16, test_case: u8 },
+    // The CPU could not go on, such as on an illegal opcode
+    Error { error: CpuError, test_case: u8 },
+    // The program ran out of instructions without trapping
+    Timeout { pc: u16, test_case: u8 }
+}
+
+impl KlausOutcome {
+    pub fn passed(&self) -> bool {
+        matches!(self, KlausOutcome::Passed { .. })
+    }
+}
+
+impl fmt::Display for KlausOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KlausOutcome::Passed { instructions, cycles } => {
+                write!(f, "passed after {} instructions and {} cycles", instructions, cycles)
+            }
+            KlausOutcome::Failed { pc, test_case } => write!(f, "failed at ${:04x} in test ${:02x}", pc, test_case),
+            KlausOutcome::Error { error, test_case } => write!(f, "stopped by {:?} in test ${:02x}", error, test_case),
+            KlausOutcome::Timeout { pc, test_case } => {
+                write!(f, "did not finish, at ${:04x} in test ${:02x}", pc, test_case)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KlausError {
+    Io(std::io::Error),
+    // The image does not fit in memory at the load address
+    TooLarge(usize)
+}
+
+impl fmt::Display for KlausError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KlausError::Io(error) => write!(f, "{}", error),
+            KlausError::TooLarge(size) => write!(f, "An image of {} bytes does not fit in memory", size)
+        }
+    }
+}
+
+impl From<std::io::Error> for KlausError {
+    fn from(error: std::io::Error) -> Self {
+        KlausError::Io(error)
+    }
+}
+
+// Run a test program from its image
+pub fn run(test: &KlausTest, image: &[u8]) -> Result<KlausOutcome, KlausError> {
+    if test.load as usize + image.len() > 0x10000 {
+        return Err(KlausError::TooLarge(image.len()));
+    }
+
+    let mut memory = MemoryMap::new();
+    memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+    for (offset, &byte) in image.iter().enumerate() {
+        memory.write(test.load + offset as u16, byte).unwrap();
+    }
+    let mut cpu = CPU::with_model(test.model);
+    cpu.set_pc(test.start);
+
+    let test_case = |memory: &MemoryMap| memory.peek(test.test_case).unwrap_or(0);
+    for instructions in 0..test.limit {
+        let pc = cpu.pc();
+        // Without a success address the program may end with a BRK instead of a trap
+        if test.success.is_none() && memory.peek(pc) == Ok(OPCODE_BRK) {
+            return Ok(finish(test, pc, test_case(&memory), instructions, cpu.cycles()));
+        }
+        if let Err(error) = cpu.step(&mut memory) {
+            if let CpuError::Stopped(pc) = error {
+                return Ok(finish(test, pc, test_case(&memory), instructions, cpu.cycles()));
+            }
+            return Ok(KlausOutcome::Error { error, test_case: test_case(&memory) });
+        }
+        if cpu.pc() == pc {
+            return Ok(finish(test, pc, test_case(&memory), instructions + 1, cpu.cycles()));
+        }
+    }
+    Ok(KlausOutcome::Timeout { pc: cpu.pc(), test_case: test_case(&memory) })
+}
+
+// Decide the outcome once the program has come to a halt at `pc`
+fn finish(test: &KlausTest, pc: u16, test_case: u8, instructions: u64, cycles: u64) -> KlausOutcome {
+    let passed = match test.success {
+        Some(success) => pc == success,
+        None => test_case == 0
+    };
+    if passed {
+        KlausOutcome::Passed { instructions, cycles }
+    } else {
+        KlausOutcome::Failed { pc, test_case }
+    }
+}
+
+// Run a test program from its binary in `directory`, or None if the binary is not there
+pub fn run_file<P: AsRef<Path>>(test: &KlausTest, directory: P) -> Result<Option<KlausOutcome>, KlausError> {
+    let path = directory.as_ref().join(test.file);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let image = std::fs::read(path)?;
+    run(test, &image).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suite(test: &KlausTest) {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join(ROM_DIRECTORY);
+        match run_file(test, &directory).unwrap() {
+            None => {
+                let directory = directory.display();
+                panic!("The {} is missing: {} is not in {}, see its README.md", test.name, test.file, directory)
+            }
+            Some(outcome) => assert!(outcome.passed(), "The {} {}", test.name, outcome)
+        }
+    }
+
+    // A program in the style of the suites: count test cases at $0200, then trap
+    fn program(code: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; 0x0400];
+        image.extend_from_slice(code);
+        image
+    }
+
+    #[test]
+    fn klaus_harness() {
+        let test = KlausTest { success: Some(0x0407), limit: 100, ..FUNCTIONAL_TEST };
+
+        // INC $0200; INC $0200; JMP $0407 (success); JMP *
+        let passing = program(&[0xee, 0x00, 0x02, 0xee, 0x00, 0x02, 0xea, 0x4c, 0x07, 0x04]);
+        assert_eq!(run(&test, &passing).unwrap(), KlausOutcome::Passed { instructions: 4, cycles: 17 });
+
+        // INC $0200; LDA #$00; BEQ * (a failure trap in test 1)
+        let failing = program(&[0xee, 0x00, 0x02, 0xa9, 0x00, 0xf0, 0xfe]);
+        let outcome = run(&test, &failing).unwrap();
+        assert_eq!(outcome, KlausOutcome::Failed { pc: 0x0405, test_case: 1 });
+        assert_eq!(outcome.to_string(), "failed at $0405 in test $01");
+
+        // An endless loop that never traps, and an illegal opcode
+        let endless = program(&[0x4c, 0x03, 0x04, 0x4c, 0x00, 0x04]);
+        assert!(matches!(run(&test, &endless).unwrap(), KlausOutcome::Timeout { .. }));
+        assert!(matches!(run(&test, &program(&[0x02])).unwrap(), KlausOutcome::Error { .. }));
+
+        // The decimal test passes on a clear error flag
+        let decimal = KlausTest { load: 0x0400, start: 0x0400, limit: 100, ..DECIMAL_TEST };
+        assert!(run(&decimal, &[0xa9, 0x00, 0x85, 0x0b, 0x00]).unwrap().passed());
+        assert!(!run(&decimal, &[0xa9, 0x01, 0x85, 0x0b, 0x00]).unwrap().passed());
+
+        assert!(matches!(run(&test, &vec![0; 0x10001]), Err(KlausError::TooLarge(_))));
+        assert_eq!(run_file(&test, "/nonexistent").unwrap(), None);
+    }
+
+    #[test]
+    fn klaus_functional_test() {
+        suite(&FUNCTIONAL_TEST);
+    }
+
+    #[test]
+    fn klaus_extended_opcodes_test() {
+        suite(&EXTENDED_OPCODES_TEST);
+    }
+
+    #[test]
+    fn klaus_decimal_test() {
+        suite(&DECIMAL_TEST);
+    }
+}
@@ -0,0 +1,480 @@
+/*!
+ * Tom Harte's Single Step Tests
+ *
+ * Runs the JSON test cases from https://github.com/SingleStepTests/65x02 (formerly ProcessorTests). There is a file
+ * for each opcode of each CPU, and each case in it gives the registers and RAM before and after one instruction,
+ * along with every bus cycle the instruction makes:
+ *
+ *   { "name": "a9 42 ...", "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 169]] },
+ *     "final": { ... }, "cycles": [[512, 169, "read"], [513, 66, "read"]] }
+ *
+ * A case runs in a flat 64K of RAM. The final registers, the RAM listed in the final state and the number of cycles
+ * the CPU reports are compared first, then the bus activity recorded by the memory map, cycle by cycle. The JAMs, and
+ * STP on the 65C02, stop the core with an error where the chip would lock up: those cases are compared up to the
+ * last access the core made, leaving out the program counter, which the core keeps on the stopping instruction.
+ *
+ * The test data is expected in test-roms/tom_harte, laid out as in the repository (6502/v1/a9.json and so on), and the
+ * tests at the end of this file fail when it is missing. A few hand-written cases in the same format are kept in
+ * test-roms/tom_harte/sample to test the runner itself.
+ */
+
+use std::fmt;
+use std::path::Path;
+
+use crate::cpu::cpu::*;
+use crate::cpu::opcodes::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+use crate::json::json::*;
+
+pub const TEST_DIRECTORY: &str = "test-roms/tom_harte";
+pub const SAMPLE_DIRECTORY: &str = "test-roms/tom_harte/sample";
+
+// Where the cases for a CPU model are kept, relative to the test directory
+pub fn model_directory(model: CpuModel) -> &'static str {
+    match model {
+        CpuModel::MOS6502 => "6502/v1",
+        CpuModel::WDC65C02 => "wdc65c02/v1"
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16, u8)>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: State,
+    pub expected: State,
+    pub cycles: Vec<Access>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    // A register, what it should have been and what it was
+    Register(&'static str, u16, u16),
+    Memory(u16, u8, u8),
+    // The number of cycles the CPU reported for the instruction
+    CycleCount(usize, usize),
+    // The first bus cycle that differs, and the accesses expected and made there (None past the end)
+    Bus(usize, Option<Access>, Option<Access>)
+}
+
+impl Mismatch {
+    // Whether this is a difference in the bus activity, rather than in the state the instruction leaves behind
+    pub fn is_bus(&self) -> bool {
+        matches!(self, Mismatch::Bus(..))
+    }
+}
+
+fn format_access(access: &Option<Access>) -> String {
+    match access {
+        Some(Access { address, kind: AccessKind::Read, value }) => format!("read ${:02x} from ${:04x}", value, address),
+        Some(Access { address, kind: AccessKind::Write, value }) => format!("write ${:02x} to ${:04x}", value, address),
+        None => String::from("nothing")
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Register(name, expected, actual) => {
+                write!(f, "{} is ${:02x}, expected ${:02x}", name, actual, expected)
+            }
+            Mismatch::Memory(address, expected, actual) => {
+                write!(f, "${:04x} is ${:02x}, expected ${:02x}", address, actual, expected)
+            }
+            Mismatch::CycleCount(expected, actual) => write!(f, "took {} cycles, expected {}", actual, expected),
+            Mismatch::Bus(cycle, expected, actual) => write!(
+                f,
+                "cycle {} made {}, expected {}",
+                cycle + 1,
+                format_access(actual),
+                format_access(expected)
+            )
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TomHarteError {
+    Io(std::io::Error),
+    Json(JsonError),
+    // The JSON is valid but is not a list of test cases
+    Format(String)
+}
+
+impl fmt::Display for TomHarteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TomHarteError::Io(error) => write!(f, "{}", error),
+            TomHarteError::Json(error) => write!(f, "{}", error),
+            TomHarteError::Format(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl From<std::io::Error> for TomHarteError {
+    fn from(error: std::io::Error) -> Self {
+        TomHarteError::Io(error)
+    }
+}
+
+impl From<JsonError> for TomHarteError {
+    fn from(error: JsonError) -> Self {
+        TomHarteError::Json(error)
+    }
+}
+
+fn number(json: &Json, key: &str, max: i64) -> Result<i64, TomHarteError> {
+    match json.get(key).and_then(Json::as_i64) {
+        Some(value) if (0..=max).contains(&value) => Ok(value),
+        _ => Err(TomHarteError::Format(format!("Missing or invalid \"{}\"", key)))
+    }
+}
+
+// An [address, value] or [address, value, "read" | "write"] entry
+fn entry(json: &Json) -> Option<(u16, u8, Option<&str>)> {
+    let items = json.as_array()?;
+    let address = items.first()?.as_i64().filter(|&address| (0..=0xffff).contains(&address))?;
+    let value = items.get(1)?.as_i64().filter(|&value| (0..=0xff).contains(&value))?;
+    Some((address as u16, value as u8, items.get(2).and_then(Json::as_str)))
+}
+
+impl State {
+    fn parse(json: &Json) -> Result<State, TomHarteError> {
+        let ram = json
+            .get("ram")
+            .and_then(Json::as_array)
+            .ok_or_else(|| TomHarteError::Format(String::from("Missing \"ram\"")))?
+            .iter()
+            .map(|item| entry(item).map(|(address, value, _)| (address, value)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| TomHarteError::Format(String::from("Invalid \"ram\" entry")))?;
+        Ok(State {
+            pc: number(json, "pc", 0xffff)? as u16,
+            sp: number(json, "s", 0xff)? as u8,
+            a: number(json, "a", 0xff)? as u8,
+            x: number(json, "x", 0xff)? as u8,
+            y: number(json, "y", 0xff)? as u8,
+            p: number(json, "p", 0xff)? as u8,
+            ram
+        })
+    }
+}
+
+impl TestCase {
+    pub fn parse(json: &Json) -> Result<TestCase, TomHarteError> {
+        let missing = |key: &str| TomHarteError::Format(format!("Missing \"{}\"", key));
+        let name = json.get("name").and_then(Json::as_str).ok_or_else(|| missing("name"))?;
+        let cycles = json
+            .get("cycles")
+            .and_then(Json::as_array)
+            .ok_or_else(|| missing("cycles"))?
+            .iter()
+            .map(|item| match entry(item)? {
+                (address, value, Some("read")) => Some(Access { address, kind: AccessKind::Read, value }),
+                (address, value, Some("write")) => Some(Access { address, kind: AccessKind::Write, value }),
+                _ => None
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| TomHarteError::Format(format!("Invalid cycle in \"{}\"", name)))?;
+        Ok(TestCase {
+            name: String::from(name),
+            initial: State::parse(json.get("initial").ok_or_else(|| missing("initial"))?)?,
+            expected: State::parse(json.get("final").ok_or_else(|| missing("final"))?)?,
+            cycles
+        })
+    }
+
+    // Parse the cases in a JSON file, which holds an array of them
+    pub fn parse_all(text: &str) -> Result<Vec<TestCase>, TomHarteError> {
+        Json::parse(text)?
+            .as_array()
+            .ok_or_else(|| TomHarteError::Format(String::from("Expected an array of test cases")))?
+            .iter()
+            .map(TestCase::parse)
+            .collect()
+    }
+
+    // Run the instruction and return how it differs from what was expected, which is nothing when it passed, and
+    // whether it stopped the CPU. An error means the CPU could not execute it at all.
+    pub fn run(&self, model: CpuModel) -> Result<(Vec<Mismatch>, bool), CpuError> {
+        let mut memory = MemoryMap::new();
+        memory.create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        for &(address, value) in &self.initial.ram {
+            memory.write(address, value).unwrap();
+        }
+        let mut cpu = CPU::with_model(model);
+        cpu.set_pc(self.initial.pc);
+        cpu.set_sp(self.initial.sp);
+        cpu.set_a(self.initial.a);
+        cpu.set_x(self.initial.x);
+        cpu.set_y(self.initial.y);
+        cpu.set_flags(self.initial.p);
+
+        memory.set_recording(true);
+        let result = cpu.step(&mut memory);
+        let accesses = memory.take_accesses();
+        memory.set_recording(false);
+        let cycles = match result {
+            Ok(cycles) => Some(cycles as usize),
+            Err(CpuError::IllegalOpcode(..) | CpuError::Stopped(_)) => None,
+            Err(error) => return Err(error)
+        };
+
+        let mut mismatches = Vec::new();
+        let expected = &self.expected;
+        let mut registers = vec![
+            ("SP", expected.sp as u16, cpu.sp() as u16),
+            ("SP", expected.sp as u16, cpu.sp() as u16),
+            ("A", expected.a as u16, cpu.a() as u16),
+            ("X", expected.x as u16, cpu.x() as u16),
+            ("Y", expected.y as u16, cpu.y() as u16),
+            ("P", expected.p as u16, cpu.flags() as u16)
+        ];
+        if cycles.is_some() {
+            registers.insert(0, ("PC", expected.pc, cpu.pc()));
+        }
+        for (name, expected, actual) in registers {
+            if expected != actual {
+                mismatches.push(Mismatch::Register(name, expected, actual));
+            }
+        }
+        for &(address, value) in &expected.ram {
+            let actual = memory.peek(address).unwrap_or(0);
+            if actual != value {
+                mismatches.push(Mismatch::Memory(address, value, actual));
+            }
+        }
+        let expected_cycles = match cycles {
+            Some(cycles) => {
+                if cycles != self.cycles.len() {
+                    mismatches.push(Mismatch::CycleCount(self.cycles.len(), cycles));
+                }
+                &self.cycles[..]
+            }
+            None => &self.cycles[..self.cycles.len().min(accesses.len())]
+        };
+        let length = expected_cycles.len().max(accesses.len());
+        if let Some(cycle) = (0..length).find(|&cycle| expected_cycles.get(cycle) != accesses.get(cycle)) {
+            mismatches.push(Mismatch::Bus(cycle, expected_cycles.get(cycle).copied(), accesses.get(cycle).copied()));
+        }
+        Ok((mismatches, cycles.is_none()))
+    }
+}
+
+// The results for all the cases of one opcode
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OpcodeReport {
+    pub opcode: u8,
+    pub cases: usize,
+    // Cases that left the wrong registers or memory behind, or took the wrong number of cycles
+    pub state_failures: usize,
+    // Cases that only differed in their bus activity
+    pub bus_failures: usize,
+    // Cases that stopped the CPU, which are only compared up to the stop
+    pub stopped: usize,
+    // Cases the CPU could not execute
+    pub errors: usize,
+    // The name of the first failing case and how it failed
+    pub first_failure: Option<(String, Vec<Mismatch>)>
+}
+
+impl OpcodeReport {
+    pub fn passed(&self) -> bool {
+        self.state_failures == 0 && self.bus_failures == 0 && self.errors == 0
+    }
+}
+
+impl fmt::Display for OpcodeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "${:02x}: {} cases, {} state failures, {} bus failures, {} stopped, {} errors",
+            self.opcode, self.cases, self.state_failures, self.bus_failures, self.stopped, self.errors
+        )?;
+        if let Some((name, mismatches)) = &self.first_failure {
+            let mismatches = mismatches.iter().map(Mismatch::to_string).collect::<Vec<_>>();
+            write!(f, "; first in \"{}\": {}", name, mismatches.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Run the cases of one opcode
+pub fn run_cases(model: CpuModel, opcode: u8, cases: &[TestCase]) -> OpcodeReport {
+    let mut report = OpcodeReport { opcode, cases: cases.len(), ..OpcodeReport::default() };
+    for case in cases {
+        let mismatches = match case.run(model) {
+            Ok((mismatches, stopped)) => {
+                report.stopped += stopped as usize;
+                if mismatches.is_empty() {
+                    continue;
+                }
+                mismatches
+            }
+            Err(_) => {
+                report.errors += 1;
+                continue;
+            }
+        };
+        if mismatches.iter().all(Mismatch::is_bus) {
+            report.bus_failures += 1;
+        } else {
+            report.state_failures += 1;
+        }
+        if report.first_failure.is_none() {
+            report.first_failure = Some((case.name.clone(), mismatches));
+        }
+    }
+    report
+}
+
+// Run every opcode of a CPU model whose file is in `directory`, or None if there are no cases for the model there
+pub fn run_model<P: AsRef<Path>>(model: CpuModel, directory: P) -> Result<Option<Vec<OpcodeReport>>, TomHarteError> {
+    let directory = directory.as_ref().join(model_directory(model));
+    if !directory.is_dir() {
+        return Ok(None);
+    }
+    let mut reports = Vec::new();
+    for opcode in 0..=0xff {
+        let path = directory.join(format!("{:02x}.json", opcode));
+        if !path.exists() {
+            continue;
+        }
+        let cases = TestCase::parse_all(&std::fs::read_to_string(path)?)?;
+        reports.push(run_cases(model, opcode, &cases));
+    }
+    Ok(Some(reports))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CASES: &str = r#"[
+        {
+            "name": "a9 42 00",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38, "ram": [[512, 169], [513, 66]] },
+            "final": { "pc": 514, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36, "ram": [[512, 169], [513, 66]] },
+            "cycles": [[512, 169, "read"], [513, 66, "read"]]
+        },
+        {
+            "name": "8d 00 03",
+            "initial": {
+                "pc": 512, "s": 253, "a": 7, "x": 0, "y": 0, "p": 36, "ram": [[512, 141], [513, 0], [514, 3]]
+            },
+            "final": { "pc": 515, "s": 253, "a": 7, "x": 0, "y": 0, "p": 36, "ram": [[768, 7]] },
+            "cycles": [[512, 141, "read"], [513, 0, "read"], [514, 3, "read"], [768, 7, "write"]]
+        }
+    ]"#;
+
+    fn suite(model: CpuModel, directory: &str) {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join(directory);
+        let reports = run_model(model, &directory).unwrap();
+        let reports = reports.unwrap_or_else(|| {
+            panic!("The {:?} single step tests are missing, see {}/README.md", model, TEST_DIRECTORY)
+        });
+        assert!(!reports.is_empty(), "There are no {:?} single step tests", model);
+        for report in &reports {
+            if !report.passed() || report.stopped > 0 {
+                eprintln!("{}", report);
+            }
+        }
+        assert!(reports.iter().all(OpcodeReport::passed), "The {:?} single step tests failed", model);
+    }
+
+    #[test]
+    fn tom_harte_cases() {
+        let cases = TestCase::parse_all(CASES).unwrap();
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].expected.a, 0x42);
+        assert_eq!(cases[1].cycles[3], Access { address: 0x0300, kind: AccessKind::Write, value: 0x07 });
+        for case in &cases {
+            assert_eq!(case.run(CpuModel::MOS6502), Ok((vec![], false)));
+        }
+        let report = run_cases(CpuModel::WDC65C02, 0xa9, &cases[..1]);
+        assert!(report.passed());
+        assert_eq!(report.to_string(), "$a9: 1 cases, 0 state failures, 0 bus failures, 0 stopped, 0 errors");
+
+        assert!(matches!(TestCase::parse_all("{}"), Err(TomHarteError::Format(_))));
+        assert!(matches!(TestCase::parse_all("[{\"name\": \"x\"}]"), Err(TomHarteError::Format(_))));
+        assert!(matches!(TestCase::parse_all("["), Err(TomHarteError::Json(_))));
+    }
+
+    #[test]
+    fn tom_harte_mismatches() {
+        let mut cases = TestCase::parse_all(CASES).unwrap();
+
+        // The wrong result, then a dummy read the CPU does not make
+        cases[0].expected.a = 0x43;
+        cases[1].cycles.insert(3, Access { address: 0x0300, kind: AccessKind::Read, value: 0x00 });
+        cases[1].cycles.pop();
+        assert_eq!(cases[0].run(CpuModel::MOS6502), Ok((vec![Mismatch::Register("A", 0x43, 0x42)], false)));
+        let (mismatches, _) = cases[1].run(CpuModel::MOS6502).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].to_string(), "cycle 4 made write $07 to $0300, expected read $00 from $0300");
+
+        let report = run_cases(CpuModel::MOS6502, 0xa9, &cases);
+        assert_eq!((report.state_failures, report.bus_failures, report.errors), (1, 1, 0));
+        assert!(!report.passed());
+        assert_eq!(report.first_failure.as_ref().unwrap().0, "a9 42 00");
+
+        // A missing memory change and cycle are caught too
+        cases[1].expected.ram[0] = (0x0300, 0x08);
+        cases[1].cycles.push(Access { address: 0x0300, kind: AccessKind::Write, value: 0x08 });
+        let (mismatches, _) = cases[1].run(CpuModel::MOS6502).unwrap();
+        assert_eq!(mismatches[0], Mismatch::Memory(0x0300, 0x08, 0x07));
+        assert_eq!(mismatches[1], Mismatch::CycleCount(5, 4));
+
+        assert_eq!(run_model(CpuModel::MOS6502, "/nonexistent").unwrap(), None);
+    }
+
+    #[test]
+    fn tom_harte_stopped() {
+        // A JAM is compared up to where the core stops, after reading the byte that follows it, but not the cycles of
+        // the locked up bus or the program counter
+        let mut jam = TestCase::parse_all(CASES).unwrap().remove(0);
+        jam.initial.ram[0].1 = 0x02;
+        jam.expected = State { a: 0x00, p: 0x26, ..jam.initial.clone() };
+        jam.cycles[0].value = 0x02;
+        jam.cycles.push(Access { address: 0xffff, kind: AccessKind::Read, value: 0x00 });
+        assert_eq!(jam.run(CpuModel::MOS6502), Ok((vec![], true)));
+        let report = run_cases(CpuModel::MOS6502, 0x02, std::slice::from_ref(&jam));
+        assert!(report.passed());
+        assert_eq!(report.stopped, 1);
+
+        // What happens before the stop must still match
+        jam.cycles[1].address = 0x0202;
+        let (mismatches, stopped) = jam.run(CpuModel::MOS6502).unwrap();
+        assert!(stopped);
+        assert_eq!(mismatches[0].to_string(), "cycle 2 made read $42 from $0201, expected read $42 from $0202");
+        jam.expected.x = 0x01;
+        assert!(!run_cases(CpuModel::MOS6502, 0x02, &[jam]).passed());
+    }
+
+    #[test]
+    fn tom_harte_sample() {
+        suite(CpuModel::MOS6502, SAMPLE_DIRECTORY);
+        suite(CpuModel::WDC65C02, SAMPLE_DIRECTORY);
+    }
+
+    #[test]
+    fn tom_harte_mos6502() {
+        suite(CpuModel::MOS6502, TEST_DIRECTORY);
+    }
+
+    #[test]
+    fn tom_harte_wdc65c02() {
+        suite(CpuModel::WDC65C02, TEST_DIRECTORY);
+    }
+}
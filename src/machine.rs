@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+pub mod machine;
+pub mod presets;
+pub mod toml;
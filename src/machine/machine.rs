@@ -0,0 +1,873 @@
+/*!
+ * Machine Configuration
+ *
+ * A MachineConfig describes a whole board: the CPU model and clock, the RAM and ROM regions (optionally banked, and
+ * with images to load), mirrors, and the devices with their addresses and interrupt wiring. The Emulator is built
+ * from one with Emulator::from_config. Configurations are usually read from a TOML file:
+ *
+ * ```toml
+ * name = "My board"
+ *
+ * [cpu]
+ * model = "65c02"             # "6502" or "65c02"
+ * clock = 1_000_000           # Hz
+ *
+ * [[region]]
+ * name = "RAM"
+ * type = "ram"                # "ram" or "rom"
+ * start = 0x0000
+ * size = 0x4000
+ *
+ * [[region]]
+ * name = "ROM"
+ * type = "rom"
+ * start = 0x8000
+ * size = 0x8000
+ * image = "firmware.bin"      # relative to the configuration file
+ * banks = 4                   # banked regions need a register that selects the bank
+ * bank_register = 0x7000
+ *
+ * [[mirror]]
+ * start = 0x4000
+ * size = 0x1000
+ * target = 0x0000             # shows `length` bytes from here, repeated, which is `size` by default
+ *
+ * [[device]]
+ * name = "LCD"
+ * type = "text_display"
+ * address = 0x6000
+ * irq = "irq"                 # "irq", "nmi" or "none"
+ * columns = 16
+ * rows = 2
+ * ```
+ *
+ * The device types and their settings are:
+ * - text_display: columns (40), rows (25)
+ * - keyboard: mode ("ascii" or "matrix"), host (false), which reads keys from the terminal, and via (false), which puts
+ *   a matrix keyboard on the ports of a 6522 VIA at the address, with the columns on port A and the rows on port B
+ * - tms9918: cycles_per_frame (a 60th of a second), frames, a directory to save every frame to (created if need be),
+ *   and frame_format ("png" or "ppm")
+ * - sid: model ("6581" or "8580"), sample_rate (44100), wav, a file to write the output to
+ * - compact_flash: image
+ * - i2c: devices, a list of { type = "ds1307", clock = "host" or "emulated", start } and
+ *   { type = "eeprom", pins, image } tables. The clock starts at `start` seconds since the Unix epoch, and the EEPROM
+ *   image is created if it does not exist.
+ * - spi: devices, a list of { type = "sd_card", chip_select, image } tables, and via (false), which puts the bus on
+ *   port B of a 6522 VIA at the address for firmware that bit-bangs it there, instead of on a port of its own
+ * - via: a 6522 VIA
+ * - pia: a 6821 PIA, with terminal (false) to wire it up as the Apple-1 keyboard and display, and host (false) to
+ *   connect that to the terminal
+ * - riot: the I/O and timer of a 6532 RIOT, whose RAM is a region of its own
+ *
+ * Unknown keys are errors, so that a misspelt setting is not silently ignored.
+ */
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::opcodes::*;
+use crate::devices::banked_memory::*;
+use crate::devices::compact_flash::*;
+use crate::devices::disk_image::*;
+use crate::devices::ds1307::*;
+use crate::devices::eeprom::*;
+use crate::devices::framebuffer::*;
+use crate::devices::i2c::*;
+use crate::devices::keyboard::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+use crate::devices::pia::*;
+use crate::devices::riot::*;
+use crate::devices::sd_card::*;
+use crate::devices::sid::*;
+use crate::devices::spi::*;
+use crate::devices::text_display::*;
+use crate::devices::tms9918::*;
+use crate::devices::via::*;
+use crate::emulator::emulator::DEFAULT_CLOCK_RATE;
+use crate::machine::toml::*;
+
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MachineError {
+    Toml(TomlError),
+    // A setting that is missing, of the wrong type or out of range
+    Config(String),
+    // A file that could not be read, with the reason
+    Io(PathBuf, String),
+    // Regions, mirrors or devices that cannot all be mapped
+    Map(String)
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MachineError::Toml(error) => write!(f, "{}", error),
+            MachineError::Config(message) => write!(f, "{}", message),
+            MachineError::Io(path, message) => write!(f, "{}: {}", path.display(), message),
+            MachineError::Map(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl From<TomlError> for MachineError {
+    fn from(error: TomlError) -> Self {
+        MachineError::Toml(error)
+    }
+}
+
+type MachineResult<T> = Result<T, MachineError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionConfig {
+    pub name: String,
+    // RAM or ROM
+    pub memory_type: MemoryType,
+    pub start: u16,
+    pub size: u32,
+    pub image: Option<PathBuf>,
+    // The number of banks and the address of the register that selects one
+    pub banks: Option<(usize, u16)>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorConfig {
+    pub start: u16,
+    pub size: u32,
+    pub target: u16,
+    pub length: u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum I2cDeviceConfig {
+    // Host time, or emulated time from a start time and the CPU clock
+    DS1307 { start: Option<i64> },
+    Eeprom { pins: u8, image: Option<PathBuf> }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpiDeviceConfig {
+    SdCard { chip_select: usize, image: PathBuf }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceKind {
+    TextDisplay { columns: u8, rows: u8 },
+    // A matrix keyboard can be wired to a VIA
+    Keyboard { mode: KeyboardMode, host: bool, via: bool },
+    // Where to save frames, and in which format
+    Tms9918 { cycles_per_frame: Option<u32>, frames: Option<(PathBuf, ImageFormat)> },
+    Sid { model: SidModel, sample_rate: u32, wav: Option<PathBuf> },
+    CompactFlash { image: PathBuf },
+    I2c(Vec<I2cDeviceConfig>),
+    // The bus can be on its own port or on a VIA's port B
+    Spi { devices: Vec<SpiDeviceConfig>, via: bool },
+    Via,
+    // The Apple-1 terminal, and whether it is connected to the host's
+    Pia { terminal: bool, host: bool },
+    Riot
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub kind: DeviceKind,
+    pub address: u16,
+    pub line: InterruptLine
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineConfig {
+    pub name: String,
+    pub model: CpuModel,
+    pub clock_rate: u32,
+    pub regions: Vec<RegionConfig>,
+    pub mirrors: Vec<MirrorConfig>,
+    pub devices: Vec<DeviceConfig>,
+    // Relative image paths are found from here
+    pub base: PathBuf
+}
+
+impl MachineConfig {
+    // An empty machine, with the default CPU
+    pub fn new(name: &str) -> MachineConfig {
+        MachineConfig {
+            name: String::from(name),
+            model: CpuModel::MOS6502,
+            clock_rate: DEFAULT_CLOCK_RATE,
+            regions: Vec::new(),
+            mirrors: Vec::new(),
+            devices: Vec::new(),
+            base: PathBuf::from(".")
+        }
+    }
+
+    // Read a configuration file. Images are found relative to the directory it is in.
+    pub fn load<P: AsRef<Path>>(path: P) -> MachineResult<MachineConfig> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| MachineError::Io(path.into(), error.to_string()))?;
+        let mut config = MachineConfig::parse(&text)?;
+        config.base = path.parent().map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> MachineResult<MachineConfig> {
+        let toml = Toml::parse(text)?;
+        let root = Table::new(&toml, String::from("The machine"))?;
+        root.check(&["name", "cpu", "region", "mirror", "device"])?;
+
+        let mut config = MachineConfig::new(root.string("name")?.unwrap_or("Unnamed"));
+        if let Some(cpu) = root.table("cpu")? {
+            cpu.check(&["model", "clock"])?;
+            config.model = match cpu.string("model")? {
+                None | Some("6502") => CpuModel::MOS6502,
+                Some("65c02") | Some("65C02") => CpuModel::WDC65C02,
+                Some(model) => return Err(cpu.invalid("model", model))
+            };
+            let clock_rate = cpu.integer("clock", 1, u32::MAX as i64)?;
+            config.clock_rate = clock_rate.map_or(DEFAULT_CLOCK_RATE, |rate| rate as u32);
+        }
+
+        for region in root.tables("region")? {
+            region.check(&["name", "type", "start", "size", "image", "banks", "bank_register"])?;
+            let memory_type = match region.required_string("type")? {
+                "ram" => MemoryType::RAM,
+                "rom" => MemoryType::ROM,
+                kind => return Err(region.invalid("type", kind))
+            };
+            let banks = match region.integer("banks", 1, 256)? {
+                Some(banks) => Some((banks as usize, region.required_address("bank_register")?)),
+                None => None
+            };
+            config.regions.push(RegionConfig {
+                name: String::from(region.required_string("name")?),
+                memory_type,
+                start: region.required_address("start")?,
+                size: region.required_integer("size", 1, 0x10000)? as u32,
+                image: region.string("image")?.map(PathBuf::from),
+                banks
+            });
+        }
+
+        for mirror in root.tables("mirror")? {
+            mirror.check(&["start", "size", "target", "length"])?;
+            let size = mirror.required_integer("size", 1, 0x10000)? as u32;
+            config.mirrors.push(MirrorConfig {
+                start: mirror.required_address("start")?,
+                size,
+                target: mirror.required_address("target")?,
+                length: mirror.integer("length", 1, 0x10000)?.map_or(size, |length| length as u32)
+            });
+        }
+
+        for device in root.tables("device")? {
+            config.devices.push(device_config(&device)?);
+        }
+        Ok(config)
+    }
+
+    // Whether any device reads from or writes to the host's terminal
+    pub fn uses_host(&self) -> bool {
+        self.devices.iter().any(|device| match device.kind {
+            DeviceKind::Keyboard { host, .. } | DeviceKind::Pia { host, .. } => host,
+            _ => false
+        })
+    }
+
+    // Connect the devices that can use the host's terminal to it, or keep them all off it
+    pub fn set_host(&mut self, connect: bool) {
+        for device in &mut self.devices {
+            if let DeviceKind::Keyboard { host, .. } | DeviceKind::Pia { host, .. } = &mut device.kind {
+                *host = connect;
+            }
+        }
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        self.base.join(path)
+    }
+
+    fn read(&self, path: &Path) -> MachineResult<Vec<u8>> {
+        let path = self.path(path);
+        std::fs::read(&path).map_err(|error| MachineError::Io(path, error.to_string()))
+    }
+
+    fn disk_image(&self, path: &Path) -> MachineResult<DiskImage> {
+        let path = self.path(path);
+        DiskImage::open(&path).map_err(|error| MachineError::Io(path, error.to_string()))
+    }
+
+    // Build the memory map: the regions with their images loaded, then the devices and the mirrors
+    pub fn memory_map(&self) -> MachineResult<MemoryMap> {
+        let mut memory_map = MemoryMap::new();
+        let unmappable = |name: &str, start: u16, error: MemoryMapError| {
+            MachineError::Map(match error {
+                MemoryMapError::Overlap => format!("{} at ${:04x} overlaps something else", name, start),
+                MemoryMapError::OutOfBounds => format!("{} at ${:04x} does not fit below $10000", name, start)
+            })
+        };
+
+        for region in &self.regions {
+            let offset = region.start as u32;
+            if offset + region.size > 0x10000 {
+                return Err(unmappable(&region.name, region.start, MemoryMapError::OutOfBounds));
+            }
+            let mut device: Box<dyn Memory> = match region.banks {
+                Some((banks, register)) => {
+                    let memory = BankedMemory::new(region.memory_type, banks, region.size, offset);
+                    let name = format!("{} bank", region.name);
+                    let bank_register = Box::new(memory.register(register as u32));
+                    memory_map
+                        .insert(name.clone(), bank_register, BANK_REGISTER_SIZE, register as u32)
+                        .map_err(|error| unmappable(&name, register, error))?;
+                    Box::new(memory)
+                }
+                None if region.memory_type == MemoryType::ROM => {
+                    Box::new(ROM::new(vec![0; region.size as usize], region.size, offset))
+                }
+                None => Box::new(RAM::new(vec![0; region.size as usize], region.size, offset))
+            };
+            if let Some(image) = &region.image {
+                let data = self.read(image)?;
+                let message = format!("{} is {} bytes, too big for {}", image.display(), data.len(), region.name);
+                device.load(data).map_err(|_| MachineError::Config(message))?;
+            }
+            memory_map
+                .insert(region.name.clone(), device, region.size, offset)
+                .map_err(|error| unmappable(&region.name, region.start, error))?;
+        }
+
+        for device in &self.devices {
+            let (memory, size) = self.device(device)?;
+            memory_map
+                .insert(device.name.clone(), memory, size, device.address as u32)
+                .map_err(|error| unmappable(&device.name, device.address, error))?;
+            memory_map.set_interrupt_line(&device.name, device.line).unwrap();
+        }
+
+        for mirror in &self.mirrors {
+            let name = format!("The mirror of ${:04x}", mirror.target);
+            memory_map
+                .mirror(mirror.start as u32, mirror.size, mirror.target as u32, mirror.length)
+                .map_err(|error| unmappable(&name, mirror.start, error))?;
+        }
+        Ok(memory_map)
+    }
+
+    // Create a device, returning it with the number of addresses it takes up
+    fn device(&self, device: &DeviceConfig) -> MachineResult<(Box<dyn Memory>, u32)> {
+        let offset = device.address as u32;
+        Ok(match &device.kind {
+            DeviceKind::TextDisplay { columns, rows } => {
+                let display = TextDisplay::new(*columns, *rows, offset);
+                let size = display.size();
+                (Box::new(display), size)
+            }
+            DeviceKind::Keyboard { mode, host, via } => {
+                let mut keyboard = Keyboard::new(*mode, offset);
+                if *host {
+                    keyboard.attach_host();
+                }
+                if *via {
+                    let mut via = VIA::new(offset);
+                    via.attach(Box::new(keyboard));
+                    (Box::new(via), VIA_SIZE)
+                } else {
+                    (Box::new(keyboard), KEYBOARD_SIZE)
+                }
+            }
+            DeviceKind::Tms9918 { cycles_per_frame, frames } => {
+                let cycles_per_frame = cycles_per_frame.unwrap_or(self.clock_rate / 60);
+                let mut vdp = TMS9918::new(offset, cycles_per_frame);
+                if let Some((directory, format)) = frames {
+                    let directory = self.path(directory);
+                    std::fs::create_dir_all(&directory)
+                        .map_err(|error| MachineError::Io(directory.clone(), error.to_string()))?;
+                    vdp.set_frame_dump(directory, *format);
+                }
+                (Box::new(vdp), TMS9918_SIZE)
+            }
+            DeviceKind::Sid { model, sample_rate, wav } => {
+                let mut sid = SID::new(*model, offset, self.clock_rate, *sample_rate);
+                if let Some(wav) = wav {
+                    let path = self.path(wav);
+                    sid.set_output(&path).map_err(|error| MachineError::Io(path, error.to_string()))?;
+                }
+                (Box::new(sid), SID_SIZE)
+            }
+            DeviceKind::CompactFlash { image } => {
+                (Box::new(CompactFlash::new(self.disk_image(image)?, offset)), COMPACT_FLASH_SIZE)
+            }
+            DeviceKind::I2c(devices) => {
+                let mut port = I2cPort::new(offset);
+                for config in devices {
+                    match config {
+                        I2cDeviceConfig::DS1307 { start } => {
+                            let source = match start {
+                                Some(start) => ClockSource::Emulated { clock_rate: self.clock_rate, start: *start },
+                                None => ClockSource::Host
+                            };
+                            port.attach(Box::new(DS1307::new(source)));
+                        }
+                        I2cDeviceConfig::Eeprom { pins, image: Some(image) } => {
+                            let path = self.path(image);
+                            match EEPROM::open(&path, *pins) {
+                                Ok(eeprom) => port.attach(Box::new(eeprom)),
+                                Err(error) => return Err(MachineError::Io(path, error.to_string()))
+                            }
+                        }
+                        I2cDeviceConfig::Eeprom { pins, image: None } => port.attach(Box::new(EEPROM::new(*pins)))
+                    }
+                }
+                (Box::new(port), I2C_PORT_SIZE)
+            }
+            DeviceKind::Spi { devices, via } => {
+                let mut bus = SpiBus::new();
+                for config in devices {
+                    match config {
+                        SpiDeviceConfig::SdCard { chip_select, image } => {
+                            bus.attach(*chip_select, Box::new(SdCard::new(self.disk_image(image)?)));
+                        }
+                    }
+                }
+                if *via {
+                    let mut via = VIA::new(offset);
+                    via.attach(Box::new(bus));
+                    (Box::new(via), VIA_SIZE)
+                } else {
+                    (Box::new(SpiPort::with_bus(bus, offset)), SPI_PORT_SIZE)
+                }
+            }
+            DeviceKind::Via => (Box::new(VIA::new(offset)), VIA_SIZE),
+            DeviceKind::Pia { terminal, host } => {
+                let mut pia = PIA::new(offset);
+                if *terminal {
+                    pia.attach_terminal(*host);
+                }
+                (Box::new(pia), PIA_SIZE)
+            }
+            DeviceKind::Riot => (Box::new(RIOT::new(offset)), RIOT_SIZE)
+        })
+    }
+}
+
+fn device_config(device: &Table) -> MachineResult<DeviceConfig> {
+    let common = ["name", "type", "address", "irq"];
+    let settings: &[&str] = match device.required_string("type")? {
+        "text_display" => &["columns", "rows"],
+        "keyboard" => &["mode", "host", "via"],
+        "tms9918" => &["cycles_per_frame", "frames", "frame_format"],
+        "sid" => &["model", "sample_rate", "wav"],
+        "compact_flash" => &["image"],
+        "i2c" => &["devices"],
+        "spi" => &["devices", "via"],
+        "via" | "riot" => &[],
+        "pia" => &["terminal", "host"],
+        kind => return Err(device.invalid("type", kind))
+    };
+    device.check(&[&common[..], settings].concat())?;
+
+    let kind = match device.required_string("type")? {
+        "text_display" => DeviceKind::TextDisplay {
+            columns: device.integer("columns", 1, 255)?.unwrap_or(40) as u8,
+            rows: device.integer("rows", 1, 255)?.unwrap_or(25) as u8
+        },
+        "keyboard" => {
+            let mode = match device.string("mode")? {
+                None | Some("ascii") => KeyboardMode::Ascii,
+                Some("matrix") => KeyboardMode::Matrix,
+                Some(mode) => return Err(device.invalid("mode", mode))
+            };
+            let via = device.boolean("via")?.unwrap_or(false);
+            if via && mode != KeyboardMode::Matrix {
+                return Err(device.error(String::from("only a matrix keyboard can be wired to a VIA")));
+            }
+            DeviceKind::Keyboard { mode, host: device.boolean("host")?.unwrap_or(false), via }
+        }
+        "tms9918" => DeviceKind::Tms9918 {
+            cycles_per_frame: device.integer("cycles_per_frame", 1, u32::MAX as i64)?.map(|cycles| cycles as u32),
+            frames: match device.string("frames")? {
+                Some(directory) => Some((PathBuf::from(directory), match device.string("frame_format")? {
+                    None | Some("png") => ImageFormat::PNG,
+                    Some("ppm") => ImageFormat::PPM,
+                    Some(format) => return Err(device.invalid("frame_format", format))
+                })),
+                None => None
+            }
+        },
+        "sid" => DeviceKind::Sid {
+            model: match device.string("model")? {
+                None | Some("6581") => SidModel::MOS6581,
+                Some("8580") => SidModel::MOS8580,
+                Some(model) => return Err(device.invalid("model", model))
+            },
+            sample_rate: device.integer("sample_rate", 1, 192_000)?.map_or(DEFAULT_SAMPLE_RATE, |rate| rate as u32),
+            wav: device.string("wav")?.map(PathBuf::from)
+        },
+        "compact_flash" => DeviceKind::CompactFlash { image: PathBuf::from(device.required_string("image")?) },
+        "i2c" => {
+            let mut devices = Vec::new();
+            for config in device.tables("devices")? {
+                devices.push(match config.required_string("type")? {
+                    "ds1307" => {
+                        config.check(&["type", "clock", "start"])?;
+                        let start = config.integer("start", i64::MIN, i64::MAX)?.unwrap_or(Y2K);
+                        I2cDeviceConfig::DS1307 {
+                            start: match config.string("clock")? {
+                                None | Some("host") => None,
+                                Some("emulated") => Some(start),
+                                Some(clock) => return Err(config.invalid("clock", clock))
+                            }
+                        }
+                    }
+                    "eeprom" => {
+                        config.check(&["type", "pins", "image"])?;
+                        I2cDeviceConfig::Eeprom {
+                            pins: config.integer("pins", 0, 7)?.unwrap_or(0) as u8,
+                            image: config.string("image")?.map(PathBuf::from)
+                        }
+                    }
+                    kind => return Err(config.invalid("type", kind))
+                });
+            }
+            DeviceKind::I2c(devices)
+        }
+        "via" => DeviceKind::Via,
+        "pia" => DeviceKind::Pia {
+            terminal: device.boolean("terminal")?.unwrap_or(false),
+            host: device.boolean("host")?.unwrap_or(false)
+        },
+        "riot" => DeviceKind::Riot,
+        "spi" => {
+            let mut devices = Vec::new();
+            for config in device.tables("devices")? {
+                devices.push(match config.required_string("type")? {
+                    "sd_card" => {
+                        config.check(&["type", "chip_select", "image"])?;
+                        SpiDeviceConfig::SdCard {
+                            chip_select: config.integer("chip_select", 0, 2)?.unwrap_or(0) as usize,
+                            image: PathBuf::from(config.required_string("image")?)
+                        }
+                    }
+                    kind => return Err(config.invalid("type", kind))
+                });
+            }
+            DeviceKind::Spi { devices, via: device.boolean("via")?.unwrap_or(false) }
+        }
+        kind => return Err(device.invalid("type", kind))
+    };
+
+    Ok(DeviceConfig {
+        name: String::from(device.required_string("name")?),
+        kind,
+        address: device.required_address("address")?,
+        line: match device.string("irq")? {
+            None | Some("irq") => InterruptLine::Irq,
+            Some("nmi") => InterruptLine::Nmi,
+            Some("none") => InterruptLine::None,
+            Some(line) => return Err(device.invalid("irq", line))
+        }
+    })
+}
+
+// A table in the configuration, with what it is called in error messages
+struct Table<'a> {
+    toml: &'a Toml,
+    context: String
+}
+
+impl<'a> Table<'a> {
+    fn new(toml: &'a Toml, context: String) -> MachineResult<Table<'a>> {
+        if toml.as_table().is_none() {
+            return Err(MachineError::Config(format!("{} must be a table", context)));
+        }
+        Ok(Table { toml, context })
+    }
+
+    fn error(&self, message: String) -> MachineError {
+        MachineError::Config(format!("{}: {}", self.context, message))
+    }
+
+    fn invalid(&self, key: &str, value: &str) -> MachineError {
+        self.error(format!("{} cannot be {}", key, value))
+    }
+
+    // Fail on any key that is not allowed
+    fn check(&self, allowed: &[&str]) -> MachineResult<()> {
+        for (key, _) in self.toml.as_table().unwrap() {
+            if !allowed.contains(&key.as_str()) {
+                return Err(self.error(format!("unknown setting {}", key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str, value: &Toml) -> MachineError {
+        self.error(format!("{} must be {}, not {}", key, expected, value.type_name()))
+    }
+
+    fn string(&self, key: &str) -> MachineResult<Option<&'a str>> {
+        match self.toml.get(key) {
+            None => Ok(None),
+            Some(value) => value.as_str().map(Some).ok_or_else(|| self.wrong_type(key, "a string", value))
+        }
+    }
+
+    fn required_string(&self, key: &str) -> MachineResult<&'a str> {
+        self.string(key)?.ok_or_else(|| self.error(format!("{} is missing", key)))
+    }
+
+    fn boolean(&self, key: &str) -> MachineResult<Option<bool>> {
+        match self.toml.get(key) {
+            None => Ok(None),
+            Some(value) => value.as_bool().map(Some).ok_or_else(|| self.wrong_type(key, "a boolean", value))
+        }
+    }
+
+    fn integer(&self, key: &str, minimum: i64, maximum: i64) -> MachineResult<Option<i64>> {
+        match self.toml.get(key) {
+            None => Ok(None),
+            Some(value) => match value.as_i64() {
+                Some(number) if number >= minimum && number <= maximum => Ok(Some(number)),
+                Some(number) => {
+                    Err(self.error(format!("{} must be from {} to {}, not {}", key, minimum, maximum, number)))
+                }
+                None => Err(self.wrong_type(key, "an integer", value))
+            }
+        }
+    }
+
+    fn required_integer(&self, key: &str, minimum: i64, maximum: i64) -> MachineResult<i64> {
+        self.integer(key, minimum, maximum)?.ok_or_else(|| self.error(format!("{} is missing", key)))
+    }
+
+    fn required_address(&self, key: &str) -> MachineResult<u16> {
+        Ok(self.required_integer(key, 0, 0xffff)? as u16)
+    }
+
+    fn table(&self, key: &str) -> MachineResult<Option<Table<'a>>> {
+        match self.toml.get(key) {
+            None => Ok(None),
+            Some(value) => Table::new(value, format!("[{}]", key)).map(Some)
+        }
+    }
+
+    // An array of tables, each named after its place in the array in error messages
+    fn tables(&self, key: &str) -> MachineResult<Vec<Table<'a>>> {
+        match self.toml.get(key) {
+            None => Ok(Vec::new()),
+            Some(value) => {
+                let values = value.as_array().ok_or_else(|| self.wrong_type(key, "an array of tables", value))?;
+                let context = |index: usize| format!("{} {} of {}", key, index + 1, self.context.to_lowercase());
+                values.iter().enumerate().map(|(index, value)| Table::new(value, context(index))).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator::*;
+
+    const CONFIG: &str = r#"
+name = "Test board"
+
+[cpu]
+model = "65c02"
+clock = 1_843_200
+
+[[region]]
+name = "RAM"
+type = "ram"
+start = 0x0000
+size = 0x4000
+
+[[region]]
+name = "ROM"
+type = "rom"
+start = 0xc000
+size = 0x4000
+image = "rom.bin"
+banks = 2
+bank_register = 0x7000
+
+[[mirror]]
+start = 0x4000
+size = 0x2000
+target = 0x0000
+length = 0x1000
+
+[[device]]
+name = "LCD"
+type = "text_display"
+address = 0x6000
+columns = 16
+rows = 2
+
+[[device]]
+name = "I2C"
+type = "i2c"
+address = 0x7100
+irq = "none"
+devices = [{ type = "ds1307", clock = "emulated", start = 946684800 }, { type = "eeprom", pins = 1 }]
+"#;
+
+    #[test]
+    fn machine_config() {
+        let config = MachineConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.name, "Test board");
+        assert_eq!((config.model, config.clock_rate), (CpuModel::WDC65C02, 1_843_200));
+        assert_eq!(config.regions[1].banks, Some((2, 0x7000)));
+        assert_eq!(config.mirrors[0], MirrorConfig { start: 0x4000, size: 0x2000, target: 0x0000, length: 0x1000 });
+        assert_eq!(config.devices[0].kind, DeviceKind::TextDisplay { columns: 16, rows: 2 });
+        assert_eq!(config.devices[0].line, InterruptLine::Irq);
+        let i2c = vec![I2cDeviceConfig::DS1307 { start: Some(Y2K) }, I2cDeviceConfig::Eeprom { pins: 1, image: None }];
+        assert_eq!(config.devices[1].kind, DeviceKind::I2c(i2c));
+        assert_eq!(config.devices[1].line, InterruptLine::None);
+
+        // The ROM image is found next to the configuration file, and fills both banks
+        let directory = std::env::temp_dir().join(format!("mini-6502-remu-machine-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut image = vec![0xea; 0x8000];
+        image[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0xc0]);
+        image[0x4000] = 0x42;
+        std::fs::write(directory.join("rom.bin"), &image).unwrap();
+        std::fs::write(directory.join("board.toml"), CONFIG).unwrap();
+        let config = MachineConfig::load(directory.join("board.toml")).unwrap();
+        let mut emulator = Emulator::from_config(&config).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(emulator.cpu().model(), CpuModel::WDC65C02);
+        assert_eq!(emulator.clock_rate(), 1_843_200);
+        emulator.start().unwrap();
+        assert_eq!(emulator.cpu().pc(), 0xc000);
+        let memory = emulator.memory_map_mut();
+        assert_eq!(memory.read(0xc000).unwrap(), 0xea);
+        memory.write(0x7000, 1).unwrap();
+        assert_eq!(memory.read(0xc000).unwrap(), 0x42);
+
+        // The mirror repeats the first 4K of RAM
+        memory.write(0x0123, 0x55).unwrap();
+        assert_eq!(memory.read(0x4123).unwrap(), 0x55);
+        assert_eq!(memory.read(0x5123).unwrap(), 0x55);
+        memory.write(0x5124, 0x66).unwrap();
+        assert_eq!(memory.read(0x0124).unwrap(), 0x66);
+        assert_eq!(memory.read(0x6000 + 32).unwrap(), 0x00);
+
+        // A matrix keyboard on a VIA takes up the VIA's registers
+        let text = "[[device]]\nname = \"Keys\"\ntype = \"keyboard\"\naddress = 0x6000\nmode = \"matrix\"\nvia = true";
+        let mut memory = MachineConfig::parse(text).unwrap().memory_map().unwrap();
+        memory.write(0x6003, 0xff).unwrap();
+        memory.write(0x6001, 0x00).unwrap();
+        assert_eq!(memory.read(0x6000).unwrap(), 0xff);
+        assert_eq!(memory.read(0x600e).unwrap(), 0x80);
+
+        // So does an SPI bus on a VIA's port B, where MISO floats high with nothing selected
+        let text = "[[device]]\nname = \"SPI\"\ntype = \"spi\"\naddress = 0x6000\ndevices = []\nvia = true";
+        let mut memory = MachineConfig::parse(text).unwrap().memory_map().unwrap();
+        memory.write(0x6002, 0x1f).unwrap();
+        memory.write(0x6000, 0x1c).unwrap();
+        assert_eq!(memory.read(0x6000).unwrap(), 0xfc);
+        assert_eq!(memory.read(0x600e).unwrap(), 0x80);
+    }
+
+    #[test]
+    fn machine_config_output() {
+        let text = r#"
+[[region]]
+name = "RAM"
+type = "ram"
+start = 0x0000
+size = 0xd000
+
+[[region]]
+name = "High RAM"
+type = "ram"
+start = 0xe000
+size = 0x2000
+
+[[device]]
+name = "VDP"
+type = "tms9918"
+address = 0xd000
+cycles_per_frame = 1000
+frames = "frames"
+frame_format = "ppm"
+
+[[device]]
+name = "SID"
+type = "sid"
+address = 0xd400
+sample_rate = 1000
+wav = "sid.wav"
+"#;
+        let directory = std::env::temp_dir().join(format!("mini-6502-remu-output-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("board.toml"), text).unwrap();
+        let config = MachineConfig::load(directory.join("board.toml")).unwrap();
+        let mut emulator = Emulator::from_config(&config).unwrap();
+
+        // The BRKs in empty RAM run for long enough to fill a few frames and some samples
+        emulator.start().unwrap();
+        emulator.run(1000).unwrap();
+        drop(emulator);
+        let frame = directory.join("frames").join("frame_00000.ppm");
+        let frame_written = frame.exists();
+        let wav = std::fs::read(directory.join("sid.wav")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(frame_written);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert!(wav.len() > 44);
+        assert_eq!(u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]) as usize, wav.len() - 44);
+
+        let text = "[[device]]\nname = \"VDP\"\ntype = \"tms9918\"\naddress = 0\nframes = \"out\"";
+        let error = MachineConfig::parse(&format!("{}\nframe_format = \"gif\"", text)).unwrap_err().to_string();
+        assert_eq!(error, "device 1 of the machine: frame_format cannot be gif");
+    }
+
+    #[test]
+    fn machine_config_errors() {
+        let error = |text: &str| MachineConfig::parse(text).unwrap_err().to_string();
+        assert_eq!(error("[cpu]\nmodel = \"z80\""), "[cpu]: model cannot be z80");
+        assert_eq!(error("[cpu]\nclock = \"fast\""), "[cpu]: clock must be an integer, not a string");
+        assert_eq!(error("[cpu]\nspeed = 1"), "[cpu]: unknown setting speed");
+        let message = "region 1 of the machine: size is missing";
+        assert_eq!(error("[[region]]\nname = \"RAM\"\ntype = \"ram\"\nstart = 0"), message);
+        assert_eq!(error("[[device]]\ntype = \"ula\""), "device 1 of the machine: type cannot be ula");
+        let message = "device 1 of the machine: only a matrix keyboard can be wired to a VIA";
+        assert_eq!(error("[[device]]\nname = \"K\"\ntype = \"keyboard\"\naddress = 0\nvia = true"), message);
+        let text = "[[device]]\nname = \"I2C\"\ntype = \"i2c\"\naddress = 0\ndevices = [{type = \"eeprom\", pins = 9}]";
+        assert_eq!(error(text), "devices 1 of device 1 of the machine: pins must be from 0 to 7, not 9");
+        let message = "mirror 1 of the machine: start must be from 0 to 65535, not 65536";
+        assert_eq!(error("[[mirror]]\nstart = 0x10000\nsize = 1"), message);
+        assert!(error("name = ").starts_with("Line 1: "));
+
+        // Regions that overlap, or an image that is not there, are found when the machine is built
+        let mut config = MachineConfig::new("Overlapping");
+        let region = |name: &str, start: u16, size: u32| RegionConfig {
+            name: String::from(name),
+            memory_type: MemoryType::RAM,
+            start,
+            size,
+            image: None,
+            banks: None
+        };
+        config.regions = vec![region("Low", 0x0000, 0x8000), region("All", 0x0000, 0x10000)];
+        let error = Emulator::from_config(&config).unwrap_err();
+        assert_eq!(error, MachineError::Map(String::from("All at $0000 overlaps something else")));
+        config.regions = vec![region("Low", 0x0000, 0x8000), region("High", 0xc000, 0x8000)];
+        let error = Emulator::from_config(&config).unwrap_err();
+        assert_eq!(error, MachineError::Map(String::from("High at $c000 does not fit below $10000")));
+
+        // So are devices that run off the top of memory
+        let text = "[[device]]\nname = \"Screen\"\ntype = \"text_display\"\naddress = 0xff00";
+        let error = Emulator::from_config(&MachineConfig::parse(text).unwrap()).unwrap_err();
+        assert_eq!(error, MachineError::Map(String::from("Screen at $ff00 does not fit below $10000")));
+        let mut missing = region("ROM", 0x8000, 0x8000);
+        missing.image = Some(PathBuf::from("/nonexistent/rom.bin"));
+        config.regions = vec![missing];
+        assert!(matches!(Emulator::from_config(&config), Err(MachineError::Io(..))));
+    }
+}
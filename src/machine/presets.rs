@@ -0,0 +1,238 @@
+/*!
+ * Machine Presets
+ *
+ * The machines built in, selected by name (such as `--machine apple1`). Each is a machine configuration written the
+ * same way as a configuration file, so any of them can be copied out and changed. None come with ROM images, so the
+ * firmware is loaded into the region called ROM:
+ * - be6502: Ben Eater's 6502 computer. 16K of RAM, a 6522 VIA at $6000 (repeated up to $7FFF, as only A13-A15 are
+ *   decoded) and a 32K ROM at $8000.
+ * - apple1: the Apple-1. 4K of RAM at $0000, 4K more at $E000 where BASIC goes, the PIA at $D010 wired up as the
+ *   keyboard and display, and Wozmon's 256 bytes of ROM at $FF00.
+ * - kim1: the KIM-1. 1K of RAM, the I/O and timers of its two 6530 RRIOTs at $1700 and $1740, their RAM at $1780
+ *   and their ROMs at $1800, with the top 1K of ROM repeated at $FC00 for the vectors. The keypad, LED display and
+ *   teletype that the ROM drives through the ports are not modelled.
+ * - generic: 64K of RAM and nothing else.
+ */
+
+use crate::machine::machine::*;
+
+const BE6502: &str = r#"
+name = "Ben Eater's 6502 computer"
+
+[cpu]
+model = "65c02"
+clock = 1_000_000
+
+[[region]]
+name = "RAM"
+type = "ram"
+start = 0x0000
+size = 0x4000
+
+[[region]]
+name = "ROM"
+type = "rom"
+start = 0x8000
+size = 0x8000
+
+[[device]]
+name = "VIA"
+type = "via"
+address = 0x6000
+
+[[mirror]]
+start = 0x6010
+size = 0x1ff0
+target = 0x6000
+length = 0x10
+"#;
+
+const APPLE1: &str = r#"
+name = "Apple-1"
+
+[cpu]
+model = "6502"
+clock = 1_022_727
+
+[[region]]
+name = "RAM"
+type = "ram"
+start = 0x0000
+size = 0x1000
+
+[[region]]
+name = "BASIC RAM"
+type = "ram"
+start = 0xe000
+size = 0x1000
+
+[[region]]
+name = "ROM"
+type = "rom"
+start = 0xff00
+size = 0x100
+
+[[device]]
+name = "PIA"
+type = "pia"
+address = 0xd010
+irq = "none"
+terminal = true
+host = true
+"#;
+
+const KIM1: &str = r#"
+name = "KIM-1"
+
+[cpu]
+model = "6502"
+clock = 1_000_000
+
+[[region]]
+name = "RAM"
+type = "ram"
+start = 0x0000
+size = 0x0400
+
+[[region]]
+name = "RRIOT RAM"
+type = "ram"
+start = 0x1780
+size = 0x80
+
+[[region]]
+name = "ROM"
+type = "rom"
+start = 0x1800
+size = 0x0800
+
+# The RRIOTs' interrupt outputs are only connected with a jumper
+[[device]]
+name = "6530-003"
+type = "riot"
+address = 0x1700
+irq = "none"
+
+[[device]]
+name = "6530-002"
+type = "riot"
+address = 0x1740
+irq = "none"
+
+[[mirror]]
+start = 0x1720
+size = 0x20
+target = 0x1700
+
+[[mirror]]
+start = 0x1760
+size = 0x20
+target = 0x1740
+
+[[mirror]]
+start = 0xfc00
+size = 0x400
+target = 0x1c00
+"#;
+
+const GENERIC: &str = r#"
+name = "Generic 64K"
+
+[[region]]
+name = "RAM"
+type = "ram"
+start = 0x0000
+size = 0x10000
+"#;
+
+// The names of the presets, with their configurations
+pub const PRESETS: [(&str, &str); 4] = [("be6502", BE6502), ("apple1", APPLE1), ("kim1", KIM1), ("generic", GENERIC)];
+
+impl MachineConfig {
+    // A machine built in, by name
+    pub fn preset(name: &str) -> Option<MachineConfig> {
+        let (_, text) = PRESETS.iter().find(|(preset, _)| preset.eq_ignore_ascii_case(name))?;
+        Some(MachineConfig::parse(text).expect("The machine presets are valid"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::opcodes::*;
+    use crate::devices::memory::*;
+    use crate::emulator::emulator::*;
+
+    // Build a preset, with any terminal kept off the host
+    fn build(name: &str) -> Emulator {
+        let mut config = MachineConfig::preset(name).unwrap();
+        config.set_host(false);
+        Emulator::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn presets() {
+        for (name, _) in PRESETS {
+            build(name);
+        }
+        assert!(MachineConfig::preset("c64").is_none());
+        let apple1 = MachineConfig::preset("Apple1").unwrap();
+        assert_eq!(apple1.name, "Apple-1");
+        assert!(apple1.uses_host() && !MachineConfig::preset("kim1").unwrap().uses_host());
+
+        // The VIA shows through all of $6000-$7FFF
+        let mut be6502 = build("be6502");
+        assert_eq!(be6502.cpu().model(), CpuModel::WDC65C02);
+        let memory = be6502.memory_map_mut();
+        memory.write(0x6003, 0xff).unwrap();
+        assert_eq!(memory.read(0x7ff3).unwrap(), 0xff);
+        assert_eq!(memory.read(0x4000), Err(MemoryError::Unmapped));
+
+        // The KIM-1's vectors come from the top of its ROM
+        let mut kim1 = build("kim1");
+        let mut rom = vec![0; 0x800];
+        rom[0x7fc..0x7fe].copy_from_slice(&[0x22, 0x1c]);
+        kim1.memory_map_mut().load("ROM", rom).unwrap();
+        kim1.start().unwrap();
+        assert_eq!(kim1.cpu().pc(), 0x1c22);
+        kim1.memory_map_mut().write(0x1761, 0x0f).unwrap();
+        assert_eq!(kim1.memory_map().read(0x1741).unwrap(), 0x0f);
+
+        // A cold reset clears the RAM each machine has, however little, and leaves its ROM alone
+        kim1.memory_map_mut().write(0x03ff, 0x55).unwrap();
+        kim1.cold_reset();
+        assert_eq!(kim1.memory_map().read(0x03ff).unwrap(), 0x00);
+        assert_eq!(kim1.memory_map().read(0x1ffc).unwrap(), 0x22);
+        for (name, _) in PRESETS {
+            let mut emulator = build(name);
+            emulator.memory_map_mut().write(0x0000, 0x55).unwrap();
+            emulator.cold_reset();
+            assert_eq!(emulator.memory_map().read(0x0000).unwrap(), 0x00);
+        }
+
+        let generic = build("generic");
+        assert_eq!(generic.memory_map().count(), 1);
+    }
+
+    #[test]
+    fn preset_apple1() {
+        // Echo each key typed to the display the way Wozmon does: $FF0D waits for a key and $FF15 for the display
+        let program = [
+            0xa0, 0x7f, 0x8c, 0x12, 0xd0, 0xa9, 0xa7, 0x8d, 0x11, 0xd0, 0x8d, 0x13, 0xd0, 0xad, 0x11, 0xd0, 0x10, 0xfb,
+            0xad, 0x10, 0xd0, 0x2c, 0x12, 0xd0, 0x30, 0xfb, 0x8d, 0x12, 0xd0, 0x4c, 0x0d, 0xff
+        ];
+        let mut rom = vec![0; 0x100];
+        rom[..program.len()].copy_from_slice(&program);
+        rom[0xfc..0xfe].copy_from_slice(&[0x00, 0xff]);
+
+        let mut emulator = build("apple1");
+        emulator.memory_map_mut().load("ROM", rom).unwrap();
+        emulator.start().unwrap();
+        emulator.memory_map_mut().input("PIA", b"hi\n").unwrap();
+        emulator.run(500).unwrap();
+        emulator.memory_map_mut().input("PIA", b"!").unwrap();
+        emulator.run(100).unwrap();
+        assert_eq!(emulator.memory_map().peek(0xd011).unwrap() & 0x80, 0);
+        assert_eq!(emulator.memory_map().read(0xd012).unwrap() & 0x7f, b'!');
+    }
+}
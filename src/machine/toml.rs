@@ -0,0 +1,507 @@
+/*!
+ * TOML Values
+ *
+ * Just enough TOML for machine descriptions: tables, arrays of tables, dotted keys, basic and literal strings,
+ * integers (decimal, 0x hex, 0o octal and 0b binary, with underscores), floats, booleans, arrays and inline tables.
+ * Multi-line strings and dates are not supported. Tables keep their keys in the order they were written.
+ */
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Toml {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Toml>),
+    Table(Vec<(String, Toml)>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TomlError {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Toml {
+    // Parse a document into its root table
+    pub fn parse(text: &str) -> Result<Toml, TomlError> {
+        let mut parser = Parser { text: text.as_bytes(), position: 0, line: 1 };
+        let mut root = Vec::new();
+        // The table that keys go into, and the tables defined by a header, which cannot be defined again
+        let mut current: Vec<String> = Vec::new();
+        let mut defined: Vec<Vec<String>> = Vec::new();
+
+        loop {
+            parser.blank_lines();
+            match parser.peek() {
+                None => break,
+                Some(b'[') => {
+                    parser.position += 1;
+                    let array = parser.peek() == Some(b'[');
+                    if array {
+                        parser.position += 1;
+                    }
+                    parser.spaces();
+                    let path = parser.key()?;
+                    parser.spaces();
+                    parser.expect(b']')?;
+                    if array {
+                        parser.expect(b']')?;
+                        push_table(&mut root, &path, parser.line)?;
+                    } else {
+                        if defined.contains(&path) {
+                            return Err(parser.error(&format!("Table {} is defined twice", path.join("."))));
+                        }
+                        table(&mut root, &path, parser.line)?;
+                        defined.push(path.clone());
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    let key = parser.key()?;
+                    parser.spaces();
+                    parser.expect(b'=')?;
+                    parser.spaces();
+                    let value = parser.value()?;
+                    let line = parser.line;
+                    let mut path = current.clone();
+                    path.extend_from_slice(&key[..key.len() - 1]);
+                    insert(table(&mut root, &path, line)?, &key[key.len() - 1], value, line)?;
+                }
+            }
+            parser.end_of_line()?;
+        }
+        Ok(Toml::Table(root))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Toml> {
+        match self {
+            Toml::Table(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Toml::String(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Toml::Integer(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    // Integers are also accepted where a float is expected
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Toml::Float(value) => Some(*value),
+            Toml::Integer(value) => Some(*value as f64),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Toml::Bool(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Toml>> {
+        match self {
+            Toml::Array(values) => Some(values),
+            _ => None
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Vec<(String, Toml)>> {
+        match self {
+            Toml::Table(fields) => Some(fields),
+            _ => None
+        }
+    }
+
+    // The name of the type, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Toml::String(_) => "a string",
+            Toml::Integer(_) => "an integer",
+            Toml::Float(_) => "a float",
+            Toml::Bool(_) => "a boolean",
+            Toml::Array(_) => "an array",
+            Toml::Table(_) => "a table"
+        }
+    }
+}
+
+fn error(line: usize, message: String) -> TomlError {
+    TomlError { line, message }
+}
+
+fn insert(fields: &mut Vec<(String, Toml)>, key: &str, value: Toml, line: usize) -> Result<(), TomlError> {
+    if fields.iter().any(|(name, _)| name == key) {
+        return Err(error(line, format!("Key {} is defined twice", key)));
+    }
+    fields.push((String::from(key), value));
+    Ok(())
+}
+
+// The table at a path, creating tables along the way. A path through an array of tables goes into its last table.
+fn table<'a>(
+    fields: &'a mut Vec<(String, Toml)>,
+    path: &[String],
+    line: usize
+) -> Result<&'a mut Vec<(String, Toml)>, TomlError> {
+    let Some((first, rest)) = path.split_first() else {
+        return Ok(fields);
+    };
+    let index = match fields.iter().position(|(name, _)| name == first) {
+        Some(index) => index,
+        None => {
+            fields.push((first.clone(), Toml::Table(Vec::new())));
+            fields.len() - 1
+        }
+    };
+    match &mut fields[index].1 {
+        Toml::Table(inner) => table(inner, rest, line),
+        Toml::Array(values) => match values.last_mut() {
+            Some(Toml::Table(inner)) => table(inner, rest, line),
+            _ => Err(error(line, format!("{} is not a table", first)))
+        },
+        _ => Err(error(line, format!("{} is not a table", first)))
+    }
+}
+
+// Add a table to the end of the array of tables at a path
+fn push_table(fields: &mut Vec<(String, Toml)>, path: &[String], line: usize) -> Result<(), TomlError> {
+    let (last, parent) = path.split_last().unwrap();
+    let parent = table(fields, parent, line)?;
+    match parent.iter_mut().find(|(name, _)| name == last) {
+        Some((_, Toml::Array(values))) if values.iter().all(|value| matches!(value, Toml::Table(_))) => {
+            values.push(Toml::Table(Vec::new()));
+        }
+        Some(_) => return Err(error(line, format!("{} is not an array of tables", last))),
+        None => parent.push((last.clone(), Toml::Array(vec![Toml::Table(Vec::new())])))
+    }
+    Ok(())
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+    line: usize
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> TomlError {
+        error(self.line, String::from(message))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), TomlError> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected '{}'", expected as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn spaces(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.position += 1;
+        }
+    }
+
+    fn comment(&mut self) {
+        if self.peek() == Some(b'#') {
+            while !matches!(self.peek(), None | Some(b'\n')) {
+                self.position += 1;
+            }
+        }
+    }
+
+    fn newline(&mut self) -> bool {
+        if self.text[self.position..].starts_with(b"\r\n") {
+            self.position += 1;
+        }
+        if self.peek() == Some(b'\n') {
+            self.position += 1;
+            self.line += 1;
+            return true;
+        }
+        false
+    }
+
+    // Skip spaces, comments and empty lines
+    fn blank_lines(&mut self) {
+        loop {
+            self.spaces();
+            self.comment();
+            if !self.newline() {
+                break;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), TomlError> {
+        self.spaces();
+        self.comment();
+        if self.peek().is_some() && !self.newline() {
+            return Err(self.error("Expected the end of the line"));
+        }
+        Ok(())
+    }
+
+    // A key, which can be dotted: a.b."c d"
+    fn key(&mut self) -> Result<Vec<String>, TomlError> {
+        let mut path = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some(b'"') => self.basic_string()?,
+                Some(b'\'') => self.literal_string()?,
+                _ => {
+                    let start = self.position;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_' || c == b'-') {
+                        self.position += 1;
+                    }
+                    if start == self.position {
+                        return Err(self.error("Expected a key"));
+                    }
+                    String::from_utf8_lossy(&self.text[start..self.position]).into_owned()
+                }
+            };
+            path.push(part);
+            self.spaces();
+            if self.peek() != Some(b'.') {
+                return Ok(path);
+            }
+            self.position += 1;
+            self.spaces();
+        }
+    }
+
+    fn value(&mut self) -> Result<Toml, TomlError> {
+        match self.peek() {
+            Some(b'"') => Ok(Toml::String(self.basic_string()?)),
+            Some(b'\'') => Ok(Toml::String(self.literal_string()?)),
+            Some(b'[') => self.array(),
+            Some(b'{') => self.inline_table(),
+            Some(b't') if self.text[self.position..].starts_with(b"true") => {
+                self.position += 4;
+                Ok(Toml::Bool(true))
+            }
+            Some(b'f') if self.text[self.position..].starts_with(b"false") => {
+                self.position += 5;
+                Ok(Toml::Bool(false))
+            }
+            Some(c) if c.is_ascii_digit() || c == b'+' || c == b'-' => self.number(),
+            _ => Err(self.error("Expected a value"))
+        }
+    }
+
+    fn array(&mut self) -> Result<Toml, TomlError> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        loop {
+            self.blank_lines();
+            if self.peek() == Some(b']') {
+                break;
+            }
+            values.push(self.value()?);
+            self.blank_lines();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => break,
+                _ => return Err(self.error("Expected ',' or ']'"))
+            }
+        }
+        self.position += 1;
+        Ok(Toml::Array(values))
+    }
+
+    // An inline table, which has to be on one line: { key = value, ... }
+    fn inline_table(&mut self) -> Result<Toml, TomlError> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        self.spaces();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Toml::Table(fields));
+        }
+        loop {
+            self.spaces();
+            let key = self.key()?;
+            self.spaces();
+            self.expect(b'=')?;
+            self.spaces();
+            let value = self.value()?;
+            let line = self.line;
+            insert(table(&mut fields, &key[..key.len() - 1], line)?, &key[key.len() - 1], value, line)?;
+            self.spaces();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => break,
+                _ => return Err(self.error("Expected ',' or '}'"))
+            }
+        }
+        self.position += 1;
+        Ok(Toml::Table(fields))
+    }
+
+    fn number(&mut self) -> Result<Toml, TomlError> {
+        let start = self.position;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || matches!(c, b'_' | b'+' | b'-' | b'.')) {
+            self.position += 1;
+        }
+        let text = String::from_utf8_lossy(&self.text[start..self.position]).replace('_', "");
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(&text))
+        };
+        let radix = match digits.get(..2) {
+            Some("0x") => Some(16),
+            Some("0o") => Some(8),
+            Some("0b") => Some(2),
+            _ => None
+        };
+        let parsed = match radix {
+            Some(radix) => i64::from_str_radix(&digits[2..], radix).ok().map(Toml::Integer),
+            None if digits.contains(['.', 'e', 'E']) => text.parse::<f64>().ok().map(Toml::Float),
+            None => text.parse::<i64>().ok().map(Toml::Integer)
+        };
+        match parsed {
+            Some(Toml::Integer(value)) if negative && radix.is_some() => Ok(Toml::Integer(-value)),
+            Some(value) => Ok(value),
+            None => Err(self.error(&format!("Bad number: {}", text)))
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, TomlError> {
+        self.expect(b'\'')?;
+        let start = self.position;
+        while !matches!(self.peek(), None | Some(b'\'' | b'\n')) {
+            self.position += 1;
+        }
+        let value = String::from_utf8_lossy(&self.text[start..self.position]).into_owned();
+        self.expect(b'\'')?;
+        Ok(value)
+    }
+
+    fn basic_string(&mut self) -> Result<String, TomlError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(b'\n') => return Err(self.error("Unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.position += 1;
+                    let escaped = self.peek().ok_or_else(|| self.error("Unterminated string"))?;
+                    self.position += 1;
+                    match escaped {
+                        b'"' | b'\\' => bytes.push(escaped),
+                        b'n' => bytes.push(b'\n'),
+                        b't' => bytes.push(b'\t'),
+                        b'r' => bytes.push(b'\r'),
+                        b'u' => {
+                            let hex = self.text.get(self.position..self.position + 4).unwrap_or_default();
+                            let c = std::str::from_utf8(hex)
+                                .ok()
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("Bad unicode escape"))?;
+                            self.position += 4;
+                            bytes.extend_from_slice(c.to_string().as_bytes());
+                        }
+                        _ => return Err(self.error("Bad escape"))
+                    }
+                }
+                Some(c) => {
+                    bytes.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+        self.position += 1;
+        String::from_utf8(bytes).map_err(|_| self.error("Bad UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml() {
+        let text = r#"
+# A machine
+name = "BE6502"  # trailing comment
+clock.rate = 1_000_000
+ratio = 1.5
+
+[cpu]
+model = '65c02'
+vectors = [0xfffa, 0o17, 0b11,
+    -2,  # the last one
+]
+
+[[region]]
+name = "RAM"
+flags = { banked = false, "bank count" = 4 }
+
+[[region]]
+name = "ROM"
+escaped = "a\tb\"A"
+
+[region.extra]
+size = 0x8000
+"#;
+        let toml = Toml::parse(text).unwrap();
+        assert_eq!(toml.get("name").unwrap().as_str(), Some("BE6502"));
+        assert_eq!(toml.get("clock").unwrap().get("rate").unwrap().as_i64(), Some(1_000_000));
+        assert_eq!(toml.get("ratio").unwrap().as_f64(), Some(1.5));
+        let cpu = toml.get("cpu").unwrap();
+        assert_eq!(cpu.get("model").unwrap().as_str(), Some("65c02"));
+        let vectors = cpu.get("vectors").unwrap().as_array().unwrap();
+        assert_eq!(vectors.iter().map(|value| value.as_i64().unwrap()).collect::<Vec<i64>>(), [0xfffa, 15, 3, -2]);
+
+        let regions = toml.get("region").unwrap().as_array().unwrap();
+        assert_eq!(regions.len(), 2);
+        let flags = regions[0].get("flags").unwrap();
+        assert_eq!(flags.get("banked").unwrap().as_bool(), Some(false));
+        assert_eq!(flags.get("bank count").unwrap().as_i64(), Some(4));
+        assert_eq!(regions[1].get("escaped").unwrap().as_str(), Some("a\tb\"A"));
+        assert_eq!(regions[1].get("extra").unwrap().get("size").unwrap().as_i64(), Some(0x8000));
+        assert!(regions[0].get("extra").is_none());
+    }
+
+    #[test]
+    fn toml_errors() {
+        let line = |text: &str| Toml::parse(text).unwrap_err().line;
+        assert_eq!(line("a = 1\na = 2"), 2);
+        assert_eq!(line("[a]\n[a]"), 2);
+        assert_eq!(line("a = 1\n[a.b]"), 2);
+        assert_eq!(line("a = 1\n[[a]]"), 2);
+        assert_eq!(line("a = \"unterminated\n"), 1);
+        assert_eq!(line("\n\na = [1, 2"), 3);
+        assert_eq!(line("a = 1 b = 2"), 1);
+        assert_eq!(line("a = 0xgg"), 1);
+        assert_eq!(line("= 1"), 1);
+        assert_eq!(Toml::parse("a = 1\n").unwrap().get("a").unwrap().type_name(), "an integer");
+    }
+}
@@ -1,20 +1,17 @@
 // Module: main
-use crate::devices::memory_map::*;
-use crate::devices::memory::*;
-
+pub mod cli;
 pub mod cpu;
+pub mod dap;
 pub mod devices;
 pub mod emulator;
+pub mod gdb;
+pub mod harness;
+pub mod json;
+pub mod machine;
+pub mod monitor;
+pub mod symbols;
 
 fn main() {
-    
-    // Create a MemoryMap and add the RAM and ROM to it
-    let mut memory_map = MemoryMap::new();
-    memory_map.create("RAM".to_string(), MemoryType::RAM, 0x4000, 0x0000).unwrap();
-    memory_map.create(String::from("IO"), MemoryType::MMIO, 0x4000, 0x4000).unwrap();
-    memory_map.create("ROM".to_string(), MemoryType::ROM, 0x8000, 0x8000).unwrap();
-
-    // Print the MemoryMap
-    memory_map.print_table();
-
+    let arguments = std::env::args().skip(1).collect::<Vec<String>>();
+    std::process::exit(cli::cli::main(&arguments));
 }
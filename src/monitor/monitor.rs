@@ -0,0 +1,1236 @@
+/*!
+ * Machine Language Monitor
+ *
+ * An interactive prompt in the style of the VICE monitor for poking at a running Emulator. Addresses and values are
+ * hexadecimal, with an optional `$` prefix, or a symbol name after a dot, such as `.main`. The commands are:
+ * - m [start [end]]            examine memory
+ * - > address byte...          deposit bytes
+ * - a address instruction      assemble an instruction into memory
+ * - r [register=value...]      show or set the registers (a, x, y, sp, p, pc)
+ * - z [count]                  step into
+ * - n [count]                  step over subroutine calls
+ * - g [address]                run until a breakpoint, a BRK or an error
+ * - break [address] [if cond]  set an execution breakpoint, or list the breakpoints
+ * - break op opcode [if cond]  break before any instruction with the given opcode
+ * - tbreak address [if cond]   set a temporary breakpoint, deleted once it is hit
+ * - watch [load|store] start [end] [if cond]  break when memory in a range is read, written, or either
+ * - del [number]               delete a breakpoint, or all of them
+ * - enable|disable number      enable or disable a breakpoint
+ * - ignore number count        let a breakpoint pass its next count hits
+ * - d [start [end]]            disassemble
+ * - f start end byte...        fill memory with a pattern
+ * - c start end destination    compare two blocks of memory
+ * - h start end byte...        hunt for a byte sequence
+ * - l "file" address           load a binary file into memory
+ * - s "file" start end         save memory to a binary file
+ * - trace "file" [lines]       trace every instruction to a file, or only the last lines before a failure
+ * - trace off                  stop tracing
+ * - debug [on|off]             show or set debug mode, where ROM can be patched
+ * - sym ["file"]                load symbols from a .dbg, .lbl or `name = $addr` file, or list them
+ * - state save|load "file"     save the whole machine to a file, or go back to a saved state
+ * - history [on [window [interval]]|off]  keep a history of the last window instructions, so they can be stepped back
+ * - back [count]               step backwards
+ * - back store start [end]     go back to the last instruction that wrote to memory in a range
+ * - record [start]              show whether input is being recorded or replayed, or start recording it
+ * - record stop ["file"]        stop recording or replaying, saving the recording to a file
+ * - record play "file"          go back to where a recording started and replay its input
+ * - reset                      run the reset sequence
+ * - x                          leave the monitor
+ */
+
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{BufRead, Write};
+
+use crate::cpu::cpu::*;
+use crate::cpu::assembler::*;
+use crate::cpu::disassembler::*;
+use crate::devices::memory::*;
+use crate::devices::memory_map::*;
+use crate::devices::save_state::*;
+use crate::emulator::breakpoints::*;
+use crate::emulator::emulator::*;
+use crate::emulator::expression::*;
+use crate::emulator::history::*;
+use crate::emulator::replay::*;
+use crate::emulator::trace::*;
+use crate::symbols::symbols::*;
+
+const MEMORY_LINE: u16 = 16;
+const MEMORY_LINES: u16 = 8;
+const DISASSEMBLY_LINES: u16 = 16;
+
+const OPCODE_BRK: u8 = 0x00;
+const OPCODE_JSR: u8 = 0x20;
+
+#[derive(Debug)]
+pub enum MonitorError {
+    Syntax(String),
+    Cpu(CpuError),
+    Assembler(AssemblerError),
+    Expression(ExpressionError),
+    Symbol(SymbolError),
+    State(StateError),
+    History(HistoryError),
+    Memory(u16, MemoryError),
+    Io(std::io::Error)
+}
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonitorError::Syntax(message) => write!(f, "{}", message),
+            MonitorError::Cpu(CpuError::IllegalOpcode(address, opcode)) => {
+                write!(f, "Illegal opcode ${:02x} at ${:04x}", opcode, address)
+            }
+            MonitorError::Cpu(CpuError::Stopped(address)) => write!(f, "CPU stopped at ${:04x}", address),
+            MonitorError::Cpu(CpuError::Memory(address, error)) | MonitorError::Memory(address, error) => {
+                write!(f, "{:?} memory access at ${:04x}", error, address)
+            }
+            MonitorError::Assembler(AssemblerError::UnknownMnemonic(mnemonic)) => {
+                write!(f, "Unknown instruction: {}", mnemonic)
+            }
+            MonitorError::Assembler(AssemblerError::BadOperand(operand)) => write!(f, "Bad operand: {}", operand),
+            MonitorError::Assembler(AssemblerError::BadAddressingMode(instruction)) => {
+                write!(f, "Bad addressing mode: {}", instruction)
+            }
+            MonitorError::Assembler(AssemblerError::BranchOutOfRange(target)) => {
+                write!(f, "Branch to ${:04x} is out of range", target)
+            }
+            MonitorError::Assembler(AssemblerError::Memory(address, error)) => {
+                write!(f, "{:?} memory access at ${:04x}", error, address)
+            }
+            MonitorError::Expression(error) => write!(f, "{}", error),
+            MonitorError::Symbol(error) => write!(f, "{}", error),
+            MonitorError::State(error) => write!(f, "{}", error),
+            MonitorError::History(HistoryError::Cpu(error)) => write!(f, "{}", MonitorError::Cpu(*error)),
+            MonitorError::History(error) => write!(f, "{}", error),
+            MonitorError::Io(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl From<CpuError> for MonitorError {
+    fn from(error: CpuError) -> Self {
+        MonitorError::Cpu(error)
+    }
+}
+
+impl From<AssemblerError> for MonitorError {
+    fn from(error: AssemblerError) -> Self {
+        MonitorError::Assembler(error)
+    }
+}
+
+impl From<ExpressionError> for MonitorError {
+    fn from(error: ExpressionError) -> Self {
+        MonitorError::Expression(error)
+    }
+}
+
+impl From<SymbolError> for MonitorError {
+    fn from(error: SymbolError) -> Self {
+        MonitorError::Symbol(error)
+    }
+}
+
+impl From<StateError> for MonitorError {
+    fn from(error: StateError) -> Self {
+        MonitorError::State(error)
+    }
+}
+
+impl From<HistoryError> for MonitorError {
+    fn from(error: HistoryError) -> Self {
+        MonitorError::History(error)
+    }
+}
+
+impl From<std::io::Error> for MonitorError {
+    fn from(error: std::io::Error) -> Self {
+        MonitorError::Io(error)
+    }
+}
+
+pub type MonitorResult = Result<String, MonitorError>;
+
+// The message for an error, with the name of the address it happened at when there is a symbol for it
+pub fn error_message(error: &MonitorError, symbols: &SymbolTable) -> String {
+    let address = match error {
+        MonitorError::Cpu(CpuError::IllegalOpcode(address, _))
+        | MonitorError::Cpu(CpuError::Stopped(address))
+        | MonitorError::Cpu(CpuError::Memory(address, _))
+        | MonitorError::Memory(address, _)
+        | MonitorError::Assembler(AssemblerError::Memory(address, _)) => Some(*address),
+        _ => None
+    };
+    match address.and_then(|address| symbols.symbolize(address)) {
+        Some(name) => format!("{} ({})", error, name),
+        None => error.to_string()
+    }
+}
+
+fn syntax<T>(message: &str) -> Result<T, MonitorError> {
+    Err(MonitorError::Syntax(String::from(message)))
+}
+
+#[derive(Debug)]
+pub struct Monitor {
+    emulator: Emulator,
+    memory_address: u16,
+    disassembly_address: Option<u16>,
+    quit: bool
+}
+
+impl Monitor {
+    pub fn new(emulator: Emulator) -> Monitor {
+        Monitor {
+            emulator,
+            memory_address: 0,
+            disassembly_address: None,
+            quit: false
+        }
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emulator
+    }
+
+    // Read commands until the input ends or the user leaves the monitor
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> std::io::Result<()> {
+        let mut lines = input.lines();
+        while !self.quit {
+            write!(output, "(C:${:04x}) ", self.emulator.cpu().pc())?;
+            output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break
+            };
+            match self.execute(&line) {
+                Ok(text) => write!(output, "{}", text)?,
+                Err(error) => writeln!(output, "?{}", error_message(&error, self.emulator.symbols()))?
+            }
+        }
+        Ok(())
+    }
+
+    // Execute one command line, returning the text to show the user
+    pub fn execute(&mut self, line: &str) -> MonitorResult {
+        let line = line.trim();
+        // The assembler needs the instruction as it was typed, commas and all
+        if let Some(("a", rest)) = line.split_once(char::is_whitespace) {
+            return self.assemble(rest);
+        }
+
+        let (command, arguments) = match line.strip_prefix('>') {
+            Some(rest) => (">", self.resolve_symbols(tokenize(rest)?)),
+            None => {
+                let mut tokens = self.resolve_symbols(tokenize(line)?);
+                if tokens.is_empty() {
+                    return Ok(String::new());
+                }
+                let command = tokens.remove(0).to_lowercase();
+                return self.dispatch(&command, &tokens);
+            }
+        };
+        self.dispatch(command, &arguments)
+    }
+
+    fn dispatch(&mut self, command: &str, arguments: &[String]) -> MonitorResult {
+        match command {
+            "m" | "mem" => self.examine(arguments),
+            ">" => self.deposit(arguments),
+            "r" | "registers" => self.registers(arguments),
+            "z" | "step" => self.step(arguments),
+            "n" | "next" => self.next(arguments),
+            "g" | "go" => self.go(arguments),
+            "break" | "bk" => self.set_breakpoint(arguments, false),
+            "tbreak" => self.set_breakpoint(arguments, true),
+            "watch" | "w" => self.watch(arguments),
+            "del" | "delete" => self.delete_breakpoint(arguments),
+            "enable" | "en" => self.enable_breakpoint(arguments, true),
+            "disable" | "dis" => self.enable_breakpoint(arguments, false),
+            "ignore" => self.ignore_breakpoint(arguments),
+            "d" | "disass" => self.disassemble(arguments),
+            "f" | "fill" => self.fill(arguments),
+            "c" | "compare" => self.compare(arguments),
+            "h" | "hunt" => self.hunt(arguments),
+            "l" | "load" => self.load(arguments),
+            "s" | "save" => self.save(arguments),
+            "debug" => self.debug(arguments),
+            "trace" => self.trace(arguments),
+            "sym" | "symbols" => self.symbols(arguments),
+            "state" => self.state(arguments),
+            "record" => self.record(arguments),
+            "history" => self.history(arguments),
+            "back" => self.back(arguments),
+            "reset" => {
+                self.emulator.start()?;
+                Ok(self.register_line())
+            }
+            "x" | "q" | "exit" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            "?" | "help" => Ok(String::from(HELP)),
+            _ => syntax(&format!("Unknown command: {}", command))
+        }
+    }
+
+    // Replace `.name` with the address of the symbol, leaving anything that is not a known symbol alone
+    fn resolve_symbols(&self, tokens: Vec<String>) -> Vec<String> {
+        let symbols = self.emulator.symbols();
+        tokens
+            .into_iter()
+            .map(|token| match token.strip_prefix('.').and_then(|name| symbols.address(name)) {
+                Some(address) => format!("{:04x}", address),
+                None => token
+            })
+            .collect()
+    }
+
+    fn symbols(&mut self, arguments: &[String]) -> MonitorResult {
+        match arguments {
+            [] => Ok(self
+                .emulator
+                .symbols()
+                .symbols()
+                .iter()
+                .map(|(name, value)| format!("{} = ${:04x}\n", name, value))
+                .collect()),
+            [path] => {
+                let before = self.emulator.symbols().len();
+                self.emulator.symbols_mut().load(path)?;
+                Ok(format!("Loaded {} symbols\n", self.emulator.symbols().len() - before))
+            }
+            _ => syntax("Usage: sym [\"file\"]")
+        }
+    }
+
+    fn state(&mut self, arguments: &[String]) -> MonitorResult {
+        match arguments {
+            [command, path] if command == "save" => {
+                self.emulator.save_state(path)?;
+                Ok(String::new())
+            }
+            [command, path] if command == "load" => {
+                self.emulator.load_state(path)?;
+                Ok(self.register_line())
+            }
+            _ => syntax("Usage: state save|load \"file\"")
+        }
+    }
+
+    fn record(&mut self, arguments: &[String]) -> MonitorResult {
+        match arguments {
+            [] => {}
+            [command] if command == "start" => self.emulator.start_recording(),
+            [command] if command == "stop" => {
+                self.emulator.stop_recording();
+            }
+            [command, path] if command == "stop" => match self.emulator.stop_recording() {
+                Some(recording) => recording.save(path)?,
+                None => return Ok(String::from("Not recording\n"))
+            },
+            [command, path] if command == "play" => {
+                self.emulator.start_replay(Recording::load(path)?)?;
+                return Ok(self.register_line());
+            }
+            _ => return syntax("Usage: record [start|stop [\"file\"]|play \"file\"]")
+        }
+        Ok(match self.emulator.input_mode() {
+            InputMode::Live => String::from("Not recording\n"),
+            InputMode::Recording(recording) => format!("Recording, {} input events\n", recording.events.len()),
+            InputMode::Replaying(recording, next) => {
+                format!("Replaying, {} of {} input events delivered\n", next, recording.events.len())
+            }
+        })
+    }
+
+    fn history(&mut self, arguments: &[String]) -> MonitorResult {
+        match arguments.first().map(|argument| argument.to_lowercase()).as_deref() {
+            Some("on") => {
+                let window = optional_number(arguments, 1)?.map_or(DEFAULT_WINDOW, u64::from);
+                let interval = optional_number(arguments, 2)?.map_or(DEFAULT_CHECKPOINT_INTERVAL, u64::from);
+                self.emulator.set_history(Some(History::new(window, interval)));
+            }
+            Some("off") => {
+                self.emulator.set_history(None);
+            }
+            Some(argument) => return syntax(&format!("Bad history mode: {}", argument)),
+            None => {}
+        }
+        Ok(match self.emulator.history() {
+            Some(history) => format!(
+                "History of instructions ${:x} to ${:x}, window ${:x}, checkpoint every ${:x}\n",
+                history.earliest(),
+                history.count(),
+                history.window(),
+                history.interval()
+            ),
+            None => String::from("History is off\n")
+        })
+    }
+
+    fn back(&mut self, arguments: &[String]) -> MonitorResult {
+        let mut out = String::new();
+        if arguments.first().map(|argument| argument.to_lowercase()).as_deref() == Some("store") {
+            let start = match optional_address(arguments, 1)? {
+                Some(start) => start,
+                None => return syntax("Usage: back store start [end]")
+            };
+            let end = optional_address(arguments, 2)?.unwrap_or(start);
+            let Some(entry) = self.emulator.rewind_to_write(start, end)? else {
+                return Ok(String::from("No store to that memory in the history\n"));
+            };
+            for (address, value) in entry.writes.iter().filter(|&&(address, _)| address >= start && address <= end) {
+                out += &format!("STORE: ${:04x} = ${:02x} by instruction ${:x}\n", address, value, entry.instruction);
+            }
+        } else {
+            let count = optional_number(arguments, 0)?.unwrap_or(1);
+            self.emulator.step_back(count as u64)?;
+        }
+        self.disassembly_address = None;
+        Ok(out + &self.disassemble_line(self.emulator.cpu().pc()).0)
+    }
+
+    fn read(&self, address: u16) -> Result<u8, MonitorError> {
+        self.emulator.memory_map().peek(address).map_err(|error| MonitorError::Memory(address, error))
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), MonitorError> {
+        self.emulator.memory_map_mut().poke(address, value).map_err(|error| MonitorError::Memory(address, error))
+    }
+
+    fn examine(&mut self, arguments: &[String]) -> MonitorResult {
+        let start = optional_address(arguments, 0)?.unwrap_or(self.memory_address);
+        let default_end = start.saturating_add(MEMORY_LINE * MEMORY_LINES - 1);
+        let end = optional_address(arguments, 1)?.unwrap_or(default_end);
+
+        let mut out = String::new();
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let line_end = (address + MEMORY_LINE as u32 - 1).min(end as u32);
+            let bytes = (address..=line_end)
+                .map(|address| self.emulator.memory_map().peek(address as u16).ok())
+                .collect::<Vec<Option<u8>>>();
+            let hex = bytes
+                .iter()
+                .map(|byte| byte.map(|byte| format!("{:02x}", byte)).unwrap_or_else(|| String::from("--")))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let ascii = bytes
+                .iter()
+                .map(|byte| match byte {
+                    Some(byte @ 0x20..=0x7e) => *byte as char,
+                    _ => '.'
+                })
+                .collect::<String>();
+            writeln!(out, ">C:{:04x}  {:<47}  {}", address, hex, ascii).unwrap();
+            address = line_end + 1;
+        }
+        self.memory_address = address as u16;
+        Ok(out)
+    }
+
+    fn deposit(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.len() < 2 {
+            return syntax("Usage: > address byte...");
+        }
+        let address = parse_address(&arguments[0])?;
+        for (index, argument) in arguments[1..].iter().enumerate() {
+            let value = parse_byte(argument)?;
+            self.write(address.wrapping_add(index as u16), value)?;
+        }
+        Ok(String::new())
+    }
+
+    fn register_line(&self) -> String {
+        let cpu = self.emulator.cpu();
+        let flags = (0..8).rev().map(|bit| if cpu.flags() & (1 << bit) != 0 { '1' } else { '0' }).collect::<String>();
+        format!(
+            "  ADDR A  X  Y  SP NV-BDIZC CYCLES\n.;{:04x} {:02x} {:02x} {:02x} {:02x} {} {}\n",
+            cpu.pc(),
+            cpu.a(),
+            cpu.x(),
+            cpu.y(),
+            cpu.sp(),
+            flags,
+            cpu.cycles()
+        )
+    }
+
+    fn assemble(&mut self, arguments: &str) -> MonitorResult {
+        let (address, instruction) = match arguments.trim().split_once(char::is_whitespace) {
+            Some((address, instruction)) => (parse_address(address)?, instruction),
+            None => return syntax("Missing instruction")
+        };
+        let model = self.emulator.cpu().model();
+        assemble_into(self.emulator.memory_map_mut(), model, address, instruction)?;
+        Ok(self.disassemble_line(address).0)
+    }
+
+    fn trace(&mut self, arguments: &[String]) -> MonitorResult {
+        match arguments.first().map(String::as_str) {
+            None => Ok(match self.emulator.tracer() {
+                Some(_) => String::from("Tracing is on\n"),
+                None => String::from("Tracing is off\n")
+            }),
+            Some("off") => {
+                self.emulator.set_tracer(None);
+                Ok(String::new())
+            }
+            Some(path) => {
+                let lines = optional_number(arguments, 1)?.map(|lines| lines as usize);
+                self.emulator.set_tracer(Some(Tracer::file(path, lines)?));
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn debug(&mut self, arguments: &[String]) -> MonitorResult {
+        match arguments.first().map(|argument| argument.to_lowercase()).as_deref() {
+            Some("on") => self.emulator.memory_map_mut().set_debug(true),
+            Some("off") => self.emulator.memory_map_mut().set_debug(false),
+            Some(argument) => return syntax(&format!("Bad debug mode: {}", argument)),
+            None => {}
+        }
+        Ok(format!("Debug mode is {}\n", if self.emulator.memory_map().debug() { "on" } else { "off" }))
+    }
+
+    fn registers(&mut self, arguments: &[String]) -> MonitorResult {
+        for argument in arguments {
+            let (name, value) = match argument.split_once('=') {
+                Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+                None => return syntax("Usage: r [register=value...]")
+            };
+            let cpu = self.emulator.cpu_mut();
+            match name.as_str() {
+                "pc" => cpu.set_pc(parse_address(value)?),
+                "a" => cpu.set_a(parse_byte(value)?),
+                "x" => cpu.set_x(parse_byte(value)?),
+                "y" => cpu.set_y(parse_byte(value)?),
+                "sp" => cpu.set_sp(parse_byte(value)?),
+                "p" | "fl" => cpu.set_flags(parse_byte(value)?),
+                _ => return syntax(&format!("Unknown register: {}", name))
+            }
+        }
+        Ok(self.register_line())
+    }
+
+    fn step(&mut self, arguments: &[String]) -> MonitorResult {
+        let count = optional_number(arguments, 0)?.unwrap_or(1);
+        let mut out = String::new();
+        for _ in 0..count {
+            self.emulator.step()?;
+            out += &self.disassemble_line(self.emulator.cpu().pc()).0;
+        }
+        self.disassembly_address = None;
+        Ok(out)
+    }
+
+    fn next(&mut self, arguments: &[String]) -> MonitorResult {
+        let count = optional_number(arguments, 0)?.unwrap_or(1);
+        let mut out = String::new();
+        for _ in 0..count {
+            let pc = self.emulator.cpu().pc();
+            if self.read(pc)? == OPCODE_JSR {
+                // Run until the subroutine returns to the instruction after the call
+                if let Some(stop) = self.run_until(Some(pc.wrapping_add(3)))? {
+                    out += &stop;
+                    break;
+                }
+            } else {
+                self.emulator.step()?;
+            }
+            out += &self.disassemble_line(self.emulator.cpu().pc()).0;
+        }
+        self.disassembly_address = None;
+        Ok(out)
+    }
+
+    fn go(&mut self, arguments: &[String]) -> MonitorResult {
+        if let Some(address) = optional_address(arguments, 0)? {
+            self.emulator.cpu_mut().set_pc(address);
+        }
+        self.disassembly_address = None;
+        let stop = self.run_until(None)?.unwrap_or_default();
+        Ok(stop + &self.disassemble_line(self.emulator.cpu().pc()).0)
+    }
+
+    fn stop_message(&self, stop: StopReason) -> String {
+        match stop {
+            StopReason::Breakpoint(number) => {
+                format!("BREAK: {} {}\n", number, self.emulator.symbols().describe(self.emulator.cpu().pc()))
+            }
+            StopReason::Watchpoint(number, access) => {
+                let kind = if access.kind == AccessKind::Read { "load" } else { "store" };
+                format!("WATCH: {} {} ${:04x} = ${:02x}\n", number, kind, access.address, access.value)
+            }
+            StopReason::Limit => String::new()
+        }
+    }
+
+    // Run until `target` is reached, returning a message if a breakpoint or BRK stopped execution first. The
+    // instruction at the starting address always runs, so that continuing from a breakpoint does not stop at once.
+    fn run_until(&mut self, target: Option<u16>) -> Result<Option<String>, MonitorError> {
+        let mut first = true;
+        loop {
+            let pc = self.emulator.cpu().pc();
+            if !first {
+                if Some(pc) == target {
+                    return Ok(None);
+                }
+                if let Some(stop) = self.emulator.check_breakpoints() {
+                    return Ok(Some(self.stop_message(stop)));
+                }
+            }
+            if self.read(pc)? == OPCODE_BRK {
+                return Ok(Some(format!("BRK: {}\n", self.emulator.symbols().describe(pc))));
+            }
+            if let Some(stop) = self.emulator.step_watched()? {
+                return Ok(Some(self.stop_message(stop)));
+            }
+            first = false;
+        }
+    }
+
+    fn breakpoint_line(number: u32, breakpoint: &Breakpoint) -> String {
+        let mut line = format!("BREAK: {} {}", number, breakpoint.kind);
+        if let Some(condition) = &breakpoint.condition {
+            line += &format!(" if {}", condition);
+        }
+        if breakpoint.temporary {
+            line += " (temporary)";
+        }
+        if !breakpoint.enabled {
+            line += " (disabled)";
+        }
+        if breakpoint.ignore > breakpoint.hits {
+            line += &format!(" (ignore {})", breakpoint.ignore - breakpoint.hits);
+        }
+        line + &format!(" hits {}\n", breakpoint.hits)
+    }
+
+    // Add a breakpoint, applying the condition that follows `if` in the arguments
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint, condition: &[String]) -> MonitorResult {
+        let breakpoint = match condition.split_first() {
+            None => breakpoint,
+            Some((keyword, condition)) if keyword.eq_ignore_ascii_case("if") && !condition.is_empty() => {
+                breakpoint.with_condition(Expression::parse(&condition.join(" "))?)
+            }
+            Some((argument, _)) => return syntax(&format!("Unexpected argument: {}", argument))
+        };
+        let number = self.emulator.breakpoints_mut().add(breakpoint);
+        Ok(Monitor::breakpoint_line(number, self.emulator.breakpoints().get(number).unwrap()))
+    }
+
+    fn set_breakpoint(&mut self, arguments: &[String], temporary: bool) -> MonitorResult {
+        let (breakpoint, condition) = match arguments.first().map(String::as_str) {
+            None if !temporary => {
+                let breakpoints = self.emulator.breakpoints();
+                let lines = breakpoints.iter().map(|(number, breakpoint)| Monitor::breakpoint_line(number, breakpoint));
+                return Ok(lines.collect());
+            }
+            None => return syntax("Usage: tbreak address [if condition]"),
+            Some("op") => match arguments.get(1) {
+                Some(opcode) => (Breakpoint::opcode(parse_byte(opcode)?), &arguments[2..]),
+                None => return syntax("Usage: break op opcode [if condition]")
+            },
+            Some(address) => (Breakpoint::execute(parse_address(address)?), &arguments[1..])
+        };
+        let breakpoint = if temporary { breakpoint.temporary() } else { breakpoint };
+        self.add_breakpoint(breakpoint, condition)
+    }
+
+    fn watch(&mut self, arguments: &[String]) -> MonitorResult {
+        let (kind, arguments) = match arguments.first().map(|argument| argument.to_lowercase()).as_deref() {
+            Some("load") => (WatchKind::Read, &arguments[1..]),
+            Some("store") => (WatchKind::Write, &arguments[1..]),
+            _ => (WatchKind::Access, arguments)
+        };
+        let start = match arguments.first() {
+            Some(start) => parse_address(start)?,
+            None => return syntax("Usage: watch [load|store] start [end] [if condition]")
+        };
+        let (end, condition) = match arguments.get(1) {
+            Some(end) if !end.eq_ignore_ascii_case("if") => (parse_address(end)?, &arguments[2..]),
+            _ => (start, &arguments[1..])
+        };
+        if end < start {
+            return syntax("The end of the range is before the start");
+        }
+        self.add_breakpoint(Breakpoint::watch(start, end, kind), condition)
+    }
+
+    fn breakpoint_number(&self, arguments: &[String]) -> Result<u32, MonitorError> {
+        let number = match arguments.first() {
+            Some(number) => number.parse::<u32>().or_else(|_| syntax(&format!("Bad breakpoint number: {}", number)))?,
+            None => return syntax("Missing breakpoint number")
+        };
+        match self.emulator.breakpoints().get(number) {
+            Some(_) => Ok(number),
+            None => syntax(&format!("No breakpoint {}", number))
+        }
+    }
+
+    fn delete_breakpoint(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.is_empty() {
+            self.emulator.breakpoints_mut().clear();
+        } else {
+            let number = self.breakpoint_number(arguments)?;
+            self.emulator.breakpoints_mut().remove(number);
+        }
+        Ok(String::new())
+    }
+
+    fn enable_breakpoint(&mut self, arguments: &[String], enabled: bool) -> MonitorResult {
+        let number = self.breakpoint_number(arguments)?;
+        let breakpoint = self.emulator.breakpoints_mut().get_mut(number).unwrap();
+        breakpoint.enabled = enabled;
+        Ok(Monitor::breakpoint_line(number, breakpoint))
+    }
+
+    fn ignore_breakpoint(&mut self, arguments: &[String]) -> MonitorResult {
+        let number = self.breakpoint_number(arguments)?;
+        let count = match arguments.get(1) {
+            Some(count) => count.parse::<u64>().or_else(|_| syntax(&format!("Bad count: {}", count)))?,
+            None => return syntax("Usage: ignore number count")
+        };
+        let breakpoint = self.emulator.breakpoints_mut().get_mut(number).unwrap();
+        breakpoint.ignore = breakpoint.hits + count;
+        Ok(Monitor::breakpoint_line(number, breakpoint))
+    }
+
+    // Disassemble the instruction at `address`, returning the line and the instruction length
+    fn disassemble_line(&self, address: u16) -> (String, u16) {
+        let memory = self.emulator.memory_map();
+        if memory.peek(address).is_err() {
+            return (format!(".C:{:04x}  --           ???\n", address), 1);
+        }
+
+        let instruction = disassemble(memory, self.emulator.cpu().model(), address);
+        let bytes = instruction.bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(" ");
+        let symbols = self.emulator.symbols();
+        let text = instruction.format(Syntax::Ca65, Some(symbols));
+        // A label gets a line of its own, as in assembler source
+        let label = symbols.name(address).map(|name| format!("{}:\n", name)).unwrap_or_default();
+        (format!("{}.C:{:04x}  {:<9}    {}\n", label, address, bytes, text), instruction.length())
+    }
+
+    fn disassemble(&mut self, arguments: &[String]) -> MonitorResult {
+        let default_start = self.disassembly_address.unwrap_or(self.emulator.cpu().pc());
+        let start = optional_address(arguments, 0)?.unwrap_or(default_start);
+        let end = optional_address(arguments, 1)?;
+
+        let mut out = String::new();
+        let mut address = start;
+        let mut lines = 0;
+        loop {
+            let (line, length) = self.disassemble_line(address);
+            out += &line;
+            lines += 1;
+            let next = address.wrapping_add(length);
+            let done = match end {
+                Some(end) => address as u32 + length as u32 > end as u32 || next < address,
+                None => lines == DISASSEMBLY_LINES
+            };
+            address = next;
+            if done {
+                break;
+            }
+        }
+        self.disassembly_address = Some(address);
+        Ok(out)
+    }
+
+    fn fill(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.len() < 3 {
+            return syntax("Usage: f start end byte...");
+        }
+        let (start, end) = range(arguments)?;
+        let pattern = parse_bytes(&arguments[2..])?;
+        for (index, address) in (start..=end).enumerate() {
+            self.write(address, pattern[index % pattern.len()])?;
+        }
+        Ok(String::new())
+    }
+
+    fn compare(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.len() != 3 {
+            return syntax("Usage: c start end destination");
+        }
+        let (start, end) = range(arguments)?;
+        let destination = parse_address(&arguments[2])?;
+        let mut out = String::new();
+        for address in start..=end {
+            let other = destination.wrapping_add(address - start);
+            let (left, right) = (self.read(address)?, self.read(other)?);
+            if left != right {
+                writeln!(out, "${:04x} ${:04x}: {:02x} {:02x}", address, other, left, right).unwrap();
+            }
+        }
+        Ok(out)
+    }
+
+    fn hunt(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.len() < 3 {
+            return syntax("Usage: h start end byte...");
+        }
+        let (start, end) = range(arguments)?;
+        let pattern = parse_bytes(&arguments[2..])?;
+        let memory = self.emulator.memory_map();
+        let mut out = String::new();
+        for address in start..=end {
+            let found = pattern.iter().enumerate().all(|(index, &byte)| {
+                let address = address as u32 + index as u32;
+                address <= end as u32 && memory.peek(address as u16).ok() == Some(byte)
+            });
+            if found {
+                writeln!(out, "${:04x}", address).unwrap();
+            }
+        }
+        Ok(out)
+    }
+
+    fn load(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.len() != 2 {
+            return syntax("Usage: l \"file\" address");
+        }
+        let data = std::fs::read(&arguments[0])?;
+        let start = parse_address(&arguments[1])?;
+        if data.is_empty() {
+            return Ok(String::new());
+        }
+        if start as usize + data.len() > 0x10000 {
+            return syntax("File does not fit in memory");
+        }
+        for (index, &byte) in data.iter().enumerate() {
+            self.write(start + index as u16, byte)?;
+        }
+        Ok(format!("Loaded ${:04x}-${:04x}\n", start, start as usize + data.len() - 1))
+    }
+
+    fn save(&mut self, arguments: &[String]) -> MonitorResult {
+        if arguments.len() != 3 {
+            return syntax("Usage: s \"file\" start end");
+        }
+        let (start, end) = range(&arguments[1..])?;
+        let data = (start..=end).map(|address| self.read(address)).collect::<Result<Vec<u8>, MonitorError>>()?;
+        std::fs::write(&arguments[0], data)?;
+        Ok(String::new())
+    }
+}
+
+const HELP: &str = "\
+m [start [end]]            examine memory
+> address byte...          deposit bytes
+a address instruction      assemble an instruction
+r [register=value...]      show or set registers (a, x, y, sp, p, pc)
+z [count]                  step into
+n [count]                  step over subroutine calls
+g [address]                run until a breakpoint, BRK or error
+break [address] [if cond]  set a breakpoint, or list them
+break op opcode [if cond]  break on an opcode
+tbreak address [if cond]   set a temporary breakpoint
+watch [load|store] start [end] [if cond]
+                           break on memory reads and writes
+del [number]               delete a breakpoint, or all of them
+enable|disable number      enable or disable a breakpoint
+ignore number count        ignore the next hits of a breakpoint
+d [start [end]]            disassemble
+f start end byte...        fill memory
+c start end destination    compare memory
+h start end byte...        hunt for bytes
+l \"file\" address           load a binary file
+s \"file\" start end         save memory to a binary file
+trace \"file\" [lines]       trace instructions to a file
+trace off                  stop tracing
+debug [on|off]             show or set debug mode (patch ROM)
+sym [\"file\"]               load symbols, or list them
+state save|load \"file\"     save or restore the machine
+history [on [window [interval]]|off]
+                           keep a history to step back through
+back [count]               step backwards
+back store start [end]     go back to the last store to memory
+record [start]             show input recording, or start it
+record stop [\"file\"]       stop recording, saving it to a file
+record play \"file\"         replay recorded input
+reset                      reset the CPU
+x                          exit the monitor
+";
+
+// Split a command line on whitespace and commas, keeping quoted strings together
+fn tokenize(line: &str) -> Result<Vec<String>, MonitorError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => return syntax("Unterminated string")
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Result<u32, MonitorError> {
+    let digits = text.strip_prefix('$').unwrap_or(text);
+    u32::from_str_radix(digits, 16).or_else(|_| syntax(&format!("Bad number: {}", text)))
+}
+
+fn parse_address(text: &str) -> Result<u16, MonitorError> {
+    match parse_number(text)? {
+        value @ 0..=0xffff => Ok(value as u16),
+        _ => syntax(&format!("Bad address: {}", text))
+    }
+}
+
+fn parse_byte(text: &str) -> Result<u8, MonitorError> {
+    match parse_number(text)? {
+        value @ 0..=0xff => Ok(value as u8),
+        _ => syntax(&format!("Bad byte: {}", text))
+    }
+}
+
+fn parse_bytes(arguments: &[String]) -> Result<Vec<u8>, MonitorError> {
+    arguments.iter().map(|argument| parse_byte(argument)).collect()
+}
+
+fn optional_address(arguments: &[String], index: usize) -> Result<Option<u16>, MonitorError> {
+    arguments.get(index).map(|argument| parse_address(argument)).transpose()
+}
+
+fn optional_number(arguments: &[String], index: usize) -> Result<Option<u32>, MonitorError> {
+    arguments.get(index).map(|argument| parse_number(argument)).transpose()
+}
+
+fn range(arguments: &[String]) -> Result<(u16, u16), MonitorError> {
+    let start = parse_address(&arguments[0])?;
+    let end = parse_address(&arguments[1])?;
+    if end < start {
+        return syntax("The end of the range is before the start");
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> Monitor {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("RAM"), MemoryType::RAM, 0x10000, 0x0000).unwrap();
+        Monitor::new(emulator)
+    }
+
+    #[test]
+    fn monitor_memory() {
+        let mut monitor = monitor();
+        monitor.execute("> 0200 48 65 6c 6c 6f").unwrap();
+        assert_eq!(
+            monitor.execute("m 0200 0204").unwrap(),
+            format!(">C:0200  {:<47}  Hello\n", "48 65 6c 6c 6f")
+        );
+
+        monitor.execute("f 0300 0307 aa 55").unwrap();
+        assert_eq!(monitor.execute("h 0000 ffff 55 aa").unwrap(), "$0301\n$0303\n$0305\n");
+        monitor.execute(">0304 00").unwrap();
+        assert_eq!(monitor.execute("c 0300 0303 0304").unwrap(), "$0300 $0304: aa 00\n");
+
+        assert!(matches!(monitor.execute("> 0200 100"), Err(MonitorError::Syntax(_))));
+        assert!(matches!(monitor.execute("frob"), Err(MonitorError::Syntax(_))));
+
+        // Looking at I/O does not disturb it: the Apple-1's key stays waiting after examining, comparing and hunting
+        let mut config = crate::machine::machine::MachineConfig::preset("apple1").unwrap();
+        config.set_host(false);
+        let mut monitor = Monitor::new(Emulator::from_config(&config).unwrap());
+        monitor.execute("> d011 a7").unwrap();
+        monitor.emulator.memory_map_mut().input("PIA", b"A").unwrap();
+        monitor.execute("m d010 d011").unwrap();
+        monitor.execute("c d010 d011 0000").unwrap();
+        monitor.execute("h d010 d011 c1").unwrap();
+        assert_eq!(monitor.emulator.memory_map().peek(0xd011).unwrap() & 0x80, 0x80);
+    }
+
+    #[test]
+    fn monitor_registers() {
+        let mut monitor = monitor();
+        let registers = monitor.execute("r pc=c000, a=01 x=$ff p=81").unwrap();
+        assert_eq!(registers.lines().nth(1).unwrap(), ".;c000 01 ff 00 00 10100001 0");
+        assert!(monitor.execute("r q=1").is_err());
+    }
+
+    #[test]
+    fn monitor_step_and_go() {
+        let mut monitor = monitor();
+
+        // $0200: JSR $0210; ldx #$02; BRK ... $0210: lda #$01; RTS
+        monitor.execute("> 0200 20 10 02 a2 02 00").unwrap();
+        monitor.execute("> 0210 a9 01 60").unwrap();
+        monitor.execute("r pc=0200 sp=ff").unwrap();
+
+        assert_eq!(monitor.execute("z").unwrap(), ".C:0210  a9 01        lda #$01\n");
+        monitor.execute("r pc=0200 sp=ff").unwrap();
+        assert_eq!(monitor.execute("n").unwrap(), ".C:0203  a2 02        ldx #$02\n");
+        assert_eq!(monitor.emulator().cpu().a(), 0x01);
+
+        monitor.execute("r pc=0200 a=00 sp=ff").unwrap();
+        assert_eq!(monitor.execute("break 0212").unwrap(), "BREAK: 1 exec $0212 hits 0\n");
+        assert_eq!(monitor.execute("g").unwrap(), "BREAK: 1 $0212\n.C:0212  60           rts\n");
+        assert_eq!(monitor.execute("g").unwrap(), "BRK: $0205\n.C:0205  00           brk\n");
+        assert_eq!(monitor.emulator().cpu().x(), 0x02);
+
+        assert_eq!(monitor.execute("break").unwrap(), "BREAK: 1 exec $0212 hits 1\n");
+        monitor.execute("del").unwrap();
+        assert!(monitor.emulator().breakpoints().is_empty());
+    }
+
+    #[test]
+    fn monitor_disassemble() {
+        let mut monitor = monitor();
+        monitor.execute("> c000 a9 01 8d 00 02 b1 10 d0 f7 02").unwrap();
+        assert_eq!(
+            monitor.execute("d c000 c009").unwrap(),
+            ".C:c000  a9 01        lda #$01\n\
+             .C:c002  8d 00 02     sta $0200\n\
+             .C:c005  b1 10        lda ($10),y\n\
+             .C:c007  d0 f7        bne $c000\n\
+             .C:c009  02           jam\n"
+        );
+    }
+
+    #[test]
+    fn monitor_load_and_save() -> Result<(), MonitorError> {
+        let path = std::env::temp_dir().join(format!("monitor_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut monitor = monitor();
+        monitor.execute("> 1000 01 02 03 04")?;
+        monitor.execute(&format!("s \"{}\" 1001 1003", path))?;
+        assert_eq!(std::fs::read(path)?, [0x02, 0x03, 0x04]);
+
+        assert_eq!(monitor.execute(&format!("l \"{}\" 2000", path))?, "Loaded $2000-$2002\n");
+        std::fs::remove_file(path)?;
+        assert_eq!(monitor.read(0x2002)?, 0x04);
+        Ok(())
+    }
+
+    #[test]
+    fn monitor_run() {
+        let mut monitor = monitor();
+        let mut output = Vec::new();
+        monitor.run("r pc=0400\nbogus\nx\nm\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("(C:$0000)   ADDR"));
+        assert!(output.contains("(C:$0400) ?Unknown command: bogus\n"));
+        assert!(!output.contains(">C:"));
+    }
+
+    #[test]
+    fn monitor_assemble() {
+        let mut monitor = monitor();
+
+        assert_eq!(monitor.execute("a 0200 lda ($10),y").unwrap(), ".C:0200  b1 10        lda ($10),y\n");
+        assert_eq!(monitor.execute("a 0202 bne $0200").unwrap(), ".C:0202  d0 fc        bne $0200\n");
+        assert_eq!(monitor.execute("a 0204 foo").unwrap_err().to_string(), "Unknown instruction: foo");
+        assert_eq!(monitor.execute("a 0204 bne $0300").unwrap_err().to_string(), "Branch to $0300 is out of range");
+    }
+
+    #[test]
+    fn monitor_debug() {
+        let mut emulator = Emulator::new();
+        emulator.memory_map_mut().create(String::from("ROM"), MemoryType::ROM, 0x1000, 0xf000).unwrap();
+        let mut monitor = Monitor::new(emulator);
+
+        // ROM is only patched in debug mode
+        assert_eq!(monitor.execute("debug").unwrap(), "Debug mode is off\n");
+        monitor.execute("a f000 nop").unwrap();
+        monitor.execute("> f001 ea").unwrap();
+        assert_eq!(monitor.execute("m f000 f001").unwrap(), format!(">C:f000  {:<47}  ..\n", "00 00"));
+
+        assert_eq!(monitor.execute("debug on").unwrap(), "Debug mode is on\n");
+        monitor.execute("a f000 nop").unwrap();
+        monitor.execute("> f001 ea").unwrap();
+        assert_eq!(monitor.execute("m f000 f001").unwrap(), format!(">C:f000  {:<47}  ..\n", "ea ea"));
+        assert!(monitor.execute("debug maybe").is_err());
+    }
+
+    #[test]
+    fn monitor_breakpoints() {
+        let mut monitor = monitor();
+
+        // $0200: LDX #$00; loop: INX; TXA; STA $10,X; BNE loop
+        monitor.execute("> 0200 a2 00 e8 8a 95 10 d0 fa").unwrap();
+        monitor.execute("r pc=0200").unwrap();
+
+        assert_eq!(monitor.execute("break 0206 if x == 3").unwrap(), "BREAK: 1 exec $0206 if x == 3 hits 0\n");
+        assert_eq!(monitor.execute("g").unwrap(), "BREAK: 1 $0206\n.C:0206  d0 fa        bne $0202\n");
+        assert_eq!(monitor.emulator().cpu().x(), 3);
+
+        assert_eq!(monitor.execute("ignore 1 2").unwrap(), "BREAK: 1 exec $0206 if x == 3 (ignore 2) hits 1\n");
+        assert_eq!(
+            monitor.execute("disable 1").unwrap(),
+            "BREAK: 1 exec $0206 if x == 3 (disabled) (ignore 2) hits 1\n"
+        );
+        monitor.execute("watch store 0020 0021 if a & 1").unwrap();
+        assert_eq!(monitor.execute("g").unwrap(), "WATCH: 2 store $0021 = $11\n.C:0206  d0 fa        bne $0202\n");
+
+        monitor.execute("del 2").unwrap();
+        assert_eq!(monitor.execute("tbreak 0202").unwrap(), "BREAK: 3 exec $0202 (temporary) hits 0\n");
+        monitor.execute("g").unwrap();
+        assert_eq!(monitor.execute("break").unwrap(), "BREAK: 1 exec $0206 if x == 3 (disabled) (ignore 2) hits 1\n");
+
+        monitor.execute("r pc=0200").unwrap();
+        monitor.execute("break op 8a").unwrap();
+        assert_eq!(monitor.execute("g").unwrap(), "BREAK: 4 $0203\n.C:0203  8a           txa\n");
+
+        assert!(monitor.execute("del 9").is_err());
+        assert!(monitor.execute("break 0200 if a ==").is_err());
+        assert!(monitor.execute("watch 0300 0200").is_err());
+    }
+
+    #[test]
+    fn monitor_trace() {
+        let mut monitor = monitor();
+        monitor.execute("> 0200 a9 01 e8").unwrap();
+        monitor.execute("r pc=0200").unwrap();
+
+        let path = std::env::temp_dir().join(format!("mini-6502-remu-monitor-{}.log", std::process::id()));
+        monitor.execute(&format!("trace \"{}\"", path.display())).unwrap();
+        assert_eq!(monitor.execute("trace").unwrap(), "Tracing is on\n");
+        monitor.execute("z 2").unwrap();
+        monitor.execute("trace off").unwrap();
+        assert_eq!(monitor.execute("trace").unwrap(), "Tracing is off\n");
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0200  A9 01     LDA #$01"));
+        assert!(lines[1].starts_with("0202  E8        INX"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn monitor_symbols() {
+        let mut monitor = monitor();
+
+        // $0200: main: LDX #$00; loop: INX; BNE loop; .byte $02
+        monitor.execute("> 0200 a2 00 e8 d0 fd 02").unwrap();
+        monitor.execute("r pc=0200").unwrap();
+        let path = std::env::temp_dir().join(format!("monitor_{}.lbl", std::process::id()));
+        std::fs::write(&path, "al C:0200 .main\nal C:0202 .loop\n").unwrap();
+        assert_eq!(monitor.execute(&format!("sym \"{}\"", path.display())).unwrap(), "Loaded 2 symbols\n");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(monitor.execute("sym").unwrap(), "main = $0200\nloop = $0202\n");
+
+        assert_eq!(
+            monitor.execute("d .main 0203").unwrap(),
+            "main:\n.C:0200  a2 00        ldx #$00\nloop:\n.C:0202  e8           inx\n.C:0203  d0 fd        bne loop\n"
+        );
+        monitor.execute("break .loop if x == 2").unwrap();
+        assert_eq!(monitor.execute("g").unwrap(), "BREAK: 1 $0202 (loop)\nloop:\n.C:0202  e8           inx\n");
+        monitor.execute("del").unwrap();
+
+        // Errors name the address too
+        let mut output = Vec::new();
+        monitor.run("g\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("?Illegal opcode $02 at $0205 (loop+3)\n"), "{}", output);
+        assert!(monitor.execute("sym \"/nonexistent/file.lbl\"").is_err());
+    }
+
+    #[test]
+    fn monitor_state() {
+        let mut monitor = monitor();
+        let path = std::env::temp_dir().join(format!("monitor_{}.sav", std::process::id()));
+
+        // $0200: INX; INX; INX
+        monitor.execute("> 0200 e8 e8 e8").unwrap();
+        monitor.execute("r pc=0200").unwrap();
+        monitor.execute("z").unwrap();
+        monitor.execute(&format!("state save \"{}\"", path.display())).unwrap();
+        monitor.execute("z 2").unwrap();
+        monitor.execute("> 0200 00").unwrap();
+        let registers = monitor.execute(&format!("state load \"{}\"", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(registers.contains("0201"), "{}", registers);
+        assert_eq!(monitor.emulator.cpu().x(), 1);
+        assert_eq!(monitor.read(0x0200).unwrap(), 0xe8);
+
+        assert!(monitor.execute("state load \"/nonexistent/file.sav\"").is_err());
+        assert!(monitor.execute("state").is_err());
+    }
+
+    #[test]
+    fn monitor_record() {
+        let mut monitor = monitor();
+        let path = std::env::temp_dir().join(format!("monitor_{}.rpl", std::process::id()));
+
+        // $0200: INX; INX; INX
+        monitor.execute("> 0200 e8 e8 e8").unwrap();
+        monitor.execute("r pc=0200").unwrap();
+        assert_eq!(monitor.execute("record").unwrap(), "Not recording\n");
+        assert_eq!(monitor.execute("record start").unwrap(), "Recording, 0 input events\n");
+        monitor.execute("z 2").unwrap();
+        assert_eq!(monitor.execute(&format!("record stop \"{}\"", path.display())).unwrap(), "Not recording\n");
+
+        let registers = monitor.execute(&format!("record play \"{}\"", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(registers.contains("0200"), "{}", registers);
+        assert_eq!(monitor.execute("record").unwrap(), "Replaying, 0 of 0 input events delivered\n");
+        monitor.execute("record stop").unwrap();
+
+        assert!(monitor.execute("record play \"/nonexistent/file.rpl\"").is_err());
+        assert!(monitor.execute("record pause").is_err());
+    }
+
+    #[test]
+    fn monitor_history() {
+        let mut monitor = monitor();
+
+        // $0200: LDX #$00; loop: INX; STX $10; BNE loop
+        monitor.execute("> 0200 a2 00 e8 86 10 d0 fb").unwrap();
+        monitor.execute("r pc=0200").unwrap();
+        assert_eq!(monitor.execute("back").unwrap_err().to_string(), "History is off");
+        assert_eq!(
+            monitor.execute("history on 100 10").unwrap(),
+            "History of instructions $0 to $0, window $100, checkpoint every $10\n"
+        );
+
+        monitor.execute("z 20").unwrap();
+        assert_eq!(monitor.execute("back 2").unwrap(), ".C:0205  d0 fb        bne $0202\n");
+        assert_eq!(monitor.emulator.cpu().x(), 0x0a);
+        assert_eq!(
+            monitor.execute("back store 10").unwrap(),
+            "STORE: $0010 = $0a by instruction $1d\n.C:0203  86 10        stx $10\n"
+        );
+        assert_eq!(monitor.read(0x0010).unwrap(), 0x09);
+        assert_eq!(monitor.execute("back store 20 30").unwrap(), "No store to that memory in the history\n");
+        assert!(monitor.execute("back 100").is_err());
+        assert_eq!(monitor.execute("history off").unwrap(), "History is off\n");
+    }
+}
@@ -0,0 +1,348 @@
+/*!
+ * ca65 Debug Information
+ *
+ * Reads the debug information file that ld65 writes with `--dbgfile`. Each line is a record type followed by a tab and
+ * comma separated key=value fields:
+ *
+ *     file    id=0,name="main.s",size=1342,mtime=0x65a1b2c3,mod=0
+ *     seg     id=0,name="CODE",start=0x008000,size=0x0123,addrsize=absolute,type=ro,oname="rom.bin",ooffs=0
+ *     span    id=0,seg=0,start=0,size=2
+ *     line    id=0,file=0,line=12,span=0
+ *     sym     id=0,name="main",addrsize=absolute,scope=0,def=3,val=0x8000,seg=0,type=lab
+ *
+ * Only the files, segments, spans, lines and symbols are used: enough to map addresses to source lines and back, and
+ * to name addresses. Everything else in the file is skipped.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugInfoError {
+    Io(String),
+    // A line of the file that could not be understood, with its number
+    Syntax(usize, String)
+}
+
+impl fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugInfoError::Io(message) => write!(f, "{}", message),
+            DebugInfoError::Syntax(line, message) => write!(f, "Line {}: {}", line, message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub id: u32,
+    pub name: String
+}
+
+// A source line and the addresses of the code it generated, as an inclusive range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineInfo {
+    pub file: u32,
+    pub line: u32,
+    pub start: u16,
+    pub end: u16,
+    // The line is inside a macro definition, and the code came from expanding it
+    pub macro_expansion: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugSymbol {
+    pub name: String,
+    pub value: u16,
+    pub zeropage: bool,
+    // A label in a segment rather than a constant defined with = or .set
+    pub label: bool
+}
+
+#[derive(Debug)]
+pub struct DebugInfo {
+    files: Vec<SourceFile>,
+    lines: Vec<LineInfo>,
+    symbols: Vec<DebugSymbol>
+}
+
+// Split the fields of a record, keeping commas inside quoted strings
+fn fields(text: &str) -> Vec<(&str, &str)> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&text[start..]);
+    fields
+        .into_iter()
+        .filter_map(|field| field.split_once('='))
+        .map(|(key, value)| (key, value.trim_matches('"')))
+        .collect()
+}
+
+fn number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok()
+    }
+}
+
+// Normalize a path for comparison, so that Windows and Unix separators match
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+struct Record<'a> {
+    line: usize,
+    fields: Vec<(&'a str, &'a str)>
+}
+
+impl<'a> Record<'a> {
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.fields.iter().find(|(name, _)| *name == key).map(|(_, value)| *value)
+    }
+
+    fn number(&self, key: &str) -> Result<Option<u32>, DebugInfoError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => number(value)
+                .map(Some)
+                .ok_or_else(|| DebugInfoError::Syntax(self.line, format!("Bad number for {}: {}", key, value)))
+        }
+    }
+
+    fn required(&self, key: &str) -> Result<u32, DebugInfoError> {
+        self.number(key)?.ok_or_else(|| DebugInfoError::Syntax(self.line, format!("Missing {}", key)))
+    }
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo { files: Vec::new(), lines: Vec::new(), symbols: Vec::new() }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DebugInfo, DebugInfoError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| DebugInfoError::Io(format!("Unable to read {}: {}", path.display(), error)))?;
+        DebugInfo::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<DebugInfo, DebugInfoError> {
+        let mut files = Vec::new();
+        // Segment start addresses by id, and spans as (segment, start, size) by id
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut lines = Vec::new();
+        let mut symbols = Vec::new();
+
+        let records = text.lines().enumerate().filter_map(|(index, line)| {
+            let (kind, rest) = line.trim().split_once(char::is_whitespace)?;
+            Some((kind, Record { line: index + 1, fields: fields(rest.trim()) }))
+        });
+        for (kind, record) in records {
+            match kind {
+                "file" => {
+                    let name = record.get("name").unwrap_or_default();
+                    files.push(SourceFile { id: record.required("id")?, name: String::from(name) });
+                }
+                "seg" => {
+                    segments.insert(record.required("id")?, record.required("start")?);
+                }
+                "span" => {
+                    let span = (record.required("seg")?, record.required("start")?, record.required("size")?);
+                    spans.insert(record.required("id")?, span);
+                }
+                "line" => lines.push(record),
+                "sym" => {
+                    // Imports have no value of their own, they refer to the export
+                    let Some(value) = record.number("val")? else {
+                        continue;
+                    };
+                    symbols.push(DebugSymbol {
+                        name: String::from(record.get("name").unwrap_or_default()),
+                        value: value as u16,
+                        zeropage: record.get("addrsize") == Some("zeropage"),
+                        label: record.get("type") == Some("lab")
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // Lines refer to spans, which refer to segments, and any of them can come first in the file
+        let mut line_info = Vec::new();
+        for record in lines {
+            let Some(span_list) = record.get("span") else {
+                continue;
+            };
+            let file = record.required("file")?;
+            let line = record.required("line")?;
+            // Type 1 is an assembler source line that came from a macro, type 2 is a line in the macro itself
+            let macro_expansion = record.number("type")?.unwrap_or(0) == 2;
+            for span in span_list.split('+') {
+                let id =
+                    number(span).ok_or_else(|| DebugInfoError::Syntax(record.line, format!("Bad span: {}", span)))?;
+                let &(segment, start, size) = spans
+                    .get(&id)
+                    .ok_or_else(|| DebugInfoError::Syntax(record.line, format!("Unknown span: {}", id)))?;
+                let base = *segments
+                    .get(&segment)
+                    .ok_or_else(|| DebugInfoError::Syntax(record.line, format!("Unknown segment: {}", segment)))?;
+                if size == 0 {
+                    continue;
+                }
+                let range = base.checked_add(start).and_then(|start| Some((start, start.checked_add(size - 1)?)));
+                let (start, end) = match range {
+                    Some((start, end)) if end <= 0xffff => (start as u16, end as u16),
+                    _ => return Err(DebugInfoError::Syntax(record.line, format!("Span out of range: {}", id)))
+                };
+                line_info.push(LineInfo { file, line, start, end, macro_expansion });
+            }
+        }
+        line_info.sort_by_key(|info| (info.start, info.end));
+
+        Ok(DebugInfo { files, lines: line_info, symbols })
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    pub fn lines(&self) -> &[LineInfo] {
+        &self.lines
+    }
+
+    pub fn symbols(&self) -> &[DebugSymbol] {
+        &self.symbols
+    }
+
+    pub fn file(&self, id: u32) -> Option<&SourceFile> {
+        self.files.iter().find(|file| file.id == id)
+    }
+
+    // Find a source file by path. The names ld65 records are as they were given to ca65, usually relative, so a path
+    // matches a file if it ends with the file's name.
+    pub fn find_file(&self, path: &str) -> Option<&SourceFile> {
+        let path = normalize(path);
+        self.files.iter().find(|file| {
+            let name = normalize(&file.name);
+            path == name || path.ends_with(&format!("/{}", name.trim_start_matches("./")))
+        })
+    }
+
+    // The source line that generated the code at an address. Lines in the source file proper are preferred over lines
+    // inside macro definitions, and a narrower range over a wider one.
+    pub fn line_at(&self, address: u16) -> Option<&LineInfo> {
+        self.lines
+            .iter()
+            .filter(|info| (info.start..=info.end).contains(&address))
+            .min_by_key(|info| (info.macro_expansion, info.end - info.start))
+    }
+
+    // The addresses where the code for a source line starts, for setting breakpoints on it
+    pub fn addresses(&self, file: u32, line: u32) -> Vec<u16> {
+        let mut addresses = self
+            .lines
+            .iter()
+            .filter(|info| info.file == file && info.line == line && !info.macro_expansion)
+            .map(|info| info.start)
+            .collect::<Vec<u16>>();
+        addresses.dedup();
+        addresses
+    }
+}
+
+impl Default for DebugInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBUG_INFO: &str = "version\tmajor=2,minor=0
+info\tcsym=0,file=2,lib=0,line=5,mod=1,scope=1,seg=2,span=5,sym=4,type=1
+file\tid=0,name=\"src/main.s\",size=300,mtime=0x65a1b2c3,mod=0
+file\tid=1,name=\"macros.inc\",size=100,mtime=0x65a1b2c3,mod=0
+line\tid=0,file=0,line=4,span=0
+line\tid=1,file=0,line=5,span=1
+line\tid=2,file=0,line=6,type=1,span=2+3
+line\tid=3,file=1,line=2,type=2,span=2
+line\tid=4,file=0,line=1
+mod\tid=0,name=\"main.o\",file=0
+seg\tid=0,name=\"CODE\",start=0x008000,size=0x0009,addrsize=absolute,type=ro,oname=\"rom.bin\",ooffs=0
+seg\tid=1,name=\"ZEROPAGE\",start=0x000010,size=0x0002,addrsize=zeropage,type=rw
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=0,start=5,size=2
+span\tid=3,seg=0,start=7,size=2
+span\tid=4,seg=1,start=0,size=2
+scope\tid=0,name=\"\",mod=0,size=9,span=0
+sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=0,val=0x8000,seg=0,type=lab
+sym\tid=1,name=\"counter\",addrsize=zeropage,scope=0,def=1,val=0x10,seg=1,type=lab
+sym\tid=2,name=\"SCREEN\",addrsize=absolute,scope=0,def=2,val=0x6000,type=equ
+sym\tid=3,name=\"imported\",addrsize=absolute,scope=0,ref=3,type=imp
+";
+
+    #[test]
+    fn debug_info() {
+        let info = DebugInfo::parse(DEBUG_INFO).unwrap();
+        assert_eq!(info.files().len(), 2);
+        assert_eq!(info.lines().len(), 5);
+        assert_eq!(info.symbols().len(), 3);
+        assert_eq!(
+            info.symbols()[1],
+            DebugSymbol { name: String::from("counter"), value: 0x10, zeropage: true, label: true }
+        );
+        assert!(!info.symbols()[2].label);
+
+        assert_eq!(info.find_file("/home/user/project/src/main.s").map(|file| file.id), Some(0));
+        assert_eq!(info.find_file("C:\\project\\src\\main.s").map(|file| file.id), Some(0));
+        assert_eq!(info.find_file("/home/user/project/main.s"), None);
+
+        let line = info.line_at(0x8003).unwrap();
+        assert_eq!((line.file, line.line, line.start, line.end), (0, 5, 0x8002, 0x8004));
+        // The macro invocation wins over the line in the macro definition
+        assert_eq!(info.line_at(0x8005).unwrap().line, 6);
+        assert_eq!(info.line_at(0x8008).unwrap().line, 6);
+        assert_eq!(info.line_at(0x8009), None);
+
+        assert_eq!(info.addresses(0, 6), vec![0x8005, 0x8007]);
+        assert_eq!(info.addresses(1, 2), vec![]);
+        assert_eq!(info.addresses(0, 1), vec![]);
+    }
+
+    #[test]
+    fn debug_info_errors() {
+        assert_eq!(
+            DebugInfo::parse("span\tid=0,seg=0,start=0,size=2\nline\tid=0,file=0,line=1,span=0").unwrap_err(),
+            DebugInfoError::Syntax(2, String::from("Unknown segment: 0"))
+        );
+        assert_eq!(
+            DebugInfo::parse("seg\tid=0,start=zz").unwrap_err().to_string(),
+            "Line 1: Bad number for start: zz"
+        );
+
+        // Spans must lie within the address space, however large the numbers
+        let text = "seg\tid=0,start=0xfff0\nspan\tid=0,seg=0,start=0x10,size=1\nline\tid=0,file=0,line=1,span=0";
+        assert_eq!(DebugInfo::parse(text).unwrap_err(), DebugInfoError::Syntax(3, String::from("Span out of range: 0")));
+        let text = "seg\tid=0,start=0xffffffff\nspan\tid=0,seg=0,start=1,size=1\nline\tid=0,file=0,line=1,span=0";
+        assert!(DebugInfo::parse(text).is_err());
+        let text = "seg\tid=0,start=0\nspan\tid=0,seg=0,start=0xff00,size=0xffffffff\nline\tid=0,file=0,line=1,span=0";
+        assert!(DebugInfo::parse(text).is_err());
+        assert!(matches!(DebugInfo::load("/nonexistent/file.dbg"), Err(DebugInfoError::Io(_))));
+    }
+}
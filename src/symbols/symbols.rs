@@ -0,0 +1,324 @@
+/*!
+ * Symbol Table
+ *
+ * Names for addresses, so that the disassembler, the tracer, the monitor and error messages can show `main+3` rather
+ * than `$c003`. Symbols can be loaded from:
+ * - ld65 debug information (`--dbgfile`, .dbg), which also maps addresses to source files and lines
+ * - VICE label files (.lbl, as written by ld65 `-Ln`), with lines like `al C:c003 .main`
+ * - plain listings with one `name = $addr` per line, where the address can also be 0x hex or decimal
+ *
+ * Labels name code and data, and an address after a label is shown as an offset from it. Constants, which only ld65
+ * debug information tells apart, are used for their exact value alone.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use crate::cpu::disassembler::*;
+use crate::symbols::debug_info::*;
+
+// How far past a label an address can be and still be shown as an offset from it
+const MAX_OFFSET: u16 = 0x100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolError {
+    Io(String),
+    // A line of the file that could not be understood, with its number
+    Syntax(usize, String)
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Io(message) => write!(f, "{}", message),
+            SymbolError::Syntax(line, message) => write!(f, "Line {}: {}", line, message)
+        }
+    }
+}
+
+impl From<DebugInfoError> for SymbolError {
+    fn from(error: DebugInfoError) -> SymbolError {
+        match error {
+            DebugInfoError::Io(message) => SymbolError::Io(message),
+            DebugInfoError::Syntax(line, message) => SymbolError::Syntax(line, message)
+        }
+    }
+}
+
+fn parse_value(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).or_else(|| text.strip_prefix("0X"));
+    match hex {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct SymbolTable {
+    names: HashMap<String, u16>,
+    // The first name given to each address, for labels and for constants
+    labels: BTreeMap<u16, String>,
+    constants: BTreeMap<u16, String>,
+    debug_info: Option<DebugInfo>
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { names: HashMap::new(), labels: BTreeMap::new(), constants: BTreeMap::new(), debug_info: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn clear(&mut self) {
+        *self = SymbolTable::new();
+    }
+
+    pub fn add_label(&mut self, name: &str, address: u16) {
+        self.names.insert(String::from(name), address);
+        self.labels.entry(address).or_insert_with(|| String::from(name));
+    }
+
+    pub fn add_constant(&mut self, name: &str, value: u16) {
+        self.names.insert(String::from(name), value);
+        self.constants.entry(value).or_insert_with(|| String::from(name));
+    }
+
+    // The address of a symbol
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.names.get(name).copied()
+    }
+
+    // The name of exactly this address, preferring a label to a constant
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).or_else(|| self.constants.get(&address)).map(String::as_str)
+    }
+
+    // Name an address, as an offset from the closest label before it if it has no name of its own
+    pub fn symbolize(&self, address: u16) -> Option<String> {
+        if let Some(name) = self.name(address) {
+            return Some(String::from(name));
+        }
+        let (&start, name) = self.labels.range(..address).next_back()?;
+        if address - start >= MAX_OFFSET {
+            return None;
+        }
+        Some(format!("{}+{}", name, address - start))
+    }
+
+    // An address for messages: `$c003 (main+3)`, or just `$c003` when it has no name
+    pub fn describe(&self, address: u16) -> String {
+        match self.symbolize(address) {
+            Some(name) => format!("${:04x} ({})", address, name),
+            None => format!("${:04x}", address)
+        }
+    }
+
+    // All the labels, in address order
+    pub fn labels(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.labels.iter().map(|(address, name)| (*address, name.as_str()))
+    }
+
+    // Every symbol with its value, in order of value and then name
+    pub fn symbols(&self) -> Vec<(&str, u16)> {
+        let mut symbols = self.names.iter().map(|(name, value)| (name.as_str(), *value)).collect::<Vec<(&str, u16)>>();
+        symbols.sort_by_key(|(name, value)| (*value, *name));
+        symbols
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    // The source file and line that generated the code at an address, when debug information is loaded
+    pub fn line(&self, address: u16) -> Option<(&str, u32)> {
+        let debug_info = self.debug_info.as_ref()?;
+        let line = debug_info.line_at(address)?;
+        Some((debug_info.file(line.file)?.name.as_str(), line.line))
+    }
+
+    // The addresses where the code for a source line starts
+    pub fn addresses(&self, path: &str, line: u32) -> Vec<u16> {
+        let Some(debug_info) = &self.debug_info else {
+            return Vec::new();
+        };
+        match debug_info.find_file(path) {
+            Some(file) => debug_info.addresses(file.id, line),
+            None => Vec::new()
+        }
+    }
+
+    // Load a file of symbols, picking the format from its extension. Symbols are added to those already loaded.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SymbolError> {
+        let path = path.as_ref();
+        let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        if extension.as_deref() == Some("dbg") {
+            self.add_debug_info(DebugInfo::load(path)?);
+            return Ok(());
+        }
+
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| SymbolError::Io(format!("Unable to read {}: {}", path.display(), error)))?;
+        match extension.as_deref() {
+            Some("lbl") | Some("vs") => self.parse_vice(&text),
+            _ => self.parse_labels(&text)
+        }
+    }
+
+    // Take the symbols from ld65 debug information, and keep it for mapping addresses to source lines
+    pub fn add_debug_info(&mut self, debug_info: DebugInfo) {
+        for symbol in debug_info.symbols() {
+            if symbol.label {
+                self.add_label(&symbol.name, symbol.value);
+            } else {
+                self.add_constant(&symbol.name, symbol.value);
+            }
+        }
+        self.debug_info = Some(debug_info);
+    }
+
+    // Add the labels from a VICE label file: `al C:c003 .main`, where the memory space and dot are optional
+    pub fn parse_vice(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (index, line) in text.lines().enumerate() {
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            match words.as_slice() {
+                [] => {}
+                ["al", address, name] => {
+                    let address = address.rsplit(':').next().unwrap_or_default();
+                    let value = u32::from_str_radix(address, 16)
+                        .map_err(|_| SymbolError::Syntax(index + 1, format!("Bad address: {}", address)))?;
+                    self.add_label(name.trim_start_matches('.'), value as u16);
+                }
+                _ => return Err(SymbolError::Syntax(index + 1, format!("Expected al address label: {}", line.trim())))
+            }
+        }
+        Ok(())
+    }
+
+    // Add the labels from a listing of `name = $addr` lines, with comments after a `;`
+    pub fn parse_labels(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(SymbolError::Syntax(index + 1, format!("Expected name = address: {}", line)));
+            };
+            // ca65's := and 64tass's := both mean the same here
+            let name = name.trim().trim_end_matches(':').trim();
+            let value = parse_value(value)
+                .filter(|_| !name.is_empty() && !name.contains(char::is_whitespace))
+                .ok_or_else(|| SymbolError::Syntax(index + 1, format!("Expected name = address: {}", line)))?;
+            self.add_label(name, value);
+        }
+        Ok(())
+    }
+}
+
+impl SymbolLookup for SymbolTable {
+    fn symbol(&self, address: u16) -> Option<String> {
+        self.symbolize(address)
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols() {
+        let mut symbols = SymbolTable::new();
+        assert!(symbols.is_empty());
+        symbols.add_label("main", 0xc000);
+        symbols.add_label("start", 0xc000);
+        symbols.add_label("loop", 0xc010);
+        symbols.add_constant("VIA", 0x6000);
+        assert_eq!(symbols.len(), 4);
+
+        assert_eq!(symbols.address("loop"), Some(0xc010));
+        assert_eq!(symbols.name(0xc000), Some("main"));
+        assert_eq!(symbols.symbolize(0xc003).as_deref(), Some("main+3"));
+        assert_eq!(symbols.symbolize(0xc010).as_deref(), Some("loop"));
+        assert_eq!(symbols.symbolize(0xc10f).as_deref(), Some("loop+255"));
+        assert_eq!(symbols.symbolize(0xc110), None);
+        assert_eq!(symbols.symbolize(0xbfff), None);
+
+        // Constants only name their exact value
+        assert_eq!(symbols.symbolize(0x6000).as_deref(), Some("VIA"));
+        assert_eq!(symbols.symbolize(0x6001), None);
+
+        assert_eq!(symbols.describe(0xc003), "$c003 (main+3)");
+        assert_eq!(symbols.describe(0x0200), "$0200");
+        assert_eq!(symbols.symbols()[0], ("VIA", 0x6000));
+        assert_eq!(symbols.labels().collect::<Vec<(u16, &str)>>(), vec![(0xc000, "main"), (0xc010, "loop")]);
+    }
+
+    #[test]
+    fn symbols_files() {
+        let mut symbols = SymbolTable::new();
+        symbols.parse_vice("al C:c003 .main\nal 00c010 .loop\n\nal 0010 counter\n").unwrap();
+        assert_eq!(symbols.address("main"), Some(0xc003));
+        assert_eq!(symbols.address("loop"), Some(0xc010));
+        assert_eq!(symbols.address("counter"), Some(0x0010));
+        assert_eq!(
+            symbols.parse_vice("al C:zz .bad"),
+            Err(SymbolError::Syntax(1, String::from("Bad address: zz")))
+        );
+        assert!(symbols.parse_vice("break c000").is_err());
+
+        symbols.parse_labels("; Zero page\nptr = $fb\nirq := 0xfffe  ; vector\nreset = 65532\n").unwrap();
+        assert_eq!(symbols.address("ptr"), Some(0x00fb));
+        assert_eq!(symbols.address("irq"), Some(0xfffe));
+        assert_eq!(symbols.address("reset"), Some(0xfffc));
+        let error = symbols.parse_labels("ptr $fb").unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Expected name = address: ptr $fb");
+        assert!(symbols.parse_labels("two words = $10").is_err());
+
+        let path = std::env::temp_dir().join(format!("symbols_{}.lbl", std::process::id()));
+        std::fs::write(&path, "al C:8000 .entry\n").unwrap();
+        symbols.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(symbols.address("entry"), Some(0x8000));
+        assert!(matches!(symbols.load("/nonexistent/labels.txt"), Err(SymbolError::Io(_))));
+    }
+
+    #[test]
+    fn symbols_debug_info() {
+        let debug_info = DebugInfo::parse(
+            "file\tid=0,name=\"main.s\",size=1,mtime=0,mod=0
+seg\tid=0,name=\"CODE\",start=0x008000,size=0x0004
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+line\tid=0,file=0,line=3,span=0
+line\tid=1,file=0,line=4,span=1
+sym\tid=0,name=\"main\",addrsize=absolute,val=0x8000,seg=0,type=lab
+sym\tid=1,name=\"PORT\",addrsize=absolute,val=0x6000,type=equ
+"
+        )
+        .unwrap();
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.line(0x8000), None);
+        symbols.add_debug_info(debug_info);
+
+        assert_eq!(symbols.symbolize(0x8003).as_deref(), Some("main+3"));
+        assert_eq!(symbols.symbolize(0x6001), None);
+        assert_eq!(symbols.line(0x8003), Some(("main.s", 4)));
+        assert_eq!(symbols.addresses("/src/main.s", 4), vec![0x8002]);
+        assert_eq!(symbols.addresses("/src/other.s", 4), vec![]);
+        assert!(symbols.debug_info().is_some());
+    }
+}